@@ -0,0 +1,8 @@
+fn main() {
+    // generate the gRPC server code from the protobuf definitions
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/bwt.proto"], &["proto"])
+        .expect("failed compiling the protobuf definitions");
+}
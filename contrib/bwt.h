@@ -5,9 +5,36 @@
 
 #define BWT_OK 0
 #define BWT_ERR -1
+#define BWT_ERR_BUFFER_TOO_SMALL -2
+
+typedef struct BwtHandle BwtHandle;
 
 int32_t bwt_start(const char *json_config,
                   void (*callback)(const char*, float, const char*),
                   void *shutdown_out);
 
 int32_t bwt_shutdown(void *shutdown_ptr);
+
+int32_t bwt_boot(const char *json_config, BwtHandle **handle_out);
+
+int32_t bwt_stop(BwtHandle *handle);
+
+int32_t bwt_scripthash_history(const BwtHandle *handle,
+                               const char *scripthash,
+                               char *buf,
+                               size_t buf_len,
+                               size_t *len_out);
+
+int32_t bwt_scripthash_utxos(const BwtHandle *handle,
+                             const char *scripthash,
+                             char *buf,
+                             size_t buf_len,
+                             size_t *len_out);
+
+int32_t bwt_scripthash_balance(const BwtHandle *handle,
+                               const char *scripthash,
+                               char *buf,
+                               size_t buf_len,
+                               size_t *len_out);
+
+const char *bwt_last_error(void);
@@ -10,7 +10,7 @@ fn main() -> Result<()> {
         bitcoind_dir: Some("/home/satoshi/.bitcoin".into()),
         bitcoind_wallet: Some("bwt".into()),
         electrum_rpc_addr: Some("127.0.0.1:0".parse().unwrap()),
-        descriptors: vec![(my_desc.parse().unwrap(), RescanSince::Timestamp(0))],
+        descriptors: vec![(my_desc.parse().unwrap(), RescanSince::Timestamp(0).into())],
         verbose: 2,
         ..Default::default()
     };
@@ -22,7 +22,7 @@ fn main() -> Result<()> {
     // The index is now ready for querying
     let query = app.query();
     log::info!("synced up to {:?}", query.get_tip()?);
-    log::info!("utxos: {:?}", query.list_unspent(None, 0, None)?);
+    log::info!("utxos: {:?}", query.list_unspent(None, 0, 0, None)?);
     log::info!("electrum server running on {}", app.electrum_addr());
 
     // Start syncing new blocks/transactions in the background
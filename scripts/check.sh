@@ -5,10 +5,19 @@ feature_combos="CH CE CEH CET CHT CHW CEW CEHT CEHW CETW CHTW CEHTW"
 
 # Some simple combos with `ffi` and no `cli`
 feature_combos="$feature_combos FE FHT"
+
+# gRPC with and without the other servers
+feature_combos="$feature_combos CG CEHG"
+
+# zmq
+feature_combos="$feature_combos CEZ CEHZ"
+
+# metrics (implies http)
+feature_combos="$feature_combos CM CEM"
 # TODO test more `ffi` and `extra` combos
 
 for features in $feature_combos; do
-  features=`echo $features | sed 's/H/http /; s/E/electrum /; s/W/webhooks /; s/T/track-spends /; s/C/cli /; s/F/ffi /;'`
+  features=`echo $features | sed 's/H/http /; s/E/electrum /; s/W/webhooks /; s/T/track-spends /; s/C/cli /; s/F/ffi /; s/G/grpc /; s/Z/zmq /; s/M/metrics /;'`
   echo "Checking $features"
   cargo check --no-default-features --features "$features"
 done
@@ -1,88 +1,159 @@
+use std::cmp;
+use std::collections::BTreeSet;
 use std::sync::{mpsc, Arc, RwLock};
-use std::{net, thread, time};
+use std::{net, path, thread, time};
 
-use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+use bitcoincore_rpc::{self as rpc, RpcApi};
 
-use crate::util::{banner, debounce_sender};
+use crate::error::{BwtError, Context, Error};
+use crate::types::RescanSince;
+use crate::util::bitcoincore_ext::{RpcClient, ScanningResult, WalletRpcs};
+use crate::util::{banner, debounce_sender, SyncIntervals};
 use crate::{Config, Indexer, Query, Result, WalletWatcher};
 
 #[cfg(feature = "electrum")]
 use crate::electrum::ElectrumServer;
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcServer;
 #[cfg(feature = "http")]
 use crate::http::HttpServer;
 #[cfg(unix)]
 use crate::listener;
+#[cfg(all(feature = "http", feature = "webhooks"))]
+use crate::monitor::AddressMonitor;
 #[cfg(feature = "webhooks")]
-use crate::webhooks::WebHookNotifier;
+use crate::webhooks::{RetryPolicy, WebHookNotifier};
+#[cfg(feature = "zmq")]
+use crate::zmq_listener::ZmqListener;
 
-const DEBOUNCE_SEC: u64 = 7;
+// the maximum delay between reconnection attempts while bitcoind is unreachable
+const MAX_RECONNECT_DELAY: time::Duration = time::Duration::from_secs(300);
 
 pub struct App {
     config: Config,
     indexer: Arc<RwLock<Indexer>>,
     query: Arc<Query>,
     sync_chan: (mpsc::Sender<()>, mpsc::Receiver<()>),
+    intervals: Arc<SyncIntervals>,
 
     #[cfg(feature = "electrum")]
     electrum: ElectrumServer,
     #[cfg(feature = "http")]
     http: HttpServer,
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcServer>,
     #[cfg(feature = "webhooks")]
     webhook: Option<WebHookNotifier>,
+    #[cfg(all(feature = "http", feature = "webhooks"))]
+    monitor: Arc<AddressMonitor>,
+    #[cfg(feature = "zmq")]
+    zmq: Option<ZmqListener>,
 }
 
 impl App {
-    pub fn boot(config: Config) -> Result<Self> {
+    pub fn boot(mut config: Config) -> Result<Self> {
         debug!("{:?}", config);
 
-        let watcher = WalletWatcher::from_config(
-            &config.descriptors[..],
-            &config.xpubs[..],
-            &config.bare_xpubs[..],
-            config.network,
-            config.gap_limit,
-            config.initial_import_size,
-        )?;
+        let watcher = make_watcher(&mut config)?;
 
-        let rpc = Arc::new(RpcClient::new(
-            config.bitcoind_url(),
-            config.bitcoind_auth()?,
-        )?);
-        let indexer = Arc::new(RwLock::new(Indexer::new(rpc.clone(), watcher)));
-        let query = Arc::new(Query::new((&config).into(), rpc.clone(), indexer.clone()));
+        let mut wallet_rpcs = make_wallet_rpcs(&config, &watcher)?;
+        load_wallets(&config, &mut wallet_rpcs, /*dry_run=*/ false)?;
 
-        if let Some(bitcoind_wallet) = &config.bitcoind_wallet {
-            load_wallet(&rpc, bitcoind_wallet)?;
-        }
+        let rpc = wallet_rpcs.default_rpc().clone();
+        let indexer = Arc::new(RwLock::new(Indexer::new(wallet_rpcs.clone(), watcher)));
+        let query = Arc::new(Query::new((&config).into(), rpc.clone(), indexer.clone())?);
+
+        wait_bitcoind(&wallet_rpcs)?;
 
-        wait_bitcoind(&rpc)?;
+        indexer
+            .read()
+            .unwrap()
+            .watcher()
+            .check_orphaned_labels(&wallet_rpcs, config.relabel_orphaned)?;
 
         if config.startup_banner {
             println!("{}", banner::get_welcome_banner(&query, false)?);
         }
 
-        // do an initial sync without keeping track of updates
-        indexer.write().unwrap().initial_sync()?;
-
+        let intervals = Arc::new(SyncIntervals::new(
+            config.poll_interval,
+            config.sync_debounce,
+        ));
         let (sync_tx, sync_rx) = mpsc::channel();
         // debounce sync message rate to avoid excessive indexing when bitcoind catches up
-        let debounced_sync_tx = debounce_sender(sync_tx.clone(), DEBOUNCE_SEC);
+        let debounced_sync_tx = debounce_sender(sync_tx.clone(), intervals.clone());
 
-        #[cfg(feature = "electrum")]
-        let electrum = ElectrumServer::start(
-            config.electrum_rpc_addr(),
-            config.electrum_skip_merkle,
+        // the http server is started before the initial sync to make its progress available via
+        // GET /sync/status, with other requests being rejected until it's completed
+        #[cfg(all(feature = "http", feature = "webhooks"))]
+        let monitor = Arc::new(AddressMonitor::start(
+            config.monitors_file.clone(),
             query.clone(),
-        );
+        )?);
 
         #[cfg(feature = "http")]
         let http = HttpServer::start(
             config.http_server_addr,
             config.http_cors.clone(),
+            config.http_rpc_allowlist.clone(),
+            config.http_skip_merkle,
+            config.http_rate_limit,
+            // the electrum server is not started yet, use its configured port (unless it's
+            // an ephemeral one that's only known once bound)
+            #[cfg(feature = "electrum")]
+            Some(config.electrum_rpc_addr().port()).filter(|port| *port != 0),
+            #[cfg(feature = "webhooks")]
+            monitor.clone(),
             query.clone(),
+            intervals.clone(),
             debounced_sync_tx.clone(),
         );
 
+        // restore the index saved by the previous run (if still valid), so that the initial sync
+        // only has to catch up with what happened since
+        if let Some(index_file) = &config.index_file {
+            indexer
+                .write()
+                .unwrap()
+                .restore_snapshot(index_file)
+                .map_err(|e| warn!("failed restoring the index, syncing from scratch: {:#?}", e))
+                .ok();
+        }
+
+        // do an initial sync without keeping track of updates
+        indexer.write().unwrap().initial_sync()?;
+
+        if let Some(index_file) = &config.index_file {
+            save_index(&indexer, index_file);
+        }
+
+        #[cfg(feature = "electrum")]
+        let electrum = ElectrumServer::start(
+            config.electrum_rpc_addr(),
+            config.electrum_skip_merkle,
+            config.electrum_resume_subscriptions,
+            config.electrum_max_pending_requests,
+            config.electrum_rate_limit,
+            config.electrum_rate_limit_disconnect,
+            query.clone(),
+        )?;
+
+        #[cfg(feature = "grpc")]
+        let grpc = match config.grpc_server_addr {
+            Some(addr) => Some(GrpcServer::start(addr, query.clone())?),
+            None => None,
+        };
+
+        #[cfg(feature = "zmq")]
+        let zmq = if !config.zmq_endpoints.is_empty() {
+            Some(ZmqListener::start(
+                config.zmq_endpoints.clone(),
+                debounced_sync_tx.clone(),
+            )?)
+        } else {
+            None
+        };
+
         #[cfg(unix)]
         {
             if let Some(listener_path) = &config.unix_listener_path {
@@ -91,27 +162,103 @@ impl App {
         }
 
         #[cfg(feature = "webhooks")]
-        let webhook = config.webhook_urls.clone().map(WebHookNotifier::start);
+        let webhook = config.webhook_urls.clone().map(|urls| {
+            WebHookNotifier::start(
+                urls,
+                config.webhook_min_amount.clone(),
+                config.webhook_large_amount.clone(),
+                RetryPolicy {
+                    max_attempts: config.webhook_max_attempts,
+                    base_delay: config.webhook_retry_delay,
+                },
+                config.webhook_secret.clone(),
+                config.webhook_batch_size,
+                query.clone(),
+            )
+        });
 
         Ok(App {
             config,
             indexer,
             query,
             sync_chan: (sync_tx, sync_rx),
+            intervals,
             #[cfg(feature = "electrum")]
             electrum,
             #[cfg(feature = "http")]
             http,
+            #[cfg(feature = "grpc")]
+            grpc,
             #[cfg(feature = "webhooks")]
             webhook,
+            #[cfg(all(feature = "http", feature = "webhooks"))]
+            monitor,
+            #[cfg(feature = "zmq")]
+            zmq,
         })
     }
 
-    /// Start a sync loop blocking the current thread
+    /// Validate the configuration and report the wallets that would be tracked and the addresses
+    /// that would be imported into bitcoind (accounting for previous imports), without importing
+    /// anything or starting the servers.
+    pub fn dry_run(mut config: Config) -> Result<()> {
+        debug!("{:?}", config);
+
+        let mut watcher = make_watcher(&mut config)?;
+
+        let mut wallet_rpcs = make_wallet_rpcs(&config, &watcher)?;
+        let missing_wallets = load_wallets(&config, &mut wallet_rpcs, /*dry_run=*/ true)?;
+        wait_bitcoind(&wallet_rpcs)?;
+
+        watcher.check_orphaned_labels(&wallet_rpcs, false)?;
+        watcher.check_imports(&wallet_rpcs)?;
+
+        let plan = watcher.plan_imports(/*rescan=*/ true);
+        let total: u32 = plan.iter().map(|planned| planned.count()).sum();
+
+        for name in &missing_wallets {
+            println!("Would create watch-only bitcoind wallet {}", name);
+        }
+        println!("Tracking {} wallet(s):", plan.len());
+        for planned in &plan {
+            println!("- {}", planned.desc);
+            if let Some(max_imported) = planned.max_imported_index {
+                println!("  previously imported up to index {}", max_imported);
+            }
+            match (planned.import_range, planned.rescan_since) {
+                (Some((start, end)), Some(rescan_since)) => println!(
+                    "  would import {} address(es) at {} {}-{}, {}",
+                    planned.count(),
+                    if planned.is_ranged {
+                        "indexes"
+                    } else {
+                        "index"
+                    },
+                    start,
+                    end,
+                    fmt_rescan(rescan_since)
+                ),
+                _ => println!("  no imports needed"),
+            }
+        }
+        println!(
+            "Would import a total of {} address(es). Dry run completed, exiting.",
+            total
+        );
+
+        Ok(())
+    }
+
+    /// Start a sync loop blocking the current thread, until a message is sent over `shutdown_rx`
+    /// or its sender is dropped. Runs indefinitely if no shutdown receiver is provided.
+    ///
+    /// Signal handling is left to the caller, see `App::shutdown_signal()`.
     pub fn sync(&self, shutdown_rx: Option<mpsc::Receiver<()>>) {
-        let shutdown_rx = shutdown_rx
-            .map(|rx| self.pipe_shutdown(rx))
-            .or_else(|| self.default_shutdown_signal());
+        let shutdown_rx = shutdown_rx.map(|rx| self.pipe_shutdown(rx));
+
+        let mut last_saved = time::Instant::now();
+        // the delay until the next reconnection attempt, while bitcoind is unreachable
+        let mut reconnect_delay: Option<time::Duration> = None;
 
         loop {
             if let Some(shutdown_rx) = &shutdown_rx {
@@ -121,8 +268,28 @@ impl App {
                 }
             }
 
+            let result = Indexer::sync_shared(&self.indexer);
+
+            match (&result, reconnect_delay) {
+                (Err(e), delay) if is_connection_error(e) => {
+                    let delay = delay.map_or(self.intervals.poll_interval(), |delay| {
+                        cmp::min(delay * 2, MAX_RECONNECT_DELAY)
+                    });
+                    if reconnect_delay.is_none() {
+                        warn!("bitcoind is unreachable: {}", e);
+                    }
+                    info!("attempting to reconnect to bitcoind in {:?}", delay);
+                    reconnect_delay = Some(delay);
+                }
+                (_, Some(_)) => {
+                    info!("reconnected to bitcoind, resuming sync");
+                    reconnect_delay = None;
+                }
+                _ => (),
+            }
+
             #[allow(clippy::option_map_unit_fn)]
-            match self.indexer.write().unwrap().sync() {
+            match result {
                 Ok(updates) if !updates.is_empty() => {
                     #[cfg(feature = "electrum")]
                     self.electrum.send_updates(&updates);
@@ -130,22 +297,103 @@ impl App {
                     #[cfg(feature = "http")]
                     self.http.send_updates(&updates);
 
+                    #[cfg(feature = "grpc")]
+                    self.grpc.as_ref().map(|grpc| grpc.send_updates(&updates));
+
                     #[cfg(feature = "webhooks")]
                     self.webhook
                         .as_ref()
                         .map(|webhook| webhook.send_updates(&updates));
+
+                    self.check_memory_usage();
                 }
                 Ok(_) => (), // no updates
-                Err(e) => warn!("error while updating index: {:#?}", e),
+                Err(e) if reconnect_delay.is_none() => {
+                    warn!("error while updating index: {:#?}", e)
+                }
+                Err(_) => (), // connection errors are logged above
+            }
+
+            // checked on every run (rather than only following updates) to drop expired monitors
+            #[cfg(all(feature = "http", feature = "webhooks"))]
+            self.monitor
+                .check()
+                .map_err(|e| warn!("error while checking address monitors: {:#?}", e))
+                .ok();
+
+            if let Some(index_file) = &self.config.index_file {
+                if last_saved.elapsed() >= self.config.index_save_interval {
+                    save_index(&self.indexer, index_file);
+                    last_saved = time::Instant::now();
+                }
             }
 
-            // wait for poll_interval seconds, or until we receive a sync notification message,
-            // or until the shutdown signal is emitted
+            // wait for poll_interval seconds (or the reconnection delay), or until we receive a sync
+            // notification message, or until the shutdown signal is emitted
             self.sync_chan
                 .1
-                .recv_timeout(self.config.poll_interval)
+                .recv_timeout(reconnect_delay.unwrap_or_else(|| self.intervals.poll_interval()))
                 .ok();
         }
+
+        // save the index on shutdown so that the next run can pick up from here
+        if let Some(index_file) = &self.config.index_file {
+            save_index(&self.indexer, index_file);
+        }
+    }
+
+    // Warn when the index grows beyond the configured memory limit
+    fn check_memory_usage(&self) {
+        let limit = some_or_ret!(self.config.store_memory_limit);
+        let usage = self.query.get_store_stats().memory_usage / 1_000_000;
+        if usage > limit {
+            warn!(
+                "the index is using ~{} MB of memory, exceeding the configured limit of {} MB",
+                usage, limit
+            );
+        }
+    }
+
+    /// Start the sync loop in a background thread, returning its join handle. The app is shut
+    /// down once the sync loop terminates following the shutdown signal sent over `shutdown_rx`.
+    ///
+    /// Use `query()`, `electrum_addr()` and `http_addr()` before starting to get access to
+    /// the running servers.
+    pub fn start(self, shutdown_rx: mpsc::Receiver<()>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            self.sync(Some(shutdown_rx));
+            // dropping the app shuts down the servers
+        })
+    }
+
+    /// Stop the Electrum, HTTP and gRPC servers and the webhook delivery thread, waiting for their
+    /// threads to terminate (up to a timeout). Called automatically when the `App` is dropped.
+    ///
+    /// This does not stop a running `sync()` loop, which should be terminated first using its
+    /// shutdown signal.
+    pub fn shutdown(&mut self) {
+        debug!("shutting down");
+
+        #[cfg(feature = "electrum")]
+        self.electrum.shutdown();
+
+        #[cfg(feature = "http")]
+        self.http.shutdown();
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &mut self.grpc {
+            grpc.shutdown();
+        }
+
+        #[cfg(feature = "webhooks")]
+        if let Some(webhook) = &mut self.webhook {
+            webhook.shutdown();
+        }
+
+        #[cfg(feature = "zmq")]
+        if let Some(zmq) = &mut self.zmq {
+            zmq.shutdown();
+        }
     }
 
     /// Get the `Query` instance
@@ -163,6 +411,11 @@ impl App {
         self.http.addr()
     }
 
+    #[cfg(feature = "grpc")]
+    pub fn grpc_addr(&self) -> Option<net::SocketAddr> {
+        self.grpc.as_ref().map(GrpcServer::addr)
+    }
+
     // Pipe the shutdown receiver `rx` to trigger `sync_tx`. This is needed to start the next
     // sync loop run immediately, which will then process the shutdown signal itself. Without
     // this, the shutdown signal will only be noticed after a delay.
@@ -177,42 +430,207 @@ impl App {
         c_rx
     }
 
-    #[cfg(all(unix, feature = "signal_hook"))]
-    fn default_shutdown_signal(&self) -> Option<mpsc::Receiver<()>> {
+    /// Install a handler for SIGINT/SIGTERM, returning a receiver that can be passed to `sync()`
+    /// to shut down gracefully when they're received. This is opt-in, so that library users
+    /// embedding bwt can manage their own process lifecycle.
+    ///
+    /// Only available on unix with the `signal-hook` dependency (part of the `extra` feature),
+    /// returns None otherwise.
+    #[cfg(all(unix, feature = "signal-hook"))]
+    pub fn shutdown_signal() -> Option<mpsc::Receiver<()>> {
         use signal_hook::iterator::Signals;
 
         let signals = Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM]).unwrap();
         let (shutdown_tx, shutdown_rx) = mpsc::sync_channel(1);
-        let sync_tx = self.sync_chan.0.clone();
 
         thread::spawn(move || {
             let signal = signals.into_iter().next().unwrap();
             trace!("received shutdown signal {}", signal);
-            shutdown_tx.send(()).unwrap();
-            // Need to also trigger `sync_tx`, see rational above
-            sync_tx.send(()).unwrap();
+            shutdown_tx.send(()).ok();
         });
 
         Some(shutdown_rx)
     }
 
-    #[cfg(not(all(unix, feature = "signal_hook")))]
-    fn default_shutdown_signal(&self) -> Option<mpsc::Receiver<()>> {
+    #[cfg(not(all(unix, feature = "signal-hook")))]
+    pub fn shutdown_signal() -> Option<mpsc::Receiver<()>> {
         None
     }
 }
 
-// Load the specified wallet, ignore "wallet is already loaded" errors
-fn load_wallet(rpc: &RpcClient, name: &str) -> Result<()> {
+impl Drop for App {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Check whether the error is a transient failure to communicate with bitcoind (e.g. while it's
+// restarting), as opposed to errors reported by bitcoind or local ones
+fn is_connection_error(err: &Error) -> bool {
+    err.chain().any(|e| {
+        let rpc_err = e
+            .downcast_ref::<rpc::Error>()
+            .or_else(|| match e.downcast_ref() {
+                Some(BwtError::RpcProtocol(rpc_err)) => Some(rpc_err),
+                _ => None,
+            });
+        match rpc_err {
+            Some(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Hyper(_))) | Some(rpc::Error::Io(_)) => {
+                true
+            }
+            // RPC_IN_WARMUP, returned while bitcoind is starting up
+            Some(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(e))) => e.code == -28,
+            _ => false,
+        }
+    })
+}
+
+// Save the index to the specified file, logging errors without failing
+fn save_index(indexer: &RwLock<Indexer>, path: &path::Path) {
+    indexer
+        .read()
+        .unwrap()
+        .save_snapshot(path)
+        .map_err(|e| warn!("failed saving the index to {:?}: {:#?}", path, e))
+        .ok();
+}
+
+// Load the descriptors file and create the watcher for the configured wallets
+fn make_watcher(config: &mut Config) -> Result<WalletWatcher> {
+    config.load_descriptors_file()?;
+    config.guard_future_rescans()?;
+
+    WalletWatcher::from_config(
+        &config.descriptors[..],
+        &config.xpubs[..],
+        &config.bare_xpubs[..],
+        &config.payment_codes[..],
+        config.network,
+        config.gap_limit,
+        config.initial_import_size,
+        config.verify_full_descriptors,
+    )
+}
+
+fn fmt_rescan(rescan_since: RescanSince) -> String {
+    match rescan_since {
+        RescanSince::Now => "without rescanning".into(),
+        RescanSince::Timestamp(0) => "rescanning from the genesis block".into(),
+        RescanSince::Timestamp(timestamp) => format!("rescanning since timestamp {}", timestamp),
+    }
+}
+
+// Create the RPC clients for the default bitcoind wallet and for every other bitcoind wallet
+// that the tracked wallets were configured to be imported into
+fn make_wallet_rpcs(config: &Config, watcher: &WalletWatcher) -> Result<WalletRpcs> {
+    let make_rpc = |bitcoind_wallet: Option<&str>| -> Result<_> {
+        Ok(Arc::new(RpcClient::new(
+            config.bitcoind_wallet_url(bitcoind_wallet),
+            config.bitcoind_auth()?,
+        )?))
+    };
+
+    // the default client is only used as a wallet if a default bitcoind wallet was configured
+    // or if some of the tracked wallets don't specify one of their own
+    let default_wallet = config.bitcoind_wallet.is_some()
+        || watcher
+            .wallets()
+            .values()
+            .any(|wallet| wallet.bitcoind_wallet().is_none());
+    let mut wallet_rpcs =
+        WalletRpcs::new(make_rpc(config.bitcoind_wallet.as_deref())?, default_wallet);
+
+    let names: BTreeSet<&str> = watcher
+        .wallets()
+        .values()
+        .filter_map(|wallet| wallet.bitcoind_wallet())
+        .filter(|name| Some(*name) != config.bitcoind_wallet.as_deref())
+        .collect();
+    for name in names {
+        wallet_rpcs.add(name.to_string(), make_rpc(Some(name))?);
+    }
+    Ok(wallet_rpcs)
+}
+
+// Load the configured bitcoind wallets, creating the missing ones (unless disabled). In dry run
+// mode, missing wallets are not created but skipped, and returned so they can be reported.
+fn load_wallets(
+    config: &Config,
+    wallet_rpcs: &mut WalletRpcs,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    let create = config.create_wallet && !dry_run;
+    let mut missing = Vec::new();
+
+    if let Some(bitcoind_wallet) = &config.bitcoind_wallet {
+        if !load_wallet(wallet_rpcs.default_rpc(), bitcoind_wallet, create)? {
+            wallet_rpcs.skip(None);
+            missing.push(bitcoind_wallet.clone());
+        }
+    }
+    let named: Vec<(String, Arc<RpcClient>)> = wallet_rpcs
+        .named()
+        .map(|(name, rpc)| (name.clone(), rpc.clone()))
+        .collect();
+    for (name, rpc) in named {
+        if !load_wallet(&rpc, &name, create)? {
+            wallet_rpcs.skip(Some(&name));
+            missing.push(name);
+        }
+    }
+
+    ensure!(
+        config.create_wallet || missing.is_empty(),
+        "bitcoind wallet(s) {} not found (creating them is disabled by --no-create-wallet)",
+        missing.join(", ")
+    );
+    Ok(missing)
+}
+
+// Load the specified wallet, ignore "wallet is already loaded" errors. Wallets that don't exist are
+// created as watch-only if `create` is set, otherwise false is returned for them.
+fn load_wallet(rpc: &RpcClient, name: &str, create: bool) -> Result<bool> {
     match rpc.load_wallet(name) {
-        Ok(_) => Ok(()),
-        Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e))) if e.code == -4 => Ok(()),
+        Ok(_) => Ok(true),
+        // RPC_WALLET_ERROR, returned if the wallet is already loaded
+        Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e))) if e.code == -4 => Ok(true),
+        // RPC_WALLET_NOT_FOUND
+        Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e))) if e.code == -18 => {
+            if create {
+                create_wallet(rpc, name)?;
+            }
+            Ok(create)
+        }
         Err(e) => bail!(e),
     }
 }
 
-// wait for bitcoind to sync and finish rescanning
-fn wait_bitcoind(rpc: &RpcClient) -> Result<()> {
+// Create a blank watch-only wallet. Whether it's a descriptor wallet is left up to bitcoind's
+// defaults, both kinds are supported for importing.
+fn create_wallet(rpc: &RpcClient, name: &str) -> Result<()> {
+    let result: serde_json::Value = rpc
+        .call(
+            "createwallet",
+            &[
+                json!(name),
+                /*disable_private_keys=*/ json!(true),
+                /*blank=*/ json!(true),
+            ],
+        )
+        .with_context(|| format!("failed creating bitcoind wallet {}", name))?;
+    info!(
+        "created watch-only bitcoind wallet {} (disable with --no-create-wallet)",
+        name
+    );
+    if let Some(warning) = result["warning"].as_str().filter(|w| !w.is_empty()) {
+        warn!("createwallet {}: {}", name, warning);
+    }
+    Ok(())
+}
+
+// wait for bitcoind to sync and finish rescanning the wallets
+fn wait_bitcoind(wallet_rpcs: &WalletRpcs) -> Result<()> {
+    let rpc = wallet_rpcs.default_rpc();
     let netinfo = rpc.get_network_info()?;
     let mut bcinfo = rpc.get_blockchain_info()?;
     info!(
@@ -242,7 +660,7 @@ fn wait_bitcoind(rpc: &RpcClient) -> Result<()> {
         bcinfo = rpc.get_blockchain_info()?;
     }
     loop {
-        match check_scanning(rpc)? {
+        match wallet_rpcs.check_scanning()? {
             ScanningResult::NotScanning => break,
             ScanningResult::Unsupported => {
                 warn!("Your bitcoin node does not report the `scanning` status in `getwalletinfo`. It is recommended to upgrade to Bitcoin Core v0.19+ to enable this.");
@@ -262,31 +680,3 @@ fn wait_bitcoind(rpc: &RpcClient) -> Result<()> {
 
     Ok(())
 }
-
-fn check_scanning(rpc: &RpcClient) -> Result<ScanningResult> {
-    let mut wallet_info: serde_json::Value = rpc.call("getwalletinfo", &[])?;
-
-    // the "rescanning" field is only supported as of Bitcoin Core v0.19
-    let rescanning = some_or_ret!(
-        wallet_info.get_mut("scanning"),
-        Ok(ScanningResult::Unsupported)
-    );
-
-    Ok(if rescanning.as_bool() == Some(false) {
-        ScanningResult::NotScanning
-    } else {
-        let details = serde_json::from_value(rescanning.take())?;
-        ScanningResult::Scanning(details)
-    })
-}
-
-enum ScanningResult {
-    Scanning(ScanningDetails),
-    NotScanning,
-    Unsupported,
-}
-#[derive(Deserialize)]
-struct ScanningDetails {
-    duration: u64,
-    progress: f64,
-}
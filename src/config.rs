@@ -1,13 +1,21 @@
-use std::{net, path, time};
+use std::{fs, net, path, time};
 
 use bitcoin::Network;
 use bitcoincore_rpc::Auth as RpcAuth;
 
 use crate::error::{OptionExt, Result};
+use crate::fees::{FeeSource, FeeTable};
 use crate::query::QueryConfig;
 use crate::types::RescanSince;
 use crate::util::descriptor::ExtendedDescriptor;
+#[cfg(feature = "cli")]
+use crate::util::interval_from_secs;
+use crate::util::paymentcode::PaymentCode;
 use crate::util::xpub::XyzPubKey;
+use crate::wallet::WalletOptions;
+
+#[cfg(feature = "webhooks")]
+use crate::webhooks::{AmountThreshold, WebHookUrl};
 
 #[cfg(feature = "pretty_env_logger")]
 use {log::Level, pretty_env_logger::env_logger::Builder as LogBuilder};
@@ -57,6 +65,18 @@ pub struct Config {
     #[serde(default = "default_false")]
     pub timestamp: bool,
 
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Per-module log level directives, taking precedence over the verbosity level (e.g. bwt::indexer=trace,bwt::electrum::rpc=warn)",
+            env,
+            hide_env_values(true),
+            display_order(100)
+        )
+    )]
+    pub log_filter: Option<String>,
+
     #[cfg_attr(
         feature = "cli",
         structopt(
@@ -123,29 +143,51 @@ pub struct Config {
     )]
     pub bitcoind_cookie: Option<path::PathBuf>,
 
+    // XXX this is not settable as an env var due to https://github.com/clap-rs/clap/issues/1476
+    #[cfg_attr(feature = "cli", structopt(
+        long = "no-create-wallet",
+        help = "Don't create the bitcoind wallet(s) as watch-only wallets if they don't exist",
+        parse(from_flag = std::ops::Not::not),
+        display_order(35)
+    ))]
+    #[serde(default = "default_true")]
+    pub create_wallet: bool,
+
     #[cfg_attr(feature = "cli", structopt(
         short = "d",
         long = "descriptor",
-        help = "Descriptors to track (scans for history from the genesis by default, use <desc>@<yyyy-mm-dd> or <desc>@<unix-epoch> to specify a rescan timestmap, or <desc>@none to disable rescan)",
+        help = "Descriptors to track (scans for history from the genesis by default, use <desc>@<yyyy-mm-dd> or <desc>@<unix-epoch> to specify a rescan timestmap, or <desc>@none to disable rescan. Use <desc>@gap=<n>,import=<n> to set a gap limit and initial import size for this descriptor, optionally along with the rescan as <desc>@<rescan>,gap=<n>)",
         parse(try_from_str = parse_desc),
         env, hide_env_values(true),
         use_delimiter(true), value_delimiter(";"),
         display_order(20)
     ))]
     #[serde(default = "default_empty_vec")]
-    pub descriptors: Vec<(ExtendedDescriptor, RescanSince)>,
+    pub descriptors: Vec<(ExtendedDescriptor, WalletOptions)>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to a file with descriptors to track, one per line (in the same format as --descriptor), or the json output of bitcoind's listdescriptors",
+            env,
+            hide_env_values(true),
+            display_order(23)
+        )
+    )]
+    pub descriptors_file: Option<path::PathBuf>,
 
     #[cfg_attr(feature = "cli", structopt(
         short = "x",
         long = "xpub",
-        help = "xpubs to track (represented as two separate descriptors for the internal/external chains, supports <xpub>@<rescan-time>)",
+        help = "xpubs to track (represented as two separate descriptors for the internal/external chains, supports <xpub>@<rescan-time>,gap=<n>,import=<n>)",
         parse(try_from_str = parse_xpub),
         env, hide_env_values(true),
         use_delimiter(true), value_delimiter(";"),
         display_order(21)
     ))]
     #[serde(default = "default_empty_vec")]
-    pub xpubs: Vec<(XyzPubKey, RescanSince)>,
+    pub xpubs: Vec<(XyzPubKey, WalletOptions)>,
 
     #[cfg_attr(feature = "cli", structopt(
         short = "X",
@@ -156,14 +198,24 @@ pub struct Config {
         display_order(22)
     ))]
     #[serde(default = "default_empty_vec")]
-    pub bare_xpubs: Vec<(XyzPubKey, RescanSince)>,
+    pub bare_xpubs: Vec<(XyzPubKey, WalletOptions)>,
+
+    #[cfg_attr(feature = "cli", structopt(
+        long = "payment-code",
+        help = "BIP 47 payment codes to track the notification address of (supports <payment-code>@<rescan-time>)",
+        parse(try_from_str = parse_payment_code),
+        env, hide_env_values(true), use_delimiter(true),
+        display_order(24)
+    ))]
+    #[serde(default = "default_empty_vec")]
+    pub payment_codes: Vec<(PaymentCode, WalletOptions)>,
 
     #[cfg_attr(
         feature = "cli",
         structopt(
             short = "g",
             long,
-            help = "Gap limit for importing child addresses",
+            help = "Gap limit for importing child addresses (for wallets that don't specify their own)",
             default_value = "20",
             env,
             hide_env_values(true),
@@ -178,7 +230,7 @@ pub struct Config {
         structopt(
             short = "G",
             long,
-            help = "The batch size for importing addresses during the initial sync (set higher to reduce number of rescans)",
+            help = "The batch size for importing addresses during the initial sync (set higher to reduce number of rescans, for wallets that don't specify their own)",
             default_value = "350",
             env,
             hide_env_values(true),
@@ -188,6 +240,54 @@ pub struct Config {
     #[serde(default = "default_initial_import_size")]
     pub initial_import_size: u32,
 
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Relabel the bitcoind addresses of wallets that are no longer tracked (e.g. after editing a descriptor), so that they stop being picked up",
+            display_order(53)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub relabel_orphaned: bool,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Verify that wallets with the same descriptor checksum are duplicates using a hash of their full descriptor, rejecting distinct descriptors with colliding checksums (which labels cannot tell apart)",
+            display_order(54)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub verify_full_descriptors: bool,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Fail on rescan timestamps that are in the future, instead of warning and skipping the rescan",
+            display_order(55)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub reject_future_rescan: bool,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Validate the configuration, connect to bitcoind and report the wallets that would be tracked and the addresses that would be imported, then exit without importing anything",
+            display_order(56)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub dry_run: bool,
+
     //// TODO
     //#[structopt(
     //short,
@@ -223,6 +323,71 @@ pub struct Config {
     #[serde(default = "default_false")]
     pub electrum_skip_merkle: bool,
 
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg(feature = "electrum")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Keep the subscriptions of disconnected clients and restore them when they reconnect with the same session token",
+            display_order(42)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub electrum_resume_subscriptions: bool,
+
+    #[cfg(feature = "electrum")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Maximum number of requests a single Electrum session may have pending, with excess ones rejected with an error [default: unlimited]",
+            env,
+            hide_env_values(true),
+            display_order(43)
+        )
+    )]
+    pub electrum_max_pending_requests: Option<usize>,
+
+    #[cfg(feature = "electrum")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Maximum number of requests per minute a single Electrum session may make, with excess ones rejected with an error [default: unlimited]",
+            env,
+            hide_env_values(true),
+            display_order(43)
+        )
+    )]
+    pub electrum_rate_limit: Option<u32>,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg(feature = "electrum")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Disconnect Electrum sessions that exceed the --electrum-rate-limit instead of rejecting their excess requests",
+            requires = "electrum-rate-limit",
+            display_order(43)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub electrum_rate_limit_disconnect: bool,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Externally reachable hostname or Tor onion address of this server, advertised in the welcome banner and in Electrum's server.features",
+            env,
+            hide_env_values(true),
+            display_order(44)
+        )
+    )]
+    pub public_host: Option<String>,
+
     #[cfg(feature = "http")]
     #[cfg_attr(
         feature = "cli",
@@ -244,7 +409,7 @@ pub struct Config {
         feature = "cli",
         structopt(
             long,
-            help = "Allowed cross-origins for http api server (Access-Control-Allow-Origin)",
+            help = "Allowed cross-origins for http api server, as a comma-separated list of origins or * for any (Access-Control-Allow-Origin)",
             env,
             hide_env_values(true),
             display_order(46)
@@ -252,6 +417,60 @@ pub struct Config {
     )]
     pub http_cors: Option<String>,
 
+    #[cfg(feature = "http")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long = "http-rpc-allow",
+            help = "Bitcoin Core RPC methods to allow calling through the http api server's POST /rpc endpoint (disabled by default)",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            display_order(47)
+        )
+    )]
+    #[serde(default = "default_empty_vec")]
+    pub http_rpc_allowlist: Vec<String>,
+
+    // XXX not settable as an env var due to https://github.com/TeXitoi/structopt/issues/305
+    #[cfg(feature = "http")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Disable the http api server's GET /tx/:txid/merkle endpoint, which requires fetching the full block",
+            display_order(48)
+        )
+    )]
+    #[serde(default = "default_false")]
+    pub http_skip_merkle: bool,
+
+    #[cfg(feature = "http")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Maximum number of requests per minute accepted by the http api server from a single IP address, with excess ones rejected with a 429 error [default: unlimited]",
+            env,
+            hide_env_values(true),
+            display_order(48)
+        )
+    )]
+    pub http_rate_limit: Option<u32>,
+
+    #[cfg(feature = "grpc")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Address to bind the grpc server (disabled by default)",
+            env,
+            hide_env_values(true),
+            display_order(49)
+        )
+    )]
+    pub grpc_server_addr: Option<net::SocketAddr>,
+
     #[cfg_attr(feature = "cli", structopt(
         short = "i",
         long,
@@ -264,6 +483,17 @@ pub struct Config {
     #[serde(default = "default_poll_interval")]
     pub poll_interval: time::Duration,
 
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        help = "Minimum time between real-time syncs triggered by notifications, to avoid excessive indexing when many arrive at once (in seconds)",
+        default_value = "7",
+        parse(try_from_str = parse_duration),
+        env, hide_env_values(true),
+        display_order(91)
+    ))]
+    #[serde(default = "default_sync_debounce")]
+    pub sync_debounce: time::Duration,
+
     #[cfg_attr(
         feature = "cli",
         structopt(
@@ -277,6 +507,75 @@ pub struct Config {
     )]
     pub broadcast_cmd: Option<String>,
 
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        help = "Use a fixed fee table instead of bitcoind's fee estimates (as <target>:<sat/vB>, e.g. '2:25,6:10,144:1')",
+        parse(try_from_str = parse_fee_table),
+        conflicts_with = "fee-estimates-cmd",
+        env, hide_env_values(true),
+        display_order(93)
+    ))]
+    pub fee_estimates: Option<FeeTable>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Custom command for fee estimation. Should print out a JSON object mapping confirmation targets to sat/vB fee rates (e.g. 'curl -s https://blockstream.info/api/fee-estimates')",
+            env,
+            hide_env_values(true),
+            display_order(94)
+        )
+    )]
+    pub fee_estimates_cmd: Option<String>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Soft limit for the estimated memory usage of the index (in MB), warns when exceeded",
+            env,
+            hide_env_values(true),
+            display_order(95)
+        )
+    )]
+    pub store_memory_limit: Option<usize>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to a file for persisting the transaction and address labels (kept in memory only if unset)",
+            env,
+            hide_env_values(true),
+            display_order(96)
+        )
+    )]
+    pub labels_file: Option<path::PathBuf>,
+
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to a file for persisting the index, to speed up restarts by syncing from where the last run left off",
+            env,
+            hide_env_values(true),
+            display_order(97)
+        )
+    )]
+    pub index_file: Option<path::PathBuf>,
+
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        help = "Interval for saving the index to the --index-file (in seconds). It is also saved on shutdown.",
+        default_value = "300",
+        parse(try_from_str = parse_duration),
+        env, hide_env_values(true),
+        display_order(97)
+    ))]
+    #[serde(default = "default_index_save_interval")]
+    pub index_save_interval: time::Duration,
+
     // XXX this is not settable as an env var due to https://github.com/clap-rs/clap/issues/1476
     #[cfg_attr(feature = "cli", structopt(
         long = "no-startup-banner",
@@ -301,13 +600,28 @@ pub struct Config {
     )]
     pub unix_listener_path: Option<path::PathBuf>,
 
+    #[cfg(feature = "zmq")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long = "zmq-endpoint",
+            help = "Bitcoin Core ZMQ endpoint(s) to subscribe to for block and transaction notifications, triggering an immediate sync (e.g. tcp://127.0.0.1:28332)",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            display_order(110)
+        )
+    )]
+    #[serde(default = "default_empty_vec")]
+    pub zmq_endpoints: Vec<String>,
+
     #[cfg(feature = "webhooks")]
     #[cfg_attr(
         feature = "cli",
         structopt(
             long = "webhook-url",
             short = "H",
-            help = "Webhook url(s) to notify with index event updates",
+            help = "Webhook url(s) to notify with index event updates, optionally with filters as <url>#<filter>,... (see README)",
             env,
             hide_env_values(true),
             use_delimiter(true),
@@ -315,7 +629,111 @@ pub struct Config {
             display_order(102)
         )
     )]
-    pub webhook_urls: Option<Vec<String>>,
+    pub webhook_urls: Option<Vec<WebHookUrl>>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Only notify webhooks about incoming transactions that received at least this amount (in sats, as <sats> for all wallets or <checksum>:<sats> for a specific wallet)",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            value_delimiter(";"),
+            display_order(103)
+        )
+    )]
+    #[serde(default = "default_empty_vec")]
+    pub webhook_min_amount: Vec<AmountThreshold>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Send a LargeDeposit webhook alert for incoming transactions that received at least this amount (in sats, as <sats> for all wallets or <checksum>:<sats> for a specific wallet)",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            value_delimiter(";"),
+            display_order(104)
+        )
+    )]
+    #[serde(default = "default_empty_vec")]
+    pub webhook_large_amount: Vec<AmountThreshold>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "The maximum number of attempts for delivering webhook requests, including the first one",
+            default_value = "5",
+            env,
+            hide_env_values(true),
+            display_order(105)
+        )
+    )]
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Delay before retrying failed webhook requests (in seconds), doubled for every subsequent retry",
+            default_value = "5",
+            parse(try_from_str = parse_duration),
+            env,
+            hide_env_values(true),
+            display_order(106)
+        )
+    )]
+    #[serde(default = "default_webhook_retry_delay")]
+    pub webhook_retry_delay: time::Duration,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Shared secret for signing webhook requests with HMAC-SHA256 (sent in the X-Bwt-Signature header)",
+            env,
+            hide_env_values(true),
+            display_order(107)
+        )
+    )]
+    pub webhook_secret: Option<String>,
+
+    #[cfg(feature = "webhooks")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "The maximum number of updates to include in a single webhook request (larger batches are split into multiple requests)",
+            default_value = "500",
+            env,
+            hide_env_values(true),
+            display_order(108)
+        )
+    )]
+    #[serde(default = "default_webhook_batch_size")]
+    pub webhook_batch_size: usize,
+
+    #[cfg(all(feature = "http", feature = "webhooks"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            help = "Path to a file for persisting the address monitors registered via POST /monitor (kept in memory only if unset)",
+            env,
+            hide_env_values(true),
+            display_order(109)
+        )
+    )]
+    pub monitors_file: Option<path::PathBuf>,
 }
 
 impl Config {
@@ -324,7 +742,50 @@ impl Config {
         dirs::home_dir().map(|home| dotenv::from_path(home.join("bwt.env")).ok());
     }
 
+    /// Load the descriptors from the `descriptors_file` (if any) into `descriptors`
+    pub fn load_descriptors_file(&mut self) -> Result<()> {
+        use crate::error::Context;
+        let path = some_or_ret!(&self.descriptors_file, Ok(()));
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed reading descriptors from {:?}", path))?;
+        let descriptors = parse_descriptors_file(&contents, self.network)
+            .with_context(|| format!("invalid descriptors file {:?}", path))?;
+        info!("loaded {} descriptor(s) from {:?}", descriptors.len(), path);
+        self.descriptors.extend(descriptors);
+        Ok(())
+    }
+
+    /// Check the wallets' rescan timestamps for ones that are in the future, according to `reject_future_rescan`
+    pub fn guard_future_rescans(&mut self) -> Result<()> {
+        use crate::error::Context;
+        let reject = self.reject_future_rescan;
+        for (desc, opt) in &mut self.descriptors {
+            opt.rescan = opt
+                .rescan
+                .guard_future(reject)
+                .with_context(|| format!("invalid rescan for descriptor {}", desc))?;
+        }
+        for (xpub, opt) in self.xpubs.iter_mut().chain(self.bare_xpubs.iter_mut()) {
+            opt.rescan = opt
+                .rescan
+                .guard_future(reject)
+                .with_context(|| format!("invalid rescan for xpub {}", xpub))?;
+        }
+        for (payment_code, opt) in &mut self.payment_codes {
+            opt.rescan = opt
+                .rescan
+                .guard_future(reject)
+                .with_context(|| format!("invalid rescan for payment code {}", payment_code))?;
+        }
+        Ok(())
+    }
+
     pub fn bitcoind_url(&self) -> String {
+        self.bitcoind_wallet_url(self.bitcoind_wallet.as_deref())
+    }
+
+    /// The RPC url of the given bitcoind wallet (or the node-level one if no wallet is given)
+    pub fn bitcoind_wallet_url(&self, bitcoind_wallet: Option<&str>) -> String {
         format!(
             "{}/{}",
             self.bitcoind_url.as_ref().map_or_else(
@@ -340,8 +801,8 @@ impl Config {
                 },
                 |url| url.trim_end_matches('/').into()
             ),
-            match self.bitcoind_wallet {
-                Some(ref wallet) => format!("wallet/{}", wallet),
+            match bitcoind_wallet {
+                Some(wallet) => format!("wallet/{}", wallet),
                 None => "".into(),
             }
         )
@@ -377,50 +838,57 @@ impl Config {
 
     pub fn setup_logger(&self) {
         #[cfg(feature = "pretty_env_logger")]
-        apply_log_env(if self.timestamp {
-            pretty_env_logger::formatted_timed_builder()
-        } else {
-            pretty_env_logger::formatted_builder()
-        })
-        .filter_module(
-            "bwt",
-            match self.verbose {
-                0 => Level::Info,
-                1 => Level::Debug,
-                _ => Level::Trace,
-            }
-            .to_level_filter(),
-        )
-        .filter_module(
-            "bitcoincore_rpc",
-            match self.verbose {
-                0 | 1 => Level::Warn,
-                2 => Level::Debug,
-                _ => Level::Trace,
-            }
-            .to_level_filter(),
-        )
-        .filter_module(
-            "warp",
-            match self.verbose {
-                0 | 1 => Level::Warn,
-                2 => Level::Info,
-                3 => Level::Debug,
-                _ => Level::Trace,
+        {
+            let mut builder = apply_log_env(if self.timestamp {
+                pretty_env_logger::formatted_timed_builder()
+            } else {
+                pretty_env_logger::formatted_builder()
+            });
+            builder
+                .filter_module(
+                    "bwt",
+                    match self.verbose {
+                        0 => Level::Info,
+                        1 => Level::Debug,
+                        _ => Level::Trace,
+                    }
+                    .to_level_filter(),
+                )
+                .filter_module(
+                    "bitcoincore_rpc",
+                    match self.verbose {
+                        0 | 1 => Level::Warn,
+                        2 => Level::Debug,
+                        _ => Level::Trace,
+                    }
+                    .to_level_filter(),
+                )
+                .filter_module(
+                    "warp",
+                    match self.verbose {
+                        0 | 1 => Level::Warn,
+                        2 => Level::Info,
+                        3 => Level::Debug,
+                        _ => Level::Trace,
+                    }
+                    .to_level_filter(),
+                )
+                .filter_module("hyper", Level::Warn.to_level_filter())
+                .filter_level(
+                    match self.verbose {
+                        0 | 1 => Level::Warn,
+                        2 | 3 => Level::Info,
+                        4 => Level::Debug,
+                        _ => Level::Trace,
+                    }
+                    .to_level_filter(),
+                );
+            // directives added last take precedence over earlier ones for the same module
+            if let Some(log_filter) = &self.log_filter {
+                builder.parse_filters(log_filter);
             }
-            .to_level_filter(),
-        )
-        .filter_module("hyper", Level::Warn.to_level_filter())
-        .filter_level(
-            match self.verbose {
-                0 | 1 => Level::Warn,
-                2 | 3 => Level::Info,
-                4 => Level::Debug,
-                _ => Level::Trace,
-            }
-            .to_level_filter(),
-        )
-        .init();
+            builder.init();
+        }
     }
 }
 
@@ -436,59 +904,146 @@ fn apply_log_env(mut builder: LogBuilder) -> LogBuilder {
     builder
 }
 
-#[cfg(feature = "cli")]
-fn parse_desc(s: &str) -> Result<(ExtendedDescriptor, RescanSince)> {
+fn parse_desc(s: &str) -> Result<(ExtendedDescriptor, WalletOptions)> {
     use crate::util::descriptor::DescriptorChecksum;
     let mut parts = s.trim().splitn(2, '@');
     let desc = ExtendedDescriptor::parse_with_checksum(parts.next().req()?)?;
-    let rescan = parse_rescan(parts.next())?;
-    Ok((desc, rescan))
+    let opt = parse_wallet_opt(parts.next())?;
+    Ok((desc, opt))
 }
 
 #[cfg(feature = "cli")]
-fn parse_xpub(s: &str) -> Result<(XyzPubKey, RescanSince)> {
+fn parse_xpub(s: &str) -> Result<(XyzPubKey, WalletOptions)> {
     let mut parts = s.trim().splitn(2, '@');
     let xpub = parts.next().req()?.parse()?;
-    let rescan = parse_rescan(parts.next())?;
-    Ok((xpub, rescan))
+    let opt = parse_wallet_opt(parts.next())?;
+    Ok((xpub, opt))
 }
 
 #[cfg(feature = "cli")]
+fn parse_payment_code(s: &str) -> Result<(PaymentCode, WalletOptions)> {
+    let mut parts = s.trim().splitn(2, '@');
+    let payment_code = parts.next().req()?.parse()?;
+    let opt = parse_wallet_opt(parts.next())?;
+    Ok((payment_code, opt))
+}
+
+// Parse the comma-separated wallet options following the `@`, formatted as `<rescan>`,
+// `gap=<gap-limit>`, `import=<initial-import-size>` or `wallet=<bitcoind-wallet>` (all optional)
+fn parse_wallet_opt(s: Option<&str>) -> Result<WalletOptions> {
+    let mut rescan = None;
+    let mut opt = WalletOptions::default();
+    for part in s.into_iter().flat_map(|s| s.split(',')) {
+        let mut kv = part.splitn(2, '=');
+        match (kv.next().req()?, kv.next()) {
+            ("gap", Some(gap_limit)) => opt.gap_limit = Some(gap_limit.parse()?),
+            ("import", Some(import_size)) => opt.initial_import_size = Some(import_size.parse()?),
+            ("wallet", Some(bitcoind_wallet)) => opt.bitcoind_wallet = Some(bitcoind_wallet.into()),
+            (rescan_str, None) if rescan.is_none() => rescan = Some(rescan_str),
+            _ => bail!("invalid wallet option: {}", part),
+        }
+    }
+    opt.rescan = parse_rescan(rescan)?;
+    Ok(opt)
+}
+
 fn parse_rescan(s: Option<&str>) -> Result<RescanSince> {
     use crate::error::Context;
     Ok(match s {
         None | Some("all") => RescanSince::Timestamp(0),
         Some("now") | Some("none") => RescanSince::Now,
         Some(s) => {
-            // try as a unix timestamp first, then as a datetime string
-            RescanSince::Timestamp(
-                s.parse::<u64>()
-                    .or_else(|_| parse_yyyymmdd(s))
-                    .context("invalid rescan value")?,
-            )
+            // try as a unix timestamp first, then as a date string
+            match s.parse::<u64>() {
+                Ok(timestamp) => RescanSince::Timestamp(timestamp),
+                Err(_) => RescanSince::from_date_str(s).context("invalid rescan value")?,
+            }
         }
     })
 }
 
+// Parse a descriptors file with one descriptor per line (blank lines and lines starting with a
+// `#` are ignored), or the json output of bitcoind's `listdescriptors`
+fn parse_descriptors_file(
+    contents: &str,
+    network: Network,
+) -> Result<Vec<(ExtendedDescriptor, WalletOptions)>> {
+    use crate::error::Context;
+    use crate::util::descriptor::{normalize_descriptor, DescriptorChecksum};
+
+    if contents.trim_start().starts_with('{') {
+        let export: ListDescriptorsResult =
+            serde_json::from_str(contents).context("invalid listdescriptors json")?;
+        // bitcoind encodes descriptors in its own format (using `h` for hardened derivation since
+        // v24), which gets verified against its checksum and converted into the canonical one.
+        // the wallets of bitcoind typically include descriptors that bwt does not support (like
+        // tr() ones), which are skipped rather than failing the whole file.
+        let descriptors: Vec<_> = export
+            .descriptors
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let desc = normalize_descriptor(&entry.desc, network)
+                    .and_then(|normalized| {
+                        ExtendedDescriptor::parse_with_checksum(&normalized.descriptor)
+                    })
+                    .map_err(|e| warn!("skipping descriptor #{} {}: {:#}", i + 1, entry.desc, e))
+                    .ok()?;
+                let opt = WalletOptions {
+                    rescan: RescanSince::Timestamp(entry.timestamp),
+                    ..Default::default()
+                };
+                Some((desc, opt))
+            })
+            .collect();
+        ensure!(!descriptors.is_empty(), "no supported descriptors found");
+        return Ok(descriptors);
+    }
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(i, line)| {
+            parse_desc(line).with_context(|| format!("invalid descriptor on line {}", i + 1))
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ListDescriptorsResult {
+    descriptors: Vec<ListDescriptorsEntry>,
+}
+
+#[derive(Deserialize)]
+struct ListDescriptorsEntry {
+    desc: String,
+    timestamp: u64,
+}
+
 #[cfg(feature = "cli")]
-fn parse_yyyymmdd(s: &str) -> Result<u64> {
-    use chrono::{TimeZone, Utc};
-    let mut parts = s.splitn(3, '-');
-    Ok(Utc
-        .ymd_opt(
-            parts.next().req()?.parse()?,
-            parts.next().req()?.parse()?,
-            parts.next().req()?.parse()?,
-        )
-        .single()
-        .req()?
-        .and_hms(0, 0, 0)
-        .timestamp() as u64)
+fn parse_fee_table(s: &str) -> Result<FeeTable> {
+    s.split(',')
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(2, ':');
+            let target = parts.next().req()?.parse()?;
+            let feerate = parts.next().or_err("expected <target>:<sat/vB>")?.parse()?;
+            Ok((target, feerate))
+        })
+        .collect()
 }
 
 #[cfg(feature = "cli")]
+// parse a duration in seconds, with fractional seconds allowed (e.g. 0.5)
 fn parse_duration(s: &str) -> Result<time::Duration> {
-    Ok(time::Duration::from_secs(s.parse()?))
+    let secs: f64 = s.parse()?;
+    interval_from_secs(secs).or_err(format!(
+        "invalid duration {}, must be between 0.1 seconds and 1 day",
+        s
+    ))
 }
 
 fn get_cookie(config: &Config) -> Option<path::PathBuf> {
@@ -531,6 +1086,13 @@ impl From<&Config> for QueryConfig {
         QueryConfig {
             network: config.network,
             broadcast_cmd: config.broadcast_cmd.clone(),
+            fee_source: match (&config.fee_estimates, &config.fee_estimates_cmd) {
+                (Some(table), _) => FeeSource::Static(table.clone()),
+                (None, Some(cmd)) => FeeSource::Command(cmd.clone()),
+                (None, None) => FeeSource::Bitcoind,
+            },
+            labels_file: config.labels_file.clone(),
+            public_host: config.public_host.clone(),
         }
     }
 }
@@ -540,17 +1102,33 @@ impl From<&Config> for QueryConfig {
 // Create a Default implementation
 defaultable!(Config,
   @default(
-    verbose, timestamp, descriptors, xpubs, bare_xpubs, broadcast_cmd, startup_banner,
-    bitcoind_wallet, bitcoind_dir, bitcoind_url, bitcoind_auth, bitcoind_cookie,
+    verbose, timestamp, log_filter, descriptors, descriptors_file, xpubs, bare_xpubs, payment_codes, broadcast_cmd, fee_estimates, fee_estimates_cmd, store_memory_limit, labels_file, index_file, public_host, startup_banner,
+    bitcoind_wallet, bitcoind_dir, bitcoind_url, bitcoind_auth, bitcoind_cookie, relabel_orphaned, verify_full_descriptors, reject_future_rescan, dry_run,
     #[cfg(feature = "electrum")] electrum_rpc_addr,
     #[cfg(feature = "electrum")] electrum_skip_merkle,
+    #[cfg(feature = "electrum")] electrum_resume_subscriptions,
+    #[cfg(feature = "electrum")] electrum_max_pending_requests,
+    #[cfg(feature = "electrum")] electrum_rate_limit,
+    #[cfg(feature = "electrum")] electrum_rate_limit_disconnect,
     #[cfg(feature = "http")] http_cors,
+    #[cfg(feature = "http")] http_rpc_allowlist,
+    #[cfg(feature = "http")] http_skip_merkle,
+    #[cfg(feature = "http")] http_rate_limit,
+    #[cfg(feature = "grpc")] grpc_server_addr,
     #[cfg(feature = "webhooks")] webhook_urls,
+    #[cfg(feature = "webhooks")] webhook_min_amount,
+    #[cfg(feature = "webhooks")] webhook_large_amount,
+    #[cfg(feature = "webhooks")] webhook_secret,
+    #[cfg(all(feature = "http", feature = "webhooks"))] monitors_file,
     #[cfg(unix)] unix_listener_path,
+    #[cfg(feature = "zmq")] zmq_endpoints,
   )
   @custom(
-    network=Network::Bitcoin, gap_limit=20, initial_import_size=350, poll_interval=time::Duration::from_secs(5),
+    create_wallet=true, network=Network::Bitcoin, gap_limit=20, initial_import_size=350, poll_interval=time::Duration::from_secs(5), sync_debounce=time::Duration::from_secs(7), index_save_interval=time::Duration::from_secs(300),
     #[cfg(feature = "http")] http_server_addr=([127,0,0,1],3060).into(),
+    #[cfg(feature = "webhooks")] webhook_max_attempts=5,
+    #[cfg(feature = "webhooks")] webhook_retry_delay=time::Duration::from_secs(5),
+    #[cfg(feature = "webhooks")] webhook_batch_size=500,
   )
 );
 
@@ -559,6 +1137,9 @@ defaultable!(Config,
 fn default_false() -> bool {
     false
 }
+fn default_true() -> bool {
+    true
+}
 fn default_network() -> Network {
     Network::Bitcoin
 }
@@ -574,10 +1155,101 @@ fn default_initial_import_size() -> u32 {
 fn default_poll_interval() -> time::Duration {
     time::Duration::from_secs(5)
 }
+fn default_sync_debounce() -> time::Duration {
+    time::Duration::from_secs(7)
+}
+fn default_index_save_interval() -> time::Duration {
+    time::Duration::from_secs(300)
+}
 fn default_empty_vec<T>() -> Vec<T> {
     vec![]
 }
+#[cfg(feature = "webhooks")]
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+#[cfg(feature = "webhooks")]
+fn default_webhook_retry_delay() -> time::Duration {
+    time::Duration::from_secs(5)
+}
+#[cfg(feature = "webhooks")]
+fn default_webhook_batch_size() -> usize {
+    500
+}
 #[cfg(feature = "http")]
 fn default_http_server_addr() -> net::SocketAddr {
     ([127, 0, 0, 1], 3060).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XPUB: &str = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+
+    #[test]
+    fn test_parse_descriptors_file() {
+        let contents = format!(
+            "# comment\n\nwpkh({xpub}/0/*)@2020-01-01\n  pkh({xpub}/1/*)@now,gap=50,wallet=cold\n",
+            xpub = XPUB
+        );
+        let descs = parse_descriptors_file(&contents, Network::Bitcoin).unwrap();
+        assert_eq!(descs.len(), 2);
+        assert_eq!(descs[0].1.rescan, RescanSince::Timestamp(1577836800));
+        assert_eq!(descs[1].1.rescan, RescanSince::Now);
+        assert_eq!(descs[1].1.gap_limit, Some(50));
+        assert_eq!(descs[1].1.bitcoind_wallet.as_deref(), Some("cold"));
+
+        let err = parse_descriptors_file(
+            &format!("wpkh({}/0/*)\n\nnot-a-desc\n", XPUB),
+            Network::Bitcoin,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid descriptor on line 3");
+
+        let json = format!(
+            r#"{{"wallet_name":"w","descriptors":[{{"desc":"wpkh({}/0/*)","timestamp":1600000000,"active":true}}]}}"#,
+            XPUB
+        );
+        let descs = parse_descriptors_file(&json, Network::Bitcoin).unwrap();
+        assert_eq!(descs.len(), 1);
+        assert_eq!(descs[0].1.rescan, RescanSince::Timestamp(1600000000));
+
+        // listdescriptors output of Bitcoin Core v24+, with `h` hardened markers and checksums.
+        // the unsupported tr() descriptor is skipped.
+        let json = format!(
+            r#"{{
+  "wallet_name": "watchonly",
+  "descriptors": [
+    {{
+      "desc": "tr([d34db33f/86h/0h/0h]{xpub}/0/*)#3zasma2l",
+      "timestamp": 1600000000,
+      "active": true,
+      "internal": false,
+      "range": [0, 999],
+      "next": 0
+    }},
+    {{
+      "desc": "wpkh([d34db33f/84h/0h/0h]{xpub}/0/*)#h36s06su",
+      "timestamp": 1600000000,
+      "active": true,
+      "internal": false,
+      "range": [0, 999],
+      "next": 0
+    }}
+  ]
+}}"#,
+            xpub = XPUB
+        );
+        let descs = parse_descriptors_file(&json, Network::Bitcoin).unwrap();
+        assert_eq!(descs.len(), 1);
+        assert_eq!(
+            descs[0].0.to_string(),
+            format!("wpkh([d34db33f/84'/0'/0']{}/0/*)", XPUB)
+        );
+
+        // a corrupted checksum is rejected, leaving no supported descriptors
+        let json = json.replace("#h36s06su", "#h36s06sv");
+        assert!(parse_descriptors_file(&json, Network::Bitcoin).is_err());
+    }
+}
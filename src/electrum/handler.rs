@@ -0,0 +1,498 @@
+use std::cmp;
+use std::sync::Arc;
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::Txid;
+use bitcoin_hashes::hex::ToHex;
+use serde_json::{from_value, Value};
+
+use crate::electrum::{electrum_height, QueryExt};
+use crate::error::{fmt_error_chain, BwtError, Context, Result};
+use crate::query::Query;
+use crate::store::HistoryEntry;
+use crate::types::{BlockId, MempoolEntry, ScriptHash};
+use crate::util::{banner, BoolThen};
+
+/// The server software version advertised in `server.version`
+pub const SERVER_VERSION: &str = concat!("bwt v", env!("CARGO_PKG_VERSION"));
+/// The Electrum protocol version spoken by the server
+pub const PROTOCOL_VERSION: &str = "1.4";
+const MAX_HEADERS: u32 = 2016;
+
+pub const LT_RPC: &str = "bwt::electrum::rpc"; // log target name for individual rpc requests
+
+/// Handles Electrum RPC method calls, shared by the Electrum TCP server and the HTTP server.
+///
+/// The handler is stateless. Subscription methods reply with the current state without
+/// subscribing, the Electrum server keeps track of the subscriptions of its connections.
+pub struct ElectrumHandler {
+    query: Arc<Query>,
+    skip_merkle: bool,
+    // the TCP port advertised alongside the public host in `server.features`, if any
+    tcp_port: Option<u16>,
+}
+
+impl ElectrumHandler {
+    pub fn new(query: Arc<Query>, skip_merkle: bool, tcp_port: Option<u16>) -> Self {
+        ElectrumHandler {
+            query,
+            skip_merkle,
+            tcp_port,
+        }
+    }
+
+    /// Call the Electrum RPC `method` with `params`
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        match method {
+            "blockchain.block.header" => self.blockchain_block_header(params),
+            "blockchain.block.headers" => self.blockchain_block_headers(params),
+            "blockchain.estimatefee" => self.blockchain_estimatefee(params),
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.relayfee" => self.blockchain_relayfee(),
+            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(params),
+            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(params),
+            "blockchain.scripthash.get_history_batch" => {
+                self.blockchain_scripthash_get_history_batch(params)
+            }
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(params),
+            "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(params),
+            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
+            "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(params),
+            "blockchain.transaction.get" => self.blockchain_transaction_get(params),
+            "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(params),
+            "blockchain.transaction.id_from_pos" => self.blockchain_transaction_id_from_pos(params),
+            "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "server.banner" => self.server_banner(),
+            "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
+            "server.peers.subscribe" => self.server_peers_subscribe(),
+            "server.ping" => Ok(Value::Null),
+            "server.version" => self.server_version(params),
+            &_ => bail!("unknown method {} {:?}", method, params),
+        }
+    }
+
+    /// Handle the `method` call and make the JSON-RPC reply
+    pub fn handle_command(&self, method: &str, params: Value, id: Value) -> Value {
+        log_request(method, &params, &id);
+        let result = self.call(method, params);
+        make_reply(method, result, id)
+    }
+
+    /// Handle a JSON-RPC request object, or a batch array of them
+    pub fn handle_jsonrpc(&self, request: Value) -> Value {
+        match request {
+            Value::Array(requests) => requests
+                .into_iter()
+                .map(|request| self.handle_jsonrpc_request(request))
+                .collect(),
+            request => self.handle_jsonrpc_request(request),
+        }
+    }
+
+    fn handle_jsonrpc_request(&self, mut request: Value) -> Value {
+        if !request.is_object() {
+            return json!({"jsonrpc": "2.0", "id": null, "error": "invalid request"});
+        }
+        match (
+            request["method"].take(),
+            request["params"].take(),
+            request["id"].take(),
+        ) {
+            (Value::String(method), params, id) => self.handle_command(&method, params, id),
+            (_, _, id) => json!({"jsonrpc": "2.0", "id": id, "error": "invalid request"}),
+        }
+    }
+
+    fn blockchain_headers_subscribe(&self) -> Result<Value> {
+        let BlockId(tip_height, tip_hash) = self.query.get_tip()?;
+        let tip_hex = self.query.get_header_hex(&tip_hash)?;
+        Ok(json!({ "height": tip_height, "hex": tip_hex }))
+    }
+
+    pub fn server_version(&self, params: Value) -> Result<Value> {
+        // the third parameter is a bwt-specific extension, used to resume the subscriptions of
+        // reconnecting clients. it is handled by the electrum server and ignored here.
+        let (_client_name, protocol_version, _session_token): (
+            Option<String>,
+            Option<Value>,
+            Option<String>,
+        ) = from_value(pad_params(params, 3))?;
+
+        check_protocol_version(protocol_version)?;
+
+        Ok(json!([SERVER_VERSION, PROTOCOL_VERSION]))
+    }
+
+    fn server_banner(&self) -> Result<Value> {
+        Ok(json!(banner::get_welcome_banner(&self.query, false)?))
+    }
+
+    fn server_features(&self) -> Result<Value> {
+        let genesis_hash = genesis_block(self.query.network()).block_hash();
+        let hosts = match (self.query.public_host(), self.tcp_port) {
+            (Some(host), Some(tcp_port)) => {
+                json!({ host: { "tcp_port": tcp_port, "ssl_port": null } })
+            }
+            _ => json!({}),
+        };
+        Ok(json!({
+            "genesis_hash": genesis_hash,
+            "server_version": SERVER_VERSION,
+            "protocol_min": PROTOCOL_VERSION,
+            "protocol_max": PROTOCOL_VERSION,
+            "hash_function": "sha256",
+            "pruning": null,
+            "hosts": hosts,
+        }))
+    }
+
+    fn server_donation_address(&self) -> Result<Value> {
+        Ok(json!("bc1qmuagsjvq0lh3admnafk0qnlql0vvxv08au9l2d"))
+    }
+
+    fn server_peers_subscribe(&self) -> Result<Value> {
+        Ok(json!([]))
+    }
+
+    fn mempool_get_fee_histogram(&self) -> Result<Value> {
+        let histogram = &self.query.fee_histogram()?;
+        Ok(json!(histogram))
+    }
+
+    fn blockchain_block_header(&self, params: Value) -> Result<Value> {
+        let (height, cp_height): (u32, Option<u32>) = from_value(pad_params(params, 2))?;
+        // a cp_height of 0 indicates that no checkpoint proof is requested
+        let cp_height = cp_height.filter(|cp_height| *cp_height > 0);
+
+        let blockhash = self.query.get_block_hash(height)?;
+        let header_hex = self.query.get_header_hex(&blockhash)?;
+
+        Ok(match cp_height {
+            Some(cp_height) => {
+                let (branch, root) = self.query.electrum_header_merkle_proof(height, cp_height)?;
+
+                json!({
+                    "header": header_hex,
+                    "root": root,
+                    "branch": branch,
+                })
+            }
+            None => json!(header_hex),
+        })
+    }
+
+    fn blockchain_block_headers(&self, params: Value) -> Result<Value> {
+        let (start_height, count, cp_height): (u32, u32, Option<u32>) =
+            from_value(pad_params(params, 3))?;
+        let cp_height = cp_height.filter(|cp_height| *cp_height > 0);
+
+        let count = cmp::min(count, MAX_HEADERS);
+
+        // drop unknown heights (from the specs: "If the chain has not extended sufficiently far,
+        // only the available headers will be returned. If more headers than max were requested at
+        // most max will be returned.")
+        let max_height = cmp::min(start_height + count, self.query.get_tip_height()?);
+
+        // TODO use batch rpc when available in rust-bitcoincore-rpc
+        let headers: Vec<String> = (start_height..=max_height)
+            .map(|height| {
+                let blockhash = self.query.get_block_hash(height)?;
+                self.query.get_header_hex(&blockhash)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut result = json!({
+            "count": headers.len(),
+            "hex": headers.join(""),
+            "max": MAX_HEADERS,
+        });
+
+        if count > 0 {
+            if let Some(cp_height) = cp_height {
+                let (branch, root) = self
+                    .query
+                    .electrum_header_merkle_proof(start_height + (count - 1), cp_height)?;
+
+                result["root"] = json!(root);
+                result["branch"] = json!(branch);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn blockchain_estimatefee(&self, params: Value) -> Result<Value> {
+        let (target,): (u16,) = from_value(params)?;
+        let fee_rate = self.query.estimate_fee(target)?;
+
+        // format for electrum: from sat/b to BTC/kB, -1 to indicate no estimate is available
+        Ok(json!(fee_rate.map_or(-1.0, |rate| rate / 100_000f64)))
+    }
+
+    fn blockchain_relayfee(&self) -> Result<Value> {
+        let fee_rate = self.query.relay_fee()?;
+        // sat/b to BTC/kB
+        Ok(json!(fee_rate / 100_000f64))
+    }
+
+    fn blockchain_scripthash_subscribe(&self, params: Value) -> Result<Value> {
+        let (script_hash,): (ScriptHash,) = from_value(params)?;
+
+        let status_hash = self.query.get_status_hash(&script_hash);
+        Ok(json!(status_hash))
+    }
+
+    fn blockchain_scripthash_get_balance(&self, params: Value) -> Result<Value> {
+        let (script_hash,): (ScriptHash,) = from_value(params)?;
+
+        // following the electrum semantics, the unconfirmed balance is the net change made by
+        // mempool transactions and may be negative
+        let (confirmed_balance, mempool_balance) =
+            self.query.get_script_balance_changes(&script_hash);
+
+        Ok(json!({
+            "confirmed": confirmed_balance,
+            "unconfirmed": mempool_balance,
+        }))
+    }
+
+    fn blockchain_scripthash_get_history(&self, params: Value) -> Result<Value> {
+        let (script_hash,): (ScriptHash,) = from_value(params)?;
+
+        let mut histories = self.query.map_histories(&[script_hash], history_entry_json);
+        Ok(json!(histories.remove(&script_hash).unwrap_or_default()))
+    }
+
+    // a bwt-specific extension for fetching the history of multiple scripthashes in a single
+    // round-trip, returned as a map of scripthash to the history array
+    fn blockchain_scripthash_get_history_batch(&self, params: Value) -> Result<Value> {
+        let (script_hashes,): (Vec<ScriptHash>,) = from_value(params)?;
+
+        let histories = self.query.map_histories(&script_hashes, history_entry_json);
+        Ok(json!(histories))
+    }
+
+    fn blockchain_scripthash_get_mempool(&self, params: Value) -> Result<Value> {
+        let (script_hash,): (ScriptHash,) = from_value(params)?;
+
+        // the unconfirmed portion of the history, with the same (effective) fee as in get_history
+        let mut histories = self
+            .query
+            .map_histories(&[script_hash], |txhist, mempool_entry| {
+                txhist
+                    .status
+                    .is_unconfirmed()
+                    .do_then(|| history_entry_json(txhist, mempool_entry))
+            });
+        let mempool: Vec<Value> = histories
+            .remove(&script_hash)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(json!(mempool))
+    }
+
+    fn blockchain_scripthash_listunspent(&self, params: Value) -> Result<Value> {
+        let (script_hash,): (ScriptHash,) = from_value(params)?;
+
+        let mut utxos = self.query.list_unspent(Some(&script_hash), 0, 0, None)?;
+        // in blockchain order, with the unconfirmed ones last
+        utxos.sort_by_key(|utxo| (utxo.status, utxo.txid, utxo.vout));
+
+        let utxos: Vec<Value> = utxos
+            .into_iter()
+            .map(|utxo| {
+                let has_unconfirmed_parents = utxo.status.is_unconfirmed().and_then(|| {
+                    self.query
+                        .with_mempool_entry(&utxo.txid, MempoolEntry::has_unconfirmed_parents)
+                });
+                json!({
+                    "height": electrum_height(utxo.status, has_unconfirmed_parents),
+                    "tx_hash": utxo.txid,
+                    "tx_pos": utxo.vout,
+                    "value": utxo.amount,
+                })
+            })
+            .collect();
+        Ok(json!(utxos))
+    }
+
+    fn blockchain_transaction_broadcast(&self, params: Value) -> Result<Value> {
+        let (tx_hex,): (String,) = from_value(params)?;
+
+        let txid = self.query.broadcast(&tx_hex).map_err(|e| {
+            // reply with bitcoind's rejection reason as-is, which electrum displays to the user
+            match e.downcast_ref::<BwtError>() {
+                Some(BwtError::Rpc(rpc_error)) => anyhow!(rpc_error.message.clone()),
+                _ => e,
+            }
+        })?;
+        Ok(json!(txid))
+    }
+
+    fn blockchain_transaction_get(&self, params: Value) -> Result<Value> {
+        let (txid, verbose): (Txid, Option<bool>) = from_value(pad_params(params, 2))?;
+        let verbose = verbose.unwrap_or(false);
+
+        Ok(if verbose {
+            json!(self.query.get_tx_json(&txid)?)
+        } else {
+            let raw = self.query.get_tx_raw(&txid)?;
+            json!(raw.to_hex())
+        })
+    }
+
+    fn blockchain_transaction_get_merkle(&self, params: Value) -> Result<Value> {
+        let (txid, height): (Txid, u32) = from_value(params)?;
+
+        let (merkle, pos) = if !self.skip_merkle {
+            match self.query.get_merkle_proof(&txid, height) {
+                Ok(proof) => proof,
+                Err(e) => {
+                    if let Some(BwtError::PrunedBlocks) = e.downcast_ref::<BwtError>() {
+                        // if we can't generate the spv proof due to pruning, return a dummy proof instead of an
+                        // error, which electrum will accept when run with --skipmerklecheck.
+                        (vec![], 0)
+                    } else {
+                        bail!(e)
+                    }
+                }
+            }
+        } else {
+            // always return dummy SPV proofs when --electrum-skip-merkle is set
+            (vec![], 0)
+        };
+
+        Ok(json!({
+            "block_height": height,
+            "merkle": merkle,
+            "pos": pos,
+        }))
+    }
+
+    fn blockchain_transaction_id_from_pos(&self, params: Value) -> Result<Value> {
+        let (height, tx_pos, want_merkle): (u32, usize, Option<bool>) =
+            from_value(pad_params(params, 3))?;
+        let want_merkle = want_merkle.unwrap_or(false);
+
+        let (txid, merkle) = self
+            .query
+            .electrum_id_from_pos(height, tx_pos, want_merkle)?;
+
+        Ok(if !want_merkle {
+            json!(txid)
+        } else {
+            json!({
+                "tx_hash": txid,
+                "merkle": merkle,
+            })
+        })
+    }
+}
+
+pub fn log_request(method: &str, params: &Value, id: &Value) {
+    match method {
+        "server.ping"
+        | "blockchain.scripthash.subscribe"
+        | "blockchain.estimatefee"
+        | "mempool.get_fee_histogram" => {
+            trace!(target: LT_RPC, "rpc #{} <- {} {}", id, method, params);
+        }
+        _ => {
+            debug!(target: LT_RPC, "rpc #{} <- {} {}", id, method, params);
+        }
+    }
+}
+
+pub fn make_reply(method: &str, result: Result<Value>, id: Value) -> Value {
+    match result {
+        Ok(result) => {
+            trace!(target: LT_RPC, "rpc #{} -> {} {}", id, method, result);
+            json!({"jsonrpc": "2.0", "id": id, "result": result})
+        }
+        Err(e) => {
+            warn!(target: LT_RPC, "rpc #{} {} failed: {:?}", id, method, e,);
+            json!({"jsonrpc": "2.0", "id": id, "error": fmt_error_chain(&e)})
+        }
+    }
+}
+
+fn history_entry_json(txhist: &HistoryEntry, mempool_entry: Option<&MempoolEntry>) -> Value {
+    // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
+    // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
+    let (effective_fee, has_unconfirmed_parents) = mempool_entry
+        .map(|mempool_entry| {
+            // report the fee as the effective feerate multiplied by the size, to get electrum to
+            // display the effective feerate when it divides this back by the size.
+            let effective_fee =
+                (mempool_entry.effective_feerate() * mempool_entry.vsize as f64) as u64;
+            (
+                Some(effective_fee),
+                Some(mempool_entry.has_unconfirmed_parents()),
+            )
+        })
+        .unwrap_or((None, None));
+
+    json!({
+        "height": electrum_height(txhist.status, has_unconfirmed_parents),
+        "tx_hash": txhist.txid,
+        "fee": effective_fee,
+    })
+}
+
+// Check that the protocol version requested by the client, given as either a single version
+// or a [min, max] range, is compatible with the one supported by the server
+fn check_protocol_version(requested: Option<Value>) -> Result<()> {
+    let (min, max) = match requested {
+        // the protocol version defaults to 1.4 when not specified
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::String(version)) => (version.clone(), version),
+        Some(range) => from_value(range).context("invalid protocol version")?,
+    };
+    let supported = parse_version(PROTOCOL_VERSION)?;
+    ensure!(
+        parse_version(&min)? <= supported && supported <= parse_version(&max)?,
+        "unsupported protocol version {}-{} (the server supports {})",
+        min,
+        max,
+        PROTOCOL_VERSION
+    );
+    Ok(())
+}
+
+fn parse_version(version: &str) -> Result<Vec<u32>> {
+    version
+        .split('.')
+        .map(|part| Ok(part.parse()?))
+        .collect::<Result<_>>()
+        .with_context(|| format!("invalid protocol version {}", version))
+}
+
+pub fn pad_params(mut params: Value, n: usize) -> Value {
+    if let Value::Array(ref mut values) = params {
+        while values.len() < n {
+            values.push(Value::Null);
+        }
+    } // passing a non-array is a noop
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_version() {
+        assert!(SERVER_VERSION.starts_with("bwt v"));
+
+        assert!(check_protocol_version(None).is_ok());
+        assert!(check_protocol_version(Some(json!(PROTOCOL_VERSION))).is_ok());
+        assert!(check_protocol_version(Some(json!(["1.2", "1.4.2"]))).is_ok());
+
+        assert!(check_protocol_version(Some(json!("1.2"))).is_err());
+        assert!(check_protocol_version(Some(json!(["1.5", "2.0"]))).is_err());
+        assert!(check_protocol_version(Some(json!("foo"))).is_err());
+    }
+}
@@ -4,9 +4,12 @@ use bitcoin_hashes::{sha256d, Hash, HashEngine};
 use crate::error::{OptionExt, Result};
 use crate::query::Query;
 use crate::types::{MempoolEntry, ScriptHash, StatusHash, TxStatus};
+use crate::util::merkle::create_merkle_branch_and_root;
 use crate::util::BoolThen;
 
+mod handler;
 mod server;
+pub use handler::{ElectrumHandler, PROTOCOL_VERSION, SERVER_VERSION};
 pub use server::ElectrumServer;
 
 pub fn electrum_height(status: TxStatus, has_unconfirmed_parents: Option<bool>) -> i32 {
@@ -26,12 +29,6 @@ pub fn electrum_height(status: TxStatus, has_unconfirmed_parents: Option<bool>)
 trait QueryExt {
     fn get_status_hash(&self, scripthash: &ScriptHash) -> Option<StatusHash>;
 
-    fn electrum_merkle_proof(
-        &self,
-        txid: &Txid,
-        height: u32,
-    ) -> Result<(Vec<sha256d::Hash>, usize)>;
-
     fn electrum_header_merkle_proof(
         &self,
         height: u32,
@@ -69,23 +66,6 @@ impl QueryExt for Query {
         }
     }
 
-    fn electrum_merkle_proof(
-        &self,
-        txid: &Txid,
-        height: u32,
-    ) -> Result<(Vec<sha256d::Hash>, usize)> {
-        let block_hash = self.get_block_hash(height)?;
-        let txids = self.get_block_txids(&block_hash)?;
-        let pos = txids
-            .iter()
-            .position(|c_txid| c_txid == txid)
-            .or_err("missing tx")?;
-
-        let hashes = txids.into_iter().map(sha256d::Hash::from).collect();
-        let (branch, _root) = create_merkle_branch_and_root(hashes, pos);
-        Ok((branch, pos))
-    }
-
     fn electrum_header_merkle_proof(
         &self,
         height: u32,
@@ -104,11 +84,11 @@ impl QueryExt for Query {
             );
         }
 
-        let heights: Vec<u32> = (0..=cp_height).collect();
-        let header_hashes = heights
+        let header_hashes = self
+            .get_block_hashes(0, cp_height)?
             .into_iter()
-            .map(|height| self.get_block_hash(height).map(sha256d::Hash::from))
-            .collect::<Result<Vec<sha256d::Hash>>>()?;
+            .map(sha256d::Hash::from)
+            .collect();
 
         Ok(create_merkle_branch_and_root(
             header_hashes,
@@ -138,29 +118,3 @@ impl QueryExt for Query {
         Ok((txid, branch))
     }
 }
-
-fn merklize(left: sha256d::Hash, right: sha256d::Hash) -> sha256d::Hash {
-    let data = [&left[..], &right[..]].concat();
-    sha256d::Hash::hash(&data)
-}
-
-fn create_merkle_branch_and_root(
-    mut hashes: Vec<sha256d::Hash>,
-    mut index: usize,
-) -> (Vec<sha256d::Hash>, sha256d::Hash) {
-    let mut merkle = vec![];
-    while hashes.len() > 1 {
-        if hashes.len() % 2 != 0 {
-            let last = *hashes.last().unwrap();
-            hashes.push(last);
-        }
-        index = if index % 2 == 0 { index + 1 } else { index - 1 };
-        merkle.push(hashes[index]);
-        index /= 2;
-        hashes = hashes
-            .chunks(2)
-            .map(|pair| merklize(pair[0], pair[1]))
-            .collect()
-    }
-    (merkle, hashes[0])
-}
@@ -1,45 +1,57 @@
-use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
-use bitcoin::Txid;
-use bitcoin_hashes::hex::ToHex;
 use serde_json::{from_str, from_value, Value};
 
-use crate::electrum::{electrum_height, QueryExt};
-use crate::error::{fmt_error_chain, BwtError, Context, Result};
+use crate::electrum::handler::{log_request, make_reply, pad_params, ElectrumHandler, LT_RPC};
+use crate::electrum::{QueryExt, PROTOCOL_VERSION};
+use crate::error::{Context, Result};
 use crate::indexer::IndexChange;
 use crate::query::Query;
-use crate::types::{BlockId, MempoolEntry, ScriptHash, StatusHash};
-use crate::util::{banner, BoolThen};
+use crate::types::{BlockId, ScriptHash, StatusHash};
+use crate::util::{
+    join_timeout, ratelimit::TokenBucket, warn_if_public, BoolThen, SHUTDOWN_TIMEOUT,
+};
 
 // Heavily based on the RPC server implementation written by Roman Zeyde for electrs,
 // released under the MIT license. https://github.com/romanz/electrs
 
-const BWT_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PROTOCOL_VERSION: &str = "1.4";
-const MAX_HEADERS: u32 = 2016;
+const MAX_SAVED_SESSIONS: usize = 100;
 
 const LT: &str = "bwt::electrum"; // log target name
 
 struct Connection {
     query: Arc<Query>,
-    skip_merkle: bool,
+    handler: Arc<ElectrumHandler>,
     stream: TcpStream,
     addr: SocketAddr,
     chan: SyncChannel<Message>,
     subman: Arc<Mutex<SubscriptionManager>>,
     subscriber_id: usize,
+    // notifications to send following the current reply
+    pending_notifications: Vec<Message>,
+    // the number of requests read from the client that were not replied to yet
+    pending_requests: Arc<AtomicUsize>,
+    max_pending_requests: Option<usize>,
+    // the maximum number of requests per minute, and whether exceeding it drops the connection
+    rate_limit: Option<u32>,
+    rate_limit_disconnect: bool,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         query: Arc<Query>,
-        skip_merkle: bool,
+        handler: Arc<ElectrumHandler>,
+        max_pending_requests: Option<usize>,
+        rate_limit: Option<u32>,
+        rate_limit_disconnect: bool,
         stream: TcpStream,
         addr: SocketAddr,
         subman: Arc<Mutex<SubscriptionManager>>,
@@ -48,311 +60,74 @@ impl Connection {
         let subscriber_id = subman.lock().unwrap().register(chan.sender());
         Connection {
             query,
-            skip_merkle,
+            handler,
             subman,
             subscriber_id,
             stream,
             addr,
             chan,
+            pending_notifications: vec![],
+            pending_requests: Arc::new(AtomicUsize::new(0)),
+            max_pending_requests,
+            rate_limit,
+            rate_limit_disconnect,
         }
     }
 
-    fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
-        self.subman
-            .lock()
-            .unwrap()
-            .subscribe_blocks(self.subscriber_id);
-
-        let BlockId(tip_height, tip_hash) = self.query.get_tip()?;
-        let tip_hex = self.query.get_header_hex(&tip_hash)?;
-        Ok(json!({ "height": tip_height, "hex": tip_hex }))
-    }
-
-    fn server_version(&self) -> Result<Value> {
-        // TODO check the versions are compatible and disconnect otherwise
-        Ok(json!([format!("bwt v{}", BWT_VERSION), PROTOCOL_VERSION]))
-    }
-
-    fn server_banner(&self) -> Result<Value> {
-        Ok(json!(banner::get_welcome_banner(&self.query, false)?))
-    }
-
-    fn server_donation_address(&self) -> Result<Value> {
-        Ok(json!("bc1qmuagsjvq0lh3admnafk0qnlql0vvxv08au9l2d"))
-    }
-
-    fn server_peers_subscribe(&self) -> Result<Value> {
-        Ok(json!([]))
-    }
-
-    fn mempool_get_fee_histogram(&self) -> Result<Value> {
-        let histogram = &self.query.fee_histogram()?;
-        Ok(json!(histogram))
-    }
-
-    fn blockchain_block_header(&self, params: Value) -> Result<Value> {
-        let (height, cp_height): (u32, Option<u32>) = from_value(pad_params(params, 2))?;
-
-        let blockhash = self.query.get_block_hash(height)?;
-        let header_hex = self.query.get_header_hex(&blockhash)?;
-
-        Ok(match cp_height {
-            Some(cp_height) => {
-                let (branch, root) = self.query.electrum_header_merkle_proof(height, cp_height)?;
-
-                json!({
-                    "header": header_hex,
-                    "root": root,
-                    "branch": branch,
-                })
-            }
-            None => json!(header_hex),
-        })
-    }
-
-    fn blockchain_block_headers(&self, params: Value) -> Result<Value> {
-        let (start_height, count, cp_height): (u32, u32, Option<u32>) =
-            from_value(pad_params(params, 3))?;
-
-        let count = cmp::min(count, MAX_HEADERS);
-
-        // drop unknown heights (from the specs: "If the chain has not extended sufficiently far,
-        // only the available headers will be returned. If more headers than max were requested at
-        // most max will be returned.")
-        let max_height = cmp::min(start_height + count, self.query.get_tip_height()?);
-
-        // TODO use batch rpc when available in rust-bitcoincore-rpc
-        let headers: Vec<String> = (start_height..=max_height)
-            .map(|height| {
-                let blockhash = self.query.get_block_hash(height)?;
-                self.query.get_header_hex(&blockhash)
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let mut result = json!({
-            "count": headers.len(),
-            "hex": headers.join(""),
-            "max": MAX_HEADERS,
-        });
-
-        if count > 0 {
-            if let Some(cp_height) = cp_height {
-                let (branch, root) = self
-                    .query
-                    .electrum_header_merkle_proof(start_height + (count - 1), cp_height)?;
-
-                result["root"] = json!(root);
-                result["branch"] = json!(branch);
-            }
-        }
-
-        Ok(result)
-    }
-
-    fn blockchain_estimatefee(&self, params: Value) -> Result<Value> {
-        let (target,): (u16,) = from_value(params)?;
-        let fee_rate = self.query.estimate_fee(target)?;
+    fn handle_command(&mut self, method: &str, params: Value, id: Value) -> Value {
+        log_request(method, &params, &id);
 
-        // format for electrum: from sat/b to BTC/kB, -1 to indicate no estimate is available
-        Ok(json!(fee_rate.map_or(-1.0, |rate| rate / 100_000f64)))
-    }
+        // the subscription methods are handled here to keep track of the connection's subscriptions,
+        // all other methods are handled by the stateless ElectrumHandler
+        let result = match method {
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(params),
+            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
+            "server.version" => self.server_version(params),
+            _ => self.handler.call(method, params),
+        };
 
-    fn blockchain_relayfee(&self) -> Result<Value> {
-        let fee_rate = self.query.relay_fee()?;
-        // sat/b to BTC/kB
-        Ok(json!(fee_rate / 100_000f64))
+        make_reply(method, result, id)
     }
 
-    fn blockchain_scripthash_subscribe(&mut self, params: Value) -> Result<Value> {
-        let (script_hash,): (ScriptHash,) = from_value(params)?;
-
+    fn blockchain_headers_subscribe(&mut self, params: Value) -> Result<Value> {
         self.subman
             .lock()
             .unwrap()
-            .subscribe_scripthash(self.subscriber_id, script_hash);
-
-        let status_hash = self.query.get_status_hash(&script_hash);
-        Ok(json!(status_hash))
-    }
-
-    fn blockchain_scripthash_get_balance(&self, params: Value) -> Result<Value> {
-        let (script_hash,): (ScriptHash,) = from_value(params)?;
-
-        let (confirmed_balance, mempool_balance) = self.query.get_script_balance(&script_hash)?;
-
-        Ok(json!({
-            "confirmed": confirmed_balance,
-            "unconfirmed": mempool_balance,
-        }))
-    }
-
-    fn blockchain_scripthash_get_history(&self, params: Value) -> Result<Value> {
-        let (script_hash,): (ScriptHash,) = from_value(params)?;
-
-        let txs: Vec<Value> = self.query.map_history(&script_hash, |txhist| {
-            // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
-            // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
-            let (effective_fee, has_unconfirmed_parents) = txhist
-                .status
-                .is_unconfirmed()
-                .and_then(|| {
-                    self.query
-                        .with_mempool_entry(&txhist.txid, |mempool_entry| {
-                            // report the fee as the effective feerate multiplied by the size, to get electrum to
-                            // display the effective feerate when it divides this back by the size.
-                            let effective_fee = (mempool_entry.effective_feerate()
-                                * mempool_entry.vsize as f64)
-                                as u64;
-                            let has_unconfirmed_parents = mempool_entry.has_unconfirmed_parents();
-                            (Some(effective_fee), Some(has_unconfirmed_parents))
-                        })
-                })
-                .unwrap_or((None, None));
+            .subscribe_blocks(self.subscriber_id);
 
-            json!({
-                "height": electrum_height(txhist.status, has_unconfirmed_parents),
-                "tx_hash": txhist.txid,
-                "fee": effective_fee,
-            })
-        });
-        Ok(json!(txs))
+        self.handler.call("blockchain.headers.subscribe", params)
     }
 
-    fn blockchain_scripthash_listunspent(&self, params: Value) -> Result<Value> {
+    fn blockchain_scripthash_subscribe(&mut self, params: Value) -> Result<Value> {
         let (script_hash,): (ScriptHash,) = from_value(params)?;
 
-        let utxos: Vec<Value> = self
-            .query
-            .list_unspent(Some(&script_hash), 0, None)?
-            .into_iter()
-            .map(|utxo| {
-                let has_unconfirmed_parents = utxo.status.is_unconfirmed().and_then(|| {
-                    self.query
-                        .with_mempool_entry(&utxo.txid, MempoolEntry::has_unconfirmed_parents)
-                });
-                json!({
-                    "height": electrum_height(utxo.status, has_unconfirmed_parents),
-                    "tx_hash": utxo.txid,
-                    "tx_pos": utxo.vout,
-                    "value": utxo.amount,
-                })
-            })
-            .collect();
-        Ok(json!(utxos))
-    }
-
-    fn blockchain_transaction_broadcast(&self, params: Value) -> Result<Value> {
-        let (tx_hex,): (String,) = from_value(params)?;
-
-        let txid = self.query.broadcast(&tx_hex)?;
-        Ok(json!(txid))
-    }
+        let status_hash = self.query.get_status_hash(&script_hash);
 
-    fn blockchain_transaction_get(&self, params: Value) -> Result<Value> {
-        let (txid, verbose): (Txid, Option<bool>) = from_value(pad_params(params, 2))?;
-        let verbose = verbose.unwrap_or(false);
+        self.subman.lock().unwrap().subscribe_scripthash(
+            self.subscriber_id,
+            script_hash,
+            status_hash,
+        );
 
-        Ok(if verbose {
-            json!(self.query.get_tx_json(&txid)?)
-        } else {
-            let raw = self.query.get_tx_raw(&txid)?;
-            json!(raw.to_hex())
-        })
+        Ok(json!(status_hash))
     }
 
-    fn blockchain_transaction_get_merkle(&self, params: Value) -> Result<Value> {
-        let (txid, height): (Txid, u32) = from_value(params)?;
-
-        let (merkle, pos) = if !self.skip_merkle {
-            match self.query.electrum_merkle_proof(&txid, height) {
-                Ok(proof) => proof,
-                Err(e) => {
-                    if let Some(BwtError::PrunedBlocks) = e.downcast_ref::<BwtError>() {
-                        // if we can't generate the spv proof due to pruning, return a dummy proof instead of an
-                        // error, which electrum will accept when run with --skipmerklecheck.
-                        (vec![], 0)
-                    } else {
-                        bail!(e)
-                    }
-                }
-            }
-        } else {
-            // always return dummy SPV proofs when --electrum-skip-merkle is set
-            (vec![], 0)
-        };
-
-        Ok(json!({
-            "block_height": height,
-            "merkle": merkle,
-            "pos": pos,
-        }))
-    }
+    fn server_version(&mut self, params: Value) -> Result<Value> {
+        // the third parameter is a bwt-specific extension, used to resume the subscriptions of
+        // reconnecting clients (when enabled with --electrum-resume-subscriptions)
+        let (_, _, session_token): (Value, Value, Option<String>) =
+            from_value(pad_params(params.clone(), 3))?;
 
-    fn blockchain_transaction_id_from_pos(&self, params: Value) -> Result<Value> {
-        let (height, tx_pos, want_merkle): (u32, usize, Option<bool>) =
-            from_value(pad_params(params, 3))?;
-        let want_merkle = want_merkle.unwrap_or(false);
-
-        let (txid, merkle) = self
-            .query
-            .electrum_id_from_pos(height, tx_pos, want_merkle)?;
-
-        Ok(if !want_merkle {
-            json!(txid)
-        } else {
-            json!({
-                "tx_hash": txid,
-                "merkle": merkle,
-            })
-        })
-    }
+        // check the protocol version before resuming the session
+        let version = self.handler.call("server.version", params)?;
 
-    fn handle_command(&mut self, method: &str, params: Value, id: Value) -> Result<Value> {
-        match method {
-            "server.ping"
-            | "blockchain.scripthash.subscribe"
-            | "blockchain.estimatefee"
-            | "mempool.get_fee_histogram" => {
-                trace!(target: LT, "rpc #{} <- {} {}", id, method, params);
-            }
-            _ => {
-                debug!(target: LT, "rpc #{} <- {} {}", id, method, params);
-            }
+        if let Some(session_token) = session_token {
+            let mut subman = self.subman.lock().unwrap();
+            let notifications = subman.resume_session(self.subscriber_id, session_token)?;
+            self.pending_notifications.extend(notifications);
         }
 
-        let result = match method {
-            "blockchain.block.header" => self.blockchain_block_header(params),
-            "blockchain.block.headers" => self.blockchain_block_headers(params),
-            "blockchain.estimatefee" => self.blockchain_estimatefee(params),
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
-            "blockchain.relayfee" => self.blockchain_relayfee(),
-            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(params),
-            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(params),
-            "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(params),
-            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
-            "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(params),
-            "blockchain.transaction.get" => self.blockchain_transaction_get(params),
-            "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(params),
-            "blockchain.transaction.id_from_pos" => self.blockchain_transaction_id_from_pos(params),
-            "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
-            "server.banner" => self.server_banner(),
-            "server.donation_address" => self.server_donation_address(),
-            "server.peers.subscribe" => self.server_peers_subscribe(),
-            "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
-            &_ => bail!("unknown method {} {:?}", method, params),
-        };
-
-        Ok(match result {
-            Ok(result) => {
-                trace!(target: LT, "rpc #{} -> {} {}", id, method, result);
-                json!({"jsonrpc": "2.0", "id": id, "result": result})
-            }
-            Err(e) => {
-                warn!(target: LT, "rpc #{} {} failed: {:?}", id, method, e,);
-                json!({"jsonrpc": "2.0", "id": id, "error": fmt_error_chain(&e)})
-            }
-        })
+        Ok(version)
     }
 
     fn make_notification(&mut self, msg: Message) -> Result<(String, Value)> {
@@ -370,12 +145,14 @@ impl Connection {
     }
 
     fn send_values(&mut self, values: &[Value]) -> Result<()> {
-        for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .context(format!("failed to send {}", value))?;
-        }
+        // write all the values at once, so that batched notifications go out in a single write
+        let lines: String = values
+            .iter()
+            .map(|value| value.to_string() + "\n")
+            .collect();
+        self.stream
+            .write_all(lines.as_bytes())
+            .context(format!("failed to send {} value(s)", values.len()))?;
         Ok(())
     }
 
@@ -388,27 +165,64 @@ impl Connection {
                     let reply = match (cmd["method"].take(), cmd["params"].take(), cmd["id"].take())
                     {
                         (Value::String(method), params, id) => {
-                            self.handle_command(&method, params, id)?
+                            self.handle_command(&method, params, id)
                         }
                         _ => bail!("invalid command: {}", line),
                     };
-                    self.send_values(&[reply])?
+                    self.send_values(&[reply])?;
+                    self.pending_requests.fetch_sub(1, Ordering::SeqCst);
+                    let pending_notifications = std::mem::take(&mut self.pending_notifications);
+                    self.send_notifications(pending_notifications)?;
                 }
-                Message::ChainTip(..) | Message::HistoryChange(..) => {
-                    let (method, params) = self.make_notification(msg)?;
-                    debug!(target: LT, "sending notification {} {}", method, params);
+                Message::Rejected(line, reason) => {
+                    let id =
+                        from_str::<Value>(&line).map_or(Value::Null, |mut cmd| cmd["id"].take());
+                    warn!(target: LT_RPC, "[{}] rpc #{} rejected: {}", self.addr, id, reason);
                     self.send_values(&[json!({
                         "jsonrpc": "2.0",
-                        "method": method,
-                        "params": params
+                        "id": id,
+                        "error": format!("{}, try again later", reason),
                     })])?;
                 }
+                Message::ChainTip(..) | Message::HistoryChange(..) => {
+                    self.send_notifications(vec![msg])?;
+                }
+                Message::Notifications(msgs) => {
+                    self.send_notifications(msgs)?;
+                }
                 Message::Done => return Ok(()),
             }
         }
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn send_notifications(&mut self, msgs: Vec<Message>) -> Result<()> {
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        let values = msgs
+            .into_iter()
+            .map(|msg| {
+                let (method, params) = self.make_notification(msg)?;
+                debug!(target: LT_RPC, "sending notification {} {}", method, params);
+                Ok(json!({
+                    "jsonrpc": "2.0",
+                    "method": method,
+                    "params": params
+                }))
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        self.send_values(&values)
+    }
+
+    fn handle_requests(
+        mut reader: BufReader<TcpStream>,
+        tx: SyncSender<Message>,
+        pending_requests: Arc<AtomicUsize>,
+        max_pending_requests: Option<usize>,
+        rate_limit: Option<u32>,
+        rate_limit_disconnect: bool,
+    ) -> Result<()> {
+        let mut rate_limiter = rate_limit.map(TokenBucket::new);
         loop {
             let mut line = Vec::<u8>::new();
             reader
@@ -424,7 +238,29 @@ impl Connection {
                     bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
                 }
                 match String::from_utf8(line) {
-                    Ok(req) => tx.send(Message::Request(req)).context("channel closed")?,
+                    Ok(req) => {
+                        let rate_limited = rate_limiter
+                            .as_mut()
+                            .map_or(false, |bucket| !bucket.try_take());
+                        if rate_limited && rate_limit_disconnect {
+                            tx.send(Message::Rejected(req, "rate limit exceeded"))
+                                .context("channel closed")?;
+                            let _ = tx.send(Message::Done);
+                            bail!("rate limit exceeded, dropping connection");
+                        }
+                        let too_many_pending = max_pending_requests
+                            .map_or(false, |max| pending_requests.load(Ordering::SeqCst) >= max);
+                        // reject requests beyond the limits upfront, without queueing them for processing
+                        let msg = if rate_limited {
+                            Message::Rejected(req, "rate limit exceeded")
+                        } else if too_many_pending {
+                            Message::Rejected(req, "too many pending requests")
+                        } else {
+                            pending_requests.fetch_add(1, Ordering::SeqCst);
+                            Message::Request(req)
+                        };
+                        tx.send(msg).context("channel closed")?
+                    }
                     Err(err) => {
                         let _ = tx.send(Message::Done);
                         bail!("invalid UTF8: {}", err)
@@ -437,7 +273,19 @@ impl Connection {
     pub fn run(mut self) {
         let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
         let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
+        let pending_requests = self.pending_requests.clone();
+        let max_pending_requests = self.max_pending_requests;
+        let (rate_limit, rate_limit_disconnect) = (self.rate_limit, self.rate_limit_disconnect);
+        let child = spawn_thread("reader", move || {
+            Connection::handle_requests(
+                reader,
+                tx,
+                pending_requests,
+                max_pending_requests,
+                rate_limit,
+                rate_limit_disconnect,
+            )
+        });
         if let Err(e) = self.handle_replies() {
             error!(
                 target: LT,
@@ -453,23 +301,21 @@ impl Connection {
     }
 }
 
-fn pad_params(mut params: Value, n: usize) -> Value {
-    if let Value::Array(ref mut values) = params {
-        while values.len() < n {
-            values.push(Value::Null);
-        }
-    } // passing a non-array is a noop
-    params
-}
-
 #[derive(Clone, Debug)]
 pub enum Message {
     Request(String),
+    // a request exceeding the session's pending requests or rate limit, with the rejection reason
+    Rejected(String, &'static str),
     ChainTip(u32, String), // height, hex header
     HistoryChange(ScriptHash, Option<StatusHash>),
+    // the notifications produced for a client by a single index sync, sent together
+    Notifications(Vec<Message>),
     Done,
 }
 
+// a channel for the accepted connections, closed by sending a None
+type Acceptor = Channel<Option<(TcpStream, SocketAddr)>>;
+
 pub enum Notification {
     IndexChangelog(Vec<IndexChange>),
     Exit,
@@ -479,6 +325,7 @@ pub struct ElectrumServer {
     notification: Sender<Notification>,
     addr: SocketAddr,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    acceptor: Option<thread::JoinHandle<()>>,
 }
 
 impl ElectrumServer {
@@ -488,12 +335,28 @@ impl ElectrumServer {
         acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
     ) {
         spawn_thread("notification", move || {
-            for msg in notification.receiver().iter() {
+            let receiver = notification.receiver();
+            for msg in receiver.iter() {
                 match msg {
-                    Notification::IndexChangelog(changelog) => {
+                    Notification::IndexChangelog(mut changelog) => {
+                        // merge the changelogs of syncs that completed while we were busy dispatching,
+                        // so that the status of each affected scripthash is only recomputed once
+                        let mut exit = false;
+                        for pending in receiver.try_iter() {
+                            match pending {
+                                Notification::IndexChangelog(more) => changelog.extend(more),
+                                Notification::Exit => {
+                                    exit = true;
+                                    break;
+                                }
+                            }
+                        }
                         if let Err(e) = subman.lock().unwrap().dispatch(changelog) {
                             warn!(target: LT, "failed dispatching events: {:?}", e);
                         }
+                        if exit {
+                            acceptor.send(None).unwrap();
+                        }
                     }
                     Notification::Exit => acceptor.send(None).unwrap(),
                 }
@@ -501,19 +364,20 @@ impl ElectrumServer {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> (SocketAddr, Channel<Option<(TcpStream, SocketAddr)>>) {
+    fn start_acceptor(addr: SocketAddr) -> Result<(SocketAddr, Acceptor, thread::JoinHandle<()>)> {
         let listener = TcpListener::bind(addr)
-            .with_context(|| format!("bind({}) failed", addr))
-            .unwrap();
-        let bound_addr = listener.local_addr().unwrap();
+            .with_context(|| format!("failed binding the electrum rpc server on {}", addr))?;
+        // the bound address may differ from the configured one when binding on port 0
+        let bound_addr = listener.local_addr()?;
         info!(
             target: LT,
             "Electrum RPC server running on {} (protocol {})", bound_addr, PROTOCOL_VERSION
         );
+        warn_if_public("Electrum RPC", &bound_addr);
 
         let chan = Channel::unbounded();
         let acceptor = chan.sender();
-        spawn_thread("acceptor", move || loop {
+        let handle = spawn_thread("acceptor", move || loop {
             let (stream, addr) = listener.accept().expect("accept failed");
             stream
                 .set_nonblocking(false)
@@ -523,31 +387,59 @@ impl ElectrumServer {
                 break;
             }
         });
-        (bound_addr, chan)
+        Ok((bound_addr, chan, handle))
     }
 
-    pub fn start(addr: SocketAddr, skip_merkle: bool, query: Arc<Query>) -> Self {
+    pub fn start(
+        addr: SocketAddr,
+        skip_merkle: bool,
+        resume_subscriptions: bool,
+        max_pending_requests: Option<usize>,
+        rate_limit: Option<u32>,
+        rate_limit_disconnect: bool,
+        query: Arc<Query>,
+    ) -> Result<Self> {
         let notification = Channel::unbounded();
-        let (bound_addr, acceptor) = Self::start_acceptor(addr);
-        Self {
+        let (bound_addr, acceptor, acceptor_handle) = Self::start_acceptor(addr)?;
+        Ok(Self {
             notification: notification.sender(),
             addr: bound_addr,
             server: Some(spawn_thread("rpc", move || {
+                let handler = Arc::new(ElectrumHandler::new(
+                    query.clone(),
+                    skip_merkle,
+                    Some(bound_addr.port()),
+                ));
                 let subman = Arc::new(Mutex::new(SubscriptionManager {
                     next_id: 0,
                     subscribers: HashMap::new(),
+                    saved_sessions: resume_subscriptions.do_then(HashMap::new),
                     query: query.clone(),
                 }));
                 Self::start_notifier(notification, subman.clone(), acceptor.sender());
                 let mut children = vec![];
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
                     let query = query.clone();
+                    let handler = handler.clone();
                     let subman = subman.clone();
                     children.push(spawn_thread("peer", move || {
                         info!(target: LT, "[{}] connected peer", addr);
-                        let conn = Connection::new(query, skip_merkle, stream, addr, subman);
+                        #[cfg(feature = "metrics")]
+                        crate::metrics::ELECTRUM_CONNECTIONS.inc();
+                        let conn = Connection::new(
+                            query,
+                            handler,
+                            max_pending_requests,
+                            rate_limit,
+                            rate_limit_disconnect,
+                            stream,
+                            addr,
+                            subman,
+                        );
                         conn.run();
                         info!(target: LT, "[{}] disconnected peer", addr);
+                        #[cfg(feature = "metrics")]
+                        crate::metrics::ELECTRUM_CONNECTIONS.dec();
                     }));
                 }
                 let subman = subman.lock().unwrap();
@@ -565,7 +457,8 @@ impl ElectrumServer {
                 }
                 trace!(target: LT, "RPC connections are closed");
             })),
-        }
+            acceptor: Some(acceptor_handle),
+        })
     }
 
     pub fn send_updates(&self, changelog: &[IndexChange]) {
@@ -598,23 +491,46 @@ impl ElectrumServer {
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
-}
 
-impl Drop for ElectrumServer {
-    fn drop(&mut self) {
+    /// Close all RPC connections and stop listening, waiting for up to `SHUTDOWN_TIMEOUT` for
+    /// the server threads to terminate.
+    pub fn shutdown(&mut self) {
+        let server = some_or_ret!(self.server.take());
         trace!(target: LT, "stop accepting new RPCs");
         self.notification.send(Notification::Exit).unwrap();
-        if let Some(handle) = self.server.take() {
-            handle.join().unwrap();
+        join_timeout(server, SHUTDOWN_TIMEOUT, "electrum rpc");
+
+        // the acceptor is blocked waiting for new connections. wake it up with a dummy connection
+        // so that it notices the server is gone and releases the listening socket.
+        if let Some(acceptor) = self.acceptor.take() {
+            let mut wake_addr = self.addr;
+            if wake_addr.ip().is_unspecified() {
+                wake_addr.set_ip(match wake_addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            if TcpStream::connect(wake_addr).is_ok() {
+                join_timeout(acceptor, SHUTDOWN_TIMEOUT, "electrum acceptor");
+            }
         }
         trace!(target: LT, "RPC server is stopped");
     }
 }
 
+impl Drop for ElectrumServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 // unite with the http server subscription implementation?
 struct SubscriptionManager {
     next_id: usize,
     subscribers: HashMap<usize, Subscriber>,
+    // the subscriptions of disconnected clients, keyed by their session token.
+    // only available when --electrum-resume-subscriptions is enabled.
+    saved_sessions: Option<HashMap<String, SavedSession>>,
     query: Arc<Query>,
 }
 
@@ -622,8 +538,16 @@ struct Subscriber {
     sender: SyncSender<Message>,
     // wants new blocks
     blocks: bool,
-    // wants updates for these scripthashes
-    scripthashes: HashSet<ScriptHash>,
+    // wants updates for these scripthashes, mapped to the last status hash sent to the client
+    scripthashes: HashMap<ScriptHash, Option<StatusHash>>,
+    // the client-provided token identifying its session
+    session_token: Option<String>,
+}
+
+struct SavedSession {
+    blocks: bool,
+    scripthashes: HashMap<ScriptHash, Option<StatusHash>>,
+    saved_at: Instant,
 }
 
 impl SubscriptionManager {
@@ -635,7 +559,8 @@ impl SubscriptionManager {
             Subscriber {
                 sender,
                 blocks: false,
-                scripthashes: HashSet::new(),
+                scripthashes: HashMap::new(),
+                session_token: None,
             },
         );
         id
@@ -645,13 +570,82 @@ impl SubscriptionManager {
             s.blocks = true
         }
     }
-    pub fn subscribe_scripthash(&mut self, subscriber_id: usize, scripthash: ScriptHash) {
+    pub fn subscribe_scripthash(
+        &mut self,
+        subscriber_id: usize,
+        scripthash: ScriptHash,
+        status_hash: Option<StatusHash>,
+    ) {
         if let Some(s) = self.subscribers.get_mut(&subscriber_id) {
-            s.scripthashes.insert(scripthash);
+            s.scripthashes.insert(scripthash, status_hash);
         }
     }
     pub fn remove(&mut self, subscriber_id: usize) {
-        self.subscribers.remove(&subscriber_id);
+        let subscriber = some_or_ret!(self.subscribers.remove(&subscriber_id));
+        let saved_sessions = some_or_ret!(self.saved_sessions.as_mut());
+        let session_token = some_or_ret!(subscriber.session_token);
+
+        // keep the number of saved sessions bounded by evicting the oldest one
+        if saved_sessions.len() >= MAX_SAVED_SESSIONS
+            && !saved_sessions.contains_key(&session_token)
+        {
+            let oldest_token = saved_sessions
+                .iter()
+                .min_by_key(|(_, session)| session.saved_at)
+                .map(|(token, _)| token.clone())
+                .unwrap();
+            saved_sessions.remove(&oldest_token);
+        }
+
+        trace!(
+            target: LT,
+            "saving session with {} subscriptions",
+            subscriber.scripthashes.len()
+        );
+        saved_sessions.insert(
+            session_token,
+            SavedSession {
+                blocks: subscriber.blocks,
+                scripthashes: subscriber.scripthashes,
+                saved_at: Instant::now(),
+            },
+        );
+    }
+    // Associate the subscriber with the session token and restore its previous subscriptions.
+    // Returns notifications for the scripthashes whose status changed while it was disconnected.
+    pub fn resume_session(
+        &mut self,
+        subscriber_id: usize,
+        session_token: String,
+    ) -> Result<Vec<Message>> {
+        let saved_sessions = some_or_ret!(self.saved_sessions.as_mut(), Ok(vec![]));
+        let subscriber = some_or_ret!(self.subscribers.get_mut(&subscriber_id), Ok(vec![]));
+        let saved_session = saved_sessions.remove(&session_token);
+        subscriber.session_token = Some(session_token);
+        let saved_session = some_or_ret!(saved_session, Ok(vec![]));
+
+        debug!(
+            target: LT,
+            "resuming session with {} subscriptions",
+            saved_session.scripthashes.len()
+        );
+
+        let mut notifications = vec![];
+        if saved_session.blocks {
+            let BlockId(tip_height, tip_hash) = self.query.get_tip()?;
+            let hex_header = self.query.get_header_hex(&tip_hash)?;
+            notifications.push(Message::ChainTip(tip_height, hex_header));
+        }
+        for (scripthash, old_status_hash) in saved_session.scripthashes {
+            let status_hash = self.query.get_status_hash(&scripthash);
+            if status_hash != old_status_hash {
+                notifications.push(Message::HistoryChange(scripthash, status_hash));
+            }
+            subscriber.scripthashes.insert(scripthash, status_hash);
+        }
+        subscriber.blocks = subscriber.blocks || saved_session.blocks;
+
+        Ok(notifications)
     }
     pub fn dispatch(&mut self, changelog: Vec<IndexChange>) -> Result<()> {
         if self.subscribers.is_empty() {
@@ -685,23 +679,41 @@ impl SubscriptionManager {
         let query = self.query.clone();
 
         self.subscribers.retain(|subscriber_id, subscriber| {
-            if subscriber.blocks {
-                tip_msgs.clone().into_iter()
+            let tip_msgs = if subscriber.blocks {
+                tip_msgs.clone()
             } else {
-                vec![].into_iter()
+                vec![]
+            };
+            let history_msgs = scripthashes
+                .iter_mut()
+                .filter_map(|(scripthash, status_hash)| {
+                    let last_status_hash = subscriber.scripthashes.get(scripthash)?;
+                    // calculate the status hash once per script hash and cache it
+                    let status_hash =
+                        *status_hash.get_or_insert_with(|| query.get_status_hash(scripthash));
+                    // skip scripthashes whose status ended up unchanged since the last notification
+                    (status_hash != *last_status_hash)
+                        .do_then(|| Message::HistoryChange(*scripthash, status_hash))
+                })
+                .collect::<Vec<Message>>();
+
+            if tip_msgs.is_empty() && history_msgs.is_empty() {
+                return true;
             }
-            .chain(
-                scripthashes
-                    .iter_mut()
-                    .filter(|(scripthash, _)| subscriber.scripthashes.contains(*scripthash))
-                    .map(|(scripthash, status_hash)| {
-                        // calculate the status hash once per script hash and cache it
-                        let status_hash =
-                            status_hash.get_or_insert_with(|| query.get_status_hash(scripthash));
-                        Message::HistoryChange(*scripthash, *status_hash)
-                    }),
-            )
-            .all(|msg| match subscriber.sender.try_send(msg) {
+
+            let sent_statuses = history_msgs
+                .iter()
+                .filter_map(|msg| match msg {
+                    Message::HistoryChange(scripthash, status_hash) => {
+                        Some((*scripthash, *status_hash))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            // send all the client's notifications together as a single message
+            let msgs = tip_msgs.into_iter().chain(history_msgs).collect();
+            match subscriber.sender.try_send(Message::Notifications(msgs)) {
                 Err(TrySendError::Disconnected(_)) => {
                     debug!(
                         target: LT,
@@ -709,8 +721,13 @@ impl SubscriptionManager {
                     );
                     false
                 }
-                Ok(_) | Err(TrySendError::Full(_)) => true,
-            })
+                Err(TrySendError::Full(_)) => true,
+                Ok(_) => {
+                    // keep track of the last status hash sent to the client
+                    subscriber.scripthashes.extend(sent_statuses);
+                    true
+                }
+            }
         });
 
         Ok(())
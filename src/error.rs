@@ -18,6 +18,9 @@ pub enum BwtError {
     #[error("Transaction not found: {0}")]
     TxNotFound(Txid),
 
+    #[error("Transaction already confirmed: {0}")]
+    TxConfirmed(Txid),
+
     #[error("Address or script hash not found: {0}")]
     ScriptHashNotFound(ScriptHash),
 
@@ -41,6 +44,7 @@ impl BwtError {
             BwtError::ReorgDetected(..) => StatusCode::GONE,
             BwtError::PrunedBlocks => StatusCode::GONE,
             BwtError::TxNotFound(_) => StatusCode::NOT_FOUND,
+            BwtError::TxConfirmed(_) => StatusCode::CONFLICT,
             BwtError::ScriptHashNotFound(_) => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use bitcoincore_rpc::RpcApi;
+
+use crate::error::{Context, Result};
+use crate::util::bitcoincore_ext::RpcClient;
+
+const FEE_TABLE_TTL: Duration = Duration::from_secs(120);
+
+/// A mapping of confirmation targets (in blocks) to fee rates (in sat/vB)
+pub type FeeTable = BTreeMap<u16, f64>;
+
+pub trait FeeEstimator: Send + Sync {
+    /// Estimate the fee rate (in sat/vB) required to confirm within `target` blocks
+    fn estimate_fee(&self, target: u16) -> Result<Option<f64>>;
+}
+
+/// The source used for fee estimation
+#[derive(Debug, Clone)]
+pub enum FeeSource {
+    /// Use bitcoind's `estimatesmartfee`
+    Bitcoind,
+    /// Use a fixed fee table
+    Static(FeeTable),
+    /// Run a command that prints out a JSON fee table (in the format of esplora's `GET /fee-estimates`)
+    Command(String),
+}
+
+impl FeeSource {
+    pub fn into_estimator(self, rpc: Arc<RpcClient>) -> Box<dyn FeeEstimator> {
+        match self {
+            FeeSource::Bitcoind => Box::new(BitcoindFeeEstimator(rpc)),
+            FeeSource::Static(table) => Box::new(StaticFeeEstimator(table)),
+            FeeSource::Command(cmd) => Box::new(CommandFeeEstimator::new(cmd)),
+        }
+    }
+}
+
+pub struct BitcoindFeeEstimator(Arc<RpcClient>);
+
+impl FeeEstimator for BitcoindFeeEstimator {
+    fn estimate_fee(&self, target: u16) -> Result<Option<f64>> {
+        Ok(self
+            .0
+            .estimate_smart_fee(target, None)?
+            .fee_rate
+            // from sat/kB to sat/b
+            .map(|rate| rate.as_sat() as f64 / 1000f64))
+    }
+}
+
+pub struct StaticFeeEstimator(FeeTable);
+
+impl FeeEstimator for StaticFeeEstimator {
+    fn estimate_fee(&self, target: u16) -> Result<Option<f64>> {
+        Ok(lookup_fee_table(&self.0, target))
+    }
+}
+
+pub struct CommandFeeEstimator {
+    cmd: String,
+    cached_table: RwLock<Option<(FeeTable, Instant)>>,
+}
+
+impl CommandFeeEstimator {
+    pub fn new(cmd: String) -> Self {
+        CommandFeeEstimator {
+            cmd,
+            cached_table: RwLock::new(None),
+        }
+    }
+
+    fn fee_table(&self) -> Result<FeeTable> {
+        ttl_cache!(self.cached_table, FEE_TABLE_TTL, || -> Result<FeeTable> {
+            debug!("fetching fee estimates with cmd {}", self.cmd);
+            let output = Command::new("sh").arg("-c").arg(&self.cmd).output()?;
+            ensure!(
+                output.status.success(),
+                "fee estimates command failed with {}",
+                output.status
+            );
+            serde_json::from_slice(&output.stdout).context("invalid fee estimates command output")
+        });
+    }
+}
+
+impl FeeEstimator for CommandFeeEstimator {
+    fn estimate_fee(&self, target: u16) -> Result<Option<f64>> {
+        Ok(lookup_fee_table(&self.fee_table()?, target))
+    }
+}
+
+// Use the fee rate of the highest target that does not exceed the requested one, or the
+// fee rate of the lowest available target if the requested one is below all of them.
+fn lookup_fee_table(table: &FeeTable, target: u16) -> Option<f64> {
+    table
+        .range(..=target)
+        .next_back()
+        .or_else(|| table.iter().next())
+        .map(|(_, feerate)| *feerate)
+}
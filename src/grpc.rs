@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::{net, thread};
+
+use bitcoin_hashes::hex::ToHex;
+use tokio::sync::mpsc::{self as tmpsc, error::TrySendError};
+use tokio::sync::oneshot;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::error::{Context, Error, Result};
+use crate::types::{BlockId, ScriptHash, TxStatus};
+use crate::util::{join_timeout, warn_if_public, SHUTDOWN_TIMEOUT};
+use crate::{IndexChange, Query};
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("bwt");
+}
+
+use proto::bwt_server::{Bwt, BwtServer};
+use proto::index_update::Update;
+
+const UPDATES_BUFFER_SIZE: usize = 5000;
+
+type GrpcResult<T> = std::result::Result<Response<T>, Status>;
+type UpdatesSender = tmpsc::Sender<std::result::Result<proto::IndexUpdate, Status>>;
+
+type Listeners = Arc<Mutex<Vec<Listener>>>;
+
+struct Listener {
+    tx: UpdatesSender,
+    // the scripthashes the client subscribed to, or empty to receive updates for all of them
+    scripthashes: HashSet<ScriptHash>,
+}
+
+fn subscription_matches(scripthashes: &HashSet<ScriptHash>, change: &IndexChange) -> bool {
+    if scripthashes.is_empty() {
+        return true;
+    }
+    match change.scripthash() {
+        Some(scripthash) => scripthashes.contains(scripthash),
+        // chain updates are always sent, other transaction updates are only sent without a filter
+        None => matches!(change, IndexChange::ChainTip(..) | IndexChange::Reorg(..)),
+    }
+}
+
+struct BwtService {
+    query: Arc<Query>,
+    listeners: Listeners,
+}
+
+#[tonic::async_trait]
+impl Bwt for BwtService {
+    async fn get_tip(&self, _request: Request<proto::TipRequest>) -> GrpcResult<proto::BlockId> {
+        let tip = self
+            .query
+            .get_synced_tip()
+            .ok_or_else(|| Status::unavailable("the index is not synced yet"))?;
+        Ok(Response::new(tip.into()))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<proto::ScripthashRequest>,
+    ) -> GrpcResult<proto::HistoryResponse> {
+        let scripthash = parse_scripthash(&request.get_ref().scripthash)?;
+        let history = self
+            .query
+            .get_history(&scripthash)
+            .into_iter()
+            .map(|entry| proto::HistoryEntry {
+                txid: entry.txid.to_string(),
+                status: Some(entry.status.into()),
+            })
+            .collect();
+        Ok(Response::new(proto::HistoryResponse { history }))
+    }
+
+    async fn list_unspent(
+        &self,
+        request: Request<proto::ScripthashRequest>,
+    ) -> GrpcResult<proto::UnspentResponse> {
+        let scripthash = parse_scripthash(&request.get_ref().scripthash)?;
+        let utxos = self
+            .query
+            .list_unspent(Some(&scripthash), 0, 0, None)
+            .map_err(internal_error)?
+            .into_iter()
+            .map(|utxo| proto::Utxo {
+                txid: utxo.txid.to_string(),
+                vout: utxo.vout,
+                amount: utxo.amount,
+                address: utxo.script_info.address.to_string(),
+                script_pubkey: utxo.script_pubkey.as_bytes().to_hex(),
+                status: Some(utxo.status.into()),
+            })
+            .collect();
+        Ok(Response::new(proto::UnspentResponse { utxos }))
+    }
+
+    async fn get_balance(
+        &self,
+        request: Request<proto::ScripthashRequest>,
+    ) -> GrpcResult<proto::Balance> {
+        let scripthash = parse_scripthash(&request.get_ref().scripthash)?;
+        let (confirmed, unconfirmed) = self
+            .query
+            .get_script_balance(&scripthash)
+            .map_err(internal_error)?;
+        Ok(Response::new(proto::Balance {
+            confirmed,
+            unconfirmed,
+        }))
+    }
+
+    async fn estimate_fee(
+        &self,
+        request: Request<proto::FeeRequest>,
+    ) -> GrpcResult<proto::FeeEstimate> {
+        let target = request.get_ref().target;
+        if target == 0 || target >= 1024 {
+            return Err(Status::invalid_argument("target out of range"));
+        }
+        let feerate = self
+            .query
+            .estimate_fee(target as u16)
+            .map_err(internal_error)?;
+        Ok(Response::new(proto::FeeEstimate {
+            available: feerate.is_some(),
+            feerate: feerate.unwrap_or(0.0),
+        }))
+    }
+
+    type SubscribeUpdatesStream = tmpsc::Receiver<std::result::Result<proto::IndexUpdate, Status>>;
+
+    async fn subscribe_updates(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> GrpcResult<Self::SubscribeUpdatesStream> {
+        let scripthashes = request
+            .get_ref()
+            .scripthashes
+            .iter()
+            .map(|scripthash| parse_scripthash(scripthash))
+            .collect::<std::result::Result<HashSet<_>, _>>()?;
+        debug!("subscribing grpc client to {:?}", scripthashes);
+
+        let (tx, rx) = tmpsc::channel(UPDATES_BUFFER_SIZE);
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Listener { tx, scripthashes });
+        Ok(Response::new(rx))
+    }
+}
+
+pub struct GrpcServer {
+    addr: net::SocketAddr,
+    listeners: Listeners,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl GrpcServer {
+    pub fn start(addr: net::SocketAddr, query: Arc<Query>) -> Result<Self> {
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        let service = BwtService {
+            query,
+            listeners: listeners.clone(),
+        };
+
+        // bind here rather than in the server thread, to report errors and the bound address
+        // (which may differ from the configured one when binding on port 0)
+        let tcp_listener = net::TcpListener::bind(addr)
+            .with_context(|| format!("failed binding the grpc server on {}", addr))?;
+        tcp_listener.set_nonblocking(true)?;
+        let bound_addr = tcp_listener.local_addr()?;
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let thread = thread::spawn(move || {
+            spawn(service, tcp_listener, shutdown_rx);
+        });
+
+        info!("gRPC server running on {}", bound_addr);
+        warn_if_public("gRPC", &bound_addr);
+
+        Ok(GrpcServer {
+            addr: bound_addr,
+            listeners,
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn send_updates(&self, changelog: &[IndexChange]) {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.is_empty() {
+            return;
+        }
+        info!(
+            "sending {} update(s) to {} grpc client(s)",
+            changelog.len(),
+            listeners.len()
+        );
+        // send updates without blocking, skipping updates for slow clients whose buffer is full
+        // and dropping disconnected ones
+        let mut active_listeners = Vec::with_capacity(listeners.len());
+        for Listener {
+            mut tx,
+            scripthashes,
+        } in listeners.drain(..)
+        {
+            let mut skipped = 0;
+            let connected = changelog
+                .iter()
+                .filter(|change| subscription_matches(&scripthashes, change))
+                .filter_map(make_update)
+                .all(|update| match tx.try_send(Ok(update)) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        skipped += 1;
+                        true
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                });
+            if skipped > 0 {
+                warn!("skipped {} update(s) for a slow grpc client", skipped);
+            }
+            if connected {
+                active_listeners.push(Listener { tx, scripthashes });
+            }
+        }
+        *listeners = active_listeners;
+    }
+
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting new connections and wait for the server thread to terminate, for up to
+    /// `SHUTDOWN_TIMEOUT`.
+    pub fn shutdown(&mut self) {
+        let shutdown_tx = some_or_ret!(self.shutdown_tx.take());
+        trace!("gRPC server shutting down");
+        // end the SubscribeUpdates streams, which would otherwise keep the server waiting
+        self.listeners.lock().unwrap().clear();
+        shutdown_tx.send(()).ok();
+        if let Some(thread) = self.thread.take() {
+            join_timeout(thread, SHUTDOWN_TIMEOUT, "grpc server");
+        }
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[tokio::main]
+async fn spawn(
+    service: BwtService,
+    tcp_listener: net::TcpListener,
+    shutdown_rx: oneshot::Receiver<()>,
+) {
+    let mut tcp_listener = tokio::net::TcpListener::from_std(tcp_listener).unwrap();
+    let result = Server::builder()
+        .add_service(BwtServer::new(service))
+        .serve_with_incoming_shutdown(tcp_listener.incoming(), async {
+            shutdown_rx.await.ok();
+        })
+        .await;
+    if let Err(e) = result {
+        warn!("gRPC server failed: {:?}", e);
+    }
+}
+
+fn make_update(change: &IndexChange) -> Option<proto::IndexUpdate> {
+    let update = match change {
+        IndexChange::ChainTip(blockid) => Update::ChainTip((*blockid).into()),
+        IndexChange::Reorg(height, prev_hash, curr_hash) => Update::Reorg(proto::Reorg {
+            height: *height,
+            prev_hash: prev_hash.to_string(),
+            curr_hash: curr_hash.to_string(),
+        }),
+        IndexChange::Transaction(txid, status) => Update::Transaction(proto::TransactionUpdate {
+            txid: txid.to_string(),
+            status: Some((*status).into()),
+        }),
+        IndexChange::TransactionReplaced(txid) => Update::TransactionReplaced(txid.to_string()),
+        IndexChange::TxoFunded(outpoint, scripthash, amount, status) => {
+            Update::TxoFunded(proto::TxoFunded {
+                outpoint: outpoint.to_string(),
+                scripthash: scripthash.to_string(),
+                amount: *amount,
+                status: Some((*status).into()),
+            })
+        }
+        IndexChange::TxoSpent(inpoint, scripthash, prev_outpoint, status) => {
+            Update::TxoSpent(proto::TxoSpent {
+                inpoint: format!("{}:{}", inpoint.txid, inpoint.vin),
+                scripthash: scripthash.to_string(),
+                prev_outpoint: prev_outpoint.to_string(),
+                status: Some((*status).into()),
+            })
+        }
+    };
+    Some(proto::IndexUpdate {
+        update: Some(update),
+    })
+}
+
+impl From<BlockId> for proto::BlockId {
+    fn from(BlockId(height, hash): BlockId) -> Self {
+        proto::BlockId {
+            height,
+            hash: hash.to_string(),
+        }
+    }
+}
+
+impl From<TxStatus> for proto::TxStatus {
+    fn from(status: TxStatus) -> Self {
+        use proto::tx_status::State;
+        let (state, block_height) = match status {
+            TxStatus::Confirmed(height) => (State::Confirmed, height),
+            TxStatus::Unconfirmed => (State::Unconfirmed, 0),
+            TxStatus::Conflicted => (State::Conflicted, 0),
+        };
+        proto::TxStatus {
+            state: state as i32,
+            block_height,
+        }
+    }
+}
+
+fn parse_scripthash(scripthash: &str) -> std::result::Result<ScriptHash, Status> {
+    scripthash
+        .parse()
+        .map_err(|_| Status::invalid_argument(format!("invalid scripthash {}", scripthash)))
+}
+
+fn internal_error(err: Error) -> Status {
+    warn!("processing grpc request failed: {:#?}", err);
+    Status::internal(format!("{:#}", err))
+}
@@ -1,48 +1,105 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
 use std::{net, thread};
 
+use futures::SinkExt;
 use serde::{Deserialize, Deserializer};
 use tokio::stream::{self, Stream, StreamExt};
-use tokio::sync::{mpsc as tmpsc, oneshot};
+use tokio::sync::mpsc::{self as tmpsc, error::TrySendError};
+use tokio::sync::oneshot;
 use warp::http::{header, StatusCode};
 use warp::sse::ServerSentEvent;
+use warp::ws::{Message, WebSocket};
 use warp::{self, reply, Filter, Reply};
 
-use bitcoin::{Address, BlockHash, OutPoint, Txid};
+use bitcoin::{Address, BlockHash, Network, OutPoint, Txid};
 use bitcoin_hashes::hex::{FromHex, ToHex};
-
-use crate::error::{fmt_error_chain, BwtError, Error, OptionExt};
-use crate::types::{BlockId, ScriptHash};
-use crate::util::{block_on_future, descriptor::Checksum};
+use bitcoincore_rpc::RpcApi;
+
+use crate::error::{fmt_error_chain, BwtError, Context, Error, OptionExt};
+use crate::labels::LabelTarget;
+use crate::query::TxBlockInfo;
+use crate::types::{BlockId, RescanSince, ScriptHash, TxDirection, TxStatus};
+use crate::util::descriptor::{normalize_descriptor, Checksum};
+use crate::util::xpub::inspect_xyzpub;
+use crate::util::{
+    address_matches_network, block_on_future, interval_from_secs, join_timeout,
+    ratelimit::{client_ip_key, RateLimiter},
+    warn_if_public, SyncIntervals, SHUTDOWN_TIMEOUT,
+};
+use crate::wallet::WalletTxChange;
 use crate::{store, util::banner, IndexChange, Query};
 
+#[cfg(feature = "electrum")]
+use crate::electrum::ElectrumHandler;
+#[cfg(feature = "webhooks")]
+use crate::monitor::{AddressMonitor, MonitorRequest};
+
 type SyncChanSender = Arc<Mutex<mpsc::Sender<()>>>;
 
+// the default and maximum number of history entries returned per page
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
+
+// the default and maximum number of addresses derived per request
+const DEFAULT_DERIVE_COUNT: u32 = 20;
+const MAX_DERIVE_COUNT: u32 = 1000;
+
+// the confirmation targets included in GET /fee-estimates
+const FEE_ESTIMATE_TARGETS: [u16; 7] = [1, 2, 3, 6, 12, 24, 144];
+
+// the default max age of the index tip (in seconds) before the health check fails
+const DEFAULT_HEALTH_MAX_TIP_AGE: u64 = 7200;
+
+// the number of real-time updates buffered for each sse client before skipping further ones
+const SSE_BUFFER_SIZE: usize = 5000;
+
+#[allow(clippy::too_many_arguments)]
 fn setup(
     cors: Option<String>,
+    rpc_allowlist: Vec<String>,
+    skip_merkle: bool,
+    rate_limit: Option<u32>,
+    #[cfg(feature = "electrum")] electrum_port: Option<u16>,
+    #[cfg(feature = "webhooks")] monitor: Arc<AddressMonitor>,
     query: Arc<Query>,
+    intervals: Arc<SyncIntervals>,
     sync_tx: SyncChanSender,
     listeners: Listeners,
 ) -> warp::Server<impl warp::Filter<Extract = impl warp::Reply> + Clone> {
+    // advertise the electrum tcp server in server.features, the same as the tcp server itself does
+    #[cfg(feature = "electrum")]
+    let electrum = Arc::new(ElectrumHandler::new(
+        query.clone(),
+        skip_merkle,
+        electrum_port,
+    ));
     let query = warp::any().map(move || Arc::clone(&query));
     let sync_tx = warp::any().map(move || Arc::clone(&sync_tx));
+    let intervals = warp::any().map(move || Arc::clone(&intervals));
     let listeners = warp::any().map(move || Arc::clone(&listeners));
+    let rpc_allowlist: Arc<HashSet<String>> = Arc::new(rpc_allowlist.into_iter().collect());
+    let rpc_allowlist = warp::any().map(move || Arc::clone(&rpc_allowlist));
 
-    let mut headers = header::HeaderMap::new();
-    if let Some(cors) = cors {
-        // allow using "any" as an alias for "*", avoiding expansion when passing "*" can be tricky
-        let cors = if cors == "any" { "*".into() } else { cors };
-        headers.insert("Access-Control-Allow-Origin", cors.parse().unwrap());
-    }
+    let cors = cors.map(|cors| Arc::new(CorsOrigins::from(cors.as_str())));
+    let cors_origins = {
+        let cors = cors.clone();
+        warp::any().map(move || cors.clone())
+    };
+    let rate_limiter = rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+    let rate_limiter = warp::any().map(move || rate_limiter.clone());
 
     // GET /wallets
     let wallets_handler = warp::get()
         .and(warp::path!("wallets"))
         .and(query.clone())
         .map(|query: Arc<Query>| {
-            let wallets = query.get_wallets();
-            reply::json(&wallets)
-        });
+            let wallets = query.get_wallets_stats();
+            Ok(reply::json(&wallets))
+        })
+        .map(handle_error);
 
     // GET /wallet/:checksum
     let wallet_handler = warp::get()
@@ -66,18 +123,146 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/balance
+    let wallet_balance_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "balance"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            let balance = query
+                .get_wallet_balance(&checksum)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&balance))
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum/gap
     let wallet_gap_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / "gap"))
         .and(query.clone())
         .map(|checksum: Checksum, query: Arc<Query>| {
             let gap = query
-                .find_wallet_gap(&checksum)
+                .get_wallet_gap(&checksum)
                 .or_err(StatusCode::NOT_FOUND)?;
             Ok(reply::json(&gap))
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/utxos
+    let wallet_utxos_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "utxos"))
+        .and(warp::query::<UtxoOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: UtxoOptions, query: Arc<Query>| {
+                let utxos = query
+                    .list_wallets_unspent(
+                        &[checksum],
+                        options.min_conf,
+                        options.min_value,
+                        options.include_unsafe,
+                    )?
+                    .or_err(StatusCode::NOT_FOUND)?;
+                Ok(reply::json(&utxos))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/group
+    let wallet_group_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "group"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            let group = query
+                .get_wallet_group(&checksum)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&group))
+        })
+        .map(handle_error);
+
+    // POST /wallet/:checksum/rescan
+    let wallet_rescan_handler = warp::post()
+        .and(warp::path!("wallet" / Checksum / "rescan"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .map(
+            |checksum: Checksum, req: RescanRequest, query: Arc<Query>, sync_tx: SyncChanSender| {
+                let rescan_since = req
+                    .since
+                    .guard_future(true)
+                    .context(StatusCode::BAD_REQUEST)?;
+                query
+                    .rescan_wallet(&checksum, rescan_since)
+                    .or_err(StatusCode::NOT_FOUND)?;
+                // the rescan gets processed by the next sync
+                sync_tx.lock().unwrap().send(())?;
+                Ok(reply::with_status("rescan queued", StatusCode::ACCEPTED))
+            },
+        )
+        .map(handle_error);
+
+    // GET /rescan
+    let rescan_progress_handler = warp::get()
+        .and(warp::path!("rescan"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            // formatted similarly to the `scanning` field of bitcoind's `getwalletinfo`
+            let scanning = match query.get_scanning()? {
+                Some(scanning) => json!(scanning),
+                None => json!(false),
+            };
+            Ok(reply::json(&scanning))
+        })
+        .map(handle_error);
+
+    // GET /wallets/:checksums/balance
+    let wallets_balance_handler = warp::get()
+        .and(warp::path!("wallets" / ChecksumList / "balance"))
+        .and(query.clone())
+        .map(|checksums: ChecksumList, query: Arc<Query>| {
+            let balance = query
+                .get_wallets_balance(&checksums.0)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&balance))
+        })
+        .map(handle_error);
+
+    // GET /wallets/:checksums/txs
+    let wallets_txs_handler = warp::get()
+        .and(warp::path!("wallets" / ChecksumList / "txs"))
+        .and(query.clone())
+        .map(|checksums: ChecksumList, query: Arc<Query>| {
+            let history = query
+                .get_wallets_history(&checksums.0)
+                .or_err(StatusCode::NOT_FOUND)?;
+            let txs: Vec<_> = history
+                .iter()
+                .map(|txhist| query.get_tx_detail(&txhist.txid).unwrap())
+                .collect();
+            Ok(reply::json(&txs))
+        })
+        .map(handle_error);
+
+    // GET /wallets/:checksums/utxos
+    let wallets_utxos_handler = warp::get()
+        .and(warp::path!("wallets" / ChecksumList / "utxos"))
+        .and(warp::query::<UtxoOptions>())
+        .and(query.clone())
+        .map(
+            |checksums: ChecksumList, options: UtxoOptions, query: Arc<Query>| {
+                let utxos = query
+                    .list_wallets_unspent(
+                        &checksums.0,
+                        options.min_conf,
+                        options.min_value,
+                        options.include_unsafe,
+                    )?
+                    .or_err(StatusCode::NOT_FOUND)?;
+                Ok(reply::json(&utxos))
+            },
+        )
+        .map(handle_error);
+
     // GET /wallet/:checksum/next
     let wallet_next_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / "next"))
@@ -96,12 +281,107 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /wallet/:checksum/next-address
+    let wallet_next_address_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "next-address"))
+        .and(warp::query::<NextAddressOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: NextAddressOptions, query: Arc<Query>| {
+                let next_address = query
+                    .get_next_address(&checksum, options.bump)?
+                    .or_err(StatusCode::NOT_FOUND)?;
+                Ok(reply::json(&next_address))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/addresses
+    let wallet_addresses_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "addresses"))
+        .and(warp::query::<DeriveOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: DeriveOptions, query: Arc<Query>| {
+                let count = cmp::min(options.count, MAX_DERIVE_COUNT);
+                let addresses = query
+                    .derive_wallet_addresses(&checksum, options.start, count)
+                    .or_err(StatusCode::NOT_FOUND)?
+                    .or_err(StatusCode::BAD_REQUEST)?;
+                Ok(reply::json(&addresses))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/history.csv
+    let wallet_history_csv_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "history.csv"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query>| {
+            let mut txs = query
+                .get_wallet_tx_changes(&checksum, None, HISTORY_CSV_PAGE_SIZE)
+                .or_err(StatusCode::NOT_FOUND)?;
+            let disposition = format!("attachment; filename=\"{}-history.csv\"", checksum);
+
+            // formatting the rows may require blocking rpc calls for the block timestamps and fees,
+            // so they're produced on the blocking thread pool, reading the history one page at a
+            // time and waiting for the client to catch up whenever the channel buffer fills up.
+            // the block time of the last row is kept to avoid repeated lookups.
+            let (mut rows_tx, rows_rx) = tmpsc::channel(HISTORY_CSV_PAGE_SIZE);
+            tokio::task::spawn_blocking(move || {
+                let mut last_block_time = None;
+                while let Some(last_tx) = txs.last() {
+                    let after = store::HistoryEntry::new(last_tx.txid, last_tx.status);
+                    for tx in txs.iter().filter(|tx| tx.status.is_viable()) {
+                        let row = history_csv_row(&query, tx, &mut last_block_time);
+                        // stop early if the client disconnected
+                        if futures::executor::block_on(rows_tx.send(row)).is_err() {
+                            return;
+                        }
+                    }
+                    txs = some_or_ret!(query.get_wallet_tx_changes(
+                        &checksum,
+                        Some(&after),
+                        HISTORY_CSV_PAGE_SIZE
+                    ));
+                }
+            });
+            let body = stream::once(HISTORY_CSV_HEADER.to_string())
+                .chain(rows_rx)
+                .map(Ok::<_, std::convert::Infallible>);
+
+            let res = warp::http::Response::new(warp::hyper::Body::wrap_stream(body));
+            let res = reply::with_header(res, header::CONTENT_TYPE, "text/csv");
+            Ok(reply::with_header(
+                res,
+                header::CONTENT_DISPOSITION,
+                disposition,
+            ))
+        })
+        .map(handle_error);
+
     // GET /scripthash/:scripthash/*
     let scripthash_route = warp::path!("scripthash" / ScriptHash / ..);
 
     // GET /address/:address/*
     let address_route = warp::path!("address" / Address / ..).map(ScriptHash::from);
-    // TODO check address version bytes matches the configured network
+
+    // Reply with a 400 error for addresses of the wrong network, or let the request through to
+    // the address handlers otherwise
+    let address_network_handler = warp::path!("address" / Address / ..)
+        .and(query.clone())
+        .and_then(|address: Address, query: Arc<Query>| async move {
+            if address_matches_network(&address, query.network()) {
+                Err(warp::reject::not_found())
+            } else {
+                let msg = format!(
+                    "address {} is invalid for the configured network ({})",
+                    address,
+                    query.network()
+                );
+                Ok(reply::with_status(msg, StatusCode::BAD_REQUEST))
+            }
+        });
 
     // GET /wallet/:checksum/:index/*
     let wallet_key_route = warp::path!("wallet" / Checksum / u32 / ..)
@@ -159,8 +439,12 @@ fn setup(
         .and(warp::query::<UtxoOptions>())
         .and(query.clone())
         .map(|scripthash, options: UtxoOptions, query: Arc<Query>| {
-            let utxos =
-                query.list_unspent(Some(&scripthash), options.min_conf, options.include_unsafe)?;
+            let utxos = query.list_unspent(
+                Some(&scripthash),
+                options.min_conf,
+                options.min_value,
+                options.include_unsafe,
+            )?;
             Ok(reply::json(&utxos))
         })
         .map(handle_error);
@@ -171,12 +455,26 @@ fn setup(
     let spk_txs_handler = warp::get()
         .and(spk_route.clone())
         .and(warp::path!("txs"))
+        .and(warp::query::<HistoryOptions>())
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
-            let txs = query.map_history(&scripthash, |txhist| {
-                query.get_tx_detail(&txhist.txid).unwrap()
-            });
-            Ok(reply::json(&txs))
+        .map(|scripthash, options: HistoryOptions, query: Arc<Query>| {
+            let (history, total) = options.get_page(&scripthash, &query);
+            let txs: Vec<_> = history
+                .iter()
+                .map(|txhist| query.get_tx_detail(&txhist.txid).unwrap())
+                .collect();
+            let reply = if options.block_info {
+                let block_info = query.get_history_block_info(&history)?;
+                let txs: Vec<_> = txs
+                    .into_iter()
+                    .zip(block_info)
+                    .map(|(tx, block_info)| WithBlockInfo { tx, block_info })
+                    .collect();
+                reply::json(&txs)
+            } else {
+                reply::json(&txs)
+            };
+            Ok(with_total_count(reply, total))
         })
         .map(handle_error);
 
@@ -186,10 +484,28 @@ fn setup(
     let spk_txs_compact_handler = warp::get()
         .and(spk_route.clone())
         .and(warp::path!("txs" / "compact"))
+        .and(warp::query::<HistoryOptions>())
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
-            let txs = query.map_history(&scripthash, compact_history);
-            Ok(reply::json(&txs))
+        .map(|scripthash, options: HistoryOptions, query: Arc<Query>| {
+            let (history, total) = options.get_page(&scripthash, &query);
+            let txs: Vec<_> = if options.block_info {
+                let block_info = query.get_history_block_info(&history)?;
+                history
+                    .iter()
+                    .zip(block_info)
+                    .map(|(txhist, block_info)| {
+                        json!([
+                            txhist.txid,
+                            txhist.status,
+                            block_info.confirmations,
+                            block_info.block_time
+                        ])
+                    })
+                    .collect()
+            } else {
+                history.iter().map(compact_history).collect()
+            };
+            Ok(with_total_count(reply::json(&txs), total))
         })
         .map(handle_error);
 
@@ -202,7 +518,9 @@ fn setup(
         .and(warp::path::end())
         .and(query.clone())
         .map(|txid: Txid, query: Arc<Query>| {
-            let tx_info = query.get_tx_detail(&txid).or_err(StatusCode::NOT_FOUND)?;
+            let tx_info = query
+                .get_tx_detail_ext(&txid)?
+                .or_err(StatusCode::NOT_FOUND)?;
             Ok(reply::json(&tx_info))
         })
         .map(handle_error);
@@ -240,6 +558,104 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /tx/:txid/merkle
+    let tx_merkle_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("merkle"))
+        .and(query.clone())
+        .map(move |txid: Txid, query: Arc<Query>| {
+            // generating the proof requires fetching the full block, allow disabling it
+            ensure!(!skip_merkle, StatusCode::FORBIDDEN);
+            let proof = query
+                .get_tx_merkle_proof(&txid)?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&proof))
+        })
+        .map(handle_error);
+
+    // GET /tx/:txid/bump-info
+    let tx_bump_info_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("bump-info"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query>| {
+            let bump_info = query.get_tx_bump_info(&txid)?;
+            Ok(reply::json(&bump_info))
+        })
+        .map(handle_error);
+
+    // GET /tx/:txid/outspends
+    #[cfg(feature = "track-spends")]
+    let tx_outspends_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("outspends"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query>| {
+            let outspends = query
+                .get_tx_outspends(&txid)?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&outspends))
+        })
+        .map(handle_error);
+    // spends are only available with the track-spends feature
+    #[cfg(not(feature = "track-spends"))]
+    let tx_outspends_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("outspends"))
+        .map(|_| StatusCode::NOT_IMPLEMENTED);
+
+    // GET /tx/:txid/label
+    // GET /wallet/:checksum/:index/label
+    // GET /address/:address/label
+    // GET /scripthash/:scripthash/label
+    let label_route = tx_route
+        .map(LabelTarget::Tx)
+        .or(spk_route.clone().map(LabelTarget::Script))
+        .unify()
+        .and(warp::path!("label"));
+
+    let label_handler = warp::get()
+        .and(label_route.clone())
+        .and(query.clone())
+        .map(|target: LabelTarget, query: Arc<Query>| {
+            let label = query.get_label(&target).or_err(StatusCode::NOT_FOUND)?;
+            Ok(reply::json(&LabelBody { label }))
+        })
+        .map(handle_error);
+
+    // PUT /tx/:txid/label
+    // PUT /wallet/:checksum/:index/label
+    // PUT /address/:address/label
+    // PUT /scripthash/:scripthash/label
+    let label_set_handler = warp::put()
+        .and(label_route.clone())
+        .and(warp::body::json())
+        .and(query.clone())
+        .map(|target: LabelTarget, body: LabelBody, query: Arc<Query>| {
+            query.set_label(target, body.label)?;
+            Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+        })
+        .map(handle_error);
+
+    // DELETE /tx/:txid/label
+    // DELETE /wallet/:checksum/:index/label
+    // DELETE /address/:address/label
+    // DELETE /scripthash/:scripthash/label
+    let label_delete_handler = warp::delete()
+        .and(label_route)
+        .and(query.clone())
+        .map(|target: LabelTarget, query: Arc<Query>| {
+            ensure!(query.remove_label(&target)?, StatusCode::NOT_FOUND);
+            Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+        })
+        .map(handle_error);
+
+    // GET /labels
+    let labels_handler = warp::get()
+        .and(warp::path!("labels"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| reply::json(&query.get_labels()));
+
     // GET /txs/since/:block_height
     let txs_since_handler = warp::get()
         .and(warp::path!("txs" / "since" / u32))
@@ -262,6 +678,7 @@ fn setup(
 
     // POST /tx
     let tx_broadcast_handler = warp::post()
+        .and(warp::path!("tx"))
         .and(warp::body::json())
         .and(query.clone())
         .map(|body: BroadcastBody, query: Arc<Query>| {
@@ -288,7 +705,12 @@ fn setup(
         .and(warp::query::<UtxoOptions>())
         .and(query.clone())
         .map(|options: UtxoOptions, query: Arc<Query>| {
-            let utxos = query.list_unspent(None, options.min_conf, options.include_unsafe)?;
+            let utxos = query.list_unspent(
+                None,
+                options.min_conf,
+                options.min_value,
+                options.include_unsafe,
+            )?;
             Ok(reply::json(&utxos))
         })
         .map(handle_error);
@@ -314,7 +736,7 @@ fn setup(
         .and(spk_route)
         .and(warp::path!("stream"))
         .and(ChangelogFilter::param())
-        .and(listeners)
+        .and(listeners.clone())
         .and(query.clone())
         .map(
             |scripthash: ScriptHash,
@@ -328,6 +750,18 @@ fn setup(
         )
         .map(handle_error);
 
+    // GET /ws
+    let ws_handler = warp::path!("ws")
+        .and(warp::ws())
+        .and(listeners)
+        .and(query.clone())
+        .map(
+            |ws: warp::ws::Ws, listeners: Listeners, query: Arc<Query>| {
+                let network = query.network();
+                ws.on_upgrade(move |socket| handle_ws_client(socket, listeners, network))
+            },
+        );
+
     // GET /block/tip
     let block_tip_handler = warp::get()
         .and(warp::path!("block" / "tip"))
@@ -395,12 +829,95 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /fee-estimates
+    let fee_estimates_handler = warp::get()
+        .and(warp::path!("fee-estimates"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            // targets without an available estimate are omitted
+            let estimates = FEE_ESTIMATE_TARGETS
+                .iter()
+                .filter_map(|target| {
+                    let feerate = query.estimate_fee(*target).transpose()?;
+                    Some(feerate.map(|feerate| (*target, feerate)))
+                })
+                .collect::<Result<BTreeMap<u16, f64>, Error>>()?;
+            Ok(reply::json(&estimates))
+        })
+        .map(handle_error);
+
+    // POST /descriptor/checksum
+    let descriptor_checksum_handler = warp::post()
+        .and(warp::path!("descriptor" / "checksum"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .map(|body: DescriptorBody, query: Arc<Query>| {
+            let normalized = normalize_descriptor(&body.descriptor, query.network())
+                .context(StatusCode::BAD_REQUEST)?;
+            Ok(reply::json(&normalized))
+        })
+        .map(handle_error);
+
+    // POST /xpub/inspect
+    let xpub_inspect_handler = warp::post()
+        .and(warp::path!("xpub" / "inspect"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .map(|body: XpubBody, query: Arc<Query>| {
+            let info =
+                inspect_xyzpub(&body.xpub, query.network()).context(StatusCode::BAD_REQUEST)?;
+            Ok(reply::json(&info))
+        })
+        .map(handle_error);
+
+    // POST /rpc
+    let rpc_handler = warp::post()
+        .and(warp::path!("rpc"))
+        .and(warp::body::json())
+        .and(rpc_allowlist)
+        .and(query.clone())
+        .map(
+            |body: RpcBody, rpc_allowlist: Arc<HashSet<String>>, query: Arc<Query>| {
+                // only allow calling the explicitly allowed methods, the rest of the rpc is not exposed
+                ensure!(rpc_allowlist.contains(&body.method), StatusCode::FORBIDDEN);
+                let result: serde_json::Value = query
+                    .rpc()
+                    .call(&body.method, &body.params)
+                    .map_err(BwtError::from)?;
+                Ok(reply::json(&result))
+            },
+        )
+        .map(handle_error);
+
+    // POST /electrum
+    #[cfg(feature = "electrum")]
+    let electrum_handler = warp::post()
+        .and(warp::path!("electrum"))
+        .and(warp::body::json())
+        .and(warp::any().map(move || Arc::clone(&electrum)))
+        .map(
+            |request: serde_json::Value, electrum: Arc<ElectrumHandler>| {
+                reply::json(&electrum.handle_jsonrpc(request))
+            },
+        );
+    // the electrum rpc methods are only available with the electrum feature
+    #[cfg(not(feature = "electrum"))]
+    let electrum_handler = warp::post()
+        .and(warp::path!("electrum"))
+        .map(|| StatusCode::NOT_IMPLEMENTED);
+
     // GET /dump
     let dump_handler = warp::get()
         .and(warp::path!("dump"))
         .and(query.clone())
         .map(|query: Arc<Query>| reply::json(&query.dump_index()));
 
+    // GET /stats
+    let stats_handler = warp::get()
+        .and(warp::path!("stats"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| reply::json(&query.get_store_stats()));
+
     // GET /debug
     let debug_handler = warp::get()
         .and(warp::path!("debug"))
@@ -410,10 +927,130 @@ fn setup(
     // GET /banner.txt
     let banner_handler = warp::get()
         .and(warp::path!("banner.txt"))
-        .and(query)
+        .and(query.clone())
         .map(|query: Arc<Query>| banner::get_welcome_banner(&query, true))
         .map(handle_error);
 
+    // POST /monitor
+    #[cfg(feature = "webhooks")]
+    let monitor_handler = warp::post()
+        .and(warp::path!("monitor"))
+        .and(warp::body::json())
+        .and(warp::any().map(move || Arc::clone(&monitor)))
+        .map(|req: MonitorRequest, monitor: Arc<AddressMonitor>| {
+            let monitor = monitor.register(req)?;
+            Ok(reply::with_status(
+                reply::json(&monitor),
+                StatusCode::CREATED,
+            ))
+        })
+        .map(handle_error);
+    // address monitors are only available with the webhooks feature, which provides the http client
+    #[cfg(not(feature = "webhooks"))]
+    let monitor_handler = warp::post()
+        .and(warp::path!("monitor"))
+        .map(|| StatusCode::NOT_IMPLEMENTED);
+
+    // GET /sync/status
+    let sync_status_handler = warp::get()
+        .and(warp::path!("sync" / "status"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let status = query.get_sync_status()?;
+            Ok(reply::json(&status))
+        })
+        .map(handle_error);
+
+    // GET /health
+    let health_handler = warp::get()
+        .and(warp::path!("health"))
+        .and(warp::query::<HealthOptions>())
+        .and(query.clone())
+        .map(|options: HealthOptions, query: Arc<Query>| {
+            let max_tip_age = options.max_tip_age.unwrap_or(DEFAULT_HEALTH_MAX_TIP_AGE);
+            let health = query.get_health(max_tip_age);
+            let status = if health.ok {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            reply::with_status(reply::json(&health), status)
+        });
+
+    // GET /metrics
+    #[cfg(feature = "metrics")]
+    let metrics_handler = warp::get()
+        .and(warp::path!("metrics"))
+        .and(query.clone())
+        .map(|query: Arc<Query>| {
+            let metrics = crate::metrics::encode(&query)?;
+            Ok(reply::with_header(
+                metrics,
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            ))
+        })
+        .map(handle_error);
+    #[cfg(not(feature = "metrics"))]
+    let metrics_handler = warp::get()
+        .and(warp::path!("metrics"))
+        .map(|| StatusCode::NOT_IMPLEMENTED);
+
+    // Reply with a 503 error until the initial sync is completed, or let the request through
+    // to the other handlers otherwise
+    let not_ready_handler = warp::any()
+        .and(query)
+        .and_then(|query: Arc<Query>| async move {
+            if query.is_ready() {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(reply::with_status(
+                    "initial sync in progress, see GET /sync/status",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ))
+            }
+        });
+
+    // GET /sync/intervals
+    let sync_intervals_handler = warp::get()
+        .and(warp::path!("sync" / "intervals"))
+        .and(intervals.clone())
+        .map(|intervals: Arc<SyncIntervals>| {
+            reply::json(&json!({
+                "poll_interval": intervals.poll_interval().as_secs_f64(),
+                "debounce": intervals.debounce().as_secs_f64(),
+            }))
+        });
+
+    // PUT /sync/intervals
+    let sync_intervals_set_handler = warp::put()
+        .and(warp::path!("sync" / "intervals"))
+        .and(warp::body::json())
+        .and(intervals)
+        .and(sync_tx.clone())
+        .map(
+            |body: SyncIntervalsBody, intervals: Arc<SyncIntervals>, sync_tx: SyncChanSender| {
+                // validate both before applying any of them
+                let parse_interval = |secs: Option<f64>| {
+                    secs.map(|secs| interval_from_secs(secs).or_err(StatusCode::BAD_REQUEST))
+                        .transpose()
+                };
+                let debounce = parse_interval(body.debounce)?;
+                let poll_interval = parse_interval(body.poll_interval)?;
+
+                if let Some(debounce) = debounce {
+                    intervals.set_debounce(debounce);
+                }
+                if let Some(poll_interval) = poll_interval {
+                    intervals.set_poll_interval(poll_interval);
+                    // wake up the sync loop, so that it starts waiting for the new interval
+                    sync_tx.lock().unwrap().send(())?;
+                }
+                Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+            },
+        )
+        .map(handle_error);
+
     // POST /sync
     let sync_handler = warp::post()
         .and(warp::path!("sync"))
@@ -425,12 +1062,86 @@ fn setup(
         })
         .map(handle_error);
 
+    // OPTIONS /*
+    // CORS preflight requests from allowed origins, the Access-Control-Allow-Origin header gets added
+    // later. Preflight requests are left unhandled when CORS is not enabled or the origin isn't allowed.
+    let cors_preflight_handler = warp::options()
+        .and(warp::header::optional::<String>("origin"))
+        .and(cors_origins)
+        .and_then(
+            |origin: Option<String>, cors: Option<Arc<CorsOrigins>>| async move {
+                match (cors, origin) {
+                    (Some(cors), Some(origin)) if cors.allows(&origin) => {
+                        let res = reply::with_status(reply(), StatusCode::NO_CONTENT);
+                        let res = reply::with_header(
+                            res,
+                            header::ACCESS_CONTROL_ALLOW_METHODS,
+                            CORS_METHODS,
+                        );
+                        let res = reply::with_header(
+                            res,
+                            header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            CORS_HEADERS,
+                        );
+                        Ok(reply::with_header(
+                            res,
+                            header::ACCESS_CONTROL_MAX_AGE,
+                            CORS_MAX_AGE,
+                        ))
+                    }
+                    _ => Err(warp::reject::not_found()),
+                }
+            },
+        );
+
+    // Rejects requests within the per-IP rate limit so that they get handled by the next handlers,
+    // or replies with an error for ones exceeding it. IPv6 clients are limited per /64 prefix.
+    let rate_limit_handler =
+        warp::any()
+            .and(warp::addr::remote())
+            .and(rate_limiter)
+            .and_then(
+                |addr: Option<net::SocketAddr>,
+                 rate_limiter: Option<Arc<RateLimiter<net::IpAddr>>>| async move {
+                    match (rate_limiter, addr) {
+                        (Some(rate_limiter), Some(addr))
+                            if !rate_limiter.check(client_ip_key(addr.ip())) =>
+                        {
+                            debug!("rate limit exceeded for {}", addr.ip());
+                            Ok(reply::with_status(
+                                "rate limit exceeded, try again later",
+                                StatusCode::TOO_MANY_REQUESTS,
+                            ))
+                        }
+                        _ => Err(warp::reject::not_found()),
+                    }
+                },
+            );
+
     let handlers = balanced_or_tree!(
+        rate_limit_handler, // needs to be first to apply to all requests
+        cors_preflight_handler,
+        sync_status_handler,
+        health_handler,
+        metrics_handler,
+        rescan_progress_handler, // doesn't access the index, available during the initial sync
+        not_ready_handler,       // needs to be before all handlers that access the index
+        address_network_handler, // needs to be before the address_* handlers to reject addresses of the wrong network
         wallets_handler,
         wallet_handler,
         wallet_key_handler, // needs to be before spk_handler to work with keys that don't have any indexed history
+        wallet_balance_handler,
         wallet_gap_handler,
         wallet_next_handler,
+        wallet_next_address_handler,
+        wallet_addresses_handler,
+        wallet_history_csv_handler,
+        wallet_utxos_handler,
+        wallet_group_handler,
+        wallet_rescan_handler,
+        wallets_balance_handler,
+        wallets_txs_handler,
+        wallets_utxos_handler,
         spk_handler,
         spk_utxo_handler,
         spk_stats_handler,
@@ -440,6 +1151,13 @@ fn setup(
         tx_verbose_handler,
         tx_hex_handler,
         tx_proof_handler,
+        tx_merkle_handler,
+        tx_bump_info_handler,
+        tx_outspends_handler,
+        label_handler,
+        label_set_handler,
+        label_delete_handler,
+        labels_handler,
         txs_since_handler,
         txs_since_compact_handler,
         tx_broadcast_handler,
@@ -447,20 +1165,34 @@ fn setup(
         utxos_handler,
         sse_handler,
         spk_sse_handler,
+        ws_handler,
         block_tip_handler,
         block_header_handler,
         block_hex_handler,
         block_height_handler,
         mempool_histogram_handler,
         fee_estimate_handler,
+        fee_estimates_handler,
+        descriptor_checksum_handler,
+        xpub_inspect_handler,
+        rpc_handler,
+        electrum_handler,
+        monitor_handler,
+        stats_handler,
         dump_handler,
         debug_handler,
         banner_handler,
         sync_handler,
+        sync_intervals_handler,
+        sync_intervals_set_handler,
         warp::any().map(|| StatusCode::NOT_FOUND)
     )
-    .with(warp::log("bwt::http"))
-    .with(warp::reply::with::headers(headers));
+    .and(warp::header::optional::<String>("origin"))
+    .map(move |reply, origin: Option<String>| with_cors(reply, origin, cors.as_deref()))
+    .with(warp::log("bwt::http"));
+
+    #[cfg(feature = "metrics")]
+    let handlers = handlers.with(warp::log::custom(|_| crate::metrics::HTTP_REQUESTS.inc()));
 
     warp::serve(handlers)
 }
@@ -493,15 +1225,35 @@ pub struct HttpServer {
 }
 
 impl HttpServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: net::SocketAddr,
         cors: Option<String>,
+        rpc_allowlist: Vec<String>,
+        skip_merkle: bool,
+        rate_limit: Option<u32>,
+        #[cfg(feature = "electrum")] electrum_port: Option<u16>,
+        #[cfg(feature = "webhooks")] monitor: Arc<AddressMonitor>,
         query: Arc<Query>,
+        intervals: Arc<SyncIntervals>,
         sync_tx: mpsc::Sender<()>,
     ) -> Self {
         let listeners = Arc::new(Mutex::new(Vec::new()));
         let sync_tx = Arc::new(Mutex::new(sync_tx));
-        let warp_server = setup(cors, query, sync_tx, listeners.clone());
+        let warp_server = setup(
+            cors,
+            rpc_allowlist,
+            skip_merkle,
+            rate_limit,
+            #[cfg(feature = "electrum")]
+            electrum_port,
+            #[cfg(feature = "webhooks")]
+            monitor,
+            query,
+            intervals,
+            sync_tx,
+            listeners.clone(),
+        );
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         let (addr_tx, addr_rx) = oneshot::channel();
@@ -512,6 +1264,7 @@ impl HttpServer {
 
         let bound_addr = block_on_future(addr_rx).unwrap();
         info!("HTTP REST API server running on http://{}/", bound_addr);
+        warn_if_public("HTTP API", &bound_addr);
 
         HttpServer {
             listeners,
@@ -531,32 +1284,120 @@ impl HttpServer {
             changelog.len(),
             listeners.len()
         );
-        // send updates while dropping unresponsive listeners
-        listeners.retain(|listener| {
-            changelog
+        // send updates without blocking, skipping updates for slow clients whose buffer is full
+        // and dropping disconnected ones
+        let mut active_listeners = Vec::with_capacity(listeners.len());
+        for Listener { mut tx, filter } in listeners.drain(..) {
+            let mut skipped = 0;
+            let connected = changelog
                 .iter()
-                .filter(|change| listener.filter.matches(change))
-                .all(|change| listener.tx.send(change.clone()).is_ok())
-        })
+                .filter(|change| filter.matches(change))
+                .all(|change| match tx.try_send(change.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        skipped += 1;
+                        true
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                });
+            if skipped > 0 {
+                warn!("skipped {} update(s) for a slow sse client", skipped);
+            }
+            if connected {
+                active_listeners.push(Listener { tx, filter });
+            }
+        }
+        *listeners = active_listeners;
     }
 
     pub fn addr(&self) -> net::SocketAddr {
         self.addr
     }
+
+    /// Stop accepting new connections and wait for the server thread to terminate. Open
+    /// connections (like SSE and WebSocket streams) are given up to `SHUTDOWN_TIMEOUT` to close.
+    pub fn shutdown(&mut self) {
+        let shutdown_tx = some_or_ret!(self.shutdown_tx.take());
+        trace!("HTTP server shutting down");
+        // the sse/ws streams would otherwise keep the graceful shutdown waiting
+        self.listeners.lock().unwrap().clear();
+        shutdown_tx.send(()).ok();
+        if let Some(thread) = self.thread.take() {
+            join_timeout(thread, SHUTDOWN_TIMEOUT, "http server");
+        }
+    }
 }
 
 impl Drop for HttpServer {
     fn drop(&mut self) {
-        trace!("HTTP server shutting down");
-        self.shutdown_tx.take().unwrap().send(()).unwrap();
-        self.thread.take().unwrap().join().unwrap();
+        self.shutdown();
+    }
+}
+
+const CORS_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+const CORS_HEADERS: &str = "Content-Type, Last-Event-ID";
+const CORS_MAX_AGE: &str = "86400";
+
+// The origins allowed to make cross-origin requests
+#[derive(Debug)]
+enum CorsOrigins {
+    Any,
+    List(HashSet<String>),
+}
+
+impl From<&str> for CorsOrigins {
+    fn from(s: &str) -> Self {
+        // allow using "any" as an alias for "*", avoiding expansion when passing "*" can be tricky
+        if s == "*" || s == "any" {
+            CorsOrigins::Any
+        } else {
+            CorsOrigins::List(
+                s.split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .collect(),
+            )
+        }
     }
 }
 
+impl CorsOrigins {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            CorsOrigins::Any => true,
+            CorsOrigins::List(origins) => origins.contains(origin),
+        }
+    }
+}
+
+// Add the Access-Control-Allow-Origin header when the request origin is allowed
+fn with_cors(
+    reply: impl Reply,
+    origin: Option<String>,
+    cors: Option<&CorsOrigins>,
+) -> reply::Response {
+    let mut response = reply.into_response();
+    let allowed_origin = match (cors, origin) {
+        (Some(CorsOrigins::Any), _) => Some(header::HeaderValue::from_static("*")),
+        (Some(cors), Some(origin)) if cors.allows(&origin) => {
+            header::HeaderValue::from_str(&origin).ok()
+        }
+        _ => None,
+    };
+    let headers = response.headers_mut();
+    if let Some(allowed_origin) = allowed_origin {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+    }
+    if let Some(CorsOrigins::List(_)) = cors {
+        // the response varies by the origin when an explicit list of origins is used
+        headers.insert(header::VARY, header::HeaderValue::from_static("Origin"));
+    }
+    response
+}
+
 type Listeners = Arc<Mutex<Vec<Listener>>>;
 
 struct Listener {
-    tx: tmpsc::UnboundedSender<IndexChange>,
+    tx: tmpsc::Sender<IndexChange>,
     filter: ChangelogFilter,
 }
 
@@ -569,7 +1410,7 @@ fn make_sse_stream(
 ) -> Result<impl Stream<Item = Result<impl ServerSentEvent, warp::Error>>, Error> {
     debug!("subscribing sse client with {:?}", filter);
 
-    let (tx, rx) = tmpsc::unbounded_channel();
+    let (tx, rx) = tmpsc::channel(SSE_BUFFER_SIZE);
     listeners.lock().unwrap().push(Listener {
         tx,
         filter: filter.clone(),
@@ -588,17 +1429,20 @@ fn make_sse_stream(
 }
 
 fn make_sse_msg(change: IndexChange) -> impl ServerSentEvent {
+    // name the sse event after the change category, so clients can listen for specific ones
+    let event = warp::sse::event(change.category_str().to_string());
     match &change {
         IndexChange::ChainTip(blockid) => {
             // set the synced tip as the sse identifier field, so the client will send it back to
             // us on reconnection via the Last-Event-Id header.
-            (warp::sse::id(blockid.to_string()), warp::sse::json(change)).into_a()
+            let id = warp::sse::id(blockid.to_string());
+            (id, event, warp::sse::json(change)).into_a()
         }
-        _ => warp::sse::json(change).into_b(),
+        _ => (event, warp::sse::json(change)).into_b(),
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 struct ChangelogFilter {
     #[serde(default, deserialize_with = "deser_synced_tip")]
@@ -658,6 +1502,126 @@ impl ChangelogFilter {
     }
 }
 
+// Relay the changelog events matching the websocket client's subscriptions, which can be
+// modified at any time using subscribe/unsubscribe messages
+async fn handle_ws_client(mut socket: WebSocket, listeners: Listeners, network: Network) {
+    debug!("websocket client connected");
+
+    // subscribe to all events and filter them here, since the subscriptions change over time
+    let (tx, mut rx) = tmpsc::channel(SSE_BUFFER_SIZE);
+    listeners.lock().unwrap().push(Listener {
+        tx,
+        filter: ChangelogFilter::default(),
+    });
+
+    let mut subscriptions = WsSubscriptions::default();
+
+    loop {
+        let reply = tokio::select! {
+            msg = socket.next() => match msg {
+                Some(Ok(msg)) if msg.is_close() => break,
+                Some(Ok(msg)) => match msg.to_str() {
+                    Ok(text) => subscriptions.handle_request(text, network),
+                    // pings are replied to automatically, binary messages are not supported
+                    Err(()) => continue,
+                },
+                Some(Err(e)) => {
+                    debug!("websocket client error: {:?}", e);
+                    break;
+                }
+                None => break,
+            },
+            change = rx.recv() => match change {
+                Some(change) if subscriptions.matches(&change) => serde_json::to_value(&change).unwrap(),
+                Some(_) => continue,
+                // the listener was dropped by send_updates(), because the buffer was full,
+                // or by shutdown()
+                None => break,
+            },
+        };
+        if let Err(e) = socket.send(Message::text(reply.to_string())).await {
+            debug!("websocket client send error: {:?}", e);
+            break;
+        }
+    }
+
+    debug!("websocket client disconnected");
+}
+
+#[derive(Serialize, Default, Debug)]
+struct WsSubscriptions {
+    tip: bool,
+    scripthashes: HashSet<ScriptHash>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WsRequest {
+    method: WsMethod,
+    #[serde(default)]
+    tip: bool,
+    #[serde(default)]
+    scripthashes: Vec<ScriptHash>,
+    #[serde(default)]
+    addresses: Vec<Address>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum WsMethod {
+    Subscribe,
+    Unsubscribe,
+}
+
+impl WsSubscriptions {
+    // Apply the request and reply with the updated subscriptions, or with the error
+    fn handle_request(&mut self, text: &str, network: Network) -> serde_json::Value {
+        match self.apply(text, network) {
+            Ok(()) => json!({ "subscriptions": self }),
+            Err(err) => json!({ "error": fmt_error_chain(&err) }),
+        }
+    }
+
+    fn apply(&mut self, text: &str, network: Network) -> Result<(), Error> {
+        let req: WsRequest = serde_json::from_str(text).context("invalid request")?;
+        for address in &req.addresses {
+            ensure!(
+                address_matches_network(address, network),
+                "address {} is not on the {} network",
+                address,
+                network
+            );
+        }
+        let scripthashes = req
+            .scripthashes
+            .into_iter()
+            .chain(req.addresses.iter().map(ScriptHash::from));
+
+        match req.method {
+            WsMethod::Subscribe => {
+                self.tip |= req.tip;
+                self.scripthashes.extend(scripthashes);
+            }
+            WsMethod::Unsubscribe => {
+                self.tip &= !req.tip;
+                for scripthash in scripthashes {
+                    self.scripthashes.remove(&scripthash);
+                }
+            }
+        }
+        debug!("websocket client subscriptions updated: {:?}", self);
+        Ok(())
+    }
+
+    fn matches(&self, change: &IndexChange) -> bool {
+        match change {
+            IndexChange::ChainTip(_) | IndexChange::Reorg(..) => self.tip,
+            _ => change
+                .scripthash()
+                .map_or(false, |scripthash| self.scripthashes.contains(scripthash)),
+        }
+    }
+}
+
 fn parse_synced_tip(s: &str) -> Result<BlockId, Error> {
     let mut parts = s.splitn(2, ':');
     let height: u32 = parts.next().req()?.parse()?;
@@ -680,14 +1644,214 @@ where
 struct UtxoOptions {
     #[serde(default)]
     min_conf: usize,
+    #[serde(default)]
+    min_value: u64,
     include_unsafe: Option<bool>,
 }
 
+#[derive(Deserialize, Debug)]
+struct DeriveOptions {
+    #[serde(default)]
+    start: u32,
+    #[serde(default = "default_derive_count")]
+    count: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct NextAddressOptions {
+    #[serde(default)]
+    bump: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct HealthOptions {
+    // in seconds
+    max_tip_age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LabelBody {
+    label: String,
+}
+
+// in seconds
+#[derive(Deserialize, Debug)]
+struct SyncIntervalsBody {
+    poll_interval: Option<f64>,
+    debounce: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RescanRequest {
+    since: RescanSince,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryOptions {
+    direction: Option<TxDirection>,
+    from_height: Option<u32>,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+    // include the number of confirmations and the block time
+    #[serde(default)]
+    block_info: bool,
+}
+
+#[derive(Serialize)]
+struct WithBlockInfo<T> {
+    #[serde(flatten)]
+    tx: T,
+    #[serde(flatten)]
+    block_info: TxBlockInfo,
+}
+
+impl HistoryOptions {
+    // Get the requested page of the scripthash history, ordered with newest first, along with the
+    // total number of history entries matching the filters
+    fn get_page(
+        &self,
+        scripthash: &ScriptHash,
+        query: &Query,
+    ) -> (Vec<store::HistoryEntry>, usize) {
+        let limit = cmp::min(self.limit, MAX_HISTORY_LIMIT);
+        let keep = self.offset + limit;
+
+        // the history is read oldest first, keeping only the newest `offset + limit` entries that
+        // match the filters rather than a copy of the full history
+        let mut newest = VecDeque::new();
+        let mut total = 0;
+        let visit = |txhist: &store::HistoryEntry| {
+            // keep entries confirmed at or after `from_height`, including all unconfirmed
+            let matches_height = match (txhist.status, self.from_height) {
+                (_, None) | (TxStatus::Unconfirmed, _) => true,
+                (TxStatus::Confirmed(height), Some(from_height)) => height >= from_height,
+                (TxStatus::Conflicted, Some(_)) => false,
+            };
+            if matches_height {
+                total += 1;
+                newest.push_back(txhist.clone());
+                if newest.len() > keep {
+                    newest.pop_front();
+                }
+            }
+        };
+        match self.direction {
+            // filtering by direction requires looking up the tx entries, which is done by the
+            // index while collecting the (already filtered) history
+            Some(direction) => query
+                .map_history_by_direction(scripthash, direction, Clone::clone)
+                .iter()
+                .for_each(visit),
+            None => {
+                query.for_each_history(scripthash, visit);
+            }
+        }
+
+        let page = newest
+            .into_iter()
+            .rev()
+            .skip(self.offset)
+            .take(limit)
+            .collect();
+        (page, total)
+    }
+}
+
+const HISTORY_CSV_HEADER: &str = "txid,block_height,timestamp,amount,fee\n";
+
+// the number of wallet transactions read from the index at a time, also used as the number of
+// formatted rows buffered for the client
+const HISTORY_CSV_PAGE_SIZE: usize = 100;
+
+// Format a wallet transaction as a csv row. The block timestamp and fee (only known for outgoing
+// transactions) are left empty when unavailable.
+fn history_csv_row(
+    query: &Query,
+    tx: &WalletTxChange,
+    last_block_time: &mut Option<(u32, u32)>,
+) -> String {
+    let block_height = match tx.status {
+        TxStatus::Confirmed(height) => Some(height),
+        TxStatus::Unconfirmed | TxStatus::Conflicted => None,
+    };
+    let timestamp = block_height.and_then(|height| match *last_block_time {
+        Some((last_height, time)) if last_height == height => Some(time),
+        _ => {
+            let blockhash = query.get_block_hash(height).ok()?;
+            let time = query.get_header(&blockhash).ok()?.time;
+            *last_block_time = Some((height, time));
+            Some(time)
+        }
+    });
+    let fee = if tx.is_outgoing {
+        query.get_tx_fee(&tx.txid).ok().map(|(fee, _)| fee)
+    } else {
+        None
+    };
+
+    let fmt_opt = |v: Option<String>| v.unwrap_or_default();
+    format!(
+        "{},{},{},{},{}\n",
+        tx.txid,
+        fmt_opt(block_height.map(|h| h.to_string())),
+        fmt_opt(timestamp.map(|t| t.to_string())),
+        tx.balance_change,
+        fmt_opt(fee.map(|f| f.to_string())),
+    )
+}
+
+fn default_history_limit() -> usize {
+    DEFAULT_HISTORY_LIMIT
+}
+
+fn default_derive_count() -> u32 {
+    DEFAULT_DERIVE_COUNT
+}
+
+fn with_total_count(reply: impl Reply, total: usize) -> impl Reply {
+    reply::with_header(reply, "X-Total-Count", total.to_string())
+}
+
 #[derive(Deserialize, Debug)]
 struct BroadcastBody {
     tx_hex: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct DescriptorBody {
+    descriptor: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct XpubBody {
+    xpub: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcBody {
+    method: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+// A comma separated list of wallet checksums
+#[derive(Debug)]
+struct ChecksumList(Vec<Checksum>);
+
+impl FromStr for ChecksumList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let checksums = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Checksum>, Error>>()?;
+        Ok(ChecksumList(checksums))
+    }
+}
+
 fn compact_history(tx_hist: &store::HistoryEntry) -> serde_json::Value {
     json!([tx_hist.txid, tx_hist.status])
 }
@@ -1,31 +1,45 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 use std::{fmt, time};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use bitcoin::{BlockHash, OutPoint, Txid};
 use bitcoincore_rpc::json::{
     GetTransactionResultDetailCategory as TxCategory, ListTransactionResult,
 };
-use bitcoincore_rpc::{Client as RpcClient, RpcApi};
+use bitcoincore_rpc::RpcApi;
 
-use crate::error::Result;
+use crate::error::{Context, Result};
 use crate::store::{FundingInfo, MemoryStore, SpendingInfo, TxEntry};
 use crate::types::{BlockId, InPoint, ScriptHash, TxStatus};
+use crate::util::bitcoincore_ext::{RpcClient, WalletRpcs};
+use crate::util::{descriptor::Checksum, write_json_atomic, BoolThen};
 use crate::wallet::{KeyOrigin, WalletWatcher};
 
+// bumped whenever the snapshot format changes, to invalidate snapshots saved by older versions
+const SNAPSHOT_VERSION: u32 = 2;
+
+// reorgs up to this depth are handled by rolling back the affected transactions, deeper ones cause
+// the index to be rebuilt from scratch
+pub(crate) const MAX_REORG_DEPTH: u32 = 100;
+
 pub struct Indexer {
     rpc: Arc<RpcClient>,
+    wallet_rpcs: WalletRpcs,
     watcher: WalletWatcher,
     store: MemoryStore,
     tip: Option<BlockId>,
 }
 
 impl Indexer {
-    pub fn new(rpc: Arc<RpcClient>, watcher: WalletWatcher) -> Self {
+    pub fn new(wallet_rpcs: WalletRpcs, watcher: WalletWatcher) -> Self {
         Indexer {
-            rpc,
+            rpc: wallet_rpcs.default_rpc().clone(),
+            wallet_rpcs,
             watcher,
             store: MemoryStore::new(),
             tip: None,
@@ -40,64 +54,277 @@ impl Indexer {
         &self.watcher
     }
 
+    /// The RPC clients of the bitcoind wallets the tracked wallets are imported into
+    pub fn wallet_rpcs(&self) -> &WalletRpcs {
+        &self.wallet_rpcs
+    }
+
+    pub fn watcher_mut(&mut self) -> &mut WalletWatcher {
+        &mut self.watcher
+    }
+
+    /// Get the next unused derivation index of the wallet and reserve it.
+    /// Returns None if the wallet does not exist.
+    pub fn reserve_next_index(&mut self, checksum: &Checksum) -> Result<Option<u32>> {
+        self.watcher.reserve_next_index(checksum, &self.store)
+    }
+
+    /// The best block the index is synced up to, if any
+    pub fn tip(&self) -> Option<&BlockId> {
+        self.tip.as_ref()
+    }
+
+    /// Whether the index is being rebuilt from scratch, in which case its data may be stale
+    pub fn is_reindexing(&self) -> bool {
+        self.watcher.progress().is_reindexing()
+    }
+
+    /// Sync the shared index. Reorgs up to `MAX_REORG_DEPTH` are handled in place by `sync()`. When a
+    /// deeper reorg is detected, the history is re-synced from scratch into a fresh index that gets
+    /// swapped in once it's ready, so that queries can continue being served from the (stale but
+    /// consistent) current index in the meanwhile instead of blocking on the lock. Changes made to the
+    /// wallets during the rebuild (e.g. reserved indexes or rescan requests) are merged back once it's
+    /// done.
+    pub fn sync_shared(indexer: &RwLock<Indexer>) -> Result<Vec<IndexChange>> {
+        let reorg = match indexer.read().unwrap().detect_reorg()? {
+            Some(reorg) if reorg.fork_point.is_none() => reorg,
+            // no reorg or a shallow one, do a regular sync under the write lock
+            _ => return indexer.write().unwrap().sync(),
+        };
+
+        warn!(
+            "deep reorg detected, block height {} was {} and now is {}. re-syncing history from scratch...",
+            reorg.height, reorg.prev_hash, reorg.curr_hash
+        );
+
+        let mut fresh_indexer = {
+            let mut indexer = indexer.write().unwrap();
+            indexer.watcher.progress().set_reindexing(true);
+            Indexer::new(indexer.wallet_rpcs.clone(), indexer.watcher.fork())
+        };
+        let sync_result = fresh_indexer.initial_sync();
+
+        let mut indexer = indexer.write().unwrap();
+        // the imports made by the rebuild are kept regardless of whether it succeeded
+        indexer.watcher.merge(fresh_indexer.watcher);
+        indexer.watcher.progress().set_reindexing(false);
+        sync_result?;
+
+        let synced_tip = fresh_indexer.tip.unwrap(); // always set following the initial sync
+        indexer.store = fresh_indexer.store;
+        indexer.tip = Some(synced_tip);
+
+        // notify clients about the reorg, but don't collect additional events (matching the
+        // behaviour of the in-place reorg handling in sync())
+        Ok(vec![
+            IndexChange::Reorg(reorg.height, reorg.prev_hash, reorg.curr_hash),
+            IndexChange::ChainTip(synced_tip),
+        ])
+    }
+
+    // check whether the synced tip is still part of the best chain. if it's not, walk back from it
+    // to find the first orphaned block and the fork point where the best chain diverged from it.
+    // XXX the reorg test is racey
+    fn detect_reorg(&self) -> Result<Option<Reorg>> {
+        let BlockId(tip_height, tip_hash) = some_or_ret!(self.tip, Ok(None));
+        let best_height = self.rpc.get_block_count()? as u32;
+        // the best chain may be shorter than the synced tip (e.g. after `invalidateblock`)
+        let best_chain_hash = |height: u32| -> Result<Option<BlockHash>> {
+            Ok(if height <= best_height {
+                Some(self.rpc.get_block_hash(height as u64)?)
+            } else {
+                None
+            })
+        };
+
+        if best_chain_hash(tip_height)? == Some(tip_hash) {
+            return Ok(None);
+        }
+
+        let (mut height, mut blockhash) = (tip_height, tip_hash);
+        let mut fork_point = None;
+        while height > 0 && tip_height - height < MAX_REORG_DEPTH {
+            let prev_blockhash = self.rpc.get_block_header(&blockhash)?.prev_blockhash;
+            if best_chain_hash(height - 1)? == Some(prev_blockhash) {
+                fork_point = Some(BlockId(height - 1, prev_blockhash));
+                break;
+            }
+            height -= 1;
+            blockhash = prev_blockhash;
+        }
+
+        Ok(Some(Reorg {
+            height,
+            prev_hash: blockhash,
+            curr_hash: best_chain_hash(height)?.unwrap_or_default(),
+            fork_point,
+        }))
+    }
+
+    // roll back the transactions confirmed in orphaned blocks to be unconfirmed and continue syncing
+    // from the fork point. the following sync re-confirms them at their new height, or purges them
+    // if they were double-spent by the best chain.
+    fn rollback(&mut self, fork_point: BlockId, changelog: &mut Changelog) {
+        let rolled_back = self.store.rollback_to(fork_point.0);
+        info!(
+            "rolled back {} transactions confirmed after the fork point at height {}",
+            rolled_back.len(),
+            fork_point.0
+        );
+        for txid in rolled_back {
+            changelog.with(|changelog| {
+                let tx_entry = self.store.get_tx_entry(&txid).unwrap();
+                changelog.extend(IndexChange::from_tx(&txid, tx_entry));
+            });
+        }
+        self.tip = Some(fork_point);
+    }
+
+    /// Save a snapshot of the index to `path`, to be restored on the next start
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        // nothing to save before the initial sync, or while the index is known to be stale
+        let tip = some_or_ret!(self.tip, Ok(()));
+        if self.is_reindexing() {
+            return Ok(());
+        }
+
+        let timer = time::Instant::now();
+        let snapshot = IndexSnapshot {
+            version: SNAPSHOT_VERSION,
+            tip,
+            funded_indexes: self.watcher.funded_indexes(),
+            store: &self.store,
+        };
+
+        write_json_atomic(path, &snapshot)
+            .with_context(|| format!("failed writing snapshot to {:?}", path))?;
+
+        debug!(
+            "saved index snapshot at height {} to {:?} in {:?}",
+            tip.0,
+            path,
+            timer.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Restore the index from a snapshot saved by a previous run, returning whether it was restored.
+    /// The snapshot is only used if it was made for the same set of wallets and its tip is still part
+    /// of the best chain. Should be called prior to the initial sync, which then continues from the
+    /// snapshot's tip instead of syncing from scratch.
+    pub fn restore_snapshot(&mut self, path: &Path) -> Result<bool> {
+        if self.tip.is_some() || !path.exists() {
+            return Ok(false);
+        }
+
+        let file = fs::File::open(path)
+            .with_context(|| format!("failed reading snapshot from {:?}", path))?;
+        let snapshot: IndexSnapshot<MemoryStore> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("invalid snapshot file {:?}", path))?;
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            warn!("index snapshot version mismatch, ignoring it");
+            return Ok(false);
+        }
+
+        let funded_indexes = self.watcher.funded_indexes();
+        let wallets: HashSet<_> = funded_indexes.keys().collect();
+        let snapshot_wallets: HashSet<_> = snapshot.funded_indexes.keys().collect();
+        if wallets != snapshot_wallets {
+            warn!("the configured wallets changed since the index snapshot was saved, ignoring it");
+            return Ok(false);
+        }
+
+        let BlockId(tip_height, tip_hash) = snapshot.tip;
+        if self.rpc.get_block_hash(tip_height as u64).ok() != Some(tip_hash) {
+            warn!(
+                "the index snapshot tip {} is no longer part of the best chain, ignoring it",
+                snapshot.tip
+            );
+            return Ok(false);
+        }
+
+        for (checksum, max_funded_index) in snapshot.funded_indexes {
+            if let Some(max_funded_index) = max_funded_index {
+                self.watcher
+                    .mark_funded(&KeyOrigin::Descriptor(checksum, max_funded_index));
+            }
+        }
+        self.store = snapshot.store;
+        self.tip = Some(snapshot.tip);
+
+        info!(
+            "restored index snapshot from {:?} at height {}",
+            path, tip_height
+        );
+        Ok(true)
+    }
+
     // continue to sync transactions and import addresses (with rescan) until no more new addresses
     // need to be imported. the initial sync does not collect the Changelog and does not emit updates.
     pub fn initial_sync(&mut self) -> Result<()> {
         let timer = time::Instant::now();
 
         info!("starting initial sync");
-        self.watcher.check_imports(&self.rpc)?;
+        self.watcher.check_imports(&self.wallet_rpcs)?;
 
         let mut changelog = Changelog::new(false);
         let mut synced_tip;
 
         while {
             synced_tip = self.sync_transactions(&mut changelog)?;
-            self.watcher.do_imports(&self.rpc, /*rescan=*/ true)?
+            self.watcher
+                .do_imports(&self.wallet_rpcs, /*rescan=*/ true)?
         } { /* do while */ }
 
         self.sync_mempool(/*force_refresh=*/ true)?;
 
         let stats = self.store.stats();
         info!(
-            "completed initial sync in {:?} up to height {} (total {} transactions and {} addresses)",
+            "completed initial sync in {:?} up to height {} (total {} transactions and {} addresses, using ~{} MB)",
             timer.elapsed(),
             synced_tip.0,
             stats.transaction_count,
             stats.scripthash_count,
+            stats.memory_usage / 1_000_000,
         );
         self.tip = Some(synced_tip);
+        self.watcher.progress().set_synced(synced_tip.0);
         Ok(())
     }
 
     // initiate a regular sync to catch up with updates and import new addresses (no rescan)
     pub fn sync(&mut self) -> Result<Vec<IndexChange>> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::SYNC_DURATION.start_timer();
+
         let mut changelog = Changelog::new(self.tip.is_some());
 
-        // detect reorgs and sync the whole history from scratch when they happen
-        // XXX the reorg test is racey
-        if let Some(BlockId(tip_height, ref tip_hash)) = self.tip {
-            let best_chain_hash = self.rpc.get_block_hash(tip_height as u64)?;
-            if best_chain_hash != *tip_hash {
-                warn!(
-                    "reorg detected, block height {} was {} and now is {}. fetching history from scratch...",
-                    tip_height, tip_hash, best_chain_hash
-                );
-
-                // notify clients about the reorg, but don't collect additional events (apart from
-                // ChainTip, added below)
-                changelog.push(|| IndexChange::Reorg(tip_height, *tip_hash, best_chain_hash));
-                changelog.track = false;
-
-                // XXX start syncing from N blocks backs instead of from the beginning?
-                self.tip = None;
+        // detect reorgs and roll back the index to the fork point when they happen, or sync the
+        // whole history from scratch if the fork point is too deep
+        if let Some(reorg) = self.detect_reorg()? {
+            warn!(
+                "reorg detected, block height {} was {} and now is {}",
+                reorg.height, reorg.prev_hash, reorg.curr_hash
+            );
+            changelog.push(|| IndexChange::Reorg(reorg.height, reorg.prev_hash, reorg.curr_hash));
+
+            match reorg.fork_point {
+                Some(fork_point) => self.rollback(fork_point, &mut changelog),
+                None => {
+                    warn!("the reorg is too deep, fetching history from scratch...");
+                    // don't collect additional events (apart from ChainTip, added below)
+                    changelog.track = false;
+                    self.tip = None;
+                }
             }
         }
 
         let synced_tip = self.sync_transactions(&mut changelog)?;
         let tip_updated = self.tip != Some(synced_tip);
         self.sync_mempool(/*force_refresh=*/ tip_updated)?;
-        self.watcher.do_imports(&self.rpc, /*rescan=*/ false)?;
+        self.watcher
+            .do_imports(&self.wallet_rpcs, /*rescan=*/ false)?;
 
         let mut changelog = changelog.into_vec();
 
@@ -105,6 +332,7 @@ impl Indexer {
             info!("synced up to {}", synced_tip.0);
             changelog.push(IndexChange::ChainTip(synced_tip));
             self.tip = Some(synced_tip);
+            self.watcher.progress().set_synced(synced_tip.0);
         }
 
         if !changelog.is_empty() && log_enabled!(log::Level::Debug) {
@@ -125,31 +353,70 @@ impl Indexer {
         let tip_height = self.rpc.get_block_count()? as u32;
         let tip_hash = self.rpc.get_block_hash(tip_height as u64)?;
 
-        let result = self
-            .rpc
-            .list_since_block(since_block, Some(1), Some(true), Some(true))?;
+        // read the transactions of every bitcoind wallet before touching the index, so that a retry
+        // doesn't leave it partially updated
+        let rpcs: Vec<Arc<RpcClient>> = self.wallet_rpcs.all().cloned().collect();
+        let mut results = Vec::new();
+        for rpc in rpcs {
+            let result = rpc.list_since_block(since_block, Some(1), Some(true), Some(true))?;
+
+            // Workaround for https://github.com/bitcoin/bitcoin/issues/19338,
+            // listsinceblock is not atomic and could provide inconsistent results.
+            if result.lastblock != tip_hash {
+                warn!("chain tip moved while reading listsinceblock, retrying...");
+                return self.sync_transactions(changelog);
+            }
+            results.push((rpc, result));
+        }
 
-        // Workaround for https://github.com/bitcoin/bitcoin/issues/19338,
-        // listsinceblock is not atomic and could provide inconsistent results.
-        if result.lastblock != tip_hash {
-            warn!("chain tip moved while reading listsinceblock, retrying...");
-            return self.sync_transactions(changelog);
+        // the same transaction may be reported by multiple bitcoind wallets (e.g. when it pays from
+        // a wallet imported into one to a wallet imported into another), only process it once
+        let mut purged_txids: HashSet<Txid> = HashSet::new();
+        let mut seen_txos: HashSet<(Txid, u32)> = HashSet::new();
+
+        for (_, result) in &results {
+            for ltx in &result.removed {
+                // transactions that were re-added in the active chain will appear in `removed`
+                // but with a positive confirmation count, ignore these.
+                if ltx.info.confirmations < 0 && purged_txids.insert(ltx.info.txid) {
+                    let tx_deleted = self.store.purge_tx(&ltx.info.txid);
+                    if tx_deleted {
+                        changelog.push(|| IndexChange::TransactionReplaced(ltx.info.txid));
+                    }
+                }
+            }
         }
 
-        for ltx in result.removed {
-            // transactions that were re-added in the active chain will appear in `removed`
-            // but with a positive confirmation count, ignore these.
+        // the outgoing txs are fetched from the first bitcoind wallet that reported them
+        let mut buffered_outgoing: HashMap<Txid, (i32, Arc<RpcClient>)> = HashMap::new();
+
+        for (rpc, ltx) in results.into_iter().flat_map(|(rpc, result)| {
+            result
+                .transactions
+                .into_iter()
+                .map(move |ltx| (rpc.clone(), ltx))
+        }) {
+            // transactions conflicting with the best chain (e.g. double-spent by a reorg) are
+            // purged, the same as the ones reported in `removed`
             if ltx.info.confirmations < 0 {
-                let tx_deleted = self.store.purge_tx(&ltx.info.txid);
-                if tx_deleted {
-                    changelog.push(|| IndexChange::TransactionReplaced(ltx.info.txid));
+                if purged_txids.insert(ltx.info.txid) {
+                    let tx_deleted = self.store.purge_tx(&ltx.info.txid);
+                    if tx_deleted {
+                        changelog.push(|| IndexChange::TransactionReplaced(ltx.info.txid));
+                    }
                 }
+                continue;
             }
-        }
 
-        let mut buffered_outgoing: HashMap<Txid, i32> = HashMap::new();
+            // incoming txos are reported once per wallet-owned output, skip ones already seen
+            let is_incoming = match ltx.detail.category {
+                TxCategory::Receive | TxCategory::Generate | TxCategory::Immature => true,
+                TxCategory::Send | TxCategory::Orphan => false,
+            };
+            if is_incoming && !seen_txos.insert((ltx.info.txid, ltx.detail.vout)) {
+                continue;
+            }
 
-        for ltx in result.transactions {
             // "listtransactions"/"listsinceblock" in fact lists transaction outputs and not transactions.
             // for "receive" txs, it returns one entry per wallet-owned output in the tx.
             // for "send" txs, it returns one entry for every output in the tx, owned or not.
@@ -166,16 +433,25 @@ impl Indexer {
                     // straightaway because the prevouts being spent might not be indexed yet, so
                     // the outgoing txs are buffered and processed at the end, after the txs funding
                     // the prevouts are guarranted to be indexed.
-                    buffered_outgoing.insert(ltx.info.txid, ltx.info.confirmations);
+                    buffered_outgoing
+                        .entry(ltx.info.txid)
+                        .or_insert((ltx.info.confirmations, rpc));
                 }
-                // ignore mining-related transactions
-                TxCategory::Generate | TxCategory::Immature | TxCategory::Orphan => (),
+                TxCategory::Generate | TxCategory::Immature => {
+                    // coinbase outputs are indexed like incoming ones, but flagged so that they
+                    // can be reported as immature until they're spendable
+                    let txid = ltx.info.txid;
+                    self.process_incoming_txo(ltx, tip_height, changelog);
+                    self.store.set_tx_coinbase(&txid);
+                }
+                // ignore coinbase transactions of stale blocks
+                TxCategory::Orphan => (),
             };
         }
 
-        for (txid, confirmations) in buffered_outgoing {
+        for (txid, (confirmations, rpc)) in buffered_outgoing {
             let status = TxStatus::from_confirmations(confirmations, tip_height);
-            self.process_outgoing_tx(txid, status, changelog)
+            self.process_outgoing_tx(txid, status, &rpc, changelog)
                 .map_err(|err| warn!("failed processing outgoing payment: {:?}", err))
                 .ok();
         }
@@ -200,11 +476,10 @@ impl Indexer {
         tip_height: u32,
         changelog: &mut Changelog,
     ) {
-        let label = ltx.detail.label.as_ref();
-        let origin = some_or_ret!(label.and_then(|l| KeyOrigin::from_label(l)));
+        // unspendable outputs with no address (like OP_RETURN) can never be ours
         let address = some_or_ret!(ltx.detail.address);
-
-        // XXX we assume that any address with a "bwt/..." label is ours, this may not necessarily be true.
+        let label = ltx.detail.label.as_deref();
+        let origin = some_or_ret!(self.watcher.find_origin(&address, label));
 
         let txid = ltx.info.txid;
         let vout = ltx.detail.vout;
@@ -212,6 +487,13 @@ impl Indexer {
         let status = TxStatus::from_confirmations(ltx.info.confirmations, tip_height);
         let amount = ltx.detail.amount.to_unsigned().unwrap().as_sat(); // safe to unwrap, incoming payments cannot have negative amounts
 
+        // zero-value outputs don't affect the balance and are not worth tracking as utxos. the
+        // transaction will still get indexed if it touches the wallet in any other way.
+        if amount == 0 {
+            trace!("skipping zero-value txout {}:{}", txid, vout);
+            return;
+        }
+
         trace!(
             "processing incoming txout {}:{} scripthash={} address={} origin={:?} status={:?} amount={}",
             txid, vout, scripthash, address, origin, status, amount
@@ -237,6 +519,7 @@ impl Indexer {
         &mut self,
         txid: Txid,
         status: TxStatus,
+        rpc: &RpcClient,
         changelog: &mut Changelog,
     ) -> Result<()> {
         trace!("processing outgoing tx txid={} status={:?}", txid, status);
@@ -252,7 +535,7 @@ impl Indexer {
         }
 
         // TODO use batch rpc to fetch all buffered outgoing txs
-        let tx = self.rpc.get_transaction(&txid, Some(true))?.transaction()?;
+        let tx = rpc.get_transaction(&txid, Some(true))?.transaction()?;
 
         let spending: HashMap<u32, SpendingInfo> = tx
             .input
@@ -276,8 +559,19 @@ impl Indexer {
             .collect();
 
         if !spending.is_empty() {
+            // the fee can be computed locally when all the inputs are spending from the wallet
+            let fee = (spending.len() == tx.input.len()).and_then(|| {
+                let inputs_value = spending.values().map(|SpendingInfo(_, _, amount)| amount);
+                let outputs_value = tx.output.iter().map(|txout| txout.value);
+                inputs_value.sum::<u64>().checked_sub(outputs_value.sum())
+            });
+
             self.upsert_tx(&txid, status, changelog);
             self.store.index_tx_inputs_spending(&txid, spending);
+            if let Some(fee) = fee {
+                let vsize = (tx.get_weight() as f64 / 4.0).ceil() as u64;
+                self.store.set_tx_fee(&txid, fee, vsize);
+            }
         }
 
         Ok(())
@@ -288,15 +582,24 @@ impl Indexer {
     fn sync_mempool(&mut self, force_refresh: bool) -> Result<()> {
         let mempool = self.store.mempool_mut();
 
+        let txids: Vec<Txid> = mempool
+            .iter()
+            .filter(|(_, opt_entry)| force_refresh || opt_entry.is_none())
+            .map(|(txid, _)| *txid)
+            .collect();
+        if txids.is_empty() {
+            return Ok(());
+        }
+
+        // transactions that left the mempool in the meanwhile are skipped, their confirmation (or
+        // eviction) gets picked up by the next sync
+        let mut rpc_entries = self.rpc.get_mempool_entries(&txids)?;
         for (txid, opt_entry) in mempool.iter_mut() {
-            if force_refresh || opt_entry.is_none() {
-                let rpc_entry = self.rpc.get_mempool_entry(txid)?;
+            if let Some(rpc_entry) = rpc_entries.remove(txid) {
                 *opt_entry = Some(rpc_entry.into());
             }
         }
 
-        // TODO use batch rpc
-
         Ok(())
     }
 
@@ -332,6 +635,16 @@ pub enum IndexChange {
     TxoSpent(InPoint, ScriptHash, OutPoint, TxStatus),
 }
 
+struct Reorg {
+    // the first orphaned block height, along with its previous and current block hashes (the
+    // current one is all zeros if the best chain does not reach this height)
+    height: u32,
+    prev_hash: BlockHash,
+    curr_hash: BlockHash,
+    // the last block in common with the best chain, or None if the reorg is deeper than MAX_REORG_DEPTH
+    fork_point: Option<BlockId>,
+}
+
 struct Changelog {
     track: bool,
     changes: Vec<IndexChange>,
@@ -377,6 +690,16 @@ impl IndexChange {
         }
     }
 
+    // the status of the transaction the update refers to, if any
+    pub fn status(&self) -> Option<TxStatus> {
+        match self {
+            IndexChange::Transaction(_, status)
+            | IndexChange::TxoFunded(.., status)
+            | IndexChange::TxoSpent(.., status) => Some(*status),
+            _ => None,
+        }
+    }
+
     pub fn category_str(&self) -> &str {
         match self {
             Self::ChainTip(..) => "ChainTip",
@@ -415,3 +738,11 @@ impl fmt::Display for IndexChange {
         write!(f, "{:?}", self)
     }
 }
+
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot<S> {
+    version: u32,
+    tip: BlockId,
+    funded_indexes: HashMap<Checksum, Option<u32>>,
+    store: S,
+}
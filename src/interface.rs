@@ -1,18 +1,35 @@
 #[cfg(feature = "ffi")]
 mod ffi {
+    use std::cell::RefCell;
     use std::ffi::{CStr, CString};
     use std::os::raw::c_char;
-    use std::sync::mpsc;
-    use std::thread;
+    use std::sync::{mpsc, Arc};
+    use std::{ptr, thread};
 
-    use crate::{App, Config, Result};
+    use serde::Serialize;
+
+    use crate::types::ScriptHash;
+    use crate::{App, Config, Query, Result};
 
     const OK: i32 = 0;
     const ERR: i32 = -1;
+    const ERR_BUFFER_TOO_SMALL: i32 = -2;
+
+    thread_local! {
+        // the error message of the last failed call made from this thread
+        static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+    }
 
     #[repr(C)]
     pub struct ShutdownHandler(mpsc::Sender<()>);
 
+    /// Opaque handle to a running bwt instance, created with `bwt_boot()` and freed with `bwt_stop()`
+    pub struct BwtHandle {
+        query: Arc<Query>,
+        shutdown_tx: mpsc::Sender<()>,
+        thread: thread::JoinHandle<()>,
+    }
+
     /// Start bwt. Accepts the config as a json string, a callback function
     /// to receive status updates, and a pointer for the shutdown handler
     #[no_mangle]
@@ -45,7 +62,7 @@ mod ffi {
             callback("ready", 1.0, "");
 
             let (shutdown_tx, shutdown_rx) = mpsc::channel();
-            thread::spawn(move || app.sync(Some(shutdown_rx)));
+            app.start(shutdown_rx);
 
             Ok(ShutdownHandler(shutdown_tx))
         };
@@ -73,6 +90,154 @@ mod ffi {
         OK
     }
 
+    /// Boot bwt and start syncing in the background. Accepts the config as a json string and
+    /// a pointer for the opaque instance handle. Blocks until the initial sync is completed.
+    #[no_mangle]
+    pub extern "C" fn bwt_boot(json_config: *const c_char, handle_out: *mut *mut BwtHandle) -> i32 {
+        assert!(!handle_out.is_null());
+        wrap(|| {
+            let json_config = unsafe { CStr::from_ptr(json_config) }.to_str()?;
+            let config: Config = serde_json::from_str(json_config)?;
+            if config.verbose > 0 {
+                config.setup_logger();
+            }
+
+            let app = App::boot(config)?;
+            let query = app.query();
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+            let thread = app.start(shutdown_rx);
+
+            let handle = BwtHandle {
+                query,
+                shutdown_tx,
+                thread,
+            };
+            unsafe { *handle_out = Box::into_raw(Box::new(handle)) };
+            Ok(OK)
+        })
+    }
+
+    /// Stop syncing and shut down the servers, waiting for the instance to terminate.
+    /// Frees the handle, which must not be used afterwards.
+    #[no_mangle]
+    pub extern "C" fn bwt_stop(handle: *mut BwtHandle) -> i32 {
+        assert!(!handle.is_null());
+        let handle = unsafe { Box::from_raw(handle) };
+        handle.shutdown_tx.send(()).ok();
+        wrap(|| match handle.thread.join() {
+            Ok(()) => Ok(OK),
+            Err(_) => bail!("the sync thread panicked"),
+        })
+    }
+
+    /// Get the history of the (hex encoded) scripthash as a json array of `{txid,block_height}`
+    /// objects. See `write_json()` for the `buf`, `buf_len` and `len_out` semantics.
+    #[no_mangle]
+    pub extern "C" fn bwt_scripthash_history(
+        handle: *const BwtHandle,
+        scripthash: *const c_char,
+        buf: *mut c_char,
+        buf_len: usize,
+        len_out: *mut usize,
+    ) -> i32 {
+        wrap(|| {
+            let (query, scripthash) = unsafe { parse_script_req(handle, scripthash)? };
+            let history = query.get_history(&scripthash);
+            write_json(&history, buf, buf_len, len_out)
+        })
+    }
+
+    /// Get the unspent outputs of the (hex encoded) scripthash, in the format used by the HTTP API
+    #[no_mangle]
+    pub extern "C" fn bwt_scripthash_utxos(
+        handle: *const BwtHandle,
+        scripthash: *const c_char,
+        buf: *mut c_char,
+        buf_len: usize,
+        len_out: *mut usize,
+    ) -> i32 {
+        wrap(|| {
+            let (query, scripthash) = unsafe { parse_script_req(handle, scripthash)? };
+            let utxos = query.list_unspent(Some(&scripthash), 0, 0, None)?;
+            write_json(&utxos, buf, buf_len, len_out)
+        })
+    }
+
+    /// Get the balance of the (hex encoded) scripthash as `{confirmed,unconfirmed}` (in satoshis)
+    #[no_mangle]
+    pub extern "C" fn bwt_scripthash_balance(
+        handle: *const BwtHandle,
+        scripthash: *const c_char,
+        buf: *mut c_char,
+        buf_len: usize,
+        len_out: *mut usize,
+    ) -> i32 {
+        wrap(|| {
+            let (query, scripthash) = unsafe { parse_script_req(handle, scripthash)? };
+            let (confirmed, unconfirmed) = query.get_script_balance(&scripthash)?;
+            let balance = json!({ "confirmed": confirmed, "unconfirmed": unconfirmed });
+            write_json(&balance, buf, buf_len, len_out)
+        })
+    }
+
+    /// Get the error message of the last call that failed on the current thread, or NULL if there
+    /// wasn't one. The string is owned by bwt and remains valid until the next failed call.
+    #[no_mangle]
+    pub extern "C" fn bwt_last_error() -> *const c_char {
+        LAST_ERROR.with(|last_error| {
+            last_error
+                .borrow()
+                .as_ref()
+                .map_or(ptr::null(), |msg| msg.as_ptr())
+        })
+    }
+
+    // Run `f`, keeping the error message for bwt_last_error() and returning ERR if it fails
+    fn wrap(f: impl FnOnce() -> Result<i32>) -> i32 {
+        match f() {
+            Ok(code) => code,
+            Err(e) => {
+                warn!("{:?}", e);
+                let msg = CString::new(format!("{:#}", e).replace('\0', "")).unwrap();
+                LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(msg));
+                ERR
+            }
+        }
+    }
+
+    unsafe fn parse_script_req<'a>(
+        handle: *const BwtHandle,
+        scripthash: *const c_char,
+    ) -> Result<(&'a Query, ScriptHash)> {
+        assert!(!handle.is_null() && !scripthash.is_null());
+        let scripthash = CStr::from_ptr(scripthash).to_str()?.parse()?;
+        Ok((&*(*handle).query, scripthash))
+    }
+
+    // Write `value` as a NUL-terminated json string into the caller-allocated `buf`, setting
+    // `len_out` to the string length (excluding the NUL). If `buf_len` is too small, nothing is
+    // written and ERR_BUFFER_TOO_SMALL is returned, with `len_out` set to the required size
+    // (including the NUL) so that the caller can retry with a larger buffer.
+    fn write_json(
+        value: &impl Serialize,
+        buf: *mut c_char,
+        buf_len: usize,
+        len_out: *mut usize,
+    ) -> Result<i32> {
+        assert!(!len_out.is_null());
+        let json = serde_json::to_vec(value)?;
+        unsafe {
+            if buf.is_null() || json.len() + 1 > buf_len {
+                *len_out = json.len() + 1;
+                return Ok(ERR_BUFFER_TOO_SMALL);
+            }
+            ptr::copy_nonoverlapping(json.as_ptr() as *const c_char, buf, json.len());
+            *buf.add(json.len()) = 0;
+            *len_out = json.len();
+        }
+        Ok(OK)
+    }
+
     fn cstring(s: &str) -> *const c_char {
         CString::new(s).unwrap().into_raw()
     }
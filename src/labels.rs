@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use bitcoin::Txid;
+
+use crate::error::{Context, Result};
+use crate::types::ScriptHash;
+use crate::util::write_json_atomic;
+
+/// User-provided labels for transactions and scripthashes, kept in memory and optionally
+/// persisted to a JSON file to survive restarts
+pub struct LabelStore {
+    labels: RwLock<Labels>,
+    // where labels are persisted to, if at all
+    store_path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Labels {
+    #[serde(default)]
+    pub txs: HashMap<Txid, String>,
+    #[serde(default)]
+    pub scripthashes: HashMap<ScriptHash, String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LabelTarget {
+    Tx(Txid),
+    Script(ScriptHash),
+}
+
+impl LabelStore {
+    pub fn load(store_path: Option<PathBuf>) -> Result<Self> {
+        let labels = match &store_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read(path)
+                    .with_context(|| format!("failed reading labels from {:?}", path))?;
+                let labels: Labels = serde_json::from_slice(&contents)
+                    .with_context(|| format!("invalid labels file {:?}", path))?;
+                info!(
+                    "loaded {} transaction and {} address label(s) from {:?}",
+                    labels.txs.len(),
+                    labels.scripthashes.len(),
+                    path
+                );
+                labels
+            }
+            _ => Labels::default(),
+        };
+        Ok(Self {
+            labels: RwLock::new(labels),
+            store_path,
+        })
+    }
+
+    pub fn get(&self, target: &LabelTarget) -> Option<String> {
+        let labels = self.labels.read().unwrap();
+        match target {
+            LabelTarget::Tx(txid) => labels.txs.get(txid),
+            LabelTarget::Script(scripthash) => labels.scripthashes.get(scripthash),
+        }
+        .cloned()
+    }
+
+    pub fn get_all(&self) -> Labels {
+        self.labels.read().unwrap().clone()
+    }
+
+    /// Set the label, replacing the existing one (if any)
+    pub fn set(&self, target: LabelTarget, label: String) -> Result<()> {
+        let mut labels = self.labels.write().unwrap();
+        match target {
+            LabelTarget::Tx(txid) => labels.txs.insert(txid, label),
+            LabelTarget::Script(scripthash) => labels.scripthashes.insert(scripthash, label),
+        };
+        self.persist(&labels)
+    }
+
+    /// Remove the label, returning whether it existed
+    pub fn remove(&self, target: &LabelTarget) -> Result<bool> {
+        let mut labels = self.labels.write().unwrap();
+        let existed = match target {
+            LabelTarget::Tx(txid) => labels.txs.remove(txid),
+            LabelTarget::Script(scripthash) => labels.scripthashes.remove(scripthash),
+        }
+        .is_some();
+        if existed {
+            self.persist(&labels)?;
+        }
+        Ok(existed)
+    }
+
+    fn persist(&self, labels: &Labels) -> Result<()> {
+        let path = some_or_ret!(&self.store_path, Ok(()));
+        write_json_atomic(path, labels)
+            .with_context(|| format!("failed writing labels to {:?}", path))?;
+        Ok(())
+    }
+}
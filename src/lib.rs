@@ -10,6 +10,9 @@ extern crate lazy_static;
 extern crate bitcoin_hashes;
 #[macro_use]
 extern crate serde;
+#[cfg(feature = "metrics")]
+#[macro_use]
+extern crate prometheus;
 
 #[macro_use]
 pub mod util;
@@ -17,8 +20,10 @@ pub mod util;
 pub mod app;
 pub mod config;
 pub mod error;
+pub mod fees;
 pub mod indexer;
 pub mod interface;
+pub mod labels;
 pub mod query;
 pub mod store;
 pub mod types;
@@ -27,15 +32,27 @@ pub mod wallet;
 #[cfg(unix)]
 pub mod listener;
 
+#[cfg(feature = "zmq")]
+pub mod zmq_listener;
+
 #[cfg(feature = "electrum")]
 pub mod electrum;
 
 #[cfg(feature = "http")]
 pub mod http;
 
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
 #[cfg(feature = "webhooks")]
 pub mod webhooks;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(all(feature = "http", feature = "webhooks"))]
+pub mod monitor;
+
 pub use app::App;
 pub use config::Config;
 pub use error::{Error, Result};
@@ -8,8 +8,12 @@ fn main() -> Result<()> {
 
     config.setup_logger();
 
+    if config.dry_run {
+        return App::dry_run(config);
+    }
+
     let app = App::boot(config)?;
-    app.sync(None);
+    app.sync(App::shutdown_signal());
 
     Ok(())
 }
@@ -0,0 +1,86 @@
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::error::Result;
+use crate::Query;
+
+// The metrics are registered with prometheus' default registry. Gauges that reflect the index
+// state are updated when the metrics are scraped, the others are updated by the instrumented code.
+
+lazy_static! {
+    static ref WALLETS: IntGauge =
+        register_int_gauge!("bwt_wallets", "Number of tracked wallets").unwrap();
+    static ref WATCHED_ADDRESSES: IntGauge = register_int_gauge!(
+        "bwt_watched_addresses",
+        "Number of addresses imported into bitcoind for the tracked wallets"
+    )
+    .unwrap();
+    static ref TIP_HEIGHT: IntGauge =
+        register_int_gauge!("bwt_tip_height", "Block height the index is synced up to").unwrap();
+    static ref TIP_LAG_BLOCKS: IntGauge = register_int_gauge!(
+        "bwt_tip_lag_blocks",
+        "Number of blocks bitcoind is ahead of the index"
+    )
+    .unwrap();
+    static ref SYNC_LAG_SECONDS: IntGauge = register_int_gauge!(
+        "bwt_sync_lag_seconds",
+        "Seconds elapsed since the index tip block was mined"
+    )
+    .unwrap();
+    pub static ref SYNC_DURATION: Histogram = register_histogram!(
+        "bwt_sync_duration_seconds",
+        "Duration of index syncs",
+        vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    pub static ref RPC_CALLS: IntCounterVec = register_int_counter_vec!(
+        "bwt_rpc_calls_total",
+        "Number of bitcoind RPC calls",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref RPC_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "bwt_rpc_errors_total",
+        "Number of failed bitcoind RPC calls",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "bwt_rpc_call_duration_seconds",
+        "Latency of bitcoind RPC calls",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref ELECTRUM_CONNECTIONS: IntGauge = register_int_gauge!(
+        "bwt_electrum_connections",
+        "Number of connected Electrum clients"
+    )
+    .unwrap();
+    pub static ref HTTP_REQUESTS: IntCounter =
+        register_int_counter!("bwt_http_requests_total", "Number of HTTP requests").unwrap();
+}
+
+/// Update the index state gauges and encode all metrics in the prometheus text format
+pub fn encode(query: &Query) -> Result<String> {
+    let health = query.get_health(u64::MAX);
+    if let Some(tip_height) = health.tip_height {
+        TIP_HEIGHT.set(tip_height as i64);
+        if let Ok(bitcoind_height) = query.get_tip_height() {
+            TIP_LAG_BLOCKS.set(bitcoind_height.saturating_sub(tip_height) as i64);
+        }
+    }
+    if let Some(sync_lag) = health.sync_lag_seconds {
+        SYNC_LAG_SECONDS.set(sync_lag as i64);
+    }
+    // the wallets can only be read once the initial sync completes and releases the index lock
+    if health.initial_import_done {
+        let (wallets, addresses) = query.get_watched_counts();
+        WALLETS.set(wallets as i64);
+        WATCHED_ADDRESSES.set(addresses as i64);
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
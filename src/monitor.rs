@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, thread};
+
+use ::reqwest::blocking as reqwest;
+use bitcoin::{Address, Network, Txid};
+use bitcoincore_rpc::RpcApi;
+
+use crate::error::{Context, Result};
+use crate::query::Query;
+use crate::types::{ScriptHash, TxStatus};
+use crate::util::{write_json_atomic, BoolThen};
+use crate::wallet::KeyOrigin;
+
+/// Watches standalone addresses on behalf of external callers, notifying a callback url once
+/// the address receives funds with enough confirmations
+pub struct AddressMonitor {
+    monitors: Mutex<HashMap<Address, Monitor>>,
+    // where monitors are persisted to survive restarts, if at all
+    store_path: Option<PathBuf>,
+    query: Arc<Query>,
+    _thread: thread::JoinHandle<()>,
+    tx: mpsc::Sender<(String, MonitorPayment)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Monitor {
+    pub address: Address,
+    pub callback_url: String,
+    pub min_conf: u32,
+    // unix timestamp after which the monitor is dropped
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MonitorRequest {
+    pub address: Address,
+    pub callback_url: String,
+    #[serde(default = "default_min_conf")]
+    pub min_conf: u32,
+    // expiry time in seconds from now
+    pub expiry: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MonitorPayment {
+    pub address: Address,
+    // the total amount received with at least `min_conf` confirmations
+    pub amount: u64,
+    pub txids: Vec<Txid>,
+}
+
+impl AddressMonitor {
+    pub fn start(store_path: Option<PathBuf>, query: Arc<Query>) -> Result<Self> {
+        let monitors = match &store_path {
+            Some(path) if path.exists() => {
+                let contents = fs::read(path)
+                    .with_context(|| format!("failed reading monitors from {:?}", path))?;
+                let monitors: Vec<Monitor> = serde_json::from_slice(&contents)
+                    .with_context(|| format!("invalid monitors file {:?}", path))?;
+                info!(
+                    "loaded {} address monitor(s) from {:?}",
+                    monitors.len(),
+                    path
+                );
+                monitors
+                    .into_iter()
+                    .map(|monitor| (monitor.address.clone(), monitor))
+                    .collect()
+            }
+            _ => HashMap::new(),
+        };
+
+        let (tx, rx) = mpsc::channel::<(String, MonitorPayment)>();
+
+        Ok(Self {
+            monitors: Mutex::new(monitors),
+            store_path,
+            query,
+            // Spawn a separate thread for sending the callback HTTP requests
+            _thread: thread::spawn(move || {
+                let client = reqwest::Client::new();
+                while let Ok((url, payment)) = rx.recv() {
+                    debug!("notifying {} with {:?}", url, payment);
+                    client
+                        .post(&url)
+                        .json(&payment)
+                        .send()
+                        .map(|r| debug!("notifying {} succeed: {:?}", url, r.status()))
+                        .map_err(|e| warn!("notifying {} failed: {:?}", url, e))
+                        .ok();
+                }
+                trace!("address monitor shutting down");
+            }),
+            tx,
+        })
+    }
+
+    /// Start watching the address, importing it into bitcoind as a standalone address
+    pub fn register(&self, req: MonitorRequest) -> Result<Monitor> {
+        let network = self.query.network();
+        ensure!(
+            address_matches_network(&req.address, network),
+            "Address {} does not match the configured network {}",
+            req.address,
+            network
+        );
+
+        let label = KeyOrigin::Standalone.to_label();
+        // the address is expected to be fresh, no need to rescan for its history
+        self.query
+            .rpc()
+            .import_address(&req.address, Some(&label), Some(false))?;
+
+        let monitor = Monitor {
+            address: req.address,
+            callback_url: req.callback_url,
+            min_conf: req.min_conf,
+            expires_at: req.expiry.map(|expiry| now() + expiry),
+        };
+        info!("monitoring address {:?}", monitor);
+
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.insert(monitor.address.clone(), monitor.clone());
+        self.persist(&monitors)?;
+
+        Ok(monitor)
+    }
+
+    /// Notify the monitors whose address got paid and drop them, along with the expired ones
+    pub fn check(&self) -> Result<()> {
+        let mut monitors = self.monitors.lock().unwrap();
+        if monitors.is_empty() {
+            return Ok(());
+        }
+
+        let tip_height = self.query.get_tip_height()?;
+        let now = now();
+        let num_monitors = monitors.len();
+
+        monitors.retain(|address, monitor| {
+            if matches!(monitor.expires_at, Some(expires_at) if expires_at <= now) {
+                info!("address monitor for {} expired", address);
+                return false;
+            }
+            let payment = some_or_ret!(self.get_payment(monitor, tip_height), true);
+            info!("address monitor for {} paid {:?}", address, payment);
+            self.tx
+                .send((monitor.callback_url.clone(), payment))
+                .unwrap();
+            false
+        });
+
+        if monitors.len() != num_monitors {
+            self.persist(&monitors)?;
+        }
+        Ok(())
+    }
+
+    // Get the funds received by the monitored address with at least `min_conf` confirmations
+    fn get_payment(&self, monitor: &Monitor, tip_height: u32) -> Option<MonitorPayment> {
+        let scripthash = ScriptHash::from(&monitor.address);
+        let mut amount = 0;
+        let mut txids = vec![];
+        for txhist in self.query.get_history(&scripthash) {
+            let confirmations = match txhist.status {
+                TxStatus::Confirmed(height) => (tip_height + 1).saturating_sub(height),
+                TxStatus::Unconfirmed => 0,
+                TxStatus::Conflicted => continue,
+            };
+            if confirmations < monitor.min_conf {
+                continue;
+            }
+            let received = self.query.with_tx_entry(&txhist.txid, |tx_entry| {
+                tx_entry
+                    .funding
+                    .values()
+                    .filter(|funding| funding.0 == scripthash)
+                    .map(|funding| funding.1)
+                    .sum::<u64>()
+            });
+            if let Some(received) = received.filter(|received| *received > 0) {
+                amount += received;
+                txids.push(txhist.txid);
+            }
+        }
+        (amount > 0).do_then(|| MonitorPayment {
+            address: monitor.address.clone(),
+            amount,
+            txids,
+        })
+    }
+
+    fn persist(&self, monitors: &HashMap<Address, Monitor>) -> Result<()> {
+        let path = some_or_ret!(&self.store_path, Ok(()));
+        let monitors: Vec<&Monitor> = monitors.values().collect();
+        write_json_atomic(path, &monitors)
+            .with_context(|| format!("failed writing monitors to {:?}", path))?;
+        Ok(())
+    }
+}
+
+// testnet and regtest share the same base58 address version bytes
+fn address_matches_network(address: &Address, network: Network) -> bool {
+    address.network == network
+        || (address.network == Network::Testnet && network == Network::Regtest)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn default_min_conf() -> u32 {
+    1
+}
@@ -1,62 +1,189 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem::size_of;
+use std::path::PathBuf;
 use std::process::Command;
 use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use serde_json::Value;
 
-use bitcoin::{BlockHash, BlockHeader, Network, OutPoint, Transaction, Txid};
+use bitcoin::{Address, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid};
 use bitcoin_hashes::hex::FromHex;
-use bitcoincore_rpc::{json as rpcjson, Client as RpcClient, RpcApi};
+use bitcoin_hashes::sha256d;
+use bitcoincore_rpc::{json as rpcjson, RpcApi};
 
 use crate::error::{BwtError, Context, OptionExt, Result};
-use crate::indexer::{IndexChange, Indexer};
-use crate::store::{FundingInfo, HistoryEntry, ScriptInfo, SpendingInfo, TxEntry};
-use crate::types::{BlockId, MempoolEntry, ScriptHash, TxStatus};
+use crate::fees::{FeeEstimator, FeeSource};
+use crate::indexer::{IndexChange, Indexer, MAX_REORG_DEPTH};
+use crate::labels::{LabelStore, LabelTarget, Labels};
+use crate::store::{FundingInfo, HistoryEntry, ScriptInfo, SpendingInfo, StoreStats, TxEntry};
+use crate::types::{BlockId, MempoolEntry, RescanSince, ScriptHash, TxDirection, TxStatus};
+use crate::util::bitcoincore_ext::{
+    RpcApiExt, RpcClient, ScanningDetails, ScanningResult, WalletRpcs,
+};
 use crate::util::descriptor::{Checksum, DescriptorChecksum};
+use crate::util::merkle::create_merkle_branch_and_root;
+use crate::util::xpub::Bip32Origin;
 use crate::util::{make_fee_histogram, BoolThen};
-use crate::wallet::{KeyOrigin, Wallet};
+use crate::wallet::{
+    GapStats, KeyOrigin, SyncProgress, Wallet, WalletBalance, WalletGroup, WalletTxChange,
+    XpubChain,
+};
 
 #[cfg(feature = "track-spends")]
 use crate::types::InPoint;
 
 const FEE_HISTOGRAM_TTL: Duration = Duration::from_secs(120);
 const FEE_ESTIMATES_TTL: Duration = Duration::from_secs(120);
+const RELAY_FEE_TTL: Duration = Duration::from_secs(60);
+// the maximum number of raw wallet transactions kept in memory
+const RAW_TXS_CACHE_SIZE: usize = 2500;
+// the maximum number of transaction fees kept in memory
+const TX_FEES_CACHE_SIZE: usize = 10000;
 
 pub struct Query {
     config: QueryConfig,
     rpc: Arc<RpcClient>,
+    wallet_rpcs: WalletRpcs,
     indexer: Arc<RwLock<Indexer>>,
+    sync_progress: Arc<SyncProgress>,
+    fee_estimator: Box<dyn FeeEstimator>,
+    labels: LabelStore,
 
-    cached_relayfee: RwLock<Option<f64>>,
+    cached_relayfee: RwLock<Option<(f64, Instant)>>,
+    cached_incrementalfee: RwLock<Option<f64>>,
     cached_histogram: RwLock<Option<(FeeHistogram, Instant)>>,
     cached_estimates: RwLock<HashMap<u16, (Option<f64>, Instant)>>,
+    cached_tx_fees: RwLock<HashMap<Txid, (u64, u64)>>,
+    cached_raw_txs: RwLock<HashMap<Txid, Vec<u8>>>,
+    // the hashes of the best chain blocks, indexed by their height
+    cached_block_hashes: RwLock<Vec<BlockHash>>,
 }
 
 pub struct QueryConfig {
     pub network: Network,
     pub broadcast_cmd: Option<String>,
+    pub fee_source: FeeSource,
+    pub labels_file: Option<PathBuf>,
+    pub public_host: Option<String>,
 }
 
 type FeeHistogram = Vec<(f32, u32)>;
 
 impl Query {
-    pub fn new(config: QueryConfig, rpc: Arc<RpcClient>, indexer: Arc<RwLock<Indexer>>) -> Self {
-        Query {
+    pub fn new(
+        config: QueryConfig,
+        rpc: Arc<RpcClient>,
+        indexer: Arc<RwLock<Indexer>>,
+    ) -> Result<Self> {
+        let fee_estimator = config.fee_source.clone().into_estimator(rpc.clone());
+        let sync_progress = indexer.read().unwrap().watcher().progress().clone();
+        let wallet_rpcs = indexer.read().unwrap().wallet_rpcs().clone();
+        let labels = LabelStore::load(config.labels_file.clone())?;
+        Ok(Query {
             config,
             rpc,
+            wallet_rpcs,
             indexer,
+            sync_progress,
+            fee_estimator,
+            labels,
             cached_relayfee: RwLock::new(None),
+            cached_incrementalfee: RwLock::new(None),
             cached_histogram: RwLock::new(None),
             cached_estimates: RwLock::new(HashMap::new()),
-        }
+            cached_tx_fees: RwLock::new(HashMap::new()),
+            cached_raw_txs: RwLock::new(HashMap::new()),
+            cached_block_hashes: RwLock::new(vec![]),
+        })
     }
 
     pub fn rpc(&self) -> &RpcClient {
         &self.rpc
     }
 
+    pub fn network(&self) -> Network {
+        self.config.network
+    }
+
+    /// The externally reachable hostname (or onion address) advertised to clients, if configured
+    pub fn public_host(&self) -> Option<&str> {
+        self.config.public_host.as_deref()
+    }
+
+    /// Whether the index is being rebuilt following a reorg. Queries are served from the previous
+    /// index in the meanwhile, which may be stale.
+    pub fn is_reindexing(&self) -> bool {
+        self.sync_progress.is_reindexing()
+    }
+
+    /// Whether the initial sync was completed. Queries that access the index block until it is.
+    pub fn is_ready(&self) -> bool {
+        self.sync_progress.initial_import_done()
+    }
+
+    /// Get the progress of the address imports and index sync. Does not require locking the index,
+    /// so that it can be used while the initial sync is running.
+    pub fn get_sync_status(&self) -> Result<SyncStatus> {
+        let (imported, total) = self.sync_progress.imported();
+        let scanning_progress = match self.wallet_rpcs.check_scanning()? {
+            ScanningResult::Scanning(details) => Some(details.progress),
+            ScanningResult::NotScanning | ScanningResult::Unsupported => None,
+        };
+        Ok(SyncStatus {
+            initial_import_done: self.sync_progress.initial_import_done(),
+            imported,
+            total,
+            scanning_progress,
+            tip_height: self.sync_progress.tip_height(),
+            reindexing: self.sync_progress.is_reindexing(),
+        })
+    }
+
+    /// Check that bitcoind is reachable and that the sync lag is no more than `max_tip_age` seconds
+    pub fn get_health(&self, max_tip_age: u64) -> HealthStatus {
+        let tip_height = self.sync_progress.tip_height();
+        let sync_lag = match tip_height {
+            Some(_) => self.get_sync_lag(),
+            // bitcoind still has to be pinged to check for connectivity
+            None => self.get_tip_height().map(|_| None),
+        };
+        let (bitcoind_connected, sync_lag_seconds) = match sync_lag {
+            Ok(sync_lag) => (true, sync_lag),
+            Err(e) => {
+                warn!("health check failed: {:#}", e);
+                (false, None)
+            }
+        };
+        let initial_import_done = self.sync_progress.initial_import_done();
+        HealthStatus {
+            ok: bitcoind_connected
+                && initial_import_done
+                && sync_lag_seconds.map_or(false, |lag| lag <= max_tip_age),
+            bitcoind_connected,
+            tip_height,
+            sync_lag_seconds,
+            initial_import_done,
+        }
+    }
+
+    /// Get the index store stats, with the memory usage including the raw transactions and fees caches
+    pub fn get_store_stats(&self) -> StoreStats {
+        let mut stats = self.indexer.read().unwrap().store().stats();
+        let raw_txs_size: usize = self
+            .cached_raw_txs
+            .read()
+            .unwrap()
+            .values()
+            .map(|tx_raw| size_of::<(Txid, Vec<u8>)>() + tx_raw.len())
+            .sum();
+        let tx_fees_size =
+            self.cached_tx_fees.read().unwrap().len() * size_of::<(Txid, (u64, u64))>();
+        stats.memory_usage += raw_txs_size + tx_fees_size;
+        stats
+    }
+
     pub fn debug_index(&self) -> String {
         format!("{:#?}", self.indexer.read().unwrap().store())
     }
@@ -65,6 +192,27 @@ impl Query {
         json!(self.indexer.read().unwrap().store())
     }
 
+    //
+    // Labels
+    //
+
+    pub fn get_label(&self, target: &LabelTarget) -> Option<String> {
+        self.labels.get(target)
+    }
+
+    pub fn get_labels(&self) -> Labels {
+        self.labels.get_all()
+    }
+
+    pub fn set_label(&self, target: LabelTarget, label: String) -> Result<()> {
+        self.labels.set(target, label)
+    }
+
+    /// Remove the label, returning whether it existed
+    pub fn remove_label(&self, target: &LabelTarget) -> Result<bool> {
+        self.labels.remove(target)
+    }
+
     //
     // Blocks
     //
@@ -93,10 +241,81 @@ impl Query {
             .call("getblockheader", &[json!(blockhash), false.into()])?)
     }
 
+    /// Get the best block the index is synced up to, or None if the initial sync wasn't completed yet.
+    pub fn get_synced_tip(&self) -> Option<BlockId> {
+        self.indexer.read().unwrap().tip().cloned()
+    }
+
+    /// Get the number of seconds elapsed since the best block the index is synced up to was
+    /// produced (based on its header timestamp), or None if the initial sync wasn't completed yet.
+    /// A growing lag indicates that bwt or bitcoind are falling behind.
+    ///
+    /// Uses the synced height tracked by the sync progress rather than the index tip, so that it
+    /// doesn't wait for the index lock while a sync is in progress.
+    pub fn get_sync_lag(&self) -> Result<Option<u64>> {
+        let tip_height = some_or_ret!(self.sync_progress.tip_height(), Ok(None));
+        let tip_time = self.get_header(&self.get_block_hash(tip_height)?)?.time as u64;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(Some(now.saturating_sub(tip_time)))
+    }
+
     pub fn get_block_hash(&self, height: u32) -> Result<BlockHash> {
         Ok(self.rpc.get_block_hash(height as u64)?)
     }
 
+    /// Get the hashes of the best chain blocks in the `start_height..=end_height` range, up to
+    /// the indexed tip.
+    ///
+    /// The hashes are cached in memory up to the indexed tip. Once the tip moves, the blocks added
+    /// since (along with the last `MAX_REORG_DEPTH` cached ones, to detect reorgs) are fetched from
+    /// bitcoind in batches, without holding the cache lock.
+    pub fn get_block_hashes(&self, start_height: u32, end_height: u32) -> Result<Vec<BlockHash>> {
+        let BlockId(tip_height, tip_hash) = *self
+            .indexer
+            .read()
+            .unwrap()
+            .tip()
+            .or_err("the index is not synced yet")?;
+        ensure!(
+            start_height <= end_height && end_height <= tip_height,
+            "invalid block range {}-{} (the indexed tip is at height {})",
+            start_height,
+            end_height,
+            tip_height
+        );
+
+        {
+            let cache = self.cached_block_hashes.read().unwrap();
+            if cache.get(tip_height as usize) == Some(&tip_hash) {
+                return Ok(cache[start_height as usize..=end_height as usize].to_vec());
+            }
+        }
+
+        let cached_len = self.cached_block_hashes.read().unwrap().len() as u32;
+        let fetch_from = cached_len.saturating_sub(MAX_REORG_DEPTH).min(tip_height);
+        let fetched = self.rpc.get_block_hashes(fetch_from, tip_height)?;
+
+        let mut cache = self.cached_block_hashes.write().unwrap();
+        match cache.get(fetch_from as usize) {
+            // the cache was modified by a concurrent call or reorged deeper than MAX_REORG_DEPTH,
+            // start over from scratch
+            Some(cached_hash) if fetch_from > 0 && *cached_hash != fetched[0] => {
+                cache.clear();
+                drop(cache);
+                return self.get_block_hashes(start_height, end_height);
+            }
+            None if fetch_from as usize > cache.len() => {
+                drop(cache);
+                return self.get_block_hashes(start_height, end_height);
+            }
+            _ => (),
+        }
+        cache.truncate(fetch_from as usize);
+        cache.extend(fetched);
+
+        Ok(cache[start_height as usize..=end_height as usize].to_vec())
+    }
+
     pub fn get_block_txids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
         let info = self.rpc.get_block_info(blockhash).map_err(BwtError::from)?;
         Ok(info.tx)
@@ -106,8 +325,8 @@ impl Query {
     // Mempool & Fees
     //
 
-    pub fn get_raw_mempool(&self) -> Result<HashMap<Txid, Value>> {
-        Ok(self.rpc.call("getrawmempool", &[json!(true)])?)
+    pub fn get_raw_mempool(&self) -> Result<HashMap<Txid, rpcjson::GetMempoolEntryResult>> {
+        Ok(self.rpc.get_raw_mempool_verbose()?)
     }
 
     pub fn estimate_fee(&self, target: u16) -> Result<Option<f64>> {
@@ -115,31 +334,38 @@ impl Query {
 
         // regtest typically doesn't have fee estimates, just use the relay fee instead.
         // this stops electrum from complanining about unavailable dynamic fees.
-        if self.config.network == Network::Regtest {
+        if self.config.network == Network::Regtest
+            && matches!(self.config.fee_source, FeeSource::Bitcoind)
+        {
             return self.relay_fee().map(Some);
         }
 
         ttl_cache!(
             self.cached_estimates,
             FEE_ESTIMATES_TTL,
-            || -> Result<Option<f64>> {
-                let feerate = self
-                    .rpc
-                    .estimate_smart_fee(target, None)?
-                    .fee_rate
-                    // from sat/kB to sat/b
-                    .map(|rate| rate.as_sat() as f64 / 1000f64);
-                Ok(feerate)
-            },
+            || self.fee_estimator.estimate_fee(target),
             target
         );
     }
 
+    /// The minimum relay feerate, in sat/vB
     pub fn relay_fee(&self) -> Result<f64> {
-        cache_forever!(self.cached_relayfee, || -> Result<f64> {
-            let feerate = self.rpc.call::<Value>("getmempoolinfo", &[])?["minrelaytxfee"]
+        ttl_cache!(self.cached_relayfee, RELAY_FEE_TTL, || -> Result<f64> {
+            let feerate = self.rpc.call::<Value>("getnetworkinfo", &[])?["relayfee"]
                 .as_f64()
-                .or_err("invalid getmempoolinfo reply")?;
+                .or_err("invalid getnetworkinfo reply")?;
+
+            // from BTC/kB to sat/b
+            Ok(feerate * 100_000f64)
+        });
+    }
+
+    /// The minimum feerate increase required for replacements, in sat/vB
+    pub fn incremental_relay_fee(&self) -> Result<f64> {
+        cache_forever!(self.cached_incrementalfee, || -> Result<f64> {
+            let feerate = self.rpc.call::<Value>("getnetworkinfo", &[])?["incrementalfee"]
+                .as_f64()
+                .or_err("invalid getnetworkinfo reply")?;
 
             // from BTC/kB to sat/b
             Ok(feerate * 100_000f64)
@@ -176,19 +402,147 @@ impl Query {
     //
 
     pub fn get_tx_raw(&self, txid: &Txid) -> Result<Vec<u8>> {
+        if let Some(tx_raw) = self.cached_raw_txs.read().unwrap().get(txid) {
+            return Ok(tx_raw.clone());
+        }
+
         // Try fetching the transaction from bitcoind's wallet db first. This doesn't require txindex
         // and will remain available even if the containing block was since pruned.
-        if let Ok(tx_info) = self.rpc.get_transaction(txid, Some(true)) {
+        let wallet_tx = self
+            .wallet_rpcs
+            .all()
+            .find_map(|rpc| rpc.get_transaction(txid, Some(true)).ok());
+        if let Some(tx_info) = wallet_tx {
+            // keep wallet transactions cached in memory, as they're frequently requested by clients
+            if self.with_tx_entry(txid, |_| ()).is_some() {
+                let mut cache = self.cached_raw_txs.write().unwrap();
+                if cache.len() >= RAW_TXS_CACHE_SIZE {
+                    let evicted = *cache.keys().next().unwrap();
+                    cache.remove(&evicted);
+                }
+                cache.insert(*txid, tx_info.hex.clone());
+            }
             Ok(tx_info.hex)
         }
         // If that fails, try with getrawtransaction. This requires txindex (except for mempool transactions)
         // and is incompatible with pruning, but works for non-wallet transactions too.
         else {
-            let tx_hex = self.rpc.get_raw_transaction_hex(txid, None)?;
+            let tx_hex = self.rpc.get_raw_transaction_hex(txid, None).map_err(|e| {
+                match BwtError::from(e) {
+                    // RPC_INVALID_ADDRESS_OR_KEY, returned for unknown transactions
+                    BwtError::Rpc(e) if e.code == -5 => BwtError::TxNotFound(*txid),
+                    e => e,
+                }
+            })?;
             Ok(Vec::from_hex(&tx_hex)?)
         }
     }
 
+    /// Get the fee paid by the transaction and its vsize. Uses the fee stored in the index or the
+    /// mempool entry when available, or computes it from the prevouts otherwise (which may require
+    /// txindex for prevouts that don't belong to the wallet).
+    pub fn get_tx_fee(&self, txid: &Txid) -> Result<(u64, u64)> {
+        // the fee and vsize never change for a given txid, so they can be cached forever
+        if let Some(tx_fee) = self.cached_tx_fees.read().unwrap().get(txid) {
+            return Ok(*tx_fee);
+        }
+        if let Some(tx_fee) = self.with_tx_entry(txid, |tx_entry| tx_entry.fee).flatten() {
+            return Ok(tx_fee);
+        }
+
+        let tx_fee = match self.with_mempool_entry(txid, |entry| (entry.fee, entry.vsize)) {
+            Some(tx_fee) => tx_fee,
+            None => {
+                let tx: Transaction = bitcoin::consensus::deserialize(&self.get_tx_raw(txid)?)?;
+                let vsize = (tx.get_weight() as f64 / 4.0).ceil() as u64;
+                // coinbase transactions have no prevouts and pay no fee
+                if tx.is_coin_base() {
+                    (0, vsize)
+                } else {
+                    let prevouts_value = tx
+                        .input
+                        .iter()
+                        .map(|txin| self.get_prevout_value(&txin.previous_output))
+                        .sum::<Result<u64>>()?;
+                    let outputs_value = tx.output.iter().map(|txout| txout.value).sum::<u64>();
+                    let fee = prevouts_value.checked_sub(outputs_value).with_context(|| {
+                        format!("outputs exceed the prevouts value for {}", txid)
+                    })?;
+                    (fee, vsize)
+                }
+            }
+        };
+
+        let mut cache = self.cached_tx_fees.write().unwrap();
+        if cache.len() >= TX_FEES_CACHE_SIZE {
+            let evicted = *cache.keys().next().unwrap();
+            cache.remove(&evicted);
+        }
+        cache.insert(*txid, tx_fee);
+        Ok(tx_fee)
+    }
+
+    fn get_prevout_value(&self, outpoint: &OutPoint) -> Result<u64> {
+        let indexer = self.indexer.read().unwrap();
+        if let Some(FundingInfo(_, amount)) = indexer.store().lookup_txo_fund(outpoint) {
+            return Ok(amount);
+        }
+        drop(indexer);
+
+        let prev_tx: Transaction =
+            bitcoin::consensus::deserialize(&self.get_tx_raw(&outpoint.txid)?)?;
+        let prevout = prev_tx
+            .output
+            .get(outpoint.vout as usize)
+            .or_err("missing prevout")?;
+        Ok(prevout.value)
+    }
+
+    /// Get the information needed for bumping the fee of an unconfirmed transaction, using
+    /// either RBF or CPFP. Fails with `TxConfirmed` if the transaction is already confirmed.
+    pub fn get_tx_bump_info(&self, txid: &Txid) -> Result<TxBumpInfo> {
+        let entry = match self.rpc.get_mempool_entry(txid).map_err(BwtError::from) {
+            Ok(entry) => entry,
+            // RPC_INVALID_ADDRESS_OR_KEY, returned for transactions that are not in the mempool
+            Err(BwtError::Rpc(e)) if e.code == -5 => {
+                match self.with_tx_entry(txid, |tx_entry| tx_entry.status) {
+                    Some(TxStatus::Confirmed(_)) => bail!(BwtError::TxConfirmed(*txid)),
+                    _ => bail!(BwtError::TxNotFound(*txid)),
+                }
+            }
+            Err(e) => bail!(e),
+        };
+
+        let fee = entry.fees.base.as_sat();
+        let descendant_fee = entry.fees.descendant.as_sat();
+
+        // BIP125 requires replacements to pay for the fees of the replaced transaction and all of its
+        // descendants, plus the incremental relay fee for their own size. This assumes a replacement
+        // of the same size as the original.
+        let min_replacement_feerate = if entry.bip125_replaceable {
+            Some(descendant_fee as f64 / entry.vsize as f64 + self.incremental_relay_fee()?)
+        } else {
+            None
+        };
+
+        Ok(TxBumpInfo {
+            txid: *txid,
+            fee,
+            vsize: entry.vsize,
+            feerate: fee as f64 / entry.vsize as f64,
+            bip125_replaceable: entry.bip125_replaceable,
+            parents: entry.depends,
+            children: entry.spent_by,
+            ancestor_count: entry.ancestor_count,
+            ancestor_vsize: entry.ancestor_size,
+            ancestor_fee: entry.fees.ancestor.as_sat(),
+            descendant_count: entry.descendant_count,
+            descendant_vsize: entry.descendant_size,
+            descendant_fee,
+            min_replacement_feerate,
+        })
+    }
+
     pub fn get_tx_json(&self, txid: &Txid) -> Result<Value> {
         let blockhash = self.find_tx_blockhash(txid)?;
 
@@ -203,6 +557,43 @@ impl Query {
         Ok(self.rpc.get_tx_out_proof(&[*txid], blockhash.as_ref())?)
     }
 
+    /// Get the merkle branch proving the inclusion of the transaction in the block at the given
+    /// height, along with its position within the block
+    pub fn get_merkle_proof(
+        &self,
+        txid: &Txid,
+        height: u32,
+    ) -> Result<(Vec<sha256d::Hash>, usize)> {
+        let block_hash = self.get_block_hash(height)?;
+        let txids = self.get_block_txids(&block_hash)?;
+        let pos = txids
+            .iter()
+            .position(|c_txid| c_txid == txid)
+            .or_err("missing tx")?;
+
+        let hashes = txids.into_iter().map(sha256d::Hash::from).collect();
+        let (branch, _root) = create_merkle_branch_and_root(hashes, pos);
+        Ok((branch, pos))
+    }
+
+    /// Get the merkle inclusion proof for a confirmed wallet transaction, in the format
+    /// used by Electrum's `blockchain.transaction.get_merkle`. Returns None if it is unconfirmed.
+    pub fn get_tx_merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>> {
+        let status = self
+            .with_tx_entry(txid, |tx_entry| tx_entry.status)
+            .with_context(|| BwtError::TxNotFound(*txid))?;
+        let block_height = match status {
+            TxStatus::Confirmed(height) => height,
+            TxStatus::Unconfirmed | TxStatus::Conflicted => return Ok(None),
+        };
+        let (merkle, pos) = self.get_merkle_proof(txid, block_height)?;
+        Ok(Some(MerkleProof {
+            block_height,
+            merkle,
+            pos,
+        }))
+    }
+
     pub fn broadcast(&self, tx_hex: &str) -> Result<Txid> {
         if let Some(broadcast_cmd) = &self.config.broadcast_cmd {
             // re-serialize the tx to ensure validity (preventing potential code injection) and to determine the txid
@@ -215,7 +606,10 @@ impl Query {
             }
             Ok(tx.txid())
         } else {
-            Ok(self.rpc.send_raw_transaction(tx_hex)?)
+            Ok(self
+                .rpc
+                .send_raw_transaction(tx_hex)
+                .map_err(BwtError::from)?)
         }
     }
 
@@ -245,6 +639,76 @@ impl Query {
         TxDetail::make(txid, &self)
     }
 
+    /// Get the wallet transaction details along with its fee and confirmation info. This is more
+    /// expensive than `get_tx_detail()` and meant for looking up individual transactions.
+    pub fn get_tx_detail_ext(&self, txid: &Txid) -> Result<Option<TxDetailExt>> {
+        let mut detail = some_or_ret!(self.get_tx_detail(txid), Ok(None));
+        let hist_entry = HistoryEntry {
+            txid: *txid,
+            status: detail.status,
+        };
+        let block_info = self.get_history_block_info(&[hist_entry])?.remove(0);
+        if detail.fee.is_none() {
+            // may be unavailable for transactions spending non-wallet inputs without txindex
+            if let Ok((fee, vsize)) = self
+                .get_tx_fee(txid)
+                .map_err(|e| debug!("fee unavailable for {}: {:?}", txid, e))
+            {
+                detail.fee = Some(fee);
+                detail.feerate = Some(fee as f64 / vsize as f64);
+            }
+        }
+        // only fetched for unconfirmed transactions. may be unavailable if the transaction was
+        // confirmed or evicted from the mempool since the last sync.
+        let mempool = if detail.status.is_unconfirmed() {
+            self.rpc
+                .get_mempool_entry(txid)
+                .map_err(|e| debug!("mempool entry unavailable for {}: {:?}", txid, e))
+                .ok()
+                .map(TxMempoolDetails::from)
+        } else {
+            None
+        };
+
+        Ok(Some(TxDetailExt {
+            detail,
+            block_info,
+            mempool,
+        }))
+    }
+
+    /// Get the spending status of each of the wallet transaction's outputs. Outputs that don't
+    /// belong to any tracked wallet are reported with an unknown status. Returns None for
+    /// non-wallet transactions.
+    #[cfg(feature = "track-spends")]
+    pub fn get_tx_outspends(&self, txid: &Txid) -> Result<Option<Vec<TxOutSpend>>> {
+        if self.with_tx_entry(txid, |_| ()).is_none() {
+            return Ok(None);
+        }
+        // the number of outputs is not known to the index, which only keeps the wallet ones
+        let tx: Transaction = bitcoin::consensus::deserialize(&self.get_tx_raw(txid)?)?;
+
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let outspends = (0..tx.output.len() as u32)
+            .map(|vout| {
+                let outpoint = OutPoint::new(*txid, vout);
+                let spent_by = store.lookup_txo_spend(&outpoint);
+                let status = match (store.lookup_txo_fund(&outpoint), spent_by) {
+                    (None, _) => OutSpendStatus::Unknown,
+                    (Some(_), Some(_)) => OutSpendStatus::Spent,
+                    (Some(_), None) => OutSpendStatus::Unspent,
+                };
+                TxOutSpend {
+                    vout,
+                    status,
+                    spent_by,
+                }
+            })
+            .collect();
+        Ok(Some(outspends))
+    }
+
     //
     // History
     //
@@ -254,6 +718,40 @@ impl Query {
         self.map_history(scripthash, Clone::clone)
     }
 
+    /// Get the number of confirmations (relative to the synced tip) and the block time of each
+    /// history entry. Unconfirmed transactions have 0 confirmations and no block time.
+    pub fn get_history_block_info(&self, history: &[HistoryEntry]) -> Result<Vec<TxBlockInfo>> {
+        let tip_height = self.get_synced_tip().map_or(0, |tip| tip.0);
+        let mut block_times: HashMap<u32, u32> = HashMap::new();
+
+        history
+            .iter()
+            .map(|txhist| {
+                Ok(match txhist.status {
+                    TxStatus::Confirmed(height) => {
+                        let block_time = match block_times.get(&height) {
+                            Some(block_time) => *block_time,
+                            None => {
+                                let blockhash = self.get_block_hash(height)?;
+                                let block_time = self.get_header(&blockhash)?.time;
+                                block_times.insert(height, block_time);
+                                block_time
+                            }
+                        };
+                        TxBlockInfo {
+                            confirmations: (tip_height + 1).saturating_sub(height),
+                            block_time: Some(block_time),
+                        }
+                    }
+                    TxStatus::Unconfirmed | TxStatus::Conflicted => TxBlockInfo {
+                        confirmations: 0,
+                        block_time: None,
+                    },
+                })
+            })
+            .collect()
+    }
+
     /// Map the scripthash history as refs through `f`, ordered with oldest first.
     pub fn map_history<T>(
         &self,
@@ -267,11 +765,93 @@ impl Query {
             .map_or_else(Vec::new, |history| history.iter().map(f).collect())
     }
 
+    /// Map the history of multiple scripthashes as refs through `f`, keyed by the scripthash and
+    /// ordered with oldest first. The index is only locked once for the whole batch. `f` is also
+    /// provided with the mempool entry of unconfirmed transactions, when available.
+    pub fn map_histories<T>(
+        &self,
+        scripthashes: &[ScriptHash],
+        f: impl Fn(&HistoryEntry, Option<&MempoolEntry>) -> T,
+    ) -> HashMap<ScriptHash, Vec<T>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        scripthashes
+            .iter()
+            .map(|scripthash| {
+                let history = store
+                    .get_history(scripthash)
+                    .map_or_else(Vec::new, |history| {
+                        history
+                            .iter()
+                            .map(|txhist| {
+                                let mempool_entry = txhist
+                                    .status
+                                    .is_unconfirmed()
+                                    .and_then(|| store.get_mempool_entry(&txhist.txid));
+                                f(txhist, mempool_entry)
+                            })
+                            .collect()
+                    });
+                (*scripthash, history)
+            })
+            .collect()
+    }
+
+    /// Map the scripthash history entries going in the `direction` as refs through `f`, ordered with oldest first.
+    /// See `TxDirection::matches` for the semantics of the filter.
+    pub fn map_history_by_direction<T>(
+        &self,
+        scripthash: &ScriptHash,
+        direction: TxDirection,
+        f: impl Fn(&HistoryEntry) -> T,
+    ) -> Vec<T> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        store
+            .get_history(scripthash)
+            .map_or_else(Vec::new, |history| {
+                history
+                    .iter()
+                    .filter(|txhist| {
+                        let tx_direction = store
+                            .get_tx_entry(&txhist.txid)
+                            .and_then(|tx_entry| tx_entry.direction(scripthash));
+                        matches!(tx_direction, Some(tx_direction) if tx_direction.matches(direction))
+                    })
+                    .map(f)
+                    .collect()
+            })
+    }
+
     /// Call `f` with each history iterm as ref
-    pub fn for_each_history(&self, scripthash: &ScriptHash, f: impl FnMut(&HistoryEntry)) -> bool {
+    pub fn for_each_history(
+        &self,
+        scripthash: &ScriptHash,
+        mut f: impl FnMut(&HistoryEntry),
+    ) -> bool {
+        self.for_each_history_entry(scripthash, |txhist| {
+            f(txhist);
+            true
+        })
+    }
+
+    /// Call `f` with each history entry as ref, ordered with oldest first, until it returns false.
+    ///
+    /// Entries are read lazily from the store without buffering the full history, which makes this
+    /// suitable for addresses with very large histories. The index read lock is held while iterating,
+    /// so `f` should not block. Returns false if the scripthash has no history.
+    pub fn for_each_history_entry(
+        &self,
+        scripthash: &ScriptHash,
+        mut f: impl FnMut(&HistoryEntry) -> bool,
+    ) -> bool {
         let indexer = self.indexer.read().unwrap();
         if let Some(history) = indexer.store().get_history(scripthash) {
-            history.iter().for_each(f);
+            for txhist in history {
+                if !f(txhist) {
+                    break;
+                }
+            }
             true
         } else {
             false
@@ -326,10 +906,11 @@ impl Query {
         &self,
         scripthash: Option<&ScriptHash>,
         min_conf: usize,
+        min_value: u64,
         include_unsafe: Option<bool>,
     ) -> Result<Vec<Txo>> {
         let (BlockId(tip_height, _), req_script_info, unspents) = some_or_ret!(
-            self.list_unspent_raw(scripthash, min_conf, include_unsafe)?,
+            self.list_unspent_raw(scripthash, min_conf, min_value, include_unsafe)?,
             Ok(vec![])
         );
 
@@ -337,16 +918,22 @@ impl Query {
         Ok(unspents
             .into_iter()
             .filter_map(|unspent| {
-                // XXX we assume that any unspent output with a "bwt/..." label is ours, this may not necessarily be true.
                 let script_info = req_script_info.clone().or_else(|| {
                     let address = unspent.address.as_ref()?;
-                    let label = unspent.label.as_ref()?;
-                    let origin = KeyOrigin::from_label(label)?;
+                    let label = unspent.label.as_deref();
+                    let origin = indexer.watcher().find_origin(address, label)?;
                     let mut script_info = ScriptInfo::from_address(address, origin);
                     attach_wallet_info(&mut script_info, &indexer);
                     Some(script_info)
                 })?;
-                Some(Txo::from_unspent(unspent, script_info, tip_height))
+                let tx_entry = indexer.store().get_tx_entry(&unspent.txid);
+                let coinbase = tx_entry.map_or(false, |tx_entry| tx_entry.coinbase);
+                Some(Txo::from_unspent(
+                    unspent,
+                    script_info,
+                    tip_height,
+                    coinbase,
+                ))
             })
             .collect())
     }
@@ -356,6 +943,7 @@ impl Query {
         &self,
         scripthash: Option<&ScriptHash>,
         min_conf: usize,
+        min_value: u64,
         include_unsafe: Option<bool>,
     ) -> Result<
         Option<(
@@ -376,17 +964,32 @@ impl Query {
         // an empty array indicates not to filter by the address
         let addresses = script_info.as_ref().map_or(vec![], |i| vec![&i.address]);
 
+        // query the bitcoind wallet owning the script if there's one, or all of them otherwise
+        let rpcs = self.wallet_rpcs_for(script_info.as_ref());
+
         loop {
             let tip_height = self.rpc.get_block_count()? as u32;
             let tip_hash = self.rpc.get_block_hash(tip_height as u64)?;
 
-            let unspents = self.rpc.list_unspent(
-                Some(min_conf),
-                None,
-                Some(&addresses[..]),
-                include_unsafe,
-                None,
-            )?;
+            let mut unspents = vec![];
+            let mut seen = HashSet::new();
+            for rpc in &rpcs {
+                let wallet_unspents = rpc.list_unspent(
+                    Some(min_conf),
+                    None,
+                    Some(&addresses[..]),
+                    include_unsafe,
+                    None,
+                )?;
+                // outputs may show up in multiple bitcoind wallets if they were imported into both
+                unspents.extend(
+                    wallet_unspents
+                        .into_iter()
+                        .filter(|utxo| seen.insert(OutPoint::new(utxo.txid, utxo.vout))),
+                );
+            }
+            // zero-value outputs are not indexed and should not be reported as spendable
+            unspents.retain(|utxo| utxo.amount.as_sat() > 0 && utxo.amount.as_sat() >= min_value);
 
             if tip_hash != self.rpc.get_best_block_hash()? {
                 warn!("tip changed while fetching unspents, retrying...");
@@ -397,6 +1000,21 @@ impl Query {
         }
     }
 
+    // the clients of the bitcoind wallets that may hold the script (all of them if it's unknown)
+    fn wallet_rpcs_for(&self, script_info: Option<&ScriptInfo>) -> Vec<&Arc<RpcClient>> {
+        let bitcoind_wallet = match script_info.map(|i| &i.origin) {
+            Some(KeyOrigin::Descriptor(checksum, _)) => {
+                let indexer = self.indexer.read().unwrap();
+                match indexer.watcher().get(checksum) {
+                    Some(wallet) => wallet.bitcoind_wallet().map(String::from),
+                    None => return self.wallet_rpcs.all().collect(),
+                }
+            }
+            _ => return self.wallet_rpcs.all().collect(),
+        };
+        vec![self.wallet_rpcs.get(bitcoind_wallet.as_deref())]
+    }
+
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Option<Txo> {
         let indexer = self.indexer.read().unwrap();
         let store = indexer.store();
@@ -409,8 +1027,11 @@ impl Query {
             txid: outpoint.txid,
             vout: outpoint.vout,
             amount,
+            script_pubkey: script_info.address.script_pubkey(),
+            script_type: get_script_type(&script_info.address),
             script_info,
             status,
+            coinbase: store.get_tx_entry(&outpoint.txid)?.coinbase,
             #[cfg(feature = "track-spends")]
             spent_by: store.lookup_txo_spend(outpoint),
         })
@@ -428,10 +1049,24 @@ impl Query {
         Some(script_info)
     }
 
+    /// Check whether each of the addresses is tracked by bwt, either as a funded address
+    /// or as one within the imported range of a wallet (even if not yet funded)
+    pub fn are_mine(&self, addresses: Vec<Address>) -> Vec<bool> {
+        let indexer = self.indexer.read().unwrap();
+        addresses
+            .iter()
+            .map(ScriptHash::from)
+            .map(|scripthash| {
+                indexer.watcher().get_watched_origin(&scripthash).is_some()
+                    || indexer.store().get_script_info(&scripthash).is_some()
+            })
+            .collect()
+    }
+
     // returns a tuple of (confirmed_balance, unconfirmed_balance)
     pub fn get_script_balance(&self, scripthash: &ScriptHash) -> Result<(u64, u64)> {
         let (_, _, unspents) = some_or_ret!(
-            self.list_unspent_raw(Some(scripthash), 0, None)?,
+            self.list_unspent_raw(Some(scripthash), 0, 0, None)?,
             Ok((0, 0))
         );
 
@@ -445,6 +1080,27 @@ impl Query {
         ))
     }
 
+    /// Get the net balance change made by the confirmed and by the unconfirmed transactions in the
+    /// scripthash history. Unlike `get_script_balance`, confirmed outputs spent by unconfirmed
+    /// transactions are still counted as confirmed, and the unconfirmed balance may be negative.
+    pub fn get_script_balance_changes(&self, scripthash: &ScriptHash) -> (i64, i64) {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let mut confirmed = 0;
+        let mut unconfirmed = 0;
+        for txhist in store.get_history(scripthash).into_iter().flatten() {
+            if let Some(tx_entry) = store.get_tx_entry(&txhist.txid) {
+                let balance_change = tx_entry.balance_change(scripthash);
+                if txhist.status.is_confirmed() {
+                    confirmed += balance_change;
+                } else {
+                    unconfirmed += balance_change;
+                }
+            }
+        }
+        (confirmed, unconfirmed)
+    }
+
     pub fn get_script_stats(&self, scripthash: &ScriptHash) -> Result<Option<ScriptStats>> {
         let indexer = self.indexer.read().unwrap();
         let store = indexer.store();
@@ -469,6 +1125,41 @@ impl Query {
         self.indexer.read().unwrap().watcher().wallets().clone()
     }
 
+    /// Get all tracked wallets along with their transaction count and current balance (computed
+    /// from the index, like `get_wallet_balance()`)
+    pub fn get_wallets_stats(&self) -> HashMap<Checksum, WalletStats> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let tip_height = indexer.tip().map_or(0, |BlockId(height, _)| *height);
+        indexer
+            .watcher()
+            .wallets()
+            .iter()
+            .map(|(checksum, wallet)| {
+                let balance = wallet.get_balance(store, tip_height);
+                let stats = WalletStats {
+                    wallet: wallet.clone(),
+                    tx_count: wallet.get_history(store).len(),
+                    confirmed_balance: balance.confirmed,
+                    unconfirmed_balance: balance.unconfirmed,
+                    immature_balance: balance.immature,
+                };
+                (checksum.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// The number of tracked wallets and the total number of addresses imported for them
+    pub fn get_watched_counts(&self) -> (usize, u64) {
+        let indexer = self.indexer.read().unwrap();
+        let wallets = indexer.watcher().wallets();
+        let addresses = wallets
+            .values()
+            .map(|wallet| wallet.max_imported_index().map_or(0, |max| max as u64 + 1))
+            .sum();
+        (wallets.len(), addresses)
+    }
+
     pub fn get_wallet(&self, checksum: &Checksum) -> Option<Wallet> {
         self.indexer
             .read()
@@ -501,10 +1192,330 @@ impl Query {
         }
     }
 
-    pub fn find_wallet_gap(&self, checksum: &Checksum) -> Option<usize> {
+    /// Get the full activity (history, balance and unspent outputs) of the address at the given
+    /// derivation index, the natural lookup for payment systems that map orders to indexes
+    pub fn get_by_origin(&self, checksum: &Checksum, index: u32) -> Result<Option<OriginActivity>> {
+        let script_info = some_or_ret!(self.get_wallet_script_info(checksum, index), Ok(None));
+        let scripthash = script_info.scripthash;
+
+        let history = self.get_history(&scripthash);
+        let (confirmed_balance, unconfirmed_balance) = self.get_script_balance(&scripthash)?;
+        let utxos = self.list_unspent(Some(&scripthash), 0, 0, None)?;
+
+        Ok(Some(OriginActivity {
+            script_info,
+            history,
+            confirmed_balance,
+            unconfirmed_balance,
+            utxos,
+        }))
+    }
+
+    /// Derive `count` addresses of the wallet, starting from derivation index `start`. Returns None
+    /// if the wallet does not exist, or Some(None) if the range includes invalid indexes.
+    pub fn derive_wallet_addresses(
+        &self,
+        checksum: &Checksum,
+        start: u32,
+        count: u32,
+    ) -> Option<Option<Vec<DerivedAddress>>> {
         let indexer = self.indexer.read().unwrap();
         let wallet = indexer.watcher().get(checksum)?;
-        wallet.find_gap(indexer.store())
+        let end = some_or_ret!(start.checked_add(count), Some(None));
+
+        Some(
+            (start..end)
+                .map(|index| {
+                    wallet.is_valid_index(index).do_then(|| DerivedAddress {
+                        index,
+                        address: wallet.derive_address(index),
+                        bip32_origins: wallet.bip32_origins(index),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Get the next unused address of the wallet along with its derivation index. With `bump`, the
+    /// address gets reserved so that subsequent calls return distinct addresses, even before it
+    /// receives funds. Errors if there are no unused addresses left within the watched range.
+    pub fn get_next_address(&self, checksum: &Checksum, bump: bool) -> Result<Option<NextAddress>> {
+        let index = if bump {
+            // a write lock is required to reserve the address atomically
+            some_or_ret!(
+                self.indexer.write().unwrap().reserve_next_index(checksum)?,
+                Ok(None)
+            )
+        } else {
+            let indexer = self.indexer.read().unwrap();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(None));
+            wallet.next_unused_index(indexer.store())?
+        };
+        let script_info = self.get_wallet_script_info(checksum, index).req()?;
+        Ok(Some(NextAddress { index, script_info }))
+    }
+
+    /// Get the next `count` unused addresses that are already being watched, starting from the
+    /// wallet's next index. Errors if there aren't enough unused addresses within the watched range.
+    pub fn get_unused_addresses(
+        &self,
+        checksum: &Checksum,
+        count: usize,
+    ) -> Result<Option<Vec<ScriptInfo>>> {
+        let indexes = {
+            let indexer = self.indexer.read().unwrap();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(None));
+            wallet.find_unused_indexes(indexer.store(), count)?
+        };
+
+        Ok(Some(
+            indexes
+                .into_iter()
+                .map(|index| self.get_wallet_script_info(checksum, index).unwrap())
+                .collect(),
+        ))
+    }
+
+    /// Get the confirmed balance of the wallet as of the given block height. Errors if the height
+    /// is beyond the current tip or before the wallet's rescan time, where its history is incomplete.
+    pub fn get_balance_at_height(&self, checksum: &Checksum, height: u32) -> Result<Option<u64>> {
+        let tip_height = self.get_tip_height()?;
+        ensure!(
+            height <= tip_height,
+            "height {} is beyond the current tip ({})",
+            height,
+            tip_height
+        );
+        let block_time = self.get_header(&self.get_block_hash(height)?)?.time as u64;
+
+        let indexer = self.indexer.read().unwrap();
+        let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(None));
+
+        match wallet.rescan_policy() {
+            RescanSince::Timestamp(rescan_time) => ensure!(
+                block_time >= rescan_time,
+                "height {} is before the wallet's rescan time, its history is not fully indexed",
+                height
+            ),
+            RescanSince::Now => bail!(
+                "the wallet was imported without rescan, its historical balance is unavailable"
+            ),
+        }
+
+        Ok(Some(wallet.balance_at_height(indexer.store(), height)))
+    }
+
+    /// Get the wallet transactions confirmed in the block along with their net balance change,
+    /// ordered by their position within the block
+    pub fn get_wallet_txs_in_block(
+        &self,
+        checksum: &Checksum,
+        block: BlockRef,
+    ) -> Result<Option<Vec<WalletBlockTx>>> {
+        let (blockhash, height) = match block {
+            BlockRef::Height(height) => (self.get_block_hash(height)?, height),
+            BlockRef::Hash(blockhash) => {
+                let height = self.get_header_info(&blockhash)?.height as u32;
+                ensure!(
+                    self.get_block_hash(height)? == blockhash,
+                    "block {} is not part of the best chain",
+                    blockhash
+                );
+                (blockhash, height)
+            }
+        };
+
+        let mut balance_changes = {
+            let indexer = self.indexer.read().unwrap();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(None));
+            wallet.txs_at_height(indexer.store(), height)
+        };
+        if balance_changes.is_empty() {
+            return Ok(Some(vec![]));
+        }
+
+        let txs = self
+            .get_block_txids(&blockhash)?
+            .into_iter()
+            .filter_map(|txid| {
+                let balance_change = balance_changes.remove(&txid)?;
+                Some(WalletBlockTx {
+                    txid,
+                    balance_change,
+                })
+            })
+            .collect();
+        // the index may be momentarily out of sync following a reorg
+        ensure!(
+            balance_changes.is_empty(),
+            "the index is not in sync with block {}",
+            blockhash
+        );
+
+        Ok(Some(txs))
+    }
+
+    /// Get the fees paid by the outgoing transactions made by the wallet (the ones spending from it),
+    /// ordered with oldest first
+    pub fn get_wallet_fee_history(&self, checksum: &Checksum) -> Result<Option<Vec<TxFee>>> {
+        let outgoing_txs = {
+            let indexer = self.indexer.read().unwrap();
+            let wallet = some_or_ret!(indexer.watcher().get(checksum), Ok(None));
+            wallet.find_outgoing_txs(indexer.store())
+        };
+
+        let fees = outgoing_txs
+            .into_iter()
+            .map(|txhist| {
+                // may be unavailable for transactions spending non-wallet inputs without txindex
+                let tx_fee = self
+                    .get_tx_fee(&txhist.txid)
+                    .map_err(|e| debug!("fee unavailable for {}: {:?}", txhist.txid, e))
+                    .ok();
+                TxFee {
+                    txid: txhist.txid,
+                    status: txhist.status,
+                    fee: tx_fee.map(|(fee, _)| fee),
+                    vsize: tx_fee.map(|(_, vsize)| vsize),
+                    feerate: tx_fee.map(|(fee, vsize)| fee as f64 / vsize as f64),
+                }
+            })
+            .collect();
+
+        Ok(Some(fees))
+    }
+
+    pub fn get_wallet_group(&self, checksum: &Checksum) -> Option<WalletGroup> {
+        let indexer = self.indexer.read().unwrap();
+        indexer.watcher().get_group(checksum)
+    }
+
+    /// Get the combined history of multiple wallets, ordered with oldest first.
+    /// Returns None if any of the wallets are unknown.
+    pub fn get_wallets_history(&self, checksums: &[Checksum]) -> Option<Vec<HistoryEntry>> {
+        let indexer = self.indexer.read().unwrap();
+        let mut history = BTreeSet::new();
+        for checksum in checksums {
+            let wallet = indexer.watcher().get(checksum)?;
+            history.extend(wallet.get_history(indexer.store()));
+        }
+        Some(history.into_iter().cloned().collect())
+    }
+
+    /// Get the wallet's current balance, computed from the index.
+    /// Returns None if the wallet does not exist.
+    pub fn get_wallet_balance(&self, checksum: &Checksum) -> Option<WalletBalance> {
+        let indexer = self.indexer.read().unwrap();
+        let wallet = indexer.watcher().get(checksum)?;
+        let tip_height = indexer.tip().map_or(0, |BlockId(height, _)| *height);
+        Some(wallet.get_balance(indexer.store(), tip_height))
+    }
+
+    /// Get up to `limit` of the wallet's transactions that follow `after`, along with the net
+    /// change they made to its balance, ordered with oldest first. The index lock is only held
+    /// while the page is collected. Returns None if the wallet does not exist.
+    pub fn get_wallet_tx_changes(
+        &self,
+        checksum: &Checksum,
+        after: Option<&HistoryEntry>,
+        limit: usize,
+    ) -> Option<Vec<WalletTxChange>> {
+        let indexer = self.indexer.read().unwrap();
+        let wallet = indexer.watcher().get(checksum)?;
+        Some(wallet.get_tx_changes(indexer.store(), after, limit))
+    }
+
+    /// List the combined unspent outputs of multiple wallets.
+    /// Returns None if any of the wallets are unknown.
+    pub fn list_wallets_unspent(
+        &self,
+        checksums: &[Checksum],
+        min_conf: usize,
+        min_value: u64,
+        include_unsafe: Option<bool>,
+    ) -> Result<Option<Vec<Txo>>> {
+        if !self.has_wallets(checksums) {
+            return Ok(None);
+        }
+        let utxos = self.list_unspent(None, min_conf, min_value, include_unsafe)?;
+        Ok(Some(
+            utxos
+                .into_iter()
+                .filter(|utxo| match &utxo.script_info.origin {
+                    KeyOrigin::Descriptor(checksum, _) => checksums.contains(checksum),
+                    KeyOrigin::Standalone => false,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Get the combined balance of multiple wallets, computed from the index like
+    /// `get_wallet_balance()`. Returns None if any of the wallets are unknown.
+    pub fn get_wallets_balance(&self, checksums: &[Checksum]) -> Option<WalletBalance> {
+        let indexer = self.indexer.read().unwrap();
+        let tip_height = indexer.tip().map_or(0, |BlockId(height, _)| *height);
+        let mut balance = WalletBalance::default();
+        for checksum in checksums {
+            let wallet = indexer.watcher().get(checksum)?;
+            balance += wallet.get_balance(indexer.store(), tip_height);
+        }
+        Some(balance)
+    }
+
+    fn has_wallets(&self, checksums: &[Checksum]) -> bool {
+        let indexer = self.indexer.read().unwrap();
+        checksums
+            .iter()
+            .all(|checksum| indexer.watcher().get(checksum).is_some())
+    }
+
+    /// Schedule the wallet to be re-imported with a rescan since `rescan_since`, which will be
+    /// processed by the next sync. Returns None if the wallet does not exist.
+    pub fn rescan_wallet(&self, checksum: &Checksum, rescan_since: RescanSince) -> Option<()> {
+        self.indexer
+            .write()
+            .unwrap()
+            .watcher_mut()
+            .request_rescan(checksum, rescan_since)
+    }
+
+    /// Get the progress of bitcoind's wallet rescan, or None if it is not currently rescanning.
+    /// Does not require locking the index, which is held while the rescan is running.
+    pub fn get_scanning(&self) -> Result<Option<ScanningDetails>> {
+        Ok(match self.wallet_rpcs.check_scanning()? {
+            ScanningResult::Scanning(details) => Some(details),
+            ScanningResult::NotScanning => None,
+            ScanningResult::Unsupported => {
+                bail!("bitcoind does not report its scanning status (requires v0.19+)")
+            }
+        })
+    }
+
+    /// Get the gap statistics of the wallet. For wallets created from a bare xpub, the statistics
+    /// of both the receive and change chains are included as well.
+    pub fn get_wallet_gap(&self, checksum: &Checksum) -> Option<WalletGap> {
+        let indexer = self.indexer.read().unwrap();
+        let watcher = indexer.watcher();
+        let store = indexer.store();
+        let wallet = watcher.get(checksum)?;
+
+        let (receive, change) = match wallet.xpub_chain() {
+            Some((chain, sibling_checksum)) => {
+                let sibling_stats = watcher.get(sibling_checksum).map(|w| w.gap_stats(store));
+                let stats = Some(wallet.gap_stats(store));
+                match chain {
+                    XpubChain::Receive => (stats, sibling_stats),
+                    XpubChain::Change => (sibling_stats, stats),
+                }
+            }
+            None => (None, None),
+        };
+
+        Some(WalletGap {
+            stats: wallet.gap_stats(store),
+            receive,
+            change,
+        })
     }
 }
 
@@ -526,32 +1537,62 @@ pub struct Txo {
     pub txid: Txid,
     pub vout: u32,
     pub amount: u64,
+    pub script_pubkey: Script,
+    pub script_type: Option<String>,
     #[serde(flatten)]
     pub script_info: ScriptInfo,
     #[serde(rename = "block_height")]
     pub status: TxStatus,
+    pub coinbase: bool,
     #[cfg(feature = "track-spends")]
     pub spent_by: Option<InPoint>,
 }
 
+#[cfg(feature = "track-spends")]
+#[derive(Serialize, Debug)]
+pub struct TxOutSpend {
+    pub vout: u32,
+    pub status: OutSpendStatus,
+    pub spent_by: Option<InPoint>,
+}
+
+#[cfg(feature = "track-spends")]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutSpendStatus {
+    Spent,
+    Unspent,
+    // the output does not belong to any tracked wallet
+    Unknown,
+}
+
 impl Txo {
     pub fn from_unspent(
         unspent: rpcjson::ListUnspentResultEntry,
         script_info: ScriptInfo,
         tip_height: u32,
+        coinbase: bool,
     ) -> Self {
         Self {
             txid: unspent.txid,
             vout: unspent.vout,
             amount: unspent.amount.as_sat(),
+            script_type: get_script_type(&script_info.address),
+            script_pubkey: unspent.script_pub_key,
             script_info: script_info,
             status: TxStatus::from_confirmations(unspent.confirmations as i32, tip_height),
+            coinbase,
             #[cfg(feature = "track-spends")]
             spent_by: None,
         }
     }
 }
 
+// The output script type (p2pkh, p2sh, p2wpkh or p2wsh), if standard
+fn get_script_type(address: &Address) -> Option<String> {
+    address.address_type().map(|t| t.to_string())
+}
+
 #[derive(Serialize, Debug)]
 pub struct TxDetail {
     txid: Txid,
@@ -560,8 +1601,13 @@ pub struct TxDetail {
     funding: Vec<TxDetailFunding>,
     spending: Vec<TxDetailSpending>,
     balance_change: i64,
+    fee: Option<u64>,
+    // in sat/vB
+    feerate: Option<f64>,
     #[serde(flatten)]
     mempool_info: Option<TxDetailMempool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
 }
 
 impl TxDetail {
@@ -583,6 +1629,7 @@ impl TxDetail {
                     vout: *vout,
                     script_info: query.get_script_info(scripthash).unwrap(), // must exists
                     amount: *amount,
+                    label: query.get_label(&LabelTarget::Script(*scripthash)),
                     #[cfg(feature = "track-spends")]
                     spent_by: store.lookup_txo_spend(&OutPoint::new(*txid, *vout)),
                 }
@@ -598,6 +1645,7 @@ impl TxDetail {
                     script_info: query.get_script_info(scripthash).unwrap(), // must exists
                     amount: *amount,
                     prevout: *prevout,
+                    label: query.get_label(&LabelTarget::Script(*scripthash)),
                 }
             })
             .collect::<Vec<TxDetailSpending>>();
@@ -606,17 +1654,84 @@ impl TxDetail {
         let spending_sum = spending.iter().map(|s| s.amount).sum::<u64>();
         let balance_change = funding_sum as i64 - spending_sum as i64;
 
+        // the fee is known for transactions spending only wallet inputs, or from the mempool entry
+        // of unconfirmed transactions
+        let fee = tx_entry
+            .fee
+            .or_else(|| mempool_entry.map(|entry| (entry.fee, entry.vsize)));
+
         Some(TxDetail {
             txid: *txid,
             status: tx_entry.status,
             funding,
             spending,
             balance_change,
+            fee: fee.map(|(fee, _)| fee),
+            feerate: fee.map(|(fee, vsize)| fee as f64 / vsize as f64),
             mempool_info: mempool_entry.map(Into::into),
+            label: query.get_label(&LabelTarget::Tx(*txid)),
         })
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct TxDetailExt {
+    #[serde(flatten)]
+    detail: TxDetail,
+    #[serde(flatten)]
+    block_info: TxBlockInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mempool: Option<TxMempoolDetails>,
+}
+
+/// The mempool ancestor/descendant info of an unconfirmed transaction, with sizes in vbytes
+/// and fees in satoshis
+#[derive(Serialize, Debug)]
+struct TxMempoolDetails {
+    vsize: u64,
+    // the time the transaction entered the mempool
+    time: u64,
+    ancestor_count: u64,
+    ancestor_size: u64,
+    descendant_count: u64,
+    descendant_size: u64,
+    fees: TxMempoolFees,
+    depends: Vec<Txid>,
+    spent_by: Vec<Txid>,
+    bip125_replaceable: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct TxMempoolFees {
+    base: u64,
+    // the base fee adjusted by prioritisetransaction
+    modified: u64,
+    ancestor: u64,
+    descendant: u64,
+}
+
+impl From<rpcjson::GetMempoolEntryResult> for TxMempoolDetails {
+    fn from(entry: rpcjson::GetMempoolEntryResult) -> Self {
+        Self {
+            vsize: entry.vsize,
+            time: entry.time,
+            ancestor_count: entry.ancestor_count,
+            ancestor_size: entry.ancestor_size,
+            descendant_count: entry.descendant_count,
+            descendant_size: entry.descendant_size,
+            fees: TxMempoolFees {
+                base: entry.fees.base.as_sat(),
+                modified: entry.fees.modified.as_sat(),
+                ancestor: entry.fees.ancestor.as_sat(),
+                descendant: entry.fees.descendant.as_sat(),
+            },
+            depends: entry.depends,
+            spent_by: entry.spent_by,
+            bip125_replaceable: entry.bip125_replaceable,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct TxDetailFunding {
     vout: u32,
@@ -625,6 +1740,8 @@ struct TxDetailFunding {
     amount: u64,
     #[cfg(feature = "track-spends")]
     spent_by: Option<InPoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -634,6 +1751,8 @@ struct TxDetailSpending {
     script_info: ScriptInfo,
     amount: u64,
     prevout: OutPoint,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -663,3 +1782,131 @@ pub struct ScriptStats {
     confirmed_balance: u64,
     unconfirmed_balance: u64,
 }
+
+#[derive(Serialize, Debug)]
+pub struct WalletStats {
+    #[serde(flatten)]
+    wallet: Wallet,
+    tx_count: usize,
+    confirmed_balance: u64,
+    unconfirmed_balance: u64,
+    immature_balance: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct OriginActivity {
+    #[serde(flatten)]
+    script_info: ScriptInfo,
+    history: Vec<HistoryEntry>,
+    confirmed_balance: u64,
+    unconfirmed_balance: u64,
+    utxos: Vec<Txo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DerivedAddress {
+    pub index: u32,
+    pub address: Address,
+    pub bip32_origins: Vec<Bip32Origin>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NextAddress {
+    pub index: u32,
+    #[serde(flatten)]
+    pub script_info: ScriptInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncStatus {
+    pub initial_import_done: bool,
+    // the number of addresses imported so far out of the total in the current (or last) import batch
+    pub imported: usize,
+    pub total: usize,
+    // the progress of bitcoind's rescan (0-1), if it's currently rescanning
+    pub scanning_progress: Option<f64>,
+    // the height the index is synced up to
+    pub tip_height: Option<u32>,
+    // whether the index is being rebuilt following a deep reorg, in which case it may be stale
+    pub reindexing: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthStatus {
+    pub ok: bool,
+    pub bitcoind_connected: bool,
+    // the height the index is synced up to and the age of its block (see `get_sync_lag()`)
+    pub tip_height: Option<u32>,
+    pub sync_lag_seconds: Option<u64>,
+    pub initial_import_done: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletGap {
+    #[serde(flatten)]
+    pub stats: GapStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receive: Option<GapStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<GapStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MerkleProof {
+    pub block_height: u32,
+    pub merkle: Vec<sha256d::Hash>,
+    pub pos: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxBlockInfo {
+    pub confirmations: u32,
+    pub block_time: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxFee {
+    pub txid: Txid,
+    #[serde(rename = "block_height")]
+    pub status: TxStatus,
+    // unavailable for transactions spending non-wallet inputs when txindex is disabled
+    pub fee: Option<u64>,
+    pub vsize: Option<u64>,
+    // in sat/vB
+    pub feerate: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TxBumpInfo {
+    pub txid: Txid,
+    pub fee: u64,
+    pub vsize: u64,
+    // in sat/vB
+    pub feerate: f64,
+    pub bip125_replaceable: bool,
+    // the direct in-mempool parents and children
+    pub parents: Vec<Txid>,
+    pub children: Vec<Txid>,
+    // totals for the in-mempool ancestors/descendants, including the transaction itself
+    pub ancestor_count: u64,
+    pub ancestor_vsize: u64,
+    pub ancestor_fee: u64,
+    pub descendant_count: u64,
+    pub descendant_vsize: u64,
+    pub descendant_fee: u64,
+    // in sat/vB, only available for BIP125-replaceable transactions
+    pub min_replacement_feerate: Option<f64>,
+}
+
+/// A block, identified by either its hash or its height in the best chain
+#[derive(Debug, Clone, Copy)]
+pub enum BlockRef {
+    Hash(BlockHash),
+    Height(u32),
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletBlockTx {
+    pub txid: Txid,
+    pub balance_change: i64,
+}
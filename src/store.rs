@@ -1,34 +1,48 @@
 use std::cmp::Ordering;
 use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem::size_of;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use bitcoin::{Address, OutPoint, Txid};
 
-use crate::types::{MempoolEntry, ScriptHash, TxStatus};
+use crate::types::{MempoolEntry, ScriptHash, TxDirection, TxStatus};
 use crate::util::{remove_if, xpub::Bip32Origin};
 use crate::wallet::KeyOrigin;
 
 #[cfg(feature = "track-spends")]
 use crate::types::InPoint;
 
-#[derive(Debug, Serialize, Default)]
+// the number of confirmations required before coinbase outputs can be spent
+const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct MemoryStore {
     scripthashes: HashMap<ScriptHash, ScriptEntry>,
     transactions: HashMap<Txid, TxEntry>,
     mempool: HashMap<Txid, Option<MempoolEntry>>,
     #[cfg(feature = "track-spends")]
     txo_spends: HashMap<OutPoint, InPoint>,
+    // running counters of the nested entries, for estimating the memory usage without a full scan
+    counters: EntryCounters,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct EntryCounters {
+    script_bytes: usize,
+    history_entries: usize,
+    funding_entries: usize,
+    spending_entries: usize,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct ScriptEntry {
     address: Address,
     origin: KeyOrigin,
     history: BTreeSet<HistoryEntry>,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Hash, Serialize)]
+#[derive(Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize)]
 pub struct HistoryEntry {
     pub txid: Txid,
     #[serde(rename = "block_height")]
@@ -40,12 +54,18 @@ impl HistoryEntry {
         HistoryEntry { txid, status }
     }
 }
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxEntry {
     #[serde(rename = "block_height")]
     pub status: TxStatus,
     pub funding: HashMap<u32, FundingInfo>,
     pub spending: HashMap<u32, SpendingInfo>,
+    /// The fee paid by the transaction and its vsize, only known for transactions with all of
+    /// their inputs spending from the wallet
+    #[serde(default)]
+    pub fee: Option<(u64, u64)>,
+    #[serde(default)]
+    pub coinbase: bool,
 }
 
 impl TxEntry {
@@ -54,19 +74,59 @@ impl TxEntry {
             status,
             funding: HashMap::new(),
             spending: HashMap::new(),
+            fee: None,
+            coinbase: false,
         }
     }
+    /// Whether this is a coinbase transaction that cannot be spent yet as of the given tip height
+    pub fn is_immature_coinbase(&self, tip_height: u32) -> bool {
+        self.coinbase
+            && match self.status {
+                TxStatus::Confirmed(height) => {
+                    (tip_height + 1).saturating_sub(height) < COINBASE_MATURITY
+                }
+                TxStatus::Unconfirmed | TxStatus::Conflicted => true,
+            }
+    }
     pub fn scripthashes(&self) -> HashSet<&ScriptHash> {
         let funding_scripthashes = self.funding.iter().map(|(_, f)| &f.0);
         let spending_scripthashes = self.spending.iter().map(|(_, s)| &s.0);
         funding_scripthashes.chain(spending_scripthashes).collect()
     }
+    /// The net change to the balance of the given scripthash made by this transaction
+    pub fn balance_change(&self, scripthash: &ScriptHash) -> i64 {
+        let funded: u64 = self
+            .funding
+            .values()
+            .filter(|FundingInfo(funding_scripthash, _)| funding_scripthash == scripthash)
+            .map(|FundingInfo(_, amount)| amount)
+            .sum();
+        let spent: u64 = self
+            .spending
+            .values()
+            .filter(|SpendingInfo(spending_scripthash, ..)| spending_scripthash == scripthash)
+            .map(|SpendingInfo(_, _, amount)| amount)
+            .sum();
+        funded as i64 - spent as i64
+    }
+    /// The direction of this transaction relative to the given scripthash,
+    /// or None if the scripthash does not appear in it
+    pub fn direction(&self, scripthash: &ScriptHash) -> Option<TxDirection> {
+        let is_incoming = self.funding.values().any(|f| f.0 == *scripthash);
+        let is_outgoing = self.spending.values().any(|s| s.0 == *scripthash);
+        match (is_incoming, is_outgoing) {
+            (true, true) => Some(TxDirection::Both),
+            (true, false) => Some(TxDirection::Incoming),
+            (false, true) => Some(TxDirection::Outgoing),
+            (false, false) => None,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FundingInfo(pub ScriptHash, pub u64);
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpendingInfo(pub ScriptHash, pub OutPoint, pub u64);
 
 impl MemoryStore {
@@ -106,6 +166,7 @@ impl MemoryStore {
             });
 
         if !existed {
+            self.counters.script_bytes += address.script_pubkey().len();
             trace!(
                 "new script entry: scripthash={} address={} origin={:?}",
                 scripthash,
@@ -177,6 +238,7 @@ impl MemoryStore {
         }
 
         if let Some((scripthash, status)) = added {
+            self.counters.funding_entries += 1;
             self.index_history_entry(&scripthash, HistoryEntry::new(*txid, status));
             true
         } else {
@@ -192,6 +254,7 @@ impl MemoryStore {
             // the tx must already exists by now
             let tx_entry = self.transactions.get_mut(txid).unwrap();
             assert!(tx_entry.spending.is_empty());
+            self.counters.spending_entries += spending.len();
             tx_entry.spending = spending;
             let scripthashes: Vec<_> = tx_entry.scripthashes().into_iter().cloned().collect();
             (tx_entry.status, scripthashes)
@@ -204,6 +267,18 @@ impl MemoryStore {
         }
     }
 
+    pub fn set_tx_coinbase(&mut self, txid: &Txid) {
+        if let Some(tx_entry) = self.transactions.get_mut(txid) {
+            tx_entry.coinbase = true;
+        }
+    }
+
+    pub fn set_tx_fee(&mut self, txid: &Txid, fee: u64, vsize: u64) {
+        trace!("tx fee {}: {} sat, {} vB", txid, fee, vsize);
+        // the tx must already exists by now
+        self.transactions.get_mut(txid).unwrap().fee = Some((fee, vsize));
+    }
+
     fn index_history_entry(&mut self, scripthash: &ScriptHash, txhist: HistoryEntry) -> bool {
         trace!(
             "index history entry: scripthash={} txid={} status={:?}",
@@ -220,6 +295,7 @@ impl MemoryStore {
             .insert(txhist);
 
         if added {
+            self.counters.history_entries += 1;
             trace!("new history entry for {:?}", scripthash);
         }
 
@@ -279,6 +355,25 @@ impl MemoryStore {
         };
     }
 
+    /// Mark the transactions confirmed above `height` as unconfirmed, following a reorg that
+    /// orphaned their blocks. Returns their txids.
+    pub fn rollback_to(&mut self, height: u32) -> Vec<Txid> {
+        let orphaned: Vec<(Txid, TxStatus)> = self
+            .transactions
+            .iter()
+            .filter(|(_, tx_entry)| !tx_entry.status.is_confirmed_by(height))
+            .filter(|(_, tx_entry)| tx_entry.status.is_confirmed())
+            .map(|(txid, tx_entry)| (*txid, tx_entry.status))
+            .collect();
+
+        for (txid, old_status) in &orphaned {
+            self.transactions.get_mut(txid).unwrap().status = TxStatus::Unconfirmed;
+            self.update_tx_status(txid, *old_status, TxStatus::Unconfirmed);
+        }
+
+        orphaned.into_iter().map(|(txid, _)| txid).collect()
+    }
+
     pub fn purge_tx(&mut self, txid: &Txid) -> bool {
         // XXX should replaced transactions be kept around instead of purged entirely?
         if let Some(old_entry) = self.transactions.remove(txid) {
@@ -292,12 +387,20 @@ impl MemoryStore {
                 status: old_entry.status,
                 txid: *txid,
             };
+            let counters = &mut self.counters;
+            counters.funding_entries -= old_entry.funding.len();
+            counters.spending_entries -= old_entry.spending.len();
             for scripthash in old_entry.scripthashes() {
                 // remove the history entry, and remove the script entry entirely if it has no
                 // remaining history entries
                 let had_entry = remove_if(&mut self.scripthashes, *scripthash, |script_entry| {
                     assert!(script_entry.history.remove(&old_txhist));
-                    script_entry.history.is_empty()
+                    counters.history_entries -= 1;
+                    let is_empty = script_entry.history.is_empty();
+                    if is_empty {
+                        counters.script_bytes -= script_entry.address.script_pubkey().len();
+                    }
+                    is_empty
                 });
                 assert!(had_entry)
             }
@@ -375,6 +478,19 @@ impl MemoryStore {
         Some(self.scripthashes.get(scripthash)?.address.clone())
     }
 
+    /// Get the history entries of the scripthash that were confirmed at `block_height`.
+    /// Looked up using the height ordering of the history index, without a full scan.
+    pub fn get_history_at_height(
+        &self,
+        scripthash: &ScriptHash,
+        block_height: u32,
+    ) -> Vec<&HistoryEntry> {
+        let history = some_or_ret!(self.get_history(scripthash), vec![]);
+        let start = HistoryEntry::new(Txid::default(), TxStatus::Confirmed(block_height));
+        let end = HistoryEntry::new(Txid::default(), TxStatus::Confirmed(block_height + 1));
+        history.range(start..end).collect()
+    }
+
     /// Get all history entries for all scripthashes since `min_block_height` (including
     /// unconfirmed transactions) as refs, ordered with oldest first.
     pub fn get_history_since(&self, min_block_height: u32) -> Vec<&HistoryEntry> {
@@ -404,8 +520,26 @@ impl MemoryStore {
         StoreStats {
             transaction_count: self.transactions.len(),
             scripthash_count: self.scripthashes.len(),
+            memory_usage: self.estimate_memory_usage(),
         }
     }
+
+    // A rough estimate of the memory used by the store (in bytes), accounting for the size of the
+    // entries but not for the overhead of the underlying collections
+    fn estimate_memory_usage(&self) -> usize {
+        let counters = &self.counters;
+        let scripthashes_size = self.scripthashes.len() * size_of::<(ScriptHash, ScriptEntry)>()
+            + counters.script_bytes
+            + counters.history_entries * size_of::<HistoryEntry>();
+        let transactions_size = self.transactions.len() * size_of::<(Txid, TxEntry)>()
+            + counters.funding_entries * size_of::<(u32, FundingInfo)>()
+            + counters.spending_entries * size_of::<(u32, SpendingInfo)>();
+        let mempool_size = self.mempool.len() * size_of::<(Txid, Option<MempoolEntry>)>();
+        #[cfg(feature = "track-spends")]
+        let mempool_size = mempool_size + self.txo_spends.len() * size_of::<(OutPoint, InPoint)>();
+
+        scripthashes_size + transactions_size + mempool_size
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -472,7 +606,120 @@ impl PartialOrd for HistoryEntry {
     }
 }
 
+#[derive(Serialize, Debug)]
 pub struct StoreStats {
     pub transaction_count: usize,
     pub scripthash_count: usize,
+    // estimated, in bytes
+    pub memory_usage: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    struct TestStore {
+        store: MemoryStore,
+        address: Address,
+        scripthash: ScriptHash,
+        origin: KeyOrigin,
+        txid1: Txid,
+        txid2: Txid,
+    }
+
+    // Create a store with a confirmed tx funding an address and an unconfirmed one spending it,
+    // indexed the same way the indexer does
+    fn test_store() -> TestStore {
+        let address = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        let scripthash = ScriptHash::from(&address);
+        let origin = KeyOrigin::Descriptor("xjm8w0el".parse().unwrap(), 5);
+        let txid1 =
+            Txid::from_str("859d5c41661426ab13a7816b9e845a3353b66f00a3c14bc412d20f87dcf19caa")
+                .unwrap();
+        let txid2 =
+            Txid::from_str("4c8c8cd9a5a0a7b0ee5b1b5fc7e0f3a1c1f5b1c4c4d8a1f2e3b4c5d6e7f8a9b0")
+                .unwrap();
+        let prevout = OutPoint::new(txid1, 0);
+
+        let mut store = MemoryStore::new();
+        store.index_scripthash(&scripthash, &origin, &address);
+        store.upsert_tx(&txid1, TxStatus::Confirmed(100));
+        store.index_tx_output_funding(&txid1, 0, FundingInfo(scripthash, 50_000));
+        store.upsert_tx(&txid2, TxStatus::Unconfirmed);
+        #[cfg(feature = "track-spends")]
+        store.index_txo_spend(prevout, InPoint::new(txid2, 1));
+        let mut spending = HashMap::new();
+        spending.insert(1, SpendingInfo(scripthash, prevout, 50_000));
+        store.index_tx_inputs_spending(&txid2, spending);
+
+        TestStore {
+            store,
+            address,
+            scripthash,
+            origin,
+            txid1,
+            txid2,
+        }
+    }
+
+    #[test]
+    fn test_store_serde_roundtrip() {
+        let TestStore {
+            store,
+            scripthash,
+            origin,
+            txid1,
+            txid2,
+            ..
+        } = test_store();
+
+        let json = serde_json::to_value(&store).unwrap();
+        let restored: MemoryStore = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&restored).unwrap(), json);
+
+        assert_eq!(restored.get_history(&scripthash).unwrap().len(), 2);
+        assert_eq!(
+            restored.get_tx_status(&txid1),
+            Some(TxStatus::Confirmed(100))
+        );
+        assert_eq!(restored.get_tx_status(&txid2), Some(TxStatus::Unconfirmed));
+        assert_eq!(
+            restored.get_script_info(&scripthash).unwrap().origin,
+            origin
+        );
+        #[cfg(feature = "track-spends")]
+        assert_eq!(
+            restored
+                .lookup_txo_spend(&OutPoint::new(txid1, 0))
+                .unwrap()
+                .txid,
+            txid2
+        );
+    }
+
+    #[test]
+    fn test_memory_usage_counters() {
+        let TestStore {
+            mut store,
+            address,
+            scripthash,
+            txid1,
+            txid2,
+            ..
+        } = test_store();
+
+        let counters = &store.counters;
+        assert_eq!(counters.script_bytes, address.script_pubkey().len());
+        assert_eq!(counters.history_entries, 2);
+        assert_eq!(counters.funding_entries, 1);
+        assert_eq!(counters.spending_entries, 1);
+
+        // purging the transactions removes the script entry along with them
+        assert!(store.purge_tx(&txid2));
+        assert_eq!(store.counters.history_entries, 1);
+        assert!(store.purge_tx(&txid1));
+        assert!(!store.has_history(&scripthash));
+        assert_eq!(store.stats().memory_usage, 0);
+    }
 }
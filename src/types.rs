@@ -1,11 +1,14 @@
 use std::cmp::Ordering;
+use std::str::FromStr;
 
-use serde::Serialize;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 use bitcoin::{Address, BlockHash, Txid};
 use bitcoin_hashes::{sha256, Hash};
 use bitcoincore_rpc::json::GetMempoolEntryResult;
 
+use crate::error::{Error, OptionExt, Result};
+
 pub use crate::util::bitcoincore_ext::RescanSince;
 
 hash_newtype!(
@@ -31,7 +34,7 @@ impl From<Address> for ScriptHash {
 #[cfg(feature = "electrum")]
 hash_newtype!(StatusHash, sha256::Hash, 32, doc = "The status hash.");
 
-#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub struct BlockId(pub u32, pub BlockHash);
 
 impl std::fmt::Display for BlockId {
@@ -47,6 +50,18 @@ pub struct InPoint {
 }
 
 impl_string_serializer!(InPoint, input, format!("{}:{}", input.txid, input.vin));
+impl_string_deserializer!(InPoint);
+
+impl FromStr for InPoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let txid = parts.next().req()?.parse()?;
+        let vin = parts.next().req()?.parse()?;
+        Ok(InPoint { txid, vin })
+    }
+}
 
 impl InPoint {
     pub fn new(txid: Txid, vin: u32) -> Self {
@@ -62,6 +77,24 @@ pub enum ScriptType {
     P2shP2wpkh,
 }
 
+/// The direction of a transaction relative to a scripthash: whether it funds it (incoming),
+/// spends from it (outgoing), or both (e.g. a self-transfer)
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxDirection {
+    Incoming,
+    Outgoing,
+    Both,
+}
+
+impl TxDirection {
+    /// Check whether a transaction with this direction passes the `filter`. Incoming and outgoing
+    /// filters also match transactions that go in both directions.
+    pub fn matches(self, filter: TxDirection) -> bool {
+        self == filter || self == TxDirection::Both
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Copy, Hash)]
 pub enum TxStatus {
     Conflicted, // aka double spent
@@ -98,6 +131,14 @@ impl TxStatus {
             TxStatus::Confirmed(_) | TxStatus::Conflicted => false,
         }
     }
+
+    /// Check whether the transaction was confirmed at or below the given height
+    pub fn is_confirmed_by(self, height: u32) -> bool {
+        match self {
+            TxStatus::Confirmed(tx_height) => tx_height <= height,
+            TxStatus::Unconfirmed | TxStatus::Conflicted => false,
+        }
+    }
 }
 
 // Serialize confirmed transactions as the block height, unconfirmed as null and confliced as -1
@@ -114,6 +155,22 @@ impl serde::Serialize for TxStatus {
     }
 }
 
+impl<'de> Deserialize<'de> for TxStatus {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match Option::<i64>::deserialize(deserializer)? {
+            Some(-1) => TxStatus::Conflicted,
+            None => TxStatus::Unconfirmed,
+            Some(height) if height >= 0 && height <= u32::MAX as i64 => {
+                TxStatus::Confirmed(height as u32)
+            }
+            Some(height) => return Err(de::Error::custom(format!("invalid height {}", height))),
+        })
+    }
+}
+
 impl Ord for TxStatus {
     fn cmp(&self, other: &TxStatus) -> Ordering {
         match (self, other) {
@@ -136,7 +193,7 @@ impl PartialOrd for TxStatus {
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MempoolEntry {
     /// The vsize of this transaction
     pub vsize: u64,
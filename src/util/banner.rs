@@ -101,7 +101,7 @@ pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String>
 
      NETWORK: 🌐  {chain_name}
    CONNECTED: 💻  {connected_peers} ᴘᴇᴇʀs
-      UPTIME: ⏱️  {uptime}
+      UPTIME: ⏱️  {uptime}{public_host_frag}
 
    BANDWIDTH: 📶  {bandwidth_up} 🔼  {bandwidth_down} 🔽 (24ʜ ᴀᴠɢ)
   CHAIN SIZE: 💾  {chain_size}
@@ -147,6 +147,11 @@ pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String>
         ver_line1 = ver_lines.0,
         ver_line2 = ver_lines.1,
         ver_line3 = ver_lines.2,
+        public_host_frag = match query.public_host() {
+            Some(host) if is_onion(host) => format!("\n PUBLIC HOST: 🧅  {}", host),
+            Some(host) => format!("\n PUBLIC HOST: 🌍  {}", host),
+            None => "".into(),
+        },
         donation_frag = if !omit_donation {
             " SUPPORT DEV: 🚀  bc1qmuagsjvq0lh3admnafk0qnlql0vvxv08au9l2d ／ https://btcpay.shesek.info\n"
         } else {
@@ -172,6 +177,10 @@ pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String>
         height = utxo_info.height,
 */
 
+fn is_onion(host: &str) -> bool {
+    host.ends_with(".onion")
+}
+
 fn dur_from_secs(seconds: u64) -> Duration {
     Duration::from_std(StdDuration::from_secs(seconds)).unwrap()
 }
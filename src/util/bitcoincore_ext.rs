@@ -1,11 +1,22 @@
 use serde::{de, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{self, Formatter};
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bitcoincore_rpc::json::ImportMultiRescanSince;
-use bitcoincore_rpc::{Client, Result as RpcResult, RpcApi};
+use bitcoin::{blockdata::constants::genesis_block, BlockHash, Network, Txid};
+use bitcoincore_rpc::json::{GetMempoolEntryResult, ImportMultiRescanSince};
+use bitcoincore_rpc::{jsonrpc, Auth, Client, Error as RpcError, Result as RpcResult, RpcApi};
+
+use crate::error::{OptionExt, Result};
+use crate::util::BoolThen;
 
 // Extensions for rust-bitcoincore-rpc
 
+// the maximum number of requests sent in a single JSON-RPC batch
+const RPC_BATCH_SIZE: usize = 500;
+
 pub trait RpcApiExt: RpcApi {
     // Only supports the fields we're interested in (so not currently upstremable)
 
@@ -26,9 +37,260 @@ pub trait RpcApiExt: RpcApi {
     fn get_mempool_info(&self) -> RpcResult<GetMempoolInfoResult> {
         self.call("getmempoolinfo", &[])
     }
+
+    /// Get the entries of all mempool transactions in a single call, using `getrawmempool true`
+    fn get_raw_mempool_verbose(&self) -> RpcResult<HashMap<Txid, GetMempoolEntryResult>> {
+        self.call("getrawmempool", &[json!(true)])
+    }
+}
+
+impl RpcApiExt for RpcClient {}
+
+/// A wrapper for rust-bitcoincore-rpc's Client that re-reads the cookie file and retries once
+/// when requests fail, to recover from bitcoind restarts that rotated the cookie.
+pub struct RpcClient {
+    url: String,
+    auth: Auth,
+    client: RwLock<Client>,
+    // the cookie file contents the current client was created with (when using cookie auth)
+    cookie: Mutex<Option<String>>,
+}
+
+impl RpcClient {
+    pub fn new(url: String, auth: Auth) -> RpcResult<Self> {
+        let cookie = match &auth {
+            Auth::CookieFile(path) => Some(fs::read_to_string(path)?),
+            _ => None,
+        };
+        Ok(RpcClient {
+            client: RwLock::new(Client::new(url.clone(), auth.clone())?),
+            cookie: Mutex::new(cookie),
+            url,
+            auth,
+        })
+    }
+
+    // Re-create the client if the cookie file changed since it was last read,
+    // returning whether it did
+    fn refresh_cookie(&self) -> bool {
+        let path = match &self.auth {
+            Auth::CookieFile(path) => path,
+            _ => return false,
+        };
+        let mut cookie = self.cookie.lock().unwrap();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) if Some(&contents) != cookie.as_ref() => contents,
+            _ => return false,
+        };
+        match Client::new(self.url.clone(), self.auth.clone()) {
+            Ok(client) => {
+                info!("bitcoind cookie file {:?} was rotated, reconnecting", path);
+                *self.client.write().unwrap() = client;
+                *cookie = Some(contents);
+                true
+            }
+            Err(e) => {
+                warn!("failed reading the rotated cookie file {:?}: {:?}", path, e);
+                false
+            }
+        }
+    }
+
+    /// Get the mempool entries of multiple transactions using JSON-RPC batch requests.
+    /// Transactions that are not in the mempool (e.g. ones that were confirmed or evicted in the
+    /// meanwhile) are omitted from the result, other errors fail the whole batch.
+    pub fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> RpcResult<HashMap<Txid, GetMempoolEntryResult>> {
+        let mut entries = HashMap::with_capacity(txids.len());
+        for chunk in txids.chunks(RPC_BATCH_SIZE) {
+            let params: Vec<[serde_json::Value; 1]> =
+                chunk.iter().map(|txid| [json!(txid)]).collect();
+            let responses = self.call_batch("getmempoolentry", &params)?;
+            collect_mempool_entries(chunk, responses, &mut entries)?;
+        }
+        Ok(entries)
+    }
+
+    /// Get the hashes of the best chain blocks in the `start_height..=end_height` range, using
+    /// batched `getblockhash` calls
+    pub fn get_block_hashes(
+        &self,
+        start_height: u32,
+        end_height: u32,
+    ) -> RpcResult<Vec<BlockHash>> {
+        let heights: Vec<u32> = (start_height..=end_height).collect();
+        let mut hashes = Vec::with_capacity(heights.len());
+        for chunk in heights.chunks(RPC_BATCH_SIZE) {
+            let params: Vec<[serde_json::Value; 1]> =
+                chunk.iter().map(|height| [json!(height)]).collect();
+            for response in self.call_batch("getblockhash", &params)? {
+                hashes.push(response.ok_or(RpcError::UnexpectedStructure)?.result()?);
+            }
+        }
+        Ok(hashes)
+    }
+
+    // Send a JSON-RPC batch request calling `cmd` once for each of the `params`. Returns the
+    // responses in the same order, with None for responses that are missing from the reply.
+    fn call_batch<P: AsRef<[serde_json::Value]>>(
+        &self,
+        cmd: &str,
+        params: &[P],
+    ) -> RpcResult<Vec<Option<jsonrpc::Response>>> {
+        let send = || {
+            let client = self.client.read().unwrap();
+            let jsonrpc_client = client.get_jsonrpc_client();
+            let requests: Vec<_> = params
+                .iter()
+                .map(|params| jsonrpc_client.build_request(cmd, params.as_ref()))
+                .collect();
+            jsonrpc_client.send_batch(&requests)
+        };
+        // retry once with a refreshed cookie, like in call()
+        match send() {
+            Err(jsonrpc::Error::Json(_)) if self.refresh_cookie() => Ok(send()?),
+            res => Ok(res?),
+        }
+    }
 }
 
-impl RpcApiExt for Client {}
+// Collect the entries from the `getmempoolentry` batch responses for `txids`, skipping the ones
+// that are not in the mempool (RPC_INVALID_ADDRESS_OR_KEY)
+fn collect_mempool_entries(
+    txids: &[Txid],
+    responses: Vec<Option<jsonrpc::Response>>,
+    entries: &mut HashMap<Txid, GetMempoolEntryResult>,
+) -> RpcResult<()> {
+    for (txid, response) in txids.iter().zip(responses) {
+        match response.ok_or(RpcError::UnexpectedStructure)?.result() {
+            Ok(entry) => {
+                entries.insert(*txid, entry);
+            }
+            Err(jsonrpc::Error::Rpc(ref e)) if e.code == -5 => {
+                trace!("mempool entry unavailable for {}: {:?}", txid, e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+impl RpcApi for RpcClient {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[serde_json::Value],
+    ) -> RpcResult<T> {
+        #[cfg(feature = "metrics")]
+        let _timer = {
+            use crate::metrics::{RPC_CALLS, RPC_DURATION};
+            RPC_CALLS.with_label_values(&[cmd]).inc();
+            RPC_DURATION.with_label_values(&[cmd]).start_timer()
+        };
+
+        // bitcoind replies to unauthorized requests with an empty body, which surfaces as a json error
+        let res = self.client.read().unwrap().call(cmd, args);
+        let res = match res {
+            Err(RpcError::JsonRpc(jsonrpc::Error::Json(_))) if self.refresh_cookie() => {
+                self.client.read().unwrap().call(cmd, args)
+            }
+            res => res,
+        };
+
+        #[cfg(feature = "metrics")]
+        if res.is_err() {
+            crate::metrics::RPC_ERRORS.with_label_values(&[cmd]).inc();
+        }
+
+        res
+    }
+}
+
+/// The RPC clients of the bitcoind wallets that the tracked wallets are imported into. Wallets
+/// that don't specify a bitcoind wallet of their own use the default client, which is also the
+/// one used for non-wallet RPC calls.
+#[derive(Clone)]
+pub struct WalletRpcs {
+    default: Arc<RpcClient>,
+    // whether the default client is used as a wallet, either because a default bitcoind wallet
+    // was configured or because some of the tracked wallets rely on it
+    default_wallet: bool,
+    named: BTreeMap<String, Arc<RpcClient>>,
+    // named wallets that don't exist (yet), excluded from `all()`
+    skipped: BTreeSet<String>,
+}
+
+impl WalletRpcs {
+    pub fn new(default: Arc<RpcClient>, default_wallet: bool) -> Self {
+        WalletRpcs {
+            default,
+            default_wallet,
+            named: BTreeMap::new(),
+            skipped: BTreeSet::new(),
+        }
+    }
+
+    /// Add the client of a named bitcoind wallet, scoped to its `/wallet/<name>` RPC endpoint
+    pub fn add(&mut self, name: String, rpc: Arc<RpcClient>) {
+        self.named.insert(name, rpc);
+    }
+
+    pub fn default_rpc(&self) -> &Arc<RpcClient> {
+        &self.default
+    }
+
+    /// Get the client for the given bitcoind wallet name, or the default one if it has none
+    pub fn get(&self, name: Option<&str>) -> &Arc<RpcClient> {
+        name.and_then(|name| self.named.get(name))
+            .unwrap_or(&self.default)
+    }
+
+    /// Iterate over the named bitcoind wallets and their clients
+    pub fn named(&self) -> impl Iterator<Item = (&String, &Arc<RpcClient>)> {
+        self.named.iter()
+    }
+
+    /// Exclude the bitcoind wallet (or the default one, if `name` is None) from `all()`. Its client
+    /// is still returned by `get()`, so that the wallets using it aren't mixed up with the default.
+    pub fn skip(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.skipped.insert(name.to_string());
+            }
+            None => self.default_wallet = false,
+        }
+    }
+
+    /// Iterate over the clients of all the bitcoind wallets in use, starting with the default one
+    /// (if it is used as a wallet)
+    pub fn all(&self) -> impl Iterator<Item = &Arc<RpcClient>> {
+        let named = self
+            .named
+            .iter()
+            .filter(move |(name, _)| !self.skipped.contains(*name))
+            .map(|(_, rpc)| rpc);
+        self.default_wallet
+            .do_then(|| &self.default)
+            .into_iter()
+            .chain(named)
+    }
+
+    /// Check whether bitcoind is rescanning any of the wallets in use. Reports the first one found
+    /// scanning, or `Unsupported` if any of them does not report its scanning status.
+    pub fn check_scanning(&self) -> Result<ScanningResult> {
+        let mut result = ScanningResult::NotScanning;
+        for rpc in self.all() {
+            match check_scanning(rpc)? {
+                ScanningResult::Scanning(details) => return Ok(ScanningResult::Scanning(details)),
+                ScanningResult::Unsupported => result = ScanningResult::Unsupported,
+                ScanningResult::NotScanning => (),
+            }
+        }
+        Ok(result)
+    }
+}
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct GetBlockStatsResult {
@@ -55,6 +317,35 @@ pub struct GetMempoolInfoResult {
     pub mempool_min_fee: bitcoin::Amount,
 }
 
+/// Check whether bitcoind is currently rescanning the wallet, using `getwalletinfo`
+pub fn check_scanning(rpc: &RpcClient) -> Result<ScanningResult> {
+    let mut wallet_info: serde_json::Value = rpc.call("getwalletinfo", &[])?;
+
+    // the "rescanning" field is only supported as of Bitcoin Core v0.19
+    let rescanning = some_or_ret!(
+        wallet_info.get_mut("scanning"),
+        Ok(ScanningResult::Unsupported)
+    );
+
+    Ok(if rescanning.as_bool() == Some(false) {
+        ScanningResult::NotScanning
+    } else {
+        let details = serde_json::from_value(rescanning.take())?;
+        ScanningResult::Scanning(details)
+    })
+}
+
+pub enum ScanningResult {
+    Scanning(ScanningDetails),
+    NotScanning,
+    Unsupported,
+}
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ScanningDetails {
+    pub duration: u64,
+    pub progress: f64,
+}
+
 // Wrap rust-bitcoincore-rpc's RescanSince to enable deserialization
 // Pending https://github.com/rust-bitcoin/rust-bitcoincore-rpc/pull/150
 
@@ -64,6 +355,54 @@ pub enum RescanSince {
     Timestamp(u64),
 }
 
+impl RescanSince {
+    /// Parse a `yyyy-mm-dd` date string into a `RescanSince::Timestamp` (at midnight UTC).
+    /// The date must not be before the genesis block. Dates in the future are handled by
+    /// `guard_future()`, the same way as timestamps.
+    pub fn from_date_str(s: &str) -> Result<Self> {
+        use chrono::{TimeZone, Utc};
+        let mut parts = s.splitn(3, '-');
+        let timestamp = Utc
+            .ymd_opt(
+                parts.next().req()?.parse()?,
+                parts.next().req()?.parse()?,
+                parts.next().req()?.parse()?,
+            )
+            .single()
+            .or_err("invalid date, expecting yyyy-mm-dd")?
+            .and_hms(0, 0, 0)
+            .timestamp();
+
+        // the mainnet genesis block is the earliest of all networks
+        let genesis_time = genesis_block(Network::Bitcoin).header.time as i64;
+        ensure!(
+            timestamp >= genesis_time,
+            "rescan date {} is before the genesis block",
+            s
+        );
+
+        Ok(RescanSince::Timestamp(timestamp as u64))
+    }
+
+    /// Guard against rescan timestamps that are in the future (likely due to a typo), which would
+    /// silently skip scanning for historical transactions. Rejected if `reject` is set, or
+    /// replaced with `Now` with a warning otherwise.
+    pub fn guard_future(self, reject: bool) -> Result<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(match self {
+            RescanSince::Timestamp(timestamp) if timestamp > now => {
+                ensure!(!reject, "rescan timestamp {} is in the future", timestamp);
+                warn!(
+                    "rescan timestamp {} is in the future, no historical transactions will be scanned for",
+                    timestamp
+                );
+                RescanSince::Now
+            }
+            rescan => rescan,
+        })
+    }
+}
+
 impl Into<ImportMultiRescanSince> for &RescanSince {
     fn into(self) -> ImportMultiRescanSince {
         match self {
@@ -83,7 +422,7 @@ impl<'de> serde::Deserialize<'de> for RescanSince {
             type Value = RescanSince;
 
             fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
-                write!(formatter, "unix timestamp or 'now'")
+                write!(formatter, "unix timestamp, yyyy-mm-dd date or 'now'")
             }
 
             fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
@@ -100,13 +439,167 @@ impl<'de> serde::Deserialize<'de> for RescanSince {
                 if value == "now" {
                     Ok(RescanSince::Now)
                 } else {
-                    Err(de::Error::custom(format!(
-                        "invalid str '{}', expecting 'now' or unix timestamp",
-                        value
-                    )))
+                    RescanSince::from_date_str(value).map_err(|e| {
+                        de::Error::custom(format!(
+                            "invalid str '{}', expecting 'now', unix timestamp or yyyy-mm-dd date: {}",
+                            value, e
+                        ))
+                    })
                 }
             }
         }
         deserializer.deserialize_any(Visitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_rpc(url: &str) -> Arc<RpcClient> {
+        let auth = Auth::UserPass("user".into(), "pass".into());
+        Arc::new(RpcClient::new(url.into(), auth).unwrap())
+    }
+
+    #[test]
+    fn test_wallet_rpcs() {
+        let default = make_rpc("http://127.0.0.1:1");
+        let foo = make_rpc("http://127.0.0.1:1/wallet/foo");
+        let bar = make_rpc("http://127.0.0.1:1/wallet/bar");
+
+        let mut rpcs = WalletRpcs::new(default.clone(), true);
+        rpcs.add("foo".into(), foo.clone());
+        rpcs.add("bar".into(), bar.clone());
+
+        assert!(Arc::ptr_eq(rpcs.get(Some("foo")), &foo));
+        assert!(Arc::ptr_eq(rpcs.get(Some("bar")), &bar));
+        // wallets with no bitcoind wallet of their own or with an unknown one use the default
+        assert!(Arc::ptr_eq(rpcs.get(None), &default));
+        assert!(Arc::ptr_eq(rpcs.get(Some("baz")), &default));
+
+        // the default comes first, followed by the named ones in order
+        let all: Vec<_> = rpcs.all().collect();
+        assert_eq!(all.len(), 3);
+        assert!(Arc::ptr_eq(all[0], &default));
+        assert!(Arc::ptr_eq(all[1], &bar));
+        assert!(Arc::ptr_eq(all[2], &foo));
+
+        // the default client is skipped when it isn't used as a wallet
+        let mut rpcs = WalletRpcs::new(default.clone(), false);
+        rpcs.add("foo".into(), foo.clone());
+        let all: Vec<_> = rpcs.all().collect();
+        assert_eq!(all.len(), 1);
+        assert!(Arc::ptr_eq(all[0], &foo));
+        assert!(Arc::ptr_eq(rpcs.default_rpc(), &default));
+
+        // skipped wallets are excluded from all(), but their client is still used for them
+        rpcs.skip(Some("foo"));
+        assert_eq!(rpcs.all().count(), 0);
+        assert!(Arc::ptr_eq(rpcs.get(Some("foo")), &foo));
+    }
+
+    #[test]
+    fn test_refresh_rotated_cookie() {
+        let path = std::env::temp_dir().join(format!("bwt-test-cookie-{}", std::process::id()));
+        fs::write(&path, "__cookie__:first").unwrap();
+
+        let rpc =
+            RpcClient::new("http://127.0.0.1:1".into(), Auth::CookieFile(path.clone())).unwrap();
+        // unchanged cookie
+        assert!(!rpc.refresh_cookie());
+
+        // bitcoind restarted and rotated the cookie
+        fs::write(&path, "__cookie__:second").unwrap();
+        assert!(rpc.refresh_cookie());
+        assert_eq!(
+            rpc.cookie.lock().unwrap().as_deref(),
+            Some("__cookie__:second")
+        );
+        assert!(!rpc.refresh_cookie());
+
+        // the cookie file is temporarily missing while bitcoind is down
+        fs::remove_file(&path).unwrap();
+        assert!(!rpc.refresh_cookie());
+
+        let rpc = RpcClient::new(
+            "http://127.0.0.1:1".into(),
+            Auth::UserPass("u".into(), "p".into()),
+        )
+        .unwrap();
+        assert!(!rpc.refresh_cookie());
+    }
+
+    #[test]
+    fn test_rescan_from_date_str() {
+        assert_eq!(
+            RescanSince::from_date_str("2021-03-15").unwrap(),
+            RescanSince::Timestamp(1615766400)
+        );
+        assert_eq!(
+            RescanSince::from_date_str("2009-01-09").unwrap(),
+            RescanSince::Timestamp(1231459200)
+        );
+
+        // before the genesis block
+        assert!(RescanSince::from_date_str("2008-10-31").is_err());
+        // in the future, left for guard_future() to handle
+        let future = RescanSince::from_date_str("9999-01-01").unwrap();
+        assert_eq!(future.guard_future(false).unwrap(), RescanSince::Now);
+        assert!(future.guard_future(true).is_err());
+        // malformed
+        assert!(RescanSince::from_date_str("2021-13-01").is_err());
+        assert!(RescanSince::from_date_str("2021-03").is_err());
+        assert!(RescanSince::from_date_str("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_rescan_guard_future() {
+        let past = RescanSince::Timestamp(1615766400);
+        assert_eq!(past.guard_future(true).unwrap(), past);
+        assert_eq!(
+            RescanSince::Now.guard_future(true).unwrap(),
+            RescanSince::Now
+        );
+
+        let future = RescanSince::Timestamp(253402300800); // 9999-01-01
+        assert_eq!(future.guard_future(false).unwrap(), RescanSince::Now);
+        assert!(future.guard_future(true).is_err());
+    }
+
+    #[test]
+    fn test_collect_mempool_entries() {
+        let txids: Vec<Txid> = (1..=4u8)
+            .map(|i| format!("{:064x}", i).parse().unwrap())
+            .collect();
+        let entry = json!({
+            "vsize": 141, "weight": 561, "time": 1615766400, "height": 100,
+            "descendantcount": 1, "descendantsize": 141, "ancestorcount": 1, "ancestorsize": 141,
+            "wtxid": txids[0],
+            "fees": { "base": 0.0001, "modified": 0.0001, "ancestor": 0.0001, "descendant": 0.0001 },
+            "depends": [], "spentby": [], "bip125-replaceable": false,
+        });
+        let response = |result: serde_json::Value, error: serde_json::Value| {
+            serde_json::from_value::<jsonrpc::Response>(
+                json!({ "result": result, "error": error, "id": 1 }),
+            )
+            .unwrap()
+        };
+        let not_found = json!({ "code": -5, "message": "Transaction not in mempool" });
+        let warmup = json!({ "code": -28, "message": "Loading block index..." });
+
+        // transactions that are not in the mempool are skipped
+        let mut entries = HashMap::new();
+        let responses = vec![
+            Some(response(entry.clone(), json!(null))),
+            Some(response(json!(null), not_found)),
+        ];
+        collect_mempool_entries(&txids[..2], responses, &mut entries).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[&txids[0]].vsize, 141);
+
+        // other errors and missing responses fail the batch
+        let responses = vec![Some(response(json!(null), warmup))];
+        assert!(collect_mempool_entries(&txids[2..3], responses, &mut entries).is_err());
+        assert!(collect_mempool_entries(&txids[3..], vec![None], &mut entries).is_err());
+    }
+}
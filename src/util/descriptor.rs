@@ -1,18 +1,121 @@
+use std::borrow::Cow;
 use std::iter::FromIterator;
+use std::ops::Deref;
 use std::str::FromStr;
 
-use bitcoin::Network;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::{Address, Network, Script};
+use bitcoin_hashes::{sha256, Hash};
 use miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use miniscript::{Miniscript, ScriptContext, Terminal, ToPublicKey};
 
 use crate::error::{Error, OptionExt, Result};
-use crate::util::xpub::{xpub_matches_network, Bip32Origin};
+use crate::util::xpub::{xpub_matches_network, xpub_network_name, Bip32Origin};
 
-pub type ExtendedDescriptor = Descriptor<DescriptorPublicKey>;
+/// A descriptor with (possibly extended) public keys. Wraps miniscript's `Descriptor` to add support
+/// for `sortedmulti()`, which miniscript v3.0 lacks. It is represented using `multi()`, with the
+/// keys getting sorted (per BIP 67) when generating scripts, after they are derived.
+///
+/// Dereferences into the underlying miniscript `Descriptor`, which should not be used directly
+/// for generating scripts (use the `address()`, `script_pubkey()` and `witness_script()` methods
+/// provided here instead).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedDescriptor {
+    desc: Descriptor<DescriptorPublicKey>,
+    sorted_multi: bool,
+}
+
+impl_string_serializer!(
+    ExtendedDescriptor,
+    d,
+    if d.sorted_multi {
+        d.desc.to_string().replacen("multi(", "sortedmulti(", 1)
+    } else {
+        d.desc.to_string()
+    }
+);
+impl_string_deserializer!(ExtendedDescriptor);
+
+impl ExtendedDescriptor {
+    /// Derives all wildcard keys in the descriptor using the supplied `child_number`
+    ///
+    /// Panics if given a hardened child number
+    pub fn derive(&self, child_number: ChildNumber) -> ExtendedDescriptor {
+        ExtendedDescriptor {
+            desc: self.desc.derive(child_number),
+            sorted_multi: self.sorted_multi,
+        }
+    }
+
+    pub fn address(&self, network: Network) -> Option<Address> {
+        self.script_desc().address(network)
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        self.script_desc().script_pubkey()
+    }
+
+    pub fn witness_script(&self) -> Script {
+        self.script_desc().witness_script()
+    }
+
+    // The descriptor to generate scripts with, having the keys of sortedmulti() sorted
+    fn script_desc(&self) -> Cow<'_, Descriptor<DescriptorPublicKey>> {
+        if !self.sorted_multi {
+            return Cow::Borrowed(&self.desc);
+        }
+        Cow::Owned(match &self.desc {
+            Descriptor::Sh(ms) => Descriptor::Sh(sort_multi_keys(ms)),
+            Descriptor::Wsh(ms) => Descriptor::Wsh(sort_multi_keys(ms)),
+            Descriptor::ShWsh(ms) => Descriptor::ShWsh(sort_multi_keys(ms)),
+            _ => unreachable!("sortedmulti() is only parsed within sh(), wsh() and sh(wsh())"),
+        })
+    }
+}
+
+impl Deref for ExtendedDescriptor {
+    type Target = Descriptor<DescriptorPublicKey>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.desc
+    }
+}
+
+impl From<Descriptor<DescriptorPublicKey>> for ExtendedDescriptor {
+    fn from(desc: Descriptor<DescriptorPublicKey>) -> Self {
+        ExtendedDescriptor {
+            desc,
+            sorted_multi: false,
+        }
+    }
+}
+
+impl FromStr for ExtendedDescriptor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_desc(s)
+    }
+}
+
+// Sort the keys of a multi() by their serialized public key, as specified by BIP 67
+fn sort_multi_keys<Ctx: ScriptContext>(
+    ms: &Miniscript<DescriptorPublicKey, Ctx>,
+) -> Miniscript<DescriptorPublicKey, Ctx> {
+    if let Terminal::Multi(k, pks) = &ms.node {
+        let mut pks = pks.clone();
+        pks.sort_by_cached_key(|pk| pk.to_public_key().to_bytes());
+        Miniscript::from_ast(Terminal::Multi(*k, pks)).expect("reordering keys keeps multi() valid")
+    } else {
+        unreachable!("sortedmulti() is always parsed into a multi()")
+    }
+}
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Checksum(String);
 
 impl_string_serializer!(Checksum, c, c.0);
+impl_string_deserializer!(Checksum);
 
 #[derive(Debug, Clone)]
 pub struct DescKeyInfo {
@@ -42,7 +145,7 @@ impl FromStr for Checksum {
 
 impl DescKeyInfo {
     pub fn extract(desc: &ExtendedDescriptor, network: Network) -> Result<Vec<DescKeyInfo>> {
-        let mut valid_networks = true;
+        let mut mismatched_xpubs = vec![];
         let mut keys_info = vec![];
 
         tap_desc_pks(desc, |pk| match pk {
@@ -60,7 +163,9 @@ impl DescKeyInfo {
                     is_ranged: desc_xpub.is_wildcard,
                 });
 
-                valid_networks = valid_networks && xpub_matches_network(&desc_xpub.xpub, network);
+                if !xpub_matches_network(&desc_xpub.xpub, network) {
+                    mismatched_xpubs.push(desc_xpub.xpub);
+                }
             }
             DescriptorPublicKey::SinglePub(desc_single) => {
                 if let Some(bip32_origin) = &desc_single.origin {
@@ -72,11 +177,15 @@ impl DescKeyInfo {
             }
         });
 
-        ensure!(
-            valid_networks,
-            "Descriptor xpubs do not match the configured network {}",
-            network
-        );
+        if let Some(xpub) = mismatched_xpubs.first() {
+            bail!(
+                "Key {} is for {}, but the configured network is {}. Use a key for {} or set --network accordingly.",
+                xpub,
+                xpub_network_name(xpub.network),
+                network,
+                network
+            );
+        }
 
         Ok(keys_info)
     }
@@ -87,6 +196,8 @@ pub trait DescriptorChecksum: Sized {
     fn to_string_with_checksum(&self) -> String;
     /// Parse a descriptor with an optional checksum suffix
     fn parse_with_checksum(s: &str) -> Result<Self>;
+    /// Hash the full descriptor string, for telling apart descriptors with colliding checksums
+    fn full_hash(&self) -> sha256::Hash;
 }
 
 impl DescriptorChecksum for ExtendedDescriptor {
@@ -94,11 +205,15 @@ impl DescriptorChecksum for ExtendedDescriptor {
         format!("{}#{}", self, get_checksum(&self))
     }
 
+    fn full_hash(&self) -> sha256::Hash {
+        sha256::Hash::hash(self.to_string().as_bytes())
+    }
+
     fn parse_with_checksum(s: &str) -> Result<ExtendedDescriptor> {
         let parts: Vec<&str> = s.splitn(2, '#').collect();
         if parts.len() == 2 {
             let desc_str = parts[0];
-            let desc = desc_str.parse::<ExtendedDescriptor>()?;
+            let desc = parse_desc(desc_str)?;
             let provided_checksum = parts[1].parse::<Checksum>()?;
 
             // FIXME using canonical encoding should not be required, but the current implementation
@@ -120,11 +235,120 @@ impl DescriptorChecksum for ExtendedDescriptor {
             );
             Ok(desc)
         } else {
-            Ok(s.parse()?)
+            parse_desc(s)
         }
     }
 }
 
+/// A descriptor validated and normalized by `normalize_descriptor()`
+#[derive(Serialize, Debug, Clone)]
+pub struct NormalizedDescriptor {
+    /// The canonical encoding of the descriptor, with the `#checksum` suffix
+    pub descriptor: String,
+    pub checksum: Checksum,
+    /// Whether the provided descriptor was already in its canonical encoding
+    pub is_canonical: bool,
+    /// Whether the provided descriptor included a checksum (which is verified to be valid)
+    pub had_checksum: bool,
+}
+
+/// Validate the descriptor along with its checksum (if provided) and the network of its keys,
+/// and return its canonical encoding with the checksum appended.
+///
+/// Unlike `parse_with_checksum()`, this accepts non-canonical encodings with explicit checksums,
+/// verifying the checksum against the descriptor string as provided.
+pub fn normalize_descriptor(s: &str, network: Network) -> Result<NormalizedDescriptor> {
+    let s = s.trim();
+    let mut parts = s.splitn(2, '#');
+    let desc_str = parts.next().req()?;
+    let provided_checksum = parts.next().map(str::parse::<Checksum>).transpose()?;
+
+    if let Some(provided_checksum) = &provided_checksum {
+        let actual_checksum = get_str_checksum(desc_str)?;
+        ensure!(
+            *provided_checksum == actual_checksum,
+            "Invalid descriptor checksum {}, expected {}",
+            provided_checksum,
+            actual_checksum,
+        );
+    }
+
+    let desc = parse_desc(desc_str)?;
+    // validate that the xpubs match the network
+    DescKeyInfo::extract(&desc, network)?;
+
+    Ok(NormalizedDescriptor {
+        descriptor: desc.to_string_with_checksum(),
+        checksum: get_checksum(&desc),
+        is_canonical: desc.to_string() == desc_str,
+        had_checksum: provided_checksum.is_some(),
+    })
+}
+
+fn parse_desc(s: &str) -> Result<ExtendedDescriptor> {
+    // taproot is not supported by the rust-bitcoin (v0.25) and miniscript (v3.0) versions currently
+    // in use: miniscript has no tr() descriptor type, and rust-bitcoin can neither encode nor parse
+    // the bech32m addresses of segwit v1 outputs (and would fail deserializing bitcoind's RPC
+    // responses that include them). reject them with a clear error instead of the generic parse
+    // failure, until the dependencies get upgraded.
+    ensure!(
+        !s.trim_start().starts_with("tr("),
+        "Taproot tr() descriptors are not supported (requires bech32m and tr() support in rust-bitcoin/miniscript)"
+    );
+
+    // sortedmulti() is parsed as a multi(), which is only valid as the top-level script of sh(),
+    // wsh() and sh(wsh()). the keys get sorted by ExtendedDescriptor when generating scripts.
+    let sorted_multi = ["sh(sortedmulti(", "wsh(sortedmulti(", "sh(wsh(sortedmulti("]
+        .iter()
+        .any(|prefix| s.starts_with(prefix));
+    let desc_str = if sorted_multi {
+        Cow::Owned(s.replacen("sortedmulti(", "multi(", 1))
+    } else {
+        Cow::Borrowed(s)
+    };
+    ensure!(
+        !desc_str.contains("sortedmulti("),
+        "sortedmulti() is only supported as the top-level script of sh(), wsh() or sh(wsh())"
+    );
+
+    let desc = desc_str
+        .parse::<Descriptor<DescriptorPublicKey>>()
+        .map_err(|err| {
+            // replace the generic parse error with a more specific one for the common mistake of
+            // providing keys with origin information only, without the xpub needed to derive them
+            check_derivable_keys(s).err().unwrap_or_else(|| err.into())
+        })?;
+
+    Ok(ExtendedDescriptor { desc, sorted_multi })
+}
+
+// Check that keys with origin information or derivation paths are extended public keys
+fn check_derivable_keys(s: &str) -> Result<()> {
+    for (origin_end, _) in s.match_indices(']') {
+        let origin_start = s[..origin_end].rfind('[').or_err("invalid key origin")?;
+        let origin = &s[origin_start..=origin_end];
+        let rest = &s[origin_end + 1..];
+        let key = &rest[..rest.find(&[',', ')', '/'][..]).unwrap_or(rest.len())];
+
+        ensure!(
+            !key.is_empty(),
+            "Descriptor key `{}` has key origin information but no public key. bwt needs derivable \
+             keys to watch addresses, please provide the extended public key (xpub) along with its origin \
+             (e.g. `{}xpub.../0/*`)",
+            origin,
+            origin
+        );
+        ensure!(
+            key.parse::<ExtendedPubKey>().is_ok() || !rest[key.len()..].starts_with('/'),
+            "Descriptor key `{}{}` is not an extended public key and cannot be derived. bwt needs \
+             derivable keys to watch addresses, please provide the extended public key (xpub) instead",
+            origin,
+            key
+        );
+    }
+    Ok(())
+}
+
 fn tap_desc_pks<F>(desc: &ExtendedDescriptor, mut tap_fn: F)
 where
     F: FnMut(&DescriptorPublicKey),
@@ -158,14 +382,18 @@ const CHECKSUM_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
 
 /// Compute the checksum of a descriptor
 fn get_checksum(desc: &ExtendedDescriptor) -> Checksum {
-    let desc_str = desc.to_string();
+    get_str_checksum(&desc.to_string()).expect("ExtendedDescriptor's encoding cannot be invalid")
+}
+
+/// Compute the checksum of a descriptor string, as-is
+pub fn get_str_checksum(desc_str: &str) -> Result<Checksum> {
     let mut c = 1;
     let mut cls = 0;
     let mut clscount = 0;
     for ch in desc_str.chars() {
         let pos = INPUT_CHARSET
             .find(ch)
-            .expect("ExtendedDescriptor's encoding cannot be invalid") as u64;
+            .or_err("Invalid descriptor character")? as u64;
         c = poly_mod(c, pos & 31);
         cls = cls * 3 + (pos >> 5);
         clscount += 1;
@@ -191,7 +419,7 @@ fn get_checksum(desc: &ExtendedDescriptor) -> Checksum {
         );
     }
 
-    Checksum(String::from_iter(chars))
+    Ok(Checksum(String::from_iter(chars)))
 }
 
 fn poly_mod(mut c: u64, val: u64) -> u64 {
@@ -215,3 +443,89 @@ fn poly_mod(mut c: u64, val: u64) -> u64 {
 
     c
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XPUB: &str = "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL";
+
+    #[test]
+    fn test_normalize_descriptor() {
+        let canonical = format!("pkh([d34db33f/44'/0'/0']{}/1/*)", XPUB);
+        let normalized = normalize_descriptor(&canonical, Network::Bitcoin).unwrap();
+        assert_eq!(
+            normalized.descriptor,
+            format!("{}#{}", canonical, normalized.checksum)
+        );
+        assert!(normalized.is_canonical);
+        assert!(!normalized.had_checksum);
+
+        // the normalized form is accepted as-is
+        let renormalized = normalize_descriptor(&normalized.descriptor, Network::Bitcoin).unwrap();
+        assert_eq!(renormalized.descriptor, normalized.descriptor);
+        assert!(renormalized.is_canonical);
+        assert!(renormalized.had_checksum);
+
+        // non-canonical encodings are verified against their own checksum
+        let non_canonical = format!("pkh([d34db33f/44h/0h/0h]{}/1/*)", XPUB);
+        let non_canonical_checksum = get_str_checksum(&non_canonical).unwrap();
+        assert_ne!(non_canonical_checksum, normalized.checksum);
+        let normalized_nc = normalize_descriptor(
+            &format!("{}#{}", non_canonical, non_canonical_checksum),
+            Network::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(normalized_nc.descriptor, normalized.descriptor);
+        assert!(!normalized_nc.is_canonical);
+
+        // invalid checksum
+        let invalid = format!("{}#{}", non_canonical, normalized.checksum);
+        assert!(normalize_descriptor(&invalid, Network::Bitcoin).is_err());
+        // mismatching network
+        assert!(normalize_descriptor(&canonical, Network::Testnet).is_err());
+    }
+
+    #[test]
+    fn test_check_derivable_keys() {
+        const PUBKEY: &str = "02e6642fd69bd211f93f7f1f36ca51a26a5290eb2dd1b0d8279a87bb0d480c8443";
+        let origin = "[d34db33f/84'/0'/0']";
+
+        // derivable keys, with or without origin information
+        assert!(check_derivable_keys(&format!("wpkh({}{}/0/*)", origin, XPUB)).is_ok());
+        assert!(check_derivable_keys(&format!("wpkh({}/0/*)", XPUB)).is_ok());
+        assert!(check_derivable_keys(&format!("wpkh({}{})", origin, PUBKEY)).is_ok());
+        assert!(
+            check_derivable_keys(&format!("wsh(multi(1,{}{}/0/*,{}))", origin, XPUB, PUBKEY))
+                .is_ok()
+        );
+
+        // origin information with no key, at the end of the descriptor or followed by another key
+        let err = check_derivable_keys(&format!("wpkh({})", origin)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has key origin information but no public key"));
+        let err = check_derivable_keys(&format!("wpkh({}/0/*)", origin)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has key origin information but no public key"));
+        let err = check_derivable_keys(&format!("wsh(multi(1,{}{}/0/*,{}))", origin, XPUB, origin))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has key origin information but no public key"));
+
+        // non-extended keys with a derivation path
+        let err = check_derivable_keys(&format!("wpkh({}{}/0/*)", origin, PUBKEY)).unwrap_err();
+        assert!(err.to_string().contains("is not an extended public key"));
+
+        // unbalanced brackets
+        assert!(check_derivable_keys("wpkh(d34db33f]xpub)").is_err());
+
+        // the specific error is reported instead of the generic parse failure
+        let err = parse_desc(&format!("wpkh({})", origin)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("has key origin information but no public key"));
+    }
+}
@@ -19,6 +19,21 @@ macro_rules! impl_string_serializer {
     };
 }
 
+// Implements the Deserialize trait to parse the struct from a string using its FromStr implementation
+macro_rules! impl_string_deserializer {
+    ($name:ident) => {
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                let s = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+                s.parse().map_err(::serde::de::Error::custom)
+            }
+        }
+    };
+}
+
 // delegate Debug to Display
 macro_rules! impl_debug_display {
     ($name:ident) => {
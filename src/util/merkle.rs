@@ -0,0 +1,63 @@
+use bitcoin_hashes::{sha256d, Hash};
+
+fn merklize(left: sha256d::Hash, right: sha256d::Hash) -> sha256d::Hash {
+    let data = [&left[..], &right[..]].concat();
+    sha256d::Hash::hash(&data)
+}
+
+pub fn create_merkle_branch_and_root(
+    mut hashes: Vec<sha256d::Hash>,
+    mut index: usize,
+) -> (Vec<sha256d::Hash>, sha256d::Hash) {
+    let mut merkle = vec![];
+    while hashes.len() > 1 {
+        if hashes.len() % 2 != 0 {
+            let last = *hashes.last().unwrap();
+            hashes.push(last);
+        }
+        index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        merkle.push(hashes[index]);
+        index /= 2;
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merklize(pair[0], pair[1]))
+            .collect()
+    }
+    (merkle, hashes[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::hash::bitcoin_merkle_root;
+
+    #[test]
+    fn test_merkle_branch() {
+        // check blocks with varying numbers of transactions, including odd ones that require
+        // duplicating the last hash
+        for num_txs in 1..=9 {
+            let txids: Vec<sha256d::Hash> = (0..num_txs)
+                .map(|i: u8| sha256d::Hash::hash(&[i]))
+                .collect();
+            let expected_root = bitcoin_merkle_root(txids.clone().into_iter());
+
+            for pos in 0..txids.len() {
+                let (branch, root) = create_merkle_branch_and_root(txids.clone(), pos);
+                assert_eq!(root, expected_root);
+
+                // reconstruct the root from the tx and its branch, the way electrum verifies it
+                let mut index = pos;
+                let computed_root = branch.into_iter().fold(txids[pos], |hash, sibling| {
+                    let parent = if index % 2 == 0 {
+                        merklize(hash, sibling)
+                    } else {
+                        merklize(sibling, hash)
+                    };
+                    index /= 2;
+                    parent
+                });
+                assert_eq!(computed_root, expected_root);
+            }
+        }
+    }
+}
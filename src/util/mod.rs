@@ -1,11 +1,17 @@
 use std::collections::hash_map::{Entry, HashMap};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::{Duration, Instant};
-use std::{sync::mpsc, thread};
-
-use serde_json::Value;
 
 use bitcoin::secp256k1::{self, Secp256k1};
-use bitcoin::Txid;
+use bitcoin::{Address, Network, Txid};
+use bitcoincore_rpc::json::GetMempoolEntryResult;
+
+use crate::error::{Context, Result};
 
 #[macro_use]
 mod macros;
@@ -13,6 +19,9 @@ mod macros;
 pub mod banner;
 pub mod bitcoincore_ext;
 pub mod descriptor;
+pub mod merkle;
+pub mod paymentcode;
+pub mod ratelimit;
 pub mod xpub;
 
 lazy_static! {
@@ -21,18 +30,25 @@ lazy_static! {
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // vbytes
 
-// Make the fee histogram out of a list of `getrawmempool true` entries
-pub fn make_fee_histogram(mempool_entries: HashMap<Txid, Value>) -> Vec<(f32, u32)> {
+/// How long to wait for background threads to terminate when shutting down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Make the fee histogram out of a list of `getrawmempool true` entries, in the format used by
+/// Electrum's `mempool.get_fee_histogram`.
+///
+/// Transactions are sorted by their own feerate (in sat/vB) in descending order, then grouped
+/// into bins of at least `VSIZE_BIN_WIDTH` vbytes each. A bin is only closed once it exceeds the
+/// width *and* the next transaction pays a different feerate, so that transactions paying the
+/// same feerate always end up in the same bin. Each bin is reported as `(feerate, vsize)`, where
+/// `feerate` is the lowest feerate within the bin and `vsize` is the total size of its transactions.
+pub fn make_fee_histogram(
+    mempool_entries: HashMap<Txid, GetMempoolEntryResult>,
+) -> Vec<(f32, u32)> {
     let mut entries: Vec<_> = mempool_entries
         .into_iter()
         .map(|(_, entry)| {
-            let vsize = entry["vsize"]
-                .as_u64()
-                .or_else(|| entry["size"].as_u64())
-                .unwrap(); // bitcoind is borked if this fails
-            let fee = entry["fee"].as_f64().unwrap();
-            let feerate = fee as f32 / vsize as f32 * 100_000_000f32;
-            (vsize as u32, feerate)
+            let feerate = entry.fees.base.as_sat() as f32 / entry.vsize as f32;
+            (entry.vsize as u32, feerate)
         })
         .collect();
 
@@ -61,7 +77,17 @@ pub fn make_fee_histogram(mempool_entries: HashMap<Txid, Value>) -> Vec<(f32, u3
     histogram
 }
 
-pub fn remove_if<K, V>(hm: &mut HashMap<K, V>, key: K, predicate: impl Fn(&mut V) -> bool) -> bool
+pub fn address_matches_network(address: &Address, network: Network) -> bool {
+    // testnet and regtest share the same base58 version bytes, regtest addresses are parsed as testnet
+    address.network == network
+        || (address.network == Network::Testnet && network == Network::Regtest)
+}
+
+pub fn remove_if<K, V>(
+    hm: &mut HashMap<K, V>,
+    key: K,
+    predicate: impl FnOnce(&mut V) -> bool,
+) -> bool
 where
     K: Eq + std::hash::Hash,
 {
@@ -75,10 +101,62 @@ where
     }
 }
 
-// debounce a Sender to only emit events sent when `duration` seconds has passed since
-// the previous event, or after `duration` seconds elapses without new events coming in.
-pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sender<()> {
-    let duration = Duration::from_secs(duration);
+// the range accepted for configurable intervals. shorter ones would have the background loops
+// spinning, and longer ones could overflow (making Duration::from_secs_f64() panic).
+const MIN_INTERVAL_SECS: f64 = 0.1;
+const MAX_INTERVAL_SECS: f64 = 86400.0;
+
+/// Convert a number of (possibly fractional) seconds into an interval Duration.
+/// Returns None if it's outside the accepted range of 0.1 seconds to 1 day (or not a number).
+pub fn interval_from_secs(secs: f64) -> Option<Duration> {
+    (MIN_INTERVAL_SECS..=MAX_INTERVAL_SECS)
+        .contains(&secs)
+        .do_then(|| Duration::from_secs_f64(secs))
+}
+
+/// The sync loop timing settings, shared with the HTTP API to allow adjusting them at runtime
+#[derive(Debug)]
+pub struct SyncIntervals {
+    poll_interval_ms: AtomicU64,
+    debounce_ms: AtomicU64,
+}
+
+impl SyncIntervals {
+    pub fn new(poll_interval: Duration, debounce: Duration) -> Self {
+        SyncIntervals {
+            poll_interval_ms: AtomicU64::new(poll_interval.as_millis() as u64),
+            debounce_ms: AtomicU64::new(debounce.as_millis() as u64),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_poll_interval(&self, poll_interval: Duration) {
+        info!("setting the poll interval to {:?}", poll_interval);
+        self.poll_interval_ms
+            .store(poll_interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_debounce(&self, debounce: Duration) {
+        info!("setting the sync debounce to {:?}", debounce);
+        self.debounce_ms
+            .store(debounce.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+// debounce a Sender to only emit events sent when the debounce duration has passed since
+// the previous event, or after the duration elapses without new events coming in.
+// the duration is re-read for every event, so that changes apply without a restart.
+pub fn debounce_sender(
+    forward_tx: mpsc::Sender<()>,
+    intervals: Arc<SyncIntervals>,
+) -> mpsc::Sender<()> {
     let (debounce_tx, debounce_rx) = mpsc::channel();
 
     thread::spawn(move || {
@@ -88,6 +166,7 @@ pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sen
             if debounce_rx.recv().is_err() {
                 break 'outer;
             }
+            let duration = intervals.debounce();
             if tick_start.elapsed() < duration {
                 // if duration hasn't passed, debounce for another `duration` seconds
                 loop {
@@ -112,6 +191,57 @@ pub fn debounce_sender(forward_tx: mpsc::Sender<()>, duration: u64) -> mpsc::Sen
     debounce_tx
 }
 
+/// Wait for the thread to terminate for up to `timeout`, leaving it detached if it doesn't.
+/// Returns whether the thread terminated in time.
+pub fn join_timeout<T: Send + 'static>(
+    handle: thread::JoinHandle<T>,
+    timeout: Duration,
+    name: &str,
+) -> bool {
+    let (done_tx, done_rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let _ = handle.join();
+        let _ = done_tx.send(());
+    });
+    match done_rx.recv_timeout(timeout) {
+        Ok(()) => true,
+        Err(_) => {
+            warn!("timed out waiting for the {} thread to terminate", name);
+            false
+        }
+    }
+}
+
+/// Write `value` as JSON to the file at `path`. It is written to a temporary file first and then
+/// moved into place, so that a crash while writing cannot leave a corrupted file behind.
+pub fn write_json_atomic<T: serde::Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let file = fs::File::create(&tmp_path)
+        .with_context(|| format!("failed creating temporary file {:?}", tmp_path))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed moving {:?} into place", tmp_path))?;
+    Ok(())
+}
+
+/// Warn if the unauthenticated `name` server is bound on an address reachable from other hosts
+pub fn warn_if_public(name: &str, addr: &std::net::SocketAddr) {
+    if !addr.ip().is_loopback() {
+        warn!(
+            "The {} server is bound on a non-loopback address ({}) and is unauthenticated. \
+             Make sure it is not reachable from untrusted networks.",
+            name, addr
+        );
+    }
+}
+
 /// Wait for the future to resolve, blocking the current thread until it does
 #[cfg(feature = "tokio")]
 pub fn block_on_future<F: std::future::Future>(future: F) -> F::Output {
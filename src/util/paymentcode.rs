@@ -0,0 +1,130 @@
+use std::str::FromStr;
+
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChainCode, ChildNumber, ExtendedPubKey, Fingerprint};
+use bitcoin::{Address, Network, PublicKey};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorXPub};
+
+use crate::error::{Context, Error, Result};
+use crate::util::descriptor::ExtendedDescriptor;
+use crate::util::EC;
+
+// base58check version byte, resulting in the `PM8T` prefix
+const PAYMENT_CODE_PREFIX: u8 = 0x47;
+// the version byte followed by the 80 bytes payment code payload
+const PAYMENT_CODE_LEN: usize = 81;
+
+/// A BIP 47 reusable payment code (version 1 or 2)
+///
+/// The payment code's public key and chain code make up the extended public key of the
+/// `m/47'/coin'/account'` path. Its first child key is used for the notification address.
+/// The addresses used for receiving payments are derived via ECDH with each sender's key, which
+/// requires the payment code's private key, and cannot be watched from the payment code alone.
+#[derive(Clone)]
+pub struct PaymentCode {
+    version: u8,
+    public_key: PublicKey,
+    chain_code: ChainCode,
+    encoded: String,
+}
+
+impl_string_serializer!(PaymentCode, pc, pc.encoded);
+impl_string_deserializer!(PaymentCode);
+impl_debug_display!(PaymentCode);
+
+impl PaymentCode {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The extended public key represented by the payment code
+    pub fn xpub(&self, network: Network) -> ExtendedPubKey {
+        ExtendedPubKey {
+            network,
+            // the parent fingerprint is not known, but the depth and the (account) child number are
+            depth: 3,
+            parent_fingerprint: Fingerprint::default(),
+            child_number: ChildNumber::from_hardened_idx(0).unwrap(),
+            public_key: self.public_key,
+            chain_code: self.chain_code,
+        }
+    }
+
+    /// A non-ranged `pkh()` descriptor for the notification address
+    pub fn notification_descriptor(&self, network: Network) -> ExtendedDescriptor {
+        Descriptor::Pkh(DescriptorPublicKey::XPub(DescriptorXPub {
+            origin: None,
+            xpub: self.xpub(network),
+            derivation_path: [ChildNumber::from(0)][..].into(),
+            is_wildcard: false,
+        }))
+        .into()
+    }
+
+    pub fn notification_address(&self, network: Network) -> Address {
+        let key = self.xpub(network).ckd_pub(&*EC, 0.into()).unwrap();
+        Address::p2pkh(&key.public_key, network)
+    }
+}
+
+impl FromStr for PaymentCode {
+    type Err = Error;
+
+    fn from_str(inp: &str) -> Result<Self> {
+        let data = base58::from_check(inp).context("invalid payment code encoding")?;
+        ensure!(
+            data.len() == PAYMENT_CODE_LEN && data[0] == PAYMENT_CODE_PREFIX,
+            "invalid payment code"
+        );
+        // data[2] holds the feature bits and data[68..81] is reserved, both are unused here
+        let version = data[1];
+        ensure!(
+            version == 1 || version == 2,
+            "unsupported payment code version {}",
+            version
+        );
+        let public_key =
+            PublicKey::from_slice(&data[3..36]).context("invalid payment code public key")?;
+        let chain_code = ChainCode::from(&data[36..68]);
+
+        Ok(PaymentCode {
+            version,
+            public_key,
+            chain_code,
+            encoded: inp.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_address() {
+        // the payment codes of Alice and Bob from the BIP 47 test vectors
+        let vectors = [
+            ("PM8TJTLJbPRGxSbc8EJi42Wrr6QbNSaSSVJ5Y3E4pbCYiTHUskHg13935Ubb7q8tx9GVbh2UuRnBc3WSyJHhUrw8KhprKnn9eDznYGieTzFcwQRya4GA", "1JDdmqFLhpzcUwPeinhJbUPw4Co3aWLyzW"),
+            ("PM8TJS2JxQ5ztXUpBBRnpTbcUXbUHy2T1abfrb3KkAAtMEGNbey4oumH7Hc578WgQJhPjBxteQ5GHHToTYHE3A1w6p7tU6KSoFmWBVbFGjKPisZDbP97", "1ChvUUvht2hUQufHBXF8NgLhW8SwE2ecGV"),
+        ];
+        for (pc_str, address) in &vectors {
+            let pc: PaymentCode = pc_str.parse().unwrap();
+            assert_eq!(pc.version(), 1);
+            assert_eq!(pc.to_string(), *pc_str);
+            assert_eq!(
+                pc.notification_address(Network::Bitcoin).to_string(),
+                *address
+            );
+            assert_eq!(
+                pc.notification_descriptor(Network::Bitcoin)
+                    .address(Network::Bitcoin)
+                    .unwrap()
+                    .to_string(),
+                *address
+            );
+        }
+
+        // an xpub is not a payment code
+        assert!("xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL".parse::<PaymentCode>().is_err());
+    }
+}
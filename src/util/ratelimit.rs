@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// how often the buckets of idle clients get pruned. a bucket refills completely within a minute,
+// so clients idle for that long have nothing worth keeping.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket allowing bursts of up to `per_minute` requests, refilled gradually at a rate
+/// of `per_minute` tokens per minute
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u32) -> Self {
+        TokenBucket {
+            capacity: per_minute as f64,
+            tokens: per_minute as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket. Returns false if there are none left.
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated_at = now;
+    }
+}
+
+/// A rate limiter keeping a separate token bucket for every key (e.g. the client IP address)
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    per_minute: u32,
+    buckets: Mutex<Buckets<K>>,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    map: HashMap<K, TokenBucket>,
+    pruned_at: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for a request made by `key`. Returns false if it exceeds the rate limit.
+    pub fn check(&self, key: K) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.pruned_at.elapsed() >= PRUNE_INTERVAL {
+            buckets.map.retain(|_, bucket| !bucket.is_full());
+            buckets.pruned_at = Instant::now();
+        }
+        let per_minute = self.per_minute;
+        buckets
+            .map
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(per_minute))
+            .try_take()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().map.len()
+    }
+}
+
+/// The rate limiting key for a client IP address. IPv6 clients are keyed by their /64 prefix,
+/// which is typically assigned to a single host or subscriber, so that they can't evade the
+/// limit by rotating through their addresses. IPv4-mapped IPv6 addresses (seen by dual-stack
+/// listeners) are keyed by their IPv4 address.
+pub fn client_ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ipv6) => {
+            let s = ipv6.segments();
+            if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                return IpAddr::V4(ipv6.to_ipv4().unwrap());
+            }
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(3);
+        for _ in 0..3 {
+            assert!(limiter.check("a"));
+        }
+        assert!(!limiter.check("a"));
+        // every key gets its own bucket
+        assert!(limiter.check("b"));
+
+        let mut bucket = TokenBucket::new(0);
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn test_rate_limiter_prune() {
+        let limiter = RateLimiter::new(3);
+        limiter.check("a");
+        limiter.check("b");
+        assert_eq!(limiter.len(), 2);
+
+        // pretend the last prune was long ago and that "a" was idle long enough to refill
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            buckets.pruned_at -= PRUNE_INTERVAL;
+            buckets.map.get_mut("a").unwrap().updated_at -= PRUNE_INTERVAL;
+        }
+        assert!(limiter.check("c"));
+        assert_eq!(limiter.len(), 2);
+        assert!(limiter.buckets.lock().unwrap().map.get("a").is_none());
+    }
+
+    #[test]
+    fn test_client_ip_key() {
+        let key = |s: &str| client_ip_key(s.parse().unwrap()).to_string();
+        assert_eq!(key("192.0.2.1"), "192.0.2.1");
+        assert_eq!(key("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:2:ffff::1"), "2001:db8:1:2::");
+        assert_ne!(key("2001:db8:1:3::1"), key("2001:db8:1:2::1"));
+    }
+}
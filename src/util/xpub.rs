@@ -8,8 +8,9 @@ use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerpr
 use bitcoin::{util::base58, Address, Network};
 use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorXPub};
 
+use crate::error::{Context, Result};
 use crate::types::ScriptType;
-use crate::util::descriptor::ExtendedDescriptor;
+use crate::util::descriptor::{DescriptorChecksum, ExtendedDescriptor};
 use crate::util::{BoolThen, EC};
 
 pub fn xpub_matches_network(xpub: &ExtendedPubKey, network: Network) -> bool {
@@ -17,6 +18,14 @@ pub fn xpub_matches_network(xpub: &ExtendedPubKey, network: Network) -> bool {
     xpub.network == network || (xpub.network == Network::Testnet && network == Network::Regtest)
 }
 
+// testnet and regtest share the same bip32 version bytes, so they cannot be told apart
+pub fn xpub_network_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "mainnet",
+        Network::Testnet | Network::Regtest => "testnet/regtest",
+    }
+}
+
 /// An extended public key with an associated script type.
 /// Used to represent SLIP 32 [xyz]pubs, as well as simple p2*pkh descriptors.
 #[derive(Clone)]
@@ -31,6 +40,23 @@ impl_debug_display!(XyzPubKey);
 #[derive(Clone, Debug)]
 pub struct Bip32Origin(pub Fingerprint, pub DerivationPath);
 
+/// The interpretation of an [xyz]pub string, as returned by `inspect_xyzpub()`
+#[derive(Serialize, Debug, Clone)]
+pub struct XyzPubInfo {
+    /// The key re-encoded with the standard xpub/tpub version bytes
+    pub xpub: String,
+    pub script_type: ScriptType,
+    /// The BIP of the derivation scheme implied by the script type (44, 49 or 84)
+    pub bip: u32,
+    pub network: &'static str,
+    /// Whether the key is usable with the configured network
+    pub matches_network: bool,
+    /// The receive and change descriptors that get tracked for the key when used with `--xpub`
+    pub descriptors: Vec<String>,
+    /// The first receive address, for comparing against the one shown by the originating wallet
+    pub first_address: Address,
+}
+
 impl XyzPubKey {
     pub fn as_descriptor(&self, derivation_path: DerivationPath) -> ExtendedDescriptor {
         let bip32_origin = (self.xpub.depth > 0).do_then(|| {
@@ -52,11 +78,27 @@ impl XyzPubKey {
             ScriptType::P2wpkh => Descriptor::Wpkh(desc_key),
             ScriptType::P2shP2wpkh => Descriptor::ShWpkh(desc_key),
         }
+        .into()
+    }
+
+    pub fn network(&self) -> Network {
+        self.xpub.network
+    }
+
+    pub fn matches_network(&self, network: Network) -> bool {
+        xpub_matches_network(&self.xpub, network)
+    }
+
+    /// Encode using the SLIP 132 version bytes of its script type (as a ypub/zpub/upub/vpub
+    /// or as a regular xpub/tpub for p2pkh), matching the form it was originally provided in
+    pub fn to_slip132_string(&self) -> String {
+        let version = get_xyz_version(self.xpub.network, self.script_type);
+        base58::check_encode_slice(&encode_xpub(&self.xpub, version))
     }
 
     /// Convert simple p2*pkh ranged descriptors to their XyzPubKey representation
     pub fn try_from_desc(desc: &ExtendedDescriptor) -> Option<Self> {
-        let (script_type, desc_xpub) = match desc {
+        let (script_type, desc_xpub) = match &**desc {
             Descriptor::Pkh(DescriptorPublicKey::XPub(xpub)) => (ScriptType::P2pkh, xpub),
             Descriptor::Wpkh(DescriptorPublicKey::XPub(xpub)) => (ScriptType::P2wpkh, xpub),
             Descriptor::ShWpkh(DescriptorPublicKey::XPub(xpub)) => (ScriptType::P2shP2wpkh, xpub),
@@ -89,6 +131,49 @@ impl XyzPubKey {
     }
 }
 
+/// Parse an [xyz]pub string and report how it gets interpreted: the normalized xpub, its script
+/// type and network, and the descriptors and addresses derived from it.
+pub fn inspect_xyzpub(s: &str, network: Network) -> Result<XyzPubInfo> {
+    let s = s.trim();
+    let xyzpub: XyzPubKey = s
+        .parse()
+        .with_context(|| format!("Invalid extended public key {}", s))?;
+
+    let bip = match xyzpub.script_type {
+        ScriptType::P2pkh => 44,
+        ScriptType::P2shP2wpkh => 49,
+        ScriptType::P2wpkh => 84,
+    };
+    let receive_desc = xyzpub.as_descriptor([0.into()][..].into());
+    let change_desc = xyzpub.as_descriptor([1.into()][..].into());
+    let matches_network = xpub_matches_network(&xyzpub.xpub, network);
+    // encode the address for the key's own network. the configured one is used when they match,
+    // to get the regtest address prefix for testnet-versioned keys.
+    let address_network = if matches_network {
+        network
+    } else {
+        xyzpub.xpub.network
+    };
+    // cannot fail, p2*pkh descriptors always have an address representation
+    let first_address = receive_desc
+        .derive(0.into())
+        .address(address_network)
+        .unwrap();
+
+    Ok(XyzPubInfo {
+        xpub: xyzpub.xpub.to_string(),
+        script_type: xyzpub.script_type,
+        bip,
+        network: xpub_network_name(xyzpub.xpub.network),
+        matches_network,
+        descriptors: vec![
+            receive_desc.to_string_with_checksum(),
+            change_desc.to_string_with_checksum(),
+        ],
+        first_address,
+    })
+}
+
 impl FromStr for XyzPubKey {
     type Err = base58::Error;
 
@@ -166,25 +251,76 @@ impl serde::Serialize for Bip32Origin {
     }
 }
 
-fn parse_xyz_version(version: &[u8]) -> StdResult<(Network, ScriptType), base58::Error> {
-    Ok(match version {
-        [0x04u8, 0x88, 0xB2, 0x1E] => (Network::Bitcoin, ScriptType::P2pkh),
-        [0x04u8, 0xB2, 0x47, 0x46] => (Network::Bitcoin, ScriptType::P2wpkh),
-        [0x04u8, 0x9D, 0x7C, 0xB2] => (Network::Bitcoin, ScriptType::P2shP2wpkh),
+// The SLIP 132 version bytes for every network and script type. Regtest uses the testnet ones.
+const XYZ_VERSIONS: &[([u8; 4], Network, ScriptType)] = &[
+    (
+        [0x04, 0x88, 0xB2, 0x1E],
+        Network::Bitcoin,
+        ScriptType::P2pkh,
+    ),
+    (
+        [0x04, 0xB2, 0x47, 0x46],
+        Network::Bitcoin,
+        ScriptType::P2wpkh,
+    ),
+    (
+        [0x04, 0x9D, 0x7C, 0xB2],
+        Network::Bitcoin,
+        ScriptType::P2shP2wpkh,
+    ),
+    (
+        [0x04, 0x35, 0x87, 0xCF],
+        Network::Testnet,
+        ScriptType::P2pkh,
+    ),
+    (
+        [0x04, 0x5F, 0x1C, 0xF6],
+        Network::Testnet,
+        ScriptType::P2wpkh,
+    ),
+    (
+        [0x04, 0x4A, 0x52, 0x62],
+        Network::Testnet,
+        ScriptType::P2shP2wpkh,
+    ),
+];
 
-        [0x04u8, 0x35, 0x87, 0xCF] => (Network::Testnet, ScriptType::P2pkh),
-        [0x04u8, 0x5F, 0x1C, 0xF6] => (Network::Testnet, ScriptType::P2wpkh),
-        [0x04u8, 0x4A, 0x52, 0x62] => (Network::Testnet, ScriptType::P2shP2wpkh),
+fn parse_xyz_version(version: &[u8]) -> StdResult<(Network, ScriptType), base58::Error> {
+    XYZ_VERSIONS
+        .iter()
+        .find(|(v, _, _)| v[..] == *version)
+        .map(|(_, network, script_type)| (*network, *script_type))
+        .ok_or_else(|| base58::Error::InvalidVersion(version.to_vec()))
+}
 
-        _ => return Err(base58::Error::InvalidVersion(version.to_vec())),
-    })
+fn get_xyz_version(network: Network, script_type: ScriptType) -> [u8; 4] {
+    let network = match network {
+        Network::Regtest => Network::Testnet,
+        network => network,
+    };
+    // cannot fail, the table has an entry for every network and script type combination
+    XYZ_VERSIONS
+        .iter()
+        .find(|(_, n, s)| *n == network && *s == script_type)
+        .unwrap()
+        .0
 }
 
 fn get_xpub_p2pkh_version(network: Network) -> [u8; 4] {
-    match network {
-        Network::Bitcoin => [0x04u8, 0x88, 0xB2, 0x1E],
-        Network::Testnet | Network::Regtest => [0x04u8, 0x35, 0x87, 0xCF],
-    }
+    get_xyz_version(network, ScriptType::P2pkh)
+}
+
+// Serialize the xpub in its BIP 32 format with the given version bytes. Equivalent to the
+// encoding done by rust-bitcoin's Display implementation, which always uses the p2pkh ones.
+fn encode_xpub(xpub: &ExtendedPubKey, version: [u8; 4]) -> [u8; 78] {
+    let mut data = [0; 78];
+    data[0..4].copy_from_slice(&version);
+    data[4] = xpub.depth;
+    data[5..9].copy_from_slice(&xpub.parent_fingerprint[..]);
+    data[9..13].copy_from_slice(&u32::from(xpub.child_number).to_be_bytes());
+    data[13..45].copy_from_slice(&xpub.chain_code[..]);
+    data[45..78].copy_from_slice(&xpub.public_key.key.serialize());
+    data
 }
 
 #[cfg(test)]
@@ -216,6 +352,7 @@ mod tests {
             assert_eq!(desc.to_string(), *expected_desc);
             assert_eq!(xyzpub_rt.xpub, xyzpub.xpub);
             assert_eq!(xyzpub_rt.script_type, xyzpub.script_type);
+            assert_eq!(xyzpub.to_slip132_string(), *xyz_str);
 
             let address = xyzpub.derive_address(9, net);
             assert_eq!(desc.derive(9.into()).address(net).unwrap(), address);
@@ -223,6 +360,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_inspect_xyzpub() {
+        let zpub = "zpub6jftahH18ngZwwDgquxFKyv4bUWuqfwm2xtt4yt7Ek53uFigQNhhrT1EgGDZWXJBZ2dV2nyr5oesnRoUsuVz72hBc5C2YDzXuKFsrTu7JHp";
+        let info = inspect_xyzpub(zpub, Network::Bitcoin).unwrap();
+        assert_eq!(info.xpub, "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC");
+        assert_eq!(info.script_type, ScriptType::P2wpkh);
+        assert_eq!(info.bip, 84);
+        assert_eq!(info.network, "mainnet");
+        assert!(info.matches_network);
+        assert!(info.descriptors[0].starts_with("wpkh(xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC/0/*)#"));
+
+        let desc: ExtendedDescriptor = info.descriptors[0]
+            .split('#')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            desc.derive(0.into()).address(Network::Bitcoin).unwrap(),
+            info.first_address
+        );
+
+        // the first address is encoded for the key's own network, even if it mismatches
+        let mismatched = inspect_xyzpub(zpub, Network::Testnet).unwrap();
+        assert!(!mismatched.matches_network);
+        assert_eq!(mismatched.first_address, info.first_address);
+        assert!(inspect_xyzpub("zpub123", Network::Bitcoin).is_err());
+    }
+
     // Test descriptor -> xyzpub -> descriptor roundtrip
     #[test]
     fn test_desc_to_xpub_conversion() {
@@ -1,89 +1,156 @@
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::cmp;
+use std::collections::Bound;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
 use bitcoin::util::bip32::ChildNumber;
-use bitcoin::{Address, Network};
+use bitcoin::{Address, Network, OutPoint, Txid};
 use bitcoincore_rpc::json::{ImportMultiRequest, ImportMultiRequestScriptPubkey};
-use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+use bitcoincore_rpc::{self as rpc, RpcApi};
 
-use crate::error::{Context, Result};
-use crate::store::MemoryStore;
-use crate::types::RescanSince;
-use crate::util::descriptor::{Checksum, DescKeyInfo, ExtendedDescriptor};
-use crate::util::xpub::{Bip32Origin, XyzPubKey};
+use crate::error::{Context, Error, OptionExt, Result};
+use crate::store::{FundingInfo, HistoryEntry, MemoryStore, SpendingInfo};
+use crate::types::{RescanSince, ScriptHash, TxStatus};
+use crate::util::bitcoincore_ext::{RpcClient, WalletRpcs};
+use crate::util::descriptor::{Checksum, DescKeyInfo, DescriptorChecksum, ExtendedDescriptor};
+use crate::util::paymentcode::PaymentCode;
+use crate::util::xpub::{xpub_network_name, Bip32Origin, XyzPubKey};
 
 const LABEL_PREFIX: &str = "bwt";
+// prepended to the labels of wallets that are no longer tracked, so that they don't get picked up
+const ORPHANED_LABEL_PREFIX: &str = "orphaned";
 
-#[derive(Debug)]
+// the maximum number of addresses imported in a single `importmulti`/`importdescriptors` call
+const IMPORT_CHUNK_SIZE: usize = 1000;
+
+// wallets are considered at risk of missing funds once their gap headroom or lookahead drops
+// below 1/GAP_RISK_RATIO of the gap limit
+const GAP_RISK_RATIO: u32 = 4;
+
+#[derive(Debug, Clone)]
 pub struct WalletWatcher {
     wallets: HashMap<Checksum, Wallet>,
+    // reverse index of the scripthashes within the imported range of each wallet (including unfunded
+    // ones), along with the max index that was added to it for each wallet
+    watched_scripthashes: HashMap<ScriptHash, KeyOrigin>,
+    watched_indexes: HashMap<Checksum, u32>,
+    // shared with the watchers of fresh indexes created during reorgs
+    progress: Arc<SyncProgress>,
+}
+
+/// The progress of the address imports and index sync, shared outside of the index lock so that
+/// it can be read while a (potentially long) import is running
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+    initial_import_done: AtomicBool,
+    // the number of addresses imported so far out of the total in the current import batch
+    imported: AtomicUsize,
+    total: AtomicUsize,
+    tip_height: RwLock<Option<u32>>,
+    // set while the index is being rebuilt from scratch following a deep reorg
+    reindexing: AtomicBool,
 }
 
 impl WalletWatcher {
-    pub fn new(wallets: Vec<Wallet>) -> Result<Self> {
-        let num_wallets = wallets.len();
-        let wallets = wallets
-            .into_iter()
-            .map(|wallet| (wallet.checksum.clone(), wallet))
-            .collect::<HashMap<_, _>>();
-        ensure!(
-            wallets.len() == num_wallets,
-            "Descriptor checksum collision detected"
-        );
-        Ok(Self { wallets })
+    /// Create a watcher for the wallets, keyed by their descriptor checksum. Wallets with the same
+    /// checksum are assumed to be duplicates (e.g. an xpub also provided as a descriptor) and ignored.
+    /// With `verify_full_desc`, this is verified using a hash of their full descriptor, rejecting
+    /// distinct descriptors with colliding checksums.
+    pub fn new(wallets: Vec<Wallet>, verify_full_desc: bool) -> Result<Self> {
+        let mut by_checksum: HashMap<Checksum, Wallet> = HashMap::new();
+        for wallet in wallets {
+            if let Some(existing) = by_checksum.get(&wallet.checksum) {
+                // the labels only carry the short checksum, so colliding wallets cannot be told apart
+                ensure!(
+                    !verify_full_desc || existing.desc.full_hash() == wallet.desc.full_hash(),
+                    "Descriptor checksum collision detected between distinct descriptors `{}` and `{}`",
+                    existing.desc,
+                    wallet.desc
+                );
+                warn!("ignoring duplicated descriptor {}", wallet.desc);
+                continue;
+            }
+            by_checksum.insert(wallet.checksum.clone(), wallet);
+        }
+        Ok(Self {
+            wallets: by_checksum,
+            watched_scripthashes: HashMap::new(),
+            watched_indexes: HashMap::new(),
+            progress: Arc::new(SyncProgress::default()),
+        })
     }
 
+    /// Create the watcher from the configured wallets. The global `gap_limit` and `initial_import_size`
+    /// apply to wallets that don't specify their own.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_config(
-        descs: &[(ExtendedDescriptor, RescanSince)],
-        xpubs: &[(XyzPubKey, RescanSince)],
-        bare_xpubs: &[(XyzPubKey, RescanSince)],
+        descs: &[(ExtendedDescriptor, WalletOptions)],
+        xpubs: &[(XyzPubKey, WalletOptions)],
+        bare_xpubs: &[(XyzPubKey, WalletOptions)],
+        payment_codes: &[(PaymentCode, WalletOptions)],
         network: Network,
         gap_limit: u32,
         initial_import_size: u32,
+        verify_full_desc: bool,
     ) -> Result<Self> {
         let mut wallets = vec![];
-        for (desc, rescan) in descs {
+        for (desc, opt) in descs {
             wallets.push(
                 Wallet::from_descriptor(
                     desc.clone(),
                     network,
-                    gap_limit,
-                    initial_import_size,
-                    *rescan,
+                    opt.gap_limit.unwrap_or(gap_limit),
+                    opt.initial_import_size.unwrap_or(initial_import_size),
+                    opt.rescan,
                 )
-                .with_context(|| format!("invalid descriptor {}", desc))?,
+                .with_context(|| format!("invalid descriptor {}", desc))?
+                .with_bitcoind_wallet(opt.bitcoind_wallet.clone()),
             );
         }
-        for (xpub, rescan) in xpubs {
-            wallets.append(
-                &mut Wallet::from_xpub(
+        for (xpub, opt) in xpubs {
+            wallets.extend(
+                Wallet::from_xpub(
                     xpub.clone(),
                     network,
-                    gap_limit,
-                    initial_import_size,
-                    *rescan,
+                    opt.gap_limit.unwrap_or(gap_limit),
+                    opt.initial_import_size.unwrap_or(initial_import_size),
+                    opt.rescan,
                 )
-                .with_context(|| format!("invalid xpub {}", xpub))?,
+                .with_context(|| format!("invalid xpub {}", xpub))?
+                .into_iter()
+                .map(|wallet| wallet.with_bitcoind_wallet(opt.bitcoind_wallet.clone())),
             );
         }
-        for (xpub, rescan) in bare_xpubs {
+        for (xpub, opt) in bare_xpubs {
             wallets.push(
                 Wallet::from_bare_xpub(
                     xpub.clone(),
                     network,
-                    gap_limit,
-                    initial_import_size,
-                    *rescan,
+                    opt.gap_limit.unwrap_or(gap_limit),
+                    opt.initial_import_size.unwrap_or(initial_import_size),
+                    opt.rescan,
                 )
-                .with_context(|| format!("invalid xpub {}", xpub))?,
+                .with_context(|| format!("invalid xpub {}", xpub))?
+                .with_bitcoind_wallet(opt.bitcoind_wallet.clone()),
+            );
+        }
+        for (payment_code, opt) in payment_codes {
+            wallets.push(
+                Wallet::from_payment_code(payment_code, network, opt.rescan)
+                    .with_context(|| format!("invalid payment code {}", payment_code))?
+                    .with_bitcoind_wallet(opt.bitcoind_wallet.clone()),
             );
         }
         if wallets.is_empty() {
-            error!("Please provide at least one wallet to track (via --descriptor, --xpub or --bare-xpub).");
+            error!("Please provide at least one wallet to track (via --descriptor, --xpub, --bare-xpub or --payment-code).");
             bail!("no xpubs provided");
         }
-        Self::new(wallets)
+        Self::new(wallets, verify_full_desc)
     }
 
     pub fn wallets(&self) -> &HashMap<Checksum, Wallet> {
@@ -94,73 +161,294 @@ impl WalletWatcher {
         self.wallets.get(checksum)
     }
 
+    pub fn progress(&self) -> &Arc<SyncProgress> {
+        &self.progress
+    }
+
+    /// Get the group of related wallets that the wallet belongs to, which can be queried as one
+    pub fn get_group(&self, checksum: &Checksum) -> Option<WalletGroup> {
+        let wallet = self.wallets.get(checksum)?;
+        let checksums = match &wallet.xpub_chain {
+            Some((XpubChain::Receive, sibling)) => vec![checksum.clone(), sibling.clone()],
+            Some((XpubChain::Change, sibling)) => vec![sibling.clone(), checksum.clone()],
+            None => vec![checksum.clone()],
+        };
+        Some(WalletGroup { checksums })
+    }
+
+    /// Get the origin of a scripthash within the imported range of one of the wallets,
+    /// regardless of whether it has any history
+    pub fn get_watched_origin(&self, scripthash: &ScriptHash) -> Option<&KeyOrigin> {
+        self.watched_scripthashes.get(scripthash)
+    }
+
+    /// Get the origin of an address reported by bitcoind, using its "bwt/..." label when it has
+    /// one, or the watched range for ones imported without a label (via ranged descriptors)
+    pub fn find_origin(&self, address: &Address, label: Option<&str>) -> Option<KeyOrigin> {
+        // XXX we assume that any address with a "bwt/..." label is ours, this may not necessarily be true.
+        label
+            .and_then(KeyOrigin::from_label)
+            .or_else(|| self.get_watched_origin(&address.into()).cloned())
+    }
+
+    // Extend the reverse index of watched scripthashes to cover the wallet's imported range
+    fn index_watched_range(&mut self, checksum: &Checksum) {
+        let wallet = some_or_ret!(self.wallets.get(checksum));
+        let max_imported_index = some_or_ret!(wallet.max_imported_index);
+        let start_index = self.watched_indexes.get(checksum).map_or(0, |max| max + 1);
+
+        if start_index <= max_imported_index {
+            for index in start_index..=max_imported_index {
+                let scripthash = ScriptHash::from(&wallet.derive_address(index));
+                let origin = KeyOrigin::Descriptor(checksum.clone(), index);
+                self.watched_scripthashes.insert(scripthash, origin);
+            }
+            self.watched_indexes
+                .insert(checksum.clone(), max_imported_index);
+        }
+    }
+
+    /// Get the next unused derivation index of the wallet and reserve it, so that it won't be
+    /// handed out again before receiving funds. Returns None if the wallet does not exist.
+    pub fn reserve_next_index(
+        &mut self,
+        checksum: &Checksum,
+        store: &MemoryStore,
+    ) -> Result<Option<u32>> {
+        let wallet = some_or_ret!(self.wallets.get_mut(checksum), Ok(None));
+        let index = wallet.next_unused_index(store)?;
+        if wallet.is_ranged {
+            wallet.max_reserved_index = Some(index);
+        }
+        Ok(Some(index))
+    }
+
+    /// Schedule the wallet (along with the other wallets in its group) to be re-imported into
+    /// bitcoind with a rescan starting from `rescan_since`, to be processed by the next `do_imports`.
+    /// Returns None if the wallet does not exist.
+    pub fn request_rescan(&mut self, checksum: &Checksum, rescan_since: RescanSince) -> Option<()> {
+        for checksum in self.get_group(checksum)?.checksums {
+            if let Some(wallet) = self.wallets.get_mut(&checksum) {
+                info!("scheduled {} for rescan since {:?}", checksum, rescan_since);
+                wallet.pending_rescan = Some(rescan_since);
+            }
+        }
+        Some(())
+    }
+
+    /// Clone the watcher for rebuilding the index from scratch. The pending rescans are handed over
+    /// to the clone, to be processed by its initial sync.
+    pub fn fork(&mut self) -> WalletWatcher {
+        let forked = self.clone();
+        for wallet in self.wallets.values_mut() {
+            wallet.pending_rescan = None;
+        }
+        forked
+    }
+
+    /// Merge back the state of a watcher returned by `fork()`, keeping the changes made to this one
+    /// in the meanwhile (reserved indexes and rescan requests)
+    pub fn merge(&mut self, forked: WalletWatcher) {
+        for (checksum, forked_wallet) in forked.wallets {
+            let wallet = match self.wallets.get_mut(&checksum) {
+                Some(wallet) => wallet,
+                None => continue,
+            };
+            // `None` orders before any `Some` index
+            wallet.max_funded_index = wallet.max_funded_index.max(forked_wallet.max_funded_index);
+            wallet.max_imported_index = wallet
+                .max_imported_index
+                .max(forked_wallet.max_imported_index);
+            wallet.max_reserved_index = wallet
+                .max_reserved_index
+                .max(forked_wallet.max_reserved_index);
+            wallet.done_initial_import |= forked_wallet.done_initial_import;
+            // rescans requested during the rebuild take precedence over the ones it failed to process
+            wallet.pending_rescan = wallet.pending_rescan.or(forked_wallet.pending_rescan);
+            self.index_watched_range(&checksum);
+        }
+    }
+
+    /// The max funded derivation index of each wallet, if any
+    pub fn funded_indexes(&self) -> HashMap<Checksum, Option<u32>> {
+        self.wallets
+            .iter()
+            .map(|(checksum, wallet)| (checksum.clone(), wallet.max_funded_index))
+            .collect()
+    }
+
     // Mark an address as funded
     pub fn mark_funded(&mut self, origin: &KeyOrigin) {
+        if let KeyOrigin::Descriptor(checksum, index) = origin {
+            self.mark_imported(origin);
+
+            if let Some(wallet) = self.wallets.get_mut(checksum) {
+                if wallet.max_funded_index.map_or(true, |max| *index > max) {
+                    wallet.max_funded_index = Some(*index);
+                }
+            }
+            self.index_watched_range(checksum);
+        }
+    }
+
+    // Mark an address as imported
+    fn mark_imported(&mut self, origin: &KeyOrigin) {
         if let KeyOrigin::Descriptor(checksum, index) = origin {
             if let Some(wallet) = self.wallets.get_mut(checksum) {
                 if wallet.max_imported_index.map_or(true, |max| *index > max) {
                     wallet.max_imported_index = Some(*index);
                 }
+            }
+        }
+    }
 
-                if wallet.max_funded_index.map_or(true, |max| *index > max) {
-                    wallet.max_funded_index = Some(*index);
+    // Detect the labels of wallets that are no longer tracked (e.g. after editing a descriptor, which
+    // changes its checksum), and optionally relabel them so that bwt stops picking them up.
+    pub fn check_orphaned_labels(&self, rpcs: &WalletRpcs, relabel: bool) -> Result<()> {
+        for rpc in rpcs.all() {
+            self.check_orphaned_labels_in(rpc, relabel)?;
+        }
+        Ok(())
+    }
+
+    fn check_orphaned_labels_in(&self, rpc: &RpcClient, relabel: bool) -> Result<()> {
+        let labels: Vec<String> = rpc.call("listlabels", &[]).map_err(labels_error)?;
+        let mut orphaned: HashMap<Checksum, Vec<String>> = HashMap::new();
+        for label in labels {
+            if let Some(KeyOrigin::Descriptor(checksum, _)) = KeyOrigin::from_label(&label) {
+                if !self.wallets.contains_key(&checksum) {
+                    orphaned.entry(checksum).or_default().push(label);
+                }
+            }
+        }
+
+        for (checksum, labels) in orphaned {
+            if !relabel {
+                warn!(
+                    "found {} bitcoind addresses labeled for wallet {}, which is no longer tracked. \
+                     Set --relabel-orphaned to relabel them.",
+                    labels.len(),
+                    checksum
+                );
+                continue;
+            }
+
+            info!(
+                "relabeling {} addresses of orphaned wallet {}",
+                labels.len(),
+                checksum
+            );
+            for label in labels {
+                let addresses: HashMap<String, Value> =
+                    rpc.call("getaddressesbylabel", &[json!(label)])?;
+                let orphaned_label = format!("{}/{}", ORPHANED_LABEL_PREFIX, label);
+                for address in addresses.keys() {
+                    rpc.call::<Value>("setlabel", &[json!(address), json!(orphaned_label)])?;
                 }
             }
         }
+        Ok(())
     }
 
     // check previous imports and update max_imported_index
-    pub fn check_imports(&mut self, rpc: &RpcClient) -> Result<()> {
+    pub fn check_imports(&mut self, rpcs: &WalletRpcs) -> Result<()> {
         debug!("checking previous imports");
+        for rpc in rpcs.all() {
+            self.check_imports_in(rpcs, rpc)?;
+        }
+        Ok(())
+    }
+
+    // check the previous imports into one of the bitcoind wallets
+    fn check_imports_in(&mut self, rpcs: &WalletRpcs, rpc: &Arc<RpcClient>) -> Result<()> {
         let labels: Vec<String> = rpc.call("listlabels", &[]).map_err(labels_error)?;
-        let mut imported_indexes: HashMap<Checksum, u32> = HashMap::new();
-        for label in labels {
-            if let Some(KeyOrigin::Descriptor(checksum, index)) = KeyOrigin::from_label(&label) {
-                if self.wallets.contains_key(&checksum) {
-                    imported_indexes
-                        .entry(checksum)
-                        .and_modify(|current| *current = (*current).max(index))
-                        .or_insert(index);
-                }
+        let mut imported_indexes = get_imported_indexes(&labels);
+
+        // ranged wallets are imported into descriptor wallets without labels, get their range instead
+        if is_descriptor_wallet(rpc)? {
+            for (checksum, max_index) in get_imported_ranges(&list_descriptors(rpc)?) {
+                let imported_index = imported_indexes.entry(checksum).or_insert(max_index);
+                *imported_index = (*imported_index).max(max_index);
             }
         }
 
+        self.apply_imported_indexes(rpcs, rpc, imported_indexes);
+        Ok(())
+    }
+
+    // update the wallets with the indexes found imported into the bitcoind wallet of `rpc`
+    fn apply_imported_indexes(
+        &mut self,
+        rpcs: &WalletRpcs,
+        rpc: &Arc<RpcClient>,
+        imported_indexes: HashMap<Checksum, u32>,
+    ) {
         for (checksum, max_imported_index) in imported_indexes {
+            let wallet = match self.wallets.get_mut(&checksum) {
+                // only consider the imports made into the bitcoind wallet the wallet belongs to
+                Some(wallet) if Arc::ptr_eq(rpcs.get(wallet.bitcoind_wallet()), rpc) => wallet,
+                _ => continue,
+            };
             trace!(
                 "wallet {} was imported up to index {}",
                 checksum,
                 max_imported_index
             );
-            let wallet = self.wallets.get_mut(&checksum).unwrap();
             wallet.max_imported_index = Some(max_imported_index);
 
             // if anything was imported at all, assume we've finished the initial sync. this might
             // not hold true if bwt shuts down while syncing, but this only means that we'll use
             // the smaller gap_limit instead of the initial_import_size, which is acceptable.
             wallet.done_initial_import = true;
+
+            self.index_watched_range(&checksum);
         }
-        Ok(())
     }
 
-    pub fn do_imports(&mut self, rpc: &RpcClient, rescan: bool) -> Result<bool> {
-        let mut import_reqs = vec![];
+    /// Get the imports that the next `do_imports()` would make for each wallet, without importing
+    /// anything. Wallets that are already imported up to their watch index are included with no range.
+    pub fn plan_imports(&self, rescan: bool) -> Vec<PlannedImport> {
+        let mut plan: Vec<PlannedImport> = self
+            .wallets
+            .iter()
+            .map(|(checksum, wallet)| {
+                let next_import = wallet.next_import(rescan);
+                PlannedImport {
+                    checksum: checksum.clone(),
+                    desc: wallet.desc.to_string_with_checksum(),
+                    is_ranged: wallet.is_ranged,
+                    max_imported_index: wallet.max_imported_index,
+                    import_range: next_import.map(|(start, end, _)| (start, end)),
+                    rescan_since: next_import.map(|(_, _, rescan_since)| rescan_since),
+                }
+            })
+            .collect();
+        plan.sort_by(|a, b| a.desc.cmp(&b.desc));
+        plan
+    }
+
+    pub fn do_imports(&mut self, rpcs: &WalletRpcs, rescan: bool) -> Result<bool> {
+        // the import requests grouped by the bitcoind wallet they're imported into
+        let mut import_reqs: BTreeMap<Option<String>, Vec<_>> = BTreeMap::new();
         let mut pending_updates = vec![];
+        let mut rescanned = vec![];
 
         for (checksum, wallet) in self.wallets.iter_mut() {
-            let watch_index = wallet.watch_index();
-            if wallet.max_imported_index.map_or(true, |i| watch_index > i) {
-                let start_index = wallet
-                    .max_imported_index
-                    .map_or(0, |max_imported| max_imported + 1);
-
+            if let Some((start_index, end_index, rescan_since)) = wallet.next_import(rescan) {
                 debug!(
-                    "importing {} range {}-{} with rescan={}",
-                    checksum, start_index, watch_index, rescan,
+                    "importing {} range {}-{} with rescan since {:?}",
+                    checksum, start_index, end_index, rescan_since,
                 );
 
-                import_reqs.append(&mut wallet.make_imports(start_index, watch_index, rescan));
+                import_reqs
+                    .entry(wallet.bitcoind_wallet.clone())
+                    .or_default()
+                    .append(&mut wallet.make_imports_since(start_index, end_index, rescan_since));
 
-                pending_updates.push((wallet, watch_index));
+                pending_updates.push((checksum.clone(), end_index));
+                if wallet.pending_rescan.is_some() {
+                    rescanned.push(checksum.clone());
+                }
             } else if !wallet.done_initial_import {
                 debug!(
                     "done initial import for {} up to index {}",
@@ -173,30 +461,93 @@ impl WalletWatcher {
             }
         }
 
-        let has_imports = !import_reqs.is_empty();
+        let total_imports: usize = import_reqs.values().map(Vec::len).sum();
+        let has_imports = total_imports > 0;
 
         if has_imports {
-            // TODO report syncing progress
             info!(
                 "importing batch of {} addresses... (this may take awhile)",
-                import_reqs.len()
+                total_imports
             );
-            batch_import(rpc, import_reqs)?;
+            self.progress.start_batch(total_imports);
+
+            for (bitcoind_wallet, import_reqs) in import_reqs {
+                let rpc = rpcs.get(bitcoind_wallet.as_deref());
+                let descriptor_wallet = is_descriptor_wallet(rpc)?;
+
+                // import in chunks, ordered by derivation index within each wallet, and record the progress
+                // after each one. if a chunk fails, check the labels to determine which addresses actually
+                // made it in, so that the next attempt (or the next run) resumes exactly from there.
+                for chunk in import_reqs.chunks(IMPORT_CHUNK_SIZE) {
+                    if let Err(err) = batch_import(rpc, chunk, descriptor_wallet, &self.wallets) {
+                        self.check_imports_in(rpcs, rpc)?;
+                        return Err(err);
+                    }
+                    for (_, _, label) in chunk {
+                        self.mark_imported(&KeyOrigin::from_label(label).unwrap());
+                    }
+                    self.progress.add_imported(chunk.len());
+                }
+            }
             info!("done importing batch");
         }
 
-        for (wallet, imported_index) in pending_updates {
-            debug!(
-                "imported {} up to index {}",
-                wallet.checksum, imported_index
-            );
-            wallet.max_imported_index = Some(imported_index);
+        // only cleared once the imports succeed, so that failed rescans are retried on the next run
+        for checksum in rescanned {
+            info!("done rescanning {}", checksum);
+            self.wallets.get_mut(&checksum).unwrap().pending_rescan = None;
+        }
+
+        for (checksum, imported_index) in pending_updates {
+            debug!("imported {} up to index {}", checksum, imported_index);
+            self.index_watched_range(&checksum);
         }
 
         Ok(has_imports)
     }
 }
 
+impl SyncProgress {
+    fn start_batch(&self, total: usize) {
+        self.imported.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+    }
+
+    fn add_imported(&self, count: usize) {
+        self.imported.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Record the height the index is synced up to, following the completion of the initial sync
+    pub fn set_synced(&self, tip_height: u32) {
+        *self.tip_height.write().unwrap() = Some(tip_height);
+        self.initial_import_done.store(true, Ordering::SeqCst);
+    }
+
+    pub fn initial_import_done(&self) -> bool {
+        self.initial_import_done.load(Ordering::SeqCst)
+    }
+
+    /// The number of addresses imported so far and the total in the current (or last) import batch
+    pub fn imported(&self) -> (usize, usize) {
+        (
+            self.imported.load(Ordering::SeqCst),
+            self.total.load(Ordering::SeqCst),
+        )
+    }
+
+    pub fn tip_height(&self) -> Option<u32> {
+        *self.tip_height.read().unwrap()
+    }
+
+    pub fn set_reindexing(&self, reindexing: bool) {
+        self.reindexing.store(reindexing, Ordering::SeqCst);
+    }
+
+    pub fn is_reindexing(&self) -> bool {
+        self.reindexing.load(Ordering::SeqCst)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Wallet {
     desc: ExtendedDescriptor,
@@ -205,16 +556,168 @@ pub struct Wallet {
     keys_info: Vec<DescKeyInfo>,
     network: Network,
     rescan_policy: RescanSince,
+    // a manual rescan that was requested and not yet processed
+    pending_rescan: Option<RescanSince>,
 
     gap_limit: u32,
     initial_import_size: u32,
     max_funded_index: Option<u32>,
     max_imported_index: Option<u32>,
+    // the last index handed out as the next address with reservation (kept in memory only)
+    max_reserved_index: Option<u32>,
     done_initial_import: bool,
 
     // Used for optimized derivation for simple p2*pkh descriptors.
     // Not available for more complex descriptor types.
     optimized_xpub: Option<XyzPubKey>,
+
+    // For wallets created from a bare xpub, the chain this wallet tracks and the checksum of its sibling chain
+    xpub_chain: Option<(XpubChain, Checksum)>,
+
+    // The bitcoind wallet the addresses are imported into, when different from the default one
+    bitcoind_wallet: Option<String>,
+}
+
+/// The options of a configured descriptor/xpub wallet. The gap limit and initial import size
+/// fall back to the global ones when unset, and the bitcoind wallet to the `--bitcoind-wallet` one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletOptions {
+    pub rescan: RescanSince,
+    pub gap_limit: Option<u32>,
+    pub initial_import_size: Option<u32>,
+    pub bitcoind_wallet: Option<String>,
+}
+
+impl Default for WalletOptions {
+    fn default() -> Self {
+        WalletOptions {
+            rescan: RescanSince::Timestamp(0),
+            gap_limit: None,
+            initial_import_size: None,
+            bitcoind_wallet: None,
+        }
+    }
+}
+
+impl From<RescanSince> for WalletOptions {
+    fn from(rescan: RescanSince) -> Self {
+        WalletOptions {
+            rescan,
+            ..Default::default()
+        }
+    }
+}
+
+// Deserialize from a plain rescan value (for backwards compatibility), or from an object with the
+// `rescan`, `gap_limit`, `initial_import_size` and `bitcoind_wallet` fields (all optional)
+impl<'de> Deserialize<'de> for WalletOptions {
+    fn deserialize<D>(deserializer: D) -> StdResult<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Rescan(RescanSince),
+            Options {
+                rescan: Option<RescanSince>,
+                gap_limit: Option<u32>,
+                initial_import_size: Option<u32>,
+                bitcoind_wallet: Option<String>,
+            },
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Rescan(rescan) => rescan.into(),
+            Repr::Options {
+                rescan,
+                gap_limit,
+                initial_import_size,
+                bitcoind_wallet,
+            } => WalletOptions {
+                rescan: rescan.unwrap_or(RescanSince::Timestamp(0)),
+                gap_limit,
+                initial_import_size,
+                bitcoind_wallet,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XpubChain {
+    Receive,
+    Change,
+}
+
+/// Wallets derived from the same key that make up a single logical wallet. Currently this groups
+/// together the receive and change chains of wallets created from a bare xpub (receive first).
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletGroup {
+    pub checksums: Vec<Checksum>,
+}
+
+/// A wallet transaction along with the net change it made to the wallet's balance
+#[derive(Debug, Clone)]
+pub struct WalletTxChange {
+    pub txid: Txid,
+    pub status: TxStatus,
+    pub balance_change: i64,
+    // whether the transaction spent any of the wallet's outputs
+    pub is_outgoing: bool,
+}
+
+/// The wallet balance, in satoshis
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WalletBalance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+    // coinbase outputs with less than 100 confirmations, not included in the confirmed balance
+    pub immature: u64,
+}
+
+impl std::ops::AddAssign for WalletBalance {
+    fn add_assign(&mut self, other: WalletBalance) {
+        self.confirmed += other.confirmed;
+        self.unconfirmed += other.unconfirmed;
+        self.immature += other.immature;
+    }
+}
+
+/// The addresses that would be imported for a wallet, as reported by a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedImport {
+    pub checksum: Checksum,
+    pub desc: String,
+    pub is_ranged: bool,
+    // the index previously imported up to, as recorded in the bitcoind labels
+    pub max_imported_index: Option<u32>,
+    // the inclusive range of derivation indexes to import, or None if no imports are needed
+    pub import_range: Option<(u32, u32)>,
+    pub rescan_since: Option<RescanSince>,
+}
+
+impl PlannedImport {
+    pub fn count(&self) -> u32 {
+        self.import_range.map_or(0, |(start, end)| end - start + 1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GapStats {
+    pub checksum: Checksum,
+    // the largest number of consecutive unused addresses between funded ones
+    pub max_gap: usize,
+    pub gap_limit: u32,
+    // how many more consecutive unused addresses can be tolerated before hitting the gap limit
+    pub headroom: u32,
+    pub max_funded_index: Option<u32>,
+    pub max_imported_index: Option<u32>,
+    // the number of imported addresses beyond the highest funded or handed-out one
+    pub lookahead: u32,
+    // whether the headroom or the lookahead are running low, indicating that funds sent further
+    // ahead may be missed (by bwt or by other wallets restoring with the same gap limit)
+    pub at_risk: bool,
 }
 
 impl Wallet {
@@ -246,13 +749,27 @@ impl Wallet {
             // setting initial_import_size < gap_limit makes no sense, the user probably meant to increase both
             initial_import_size: initial_import_size.max(gap_limit),
             rescan_policy,
+            pending_rescan: None,
             done_initial_import: false,
             max_funded_index: None,
             max_imported_index: None,
+            max_reserved_index: None,
             optimized_xpub,
+            xpub_chain: None,
+            bitcoind_wallet: None,
         })
     }
 
+    /// Set the bitcoind wallet the addresses get imported into, instead of the default one
+    pub fn with_bitcoind_wallet(mut self, bitcoind_wallet: Option<String>) -> Self {
+        self.bitcoind_wallet = bitcoind_wallet;
+        self
+    }
+
+    pub fn bitcoind_wallet(&self) -> Option<&str> {
+        self.bitcoind_wallet.as_deref()
+    }
+
     pub fn from_bare_xpub(
         xpub: XyzPubKey,
         network: Network,
@@ -260,6 +777,7 @@ impl Wallet {
         initial_import_size: u32,
         rescan_policy: RescanSince,
     ) -> Result<Self> {
+        ensure_xpub_network(&xpub, network)?;
         Self::from_descriptor(
             xpub.as_descriptor([][..].into()),
             network,
@@ -269,6 +787,23 @@ impl Wallet {
         )
     }
 
+    /// Track the notification address of a BIP 47 payment code. The payment addresses cannot be
+    /// derived without the private key and are not tracked.
+    pub fn from_payment_code(
+        payment_code: &PaymentCode,
+        network: Network,
+        rescan_policy: RescanSince,
+    ) -> Result<Self> {
+        // the notification descriptor is non-ranged, making the gap limit and import size irrelevant
+        Self::from_descriptor(
+            payment_code.notification_descriptor(network),
+            network,
+            0,
+            0,
+            rescan_policy,
+        )
+    }
+
     pub fn from_xpub(
         xpub: XyzPubKey,
         network: Network,
@@ -276,24 +811,26 @@ impl Wallet {
         initial_import_size: u32,
         rescan_policy: RescanSince,
     ) -> Result<Vec<Self>> {
-        Ok(vec![
-            // external chain (receive)
-            Self::from_descriptor(
-                xpub.as_descriptor([0.into()][..].into()),
-                network,
-                gap_limit,
-                initial_import_size,
-                rescan_policy,
-            )?,
-            // internal chain (change)
-            Self::from_descriptor(
-                xpub.as_descriptor([1.into()][..].into()),
-                network,
-                gap_limit,
-                initial_import_size,
-                rescan_policy,
-            )?,
-        ])
+        ensure_xpub_network(&xpub, network)?;
+        // external chain (receive)
+        let mut receive = Self::from_descriptor(
+            xpub.as_descriptor([0.into()][..].into()),
+            network,
+            gap_limit,
+            initial_import_size,
+            rescan_policy,
+        )?;
+        // internal chain (change)
+        let mut change = Self::from_descriptor(
+            xpub.as_descriptor([1.into()][..].into()),
+            network,
+            gap_limit,
+            initial_import_size,
+            rescan_policy,
+        )?;
+        receive.xpub_chain = Some((XpubChain::Receive, change.checksum.clone()));
+        change.xpub_chain = Some((XpubChain::Change, receive.checksum.clone()));
+        Ok(vec![receive, change])
     }
 
     /// Derives the specified child key
@@ -320,18 +857,38 @@ impl Wallet {
             .map_or(chunk_size - 1, |max| max + chunk_size)
     }
 
-    fn make_imports(
+    // Get the range of addresses that the next import should cover (if any), along with the rescan
+    // to perform for it. Used both for importing and for reporting the planned imports.
+    fn next_import(&self, rescan: bool) -> Option<(u32, u32, RescanSince)> {
+        let watch_index = self.watch_index();
+        if let Some(rescan_since) = self.pending_rescan {
+            // re-import the entire range that was previously imported (or that needs to be),
+            // using the requested rescan timestamp
+            let end_index = self
+                .max_imported_index
+                .map_or(watch_index, |max_imported| max_imported.max(watch_index));
+            Some((0, end_index, rescan_since))
+        } else if self.max_imported_index.map_or(true, |i| watch_index > i) {
+            let start_index = self
+                .max_imported_index
+                .map_or(0, |max_imported| max_imported + 1);
+            let rescan_since = if rescan {
+                self.rescan_policy
+            } else {
+                RescanSince::Now
+            };
+            Some((start_index, watch_index, rescan_since))
+        } else {
+            None
+        }
+    }
+
+    fn make_imports_since(
         &self,
         start_index: u32,
         end_index: u32,
-        rescan: bool,
+        rescan_since: RescanSince,
     ) -> Vec<(Address, RescanSince, String)> {
-        let rescan_since = if rescan {
-            self.rescan_policy
-        } else {
-            RescanSince::Now
-        };
-
         (start_index..=end_index)
             .map(|index| {
                 let address = self.derive_address(index);
@@ -371,15 +928,58 @@ impl Wallet {
         }
     }
 
+    /// Find the next `count` derivation indexes that have no history, starting from the next
+    /// index (skipping over reserved ones) and limited to the range that was already imported
+    /// into bitcoind.
+    pub fn find_unused_indexes(&self, store: &MemoryStore, count: usize) -> Result<Vec<u32>> {
+        let max_imported_index = self
+            .max_imported_index
+            .context("wallet addresses were not imported yet")?;
+        let start_index = self
+            .max_reserved_index
+            .map_or(0, |max_reserved| max_reserved + 1)
+            .max(self.get_next_index());
+
+        let indexes: Vec<u32> = (start_index..=max_imported_index)
+            .filter(|index| !store.has_history(&self.derive_address(*index).into()))
+            .take(count)
+            .collect();
+
+        ensure!(
+            indexes.len() == count,
+            "requested {} unused addresses, but only {} are available within the watched range (up to index {})",
+            count,
+            indexes.len(),
+            max_imported_index
+        );
+
+        Ok(indexes)
+    }
+
+    /// Get the next unused derivation index. Non-ranged descriptors always return `0`.
+    pub fn next_unused_index(&self, store: &MemoryStore) -> Result<u32> {
+        if self.is_ranged {
+            Ok(self.find_unused_indexes(store, 1)?[0])
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Iterate over the scripthashes of the wallet's addresses up to its max funded index (the ones
+    /// that may have history), in derivation order. Empty if the wallet has no history at all.
+    fn funded_scripthashes(&self) -> impl Iterator<Item = ScriptHash> + '_ {
+        let end_index = self.max_funded_index.map_or(0, |max| max + 1);
+        (0..end_index).map(move |index| ScriptHash::from(&self.derive_address(index)))
+    }
+
     pub fn find_gap(&self, store: &MemoryStore) -> Option<usize> {
         // return None if this wallet has no history at all
-        let max_funded_index = self.max_funded_index?;
+        self.max_funded_index?;
 
         Some(if self.is_ranged {
-            (0..=max_funded_index)
-                .map(|derivation_index| self.derive_address(derivation_index))
-                .fold((0, 0), |(curr_gap, max_gap), address| {
-                    if store.has_history(&address.into()) {
+            self.funded_scripthashes()
+                .fold((0, 0), |(curr_gap, max_gap), scripthash| {
+                    if store.has_history(&scripthash) {
                         (0, curr_gap.max(max_gap))
                     } else {
                         (curr_gap + 1, max_gap)
@@ -391,6 +991,199 @@ impl Wallet {
         })
     }
 
+    pub fn gap_stats(&self, store: &MemoryStore) -> GapStats {
+        let max_gap = self.find_gap(store).unwrap_or(0);
+        let headroom = self.gap_limit.saturating_sub(max_gap as u32);
+
+        let max_used_index = self.max_funded_index.max(self.max_reserved_index);
+        let lookahead = match (self.max_imported_index, max_used_index) {
+            (Some(max_imported), Some(max_used)) => max_imported.saturating_sub(max_used),
+            (Some(max_imported), None) => max_imported + 1,
+            (None, _) => 0,
+        };
+
+        // consider the wallet at risk once less than a quarter of the gap limit remains
+        let risk_threshold = cmp::max(self.gap_limit / GAP_RISK_RATIO, 1);
+        let at_risk = self.is_ranged && (headroom < risk_threshold || lookahead < risk_threshold);
+
+        GapStats {
+            checksum: self.checksum.clone(),
+            max_gap,
+            gap_limit: self.gap_limit,
+            headroom,
+            max_funded_index: self.max_funded_index,
+            max_imported_index: self.max_imported_index,
+            lookahead,
+            at_risk,
+        }
+    }
+
+    pub fn max_imported_index(&self) -> Option<u32> {
+        self.max_imported_index
+    }
+
+    pub fn xpub_chain(&self) -> Option<&(XpubChain, Checksum)> {
+        self.xpub_chain.as_ref()
+    }
+
+    /// Compute the confirmed balance as of the given block height, by replaying the funding
+    /// and spending of the wallet's scripthashes in transactions confirmed up to that height
+    pub fn balance_at_height(&self, store: &MemoryStore, height: u32) -> u64 {
+        let balance: i64 = self
+            .funded_scripthashes()
+            .filter_map(|scripthash| {
+                let history = store.get_history(&scripthash)?;
+                Some(
+                    history
+                        .iter()
+                        .filter(|hist| hist.status.is_confirmed_by(height))
+                        .filter_map(|hist| store.get_tx_entry(&hist.txid))
+                        .map(|tx_entry| tx_entry.balance_change(&scripthash))
+                        .sum::<i64>(),
+                )
+            })
+            .sum();
+
+        balance as u64
+    }
+
+    /// Get the history of all of the wallet's funded addresses, ordered with oldest first
+    pub fn get_history<'a>(&self, store: &'a MemoryStore) -> BTreeSet<&'a HistoryEntry> {
+        self.funded_scripthashes()
+            .filter_map(|scripthash| store.get_history(&scripthash))
+            .flatten()
+            .collect()
+    }
+
+    /// Compute the current balance by summing the wallet outputs that are not spent by any
+    /// (confirmed or unconfirmed) wallet transaction. Coinbase outputs are considered immature
+    /// until they reach maturity as of `tip_height`.
+    pub fn get_balance(&self, store: &MemoryStore, tip_height: u32) -> WalletBalance {
+        let mut balance = WalletBalance::default();
+        let mut funded: HashMap<OutPoint, (u64, TxStatus, bool)> = HashMap::new();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        for scripthash in self.funded_scripthashes() {
+            for hist in store.get_history(&scripthash).into_iter().flatten() {
+                let tx_entry = match store.get_tx_entry(&hist.txid) {
+                    Some(tx_entry) => tx_entry,
+                    None => continue,
+                };
+                for (vout, FundingInfo(funding_scripthash, amount)) in &tx_entry.funding {
+                    if *funding_scripthash == scripthash {
+                        let outpoint = OutPoint::new(hist.txid, *vout);
+                        let is_immature = tx_entry.is_immature_coinbase(tip_height);
+                        funded.insert(outpoint, (*amount, tx_entry.status, is_immature));
+                    }
+                }
+                for SpendingInfo(spending_scripthash, prevout, _) in tx_entry.spending.values() {
+                    if *spending_scripthash == scripthash {
+                        spent.insert(*prevout);
+                    }
+                }
+            }
+        }
+
+        for (outpoint, (amount, status, is_immature)) in funded {
+            if spent.contains(&outpoint) {
+                continue;
+            }
+            if is_immature {
+                balance.immature += amount;
+            } else if status.is_confirmed() {
+                balance.confirmed += amount;
+            } else {
+                balance.unconfirmed += amount;
+            }
+        }
+        balance
+    }
+
+    /// Get up to `limit` of the wallet's transactions that follow `after` (or from the start if
+    /// it's None), along with the net change they made to its balance, ordered with oldest first.
+    /// Allows paging through large histories without collecting them in full.
+    pub fn get_tx_changes(
+        &self,
+        store: &MemoryStore,
+        after: Option<&HistoryEntry>,
+        limit: usize,
+    ) -> Vec<WalletTxChange> {
+        let mut txs: BTreeMap<&HistoryEntry, (i64, bool)> = BTreeMap::new();
+        for scripthash in self.funded_scripthashes() {
+            let history = match store.get_history(&scripthash) {
+                Some(history) => history,
+                None => continue,
+            };
+            let history = match after {
+                Some(after) => history.range((Bound::Excluded(after), Bound::Unbounded)),
+                None => history.range::<HistoryEntry, _>(..),
+            };
+            for hist in history.take(limit) {
+                if let Some(tx_entry) = store.get_tx_entry(&hist.txid) {
+                    let (balance_change, is_outgoing) = txs.entry(hist).or_insert((0, false));
+                    *balance_change += tx_entry.balance_change(&scripthash);
+                    *is_outgoing |= tx_entry.spending.values().any(|s| s.0 == scripthash);
+                }
+            }
+            // only the first `limit` txs are kept. the ones dropped can't make it back in, since
+            // there are already `limit` txs ordered before them.
+            while txs.len() > limit {
+                let last = *txs.keys().next_back().unwrap();
+                txs.remove(last);
+            }
+        }
+
+        txs.into_iter()
+            .map(|(hist, (balance_change, is_outgoing))| WalletTxChange {
+                txid: hist.txid,
+                status: hist.status,
+                balance_change,
+                is_outgoing,
+            })
+            .collect()
+    }
+
+    /// Get the transactions confirmed at `height` that touch the wallet, along with the net change
+    /// they made to its balance
+    pub fn txs_at_height(&self, store: &MemoryStore, height: u32) -> HashMap<Txid, i64> {
+        let mut txs = HashMap::new();
+        for scripthash in self.funded_scripthashes() {
+            for hist in store.get_history_at_height(&scripthash, height) {
+                if let Some(tx_entry) = store.get_tx_entry(&hist.txid) {
+                    *txs.entry(hist.txid).or_insert(0) += tx_entry.balance_change(&scripthash);
+                }
+            }
+        }
+        txs
+    }
+
+    /// Find the transactions spending from the wallet's addresses, ordered with oldest first
+    pub fn find_outgoing_txs(&self, store: &MemoryStore) -> Vec<HistoryEntry> {
+        let outgoing: BTreeSet<&HistoryEntry> = self
+            .funded_scripthashes()
+            .filter_map(|scripthash| {
+                let history = store.get_history(&scripthash)?;
+                Some(history.iter().filter(move |hist| {
+                    let tx_entry = store.get_tx_entry(&hist.txid);
+                    tx_entry
+                        .into_iter()
+                        .any(|tx_entry| tx_entry.spending.values().any(|s| s.0 == scripthash))
+                }))
+            })
+            .flatten()
+            .collect();
+
+        outgoing.into_iter().cloned().collect()
+    }
+
+    pub fn rescan_policy(&self) -> RescanSince {
+        self.rescan_policy
+    }
+
+    /// The manual rescan that is waiting to be processed, if any
+    pub fn pending_rescan(&self) -> Option<RescanSince> {
+        self.pending_rescan
+    }
+
     /// Get the bip32 origins of the public keys used at the provided index
     pub fn bip32_origins(&self, index: u32) -> Vec<Bip32Origin> {
         self.keys_info
@@ -406,9 +1199,18 @@ impl Wallet {
     }
 }
 
-fn batch_import(rpc: &RpcClient, import_reqs: Vec<(Address, RescanSince, String)>) -> Result<()> {
-    // XXX use importmulti with ranged descriptors? the key derivation info won't be
-    //     directly available on `listtransactions` and would require an additional rpc all.
+fn batch_import(
+    rpc: &RpcClient,
+    import_reqs: &[(Address, RescanSince, String)],
+    descriptor_wallet: bool,
+    wallets: &HashMap<Checksum, Wallet>,
+) -> Result<()> {
+    // legacy wallets get the addresses imported individually with a label identifying their
+    // origin, which is then directly available on `listtransactions` without additional rpc calls.
+
+    if descriptor_wallet {
+        return batch_import_descriptors(rpc, import_reqs, wallets);
+    }
 
     let results = rpc.import_multi(
         &import_reqs
@@ -440,6 +1242,142 @@ fn batch_import(rpc: &RpcClient, import_reqs: Vec<(Address, RescanSince, String)
     Ok(())
 }
 
+// Descriptor wallets don't support `importmulti`, use `importdescriptors` instead. Ranged wallets
+// are imported as ranged descriptors, with one request covering all of the wallet's addresses in
+// the batch. bitcoind only allows setting labels for non-ranged descriptors, so the origin of their
+// transactions is determined using the watched range instead (see `WalletWatcher::find_origin()`),
+// and the imported range is read back using `listdescriptors`. Non-ranged wallets are imported
+// with their label, like with legacy wallets.
+fn batch_import_descriptors(
+    rpc: &RpcClient,
+    import_reqs: &[(Address, RescanSince, String)],
+    wallets: &HashMap<Checksum, Wallet>,
+) -> Result<()> {
+    let requests = make_descriptor_imports(import_reqs, wallets)?;
+    let results: Vec<Value> = rpc.call(
+        "importdescriptors",
+        &[json!(requests
+            .iter()
+            .map(|(req, _)| req)
+            .collect::<Vec<_>>())],
+    )?;
+
+    for (i, result) in results.iter().enumerate() {
+        if result["success"].as_bool() != Some(true) {
+            let (_, label) = requests.get(i).unwrap();
+            bail!("import up to {} failed: {}", label, result);
+        } else if result.get("warnings").is_some() {
+            debug!("import succeed with warnings: {}", result);
+        }
+    }
+
+    Ok(())
+}
+
+// Group the import requests of each wallet into a single `importdescriptors` request, returned
+// along with the label of the last address it covers. The requests of each wallet are expected to
+// be consecutive and ordered by derivation index, as produced by `make_imports_since()`.
+fn make_descriptor_imports<'a>(
+    import_reqs: &'a [(Address, RescanSince, String)],
+    wallets: &HashMap<Checksum, Wallet>,
+) -> Result<Vec<(Value, &'a str)>> {
+    // the wallet, the highest index to import, and the rescan timestamp and label of that index
+    let mut groups: Vec<(&Wallet, u32, &RescanSince, &str)> = vec![];
+    for (address, rescan, label) in import_reqs {
+        trace!("importing {} as {} (descriptor)", address, label);
+
+        let (checksum, index) = match KeyOrigin::from_label(label) {
+            Some(KeyOrigin::Descriptor(checksum, index)) => (checksum, index),
+            _ => bail!("unexpected import label {}", label),
+        };
+        match groups.last_mut() {
+            Some((wallet, end_index, _, end_label)) if wallet.checksum == checksum => {
+                *end_index = index;
+                *end_label = label;
+            }
+            _ => {
+                let wallet = wallets.get(&checksum).or_err("unknown wallet")?;
+                groups.push((wallet, index, rescan, label));
+            }
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(wallet, end_index, rescan, label)| {
+            let mut req = json!({
+                "desc": wallet.desc.to_string_with_checksum(),
+                "timestamp": match rescan {
+                    RescanSince::Now => json!("now"),
+                    RescanSince::Timestamp(timestamp) => json!(timestamp),
+                },
+            });
+            if wallet.is_ranged {
+                // bitcoind requires the range of re-imported descriptors to include their current
+                // range, so it always starts from 0. next_index is bitcoind's own lookahead point.
+                req["range"] = json!([0, end_index]);
+                req["next_index"] = json!(end_index);
+            } else {
+                req["label"] = json!(label);
+            }
+            (req, label)
+        })
+        .collect())
+}
+
+// List the descriptors of a descriptor wallet. Returns an empty list for bitcoind versions that
+// don't support `listdescriptors` (added in Bitcoin Core v22), which bwt never imported ranged
+// descriptors into.
+fn list_descriptors(rpc: &RpcClient) -> Result<Vec<Value>> {
+    match rpc.call::<Value>("listdescriptors", &[]) {
+        Ok(result) => Ok(result["descriptors"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()),
+        // Method not found
+        Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e))) if e.code == -32601 => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Get the max imported index of each ranged wallet from the descriptors listed by `listdescriptors`.
+// The descriptors are identified by their checksum in bwt's canonical encoding, which bitcoind's
+// may differ from (e.g. using `h` for hardened derivation). Descriptors that can't be parsed (like
+// the tr() descriptors of bitcoind's own keys) are skipped. The range end includes the addresses
+// pre-generated by bitcoind past `next_index`, which it watches all the same.
+fn get_imported_ranges(descriptors: &[Value]) -> HashMap<Checksum, u32> {
+    descriptors
+        .iter()
+        .filter_map(|entry| {
+            let desc_str = entry["desc"].as_str()?.split('#').next()?;
+            let max_index = entry["range"].get(1)?.as_u64()?;
+            let desc: ExtendedDescriptor = desc_str.parse().ok()?;
+            Some((Checksum::from(&desc), max_index as u32))
+        })
+        .collect()
+}
+
+// Check whether the bitcoind wallet is a descriptor wallet (`descriptors` is only reported by
+// Bitcoin Core v0.21+, older versions only have legacy wallets)
+fn is_descriptor_wallet(rpc: &RpcClient) -> Result<bool> {
+    let wallet_info: Value = rpc.call("getwalletinfo", &[])?;
+    Ok(wallet_info["descriptors"].as_bool().unwrap_or(false))
+}
+
+// Checked upfront (in addition to the check done for every descriptor key) so that the error names
+// the key in the ypub/zpub form it was provided in, rather than the xpub it gets normalized into
+fn ensure_xpub_network(xpub: &XyzPubKey, network: Network) -> Result<()> {
+    ensure!(
+        xpub.matches_network(network),
+        "Key {} is for {}, but the configured network is {}. Use a key for {} or set --network accordingly.",
+        xpub.to_slip132_string(),
+        xpub_network_name(xpub.network()),
+        xpub_network_name(network),
+        xpub_network_name(network)
+    );
+    Ok(())
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum KeyOrigin {
     Descriptor(Checksum, u32),
@@ -457,6 +1395,22 @@ impl_string_serializer!(
     }
 );
 
+impl_string_deserializer!(KeyOrigin);
+
+impl FromStr for KeyOrigin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "standalone" {
+            return Ok(KeyOrigin::Standalone);
+        }
+        let mut parts = s.splitn(2, '/');
+        let checksum = parts.next().req()?.parse()?;
+        let index = parts.next().req()?.parse()?;
+        Ok(KeyOrigin::Descriptor(checksum, index))
+    }
+}
+
 impl KeyOrigin {
     pub fn to_label(&self) -> String {
         match self {
@@ -487,6 +1441,31 @@ impl KeyOrigin {
     }
 }
 
+// Get the max imported index of each wallet from the labels of the addresses imported into bitcoind.
+// Only the contiguous range starting at 0 is considered to be imported, so that any gaps left behind
+// by an interrupted import get filled in when it resumes (re-importing an address is harmless).
+fn get_imported_indexes(labels: &[String]) -> HashMap<Checksum, u32> {
+    let mut indexes: HashMap<Checksum, Vec<u32>> = HashMap::new();
+    for label in labels {
+        if let Some(KeyOrigin::Descriptor(checksum, index)) = KeyOrigin::from_label(label) {
+            indexes.entry(checksum).or_default().push(index);
+        }
+    }
+
+    indexes
+        .into_iter()
+        .filter_map(|(checksum, mut indexes)| {
+            indexes.sort_unstable();
+            let (_, max_contiguous) = indexes
+                .into_iter()
+                .enumerate()
+                .take_while(|(i, index)| *i as u32 == *index)
+                .last()?;
+            Some((checksum, max_contiguous))
+        })
+        .collect()
+}
+
 // show a specialzied error message for unsupported `listlabels` (added in Bitcoin Core 0.17.0)
 fn labels_error(error: rpc::Error) -> bitcoincore_rpc::Error {
     if let rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e)) = error {
@@ -517,6 +1496,7 @@ impl Serialize for Wallet {
         rgb.serialize_field("bip32_origins", &bip32_origins)?;
         rgb.serialize_field("rescan_policy", &self.rescan_policy)?;
         rgb.serialize_field("done_initial_import", &self.done_initial_import)?;
+        rgb.serialize_field("pending_rescan", &self.pending_rescan)?;
         rgb.serialize_field("max_funded_index", &self.max_funded_index)?;
         rgb.serialize_field("max_imported_index", &self.max_imported_index)?;
         rgb.serialize_field("satisfaction_weight", &self.desc.max_satisfaction_weight())?;
@@ -525,7 +1505,376 @@ impl Serialize for Wallet {
             rgb.serialize_field("gap_limit", &self.gap_limit)?;
             rgb.serialize_field("initial_import_size", &self.initial_import_size)?;
         }
+        if let Some(bitcoind_wallet) = &self.bitcoind_wallet {
+            rgb.serialize_field("bitcoind_wallet", bitcoind_wallet)?;
+        }
 
         rgb.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::descriptor::get_str_checksum;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::bip32::ExtendedPubKey;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+
+    fn test_wallet(desc: &str) -> Wallet {
+        let desc = desc.parse().unwrap();
+        Wallet::from_descriptor(desc, Network::Bitcoin, 20, 20, RescanSince::Now).unwrap()
+    }
+
+    // Simulate bwt crashing in the middle of a chunked import and check where it resumes from
+    #[test]
+    fn test_resume_interrupted_import() {
+        let wallet = test_wallet(&format!("wpkh({}/*)", XPUB));
+        let import_reqs = wallet.make_imports_since(0, 19, RescanSince::Now);
+        let mut chunks = import_reqs.chunks(8);
+
+        // the first chunk landed completely, the second only partially (with a gap at index 11),
+        // and the third never made it in
+        let mut labels: Vec<String> = chunks.next().unwrap().iter().map(|r| r.2.clone()).collect();
+        let second_chunk = chunks.next().unwrap();
+        labels.extend([0, 1, 2, 4].iter().map(|i| second_chunk[*i].2.clone()));
+        // labels unrelated to descriptor wallets should be ignored
+        labels.extend(vec!["bwt".into(), "foobar".into(), "bwt/invalid/1".into()]);
+
+        let imported_indexes = get_imported_indexes(&labels);
+        assert_eq!(imported_indexes.len(), 1);
+        assert_eq!(imported_indexes[&wallet.checksum], 10);
+
+        // resuming should import the gap and everything after it, starting from index 11
+        let resume_reqs =
+            wallet.make_imports_since(imported_indexes[&wallet.checksum] + 1, 19, RescanSince::Now);
+        assert_eq!(resume_reqs.len(), 9);
+        assert_eq!(resume_reqs[0].2, import_reqs[11].2);
+
+        // if the first address didn't make it in, the import should restart from scratch
+        let imported_indexes = get_imported_indexes(&labels[1..]);
+        assert!(imported_indexes.is_empty());
+    }
+
+    #[test]
+    fn test_descriptor_imports() {
+        let ranged = test_wallet(&format!("wpkh([d34db33f/84'/0'/0']{}/0/*)", XPUB));
+        let single = test_wallet(&format!("wpkh({}/1)", XPUB));
+
+        let mut import_reqs = ranged.make_imports_since(5, 9, RescanSince::Now);
+        import_reqs.extend(single.make_imports_since(0, 0, RescanSince::Now));
+        let wallets: HashMap<Checksum, Wallet> = vec![ranged.clone(), single.clone()]
+            .into_iter()
+            .map(|wallet| (wallet.checksum.clone(), wallet))
+            .collect();
+
+        // the ranged wallet is imported as a single ranged descriptor, the non-ranged one with its label
+        let requests = make_descriptor_imports(&import_reqs, &wallets).unwrap();
+        assert_eq!(requests.len(), 2);
+        let (ranged_req, ranged_label) = &requests[0];
+        assert_eq!(ranged_req["desc"], ranged.desc.to_string_with_checksum());
+        assert_eq!(ranged_req["range"], json!([0, 9]));
+        assert_eq!(ranged_req["next_index"], json!(9));
+        assert!(ranged_req.get("label").is_none());
+        assert_eq!(*ranged_label, import_reqs[4].2);
+        let (single_req, _) = &requests[1];
+        assert_eq!(single_req["label"], json!(import_reqs[5].2));
+        assert!(single_req.get("range").is_none());
+
+        // the imported range is read back from `listdescriptors`, which encodes descriptors in
+        // bitcoind's own format (using `h` for hardened derivation steps)
+        let core_desc = format!("wpkh([d34db33f/84h/0h/0h]{}/0/*)", XPUB);
+        let core_desc = format!("{}#{}", core_desc, get_str_checksum(&core_desc).unwrap());
+        let listed = vec![
+            json!({ "desc": core_desc, "timestamp": 0, "active": false, "range": [0, 1008], "next": 9 }),
+            json!({ "desc": format!("wpkh({}/1)#{}", XPUB, single.checksum), "timestamp": 0, "active": false }),
+            json!({ "desc": "tr([d34db33f/86h/0h/0h]xpub/0/*)#00000000", "timestamp": 0, "active": true, "range": [0, 999] }),
+        ];
+        let imported_ranges = get_imported_ranges(&listed);
+        assert_eq!(imported_ranges.len(), 1);
+        assert_eq!(imported_ranges[&ranged.checksum], 1008);
+    }
+
+    #[test]
+    fn test_gap_stats_risk() {
+        let mut wallet = test_wallet(&format!("wpkh({}/*)", XPUB));
+        // the store has no history, so only the lookahead is exercised here (max_gap is always 0)
+        let store = MemoryStore::new();
+
+        // nothing funded yet, with the first 20 addresses imported
+        wallet.max_imported_index = Some(19);
+        let stats = wallet.gap_stats(&store);
+        assert_eq!(
+            (stats.max_gap, stats.headroom, stats.lookahead),
+            (0, 20, 20)
+        );
+        assert!(!stats.at_risk);
+
+        // handing out addresses close to the end of the imported range
+        wallet.max_reserved_index = Some(16);
+        let stats = wallet.gap_stats(&store);
+        assert_eq!(stats.lookahead, 3);
+        assert!(stats.at_risk);
+
+        // once the imports catch up with the funded index, there's enough lookahead again
+        wallet.max_reserved_index = None;
+        wallet.max_funded_index = Some(16);
+        wallet.max_imported_index = Some(36);
+        let stats = wallet.gap_stats(&store);
+        assert_eq!(
+            (stats.max_gap, stats.headroom, stats.lookahead),
+            (0, 20, 20)
+        );
+        assert!(!stats.at_risk);
+    }
+
+    #[test]
+    fn test_network_mismatch() {
+        let xpub: XyzPubKey = XPUB.parse().unwrap();
+        let err = Wallet::from_xpub(xpub.clone(), Network::Testnet, 20, 20, RescanSince::Now)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(
+            "is for mainnet, but the configured network is testnet/regtest. Use a key for testnet/regtest"
+        ));
+
+        assert!(Wallet::from_xpub(xpub, Network::Bitcoin, 20, 20, RescanSince::Now).is_ok());
+
+        // the error names the key as it was provided, not its normalized xpub form
+        let zpub_str = "zpub6jftahH18ngZwwDgquxFKyv4bUWuqfwm2xtt4yt7Ek53uFigQNhhrT1EgGDZWXJBZ2dV2nyr5oesnRoUsuVz72hBc5C2YDzXuKFsrTu7JHp";
+        let zpub: XyzPubKey = zpub_str.parse().unwrap();
+        let err = Wallet::from_bare_xpub(zpub, Network::Regtest, 20, 20, RescanSince::Now)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(&format!("Key {} is for mainnet", zpub_str)));
+    }
+
+    #[test]
+    fn test_multisig_wallet() {
+        let xpubs: Vec<ExtendedPubKey> = vec![
+            "xpub661MyMwAqRbcEhsxS9g2qyYKSGA3seqWVNhmVhU27ddQx952PaZ6G4V26msGKrqYBjoBRwFyzaucPUkhw7DNaeMVUYJV1bqosxzVxToJdcy".parse().unwrap(),
+            XPUB.parse().unwrap(),
+            "xpub661MyMwAqRbcEuy9nKLTbGCi2NhqTWeQPT3gd2QdfmeaieDHLHiwTnSnw1GrP2xdaJwEDQJLasfw6LNK7hVADcCN9d1M1RtxitrR3CwvtjV".parse().unwrap(),
+        ];
+        let desc = format!(
+            "wsh(multi(2,[00000001/48'/0'/0'/2']{}/0/*,[00000002/48'/0'/0'/2']{}/0/*,[00000003/48'/0'/0'/2']{}/0/*))",
+            xpubs[0], xpubs[1], xpubs[2]
+        );
+        let wallet = test_wallet(&desc);
+        assert!(wallet.is_ranged);
+
+        // all participating keys are reported, with the derivation index appended
+        let origins: Vec<String> = wallet
+            .bip32_origins(5)
+            .iter()
+            .map(|o| o.to_string())
+            .collect();
+        assert_eq!(
+            origins,
+            vec![
+                "00000001/48'/0'/0'/2'/0/5",
+                "00000002/48'/0'/0'/2'/0/5",
+                "00000003/48'/0'/0'/2'/0/5"
+            ]
+        );
+
+        // the address should be the p2wsh of a witness script with the three keys derived at the index
+        let secp = Secp256k1::verification_only();
+        for index in 0..3 {
+            let witness_script = wallet.derive(index).witness_script();
+            for xpub in &xpubs {
+                let path = [ChildNumber::from(0), ChildNumber::from(index)];
+                let pubkey = xpub.derive_pub(&secp, &path).unwrap().public_key.to_bytes();
+                assert!(witness_script
+                    .as_bytes()
+                    .windows(pubkey.len())
+                    .any(|w| w == &pubkey[..]));
+            }
+            assert_eq!(
+                wallet.derive_address(index),
+                Address::p2wsh(&witness_script, Network::Bitcoin)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sortedmulti_wallet() {
+        let xpubs = [
+            "xpub661MyMwAqRbcEhsxS9g2qyYKSGA3seqWVNhmVhU27ddQx952PaZ6G4V26msGKrqYBjoBRwFyzaucPUkhw7DNaeMVUYJV1bqosxzVxToJdcy",
+            XPUB,
+        ];
+        let desc_str = format!("wsh(sortedmulti(1,{}/0/*,{}/0/*))", xpubs[0], xpubs[1]);
+        let desc: ExtendedDescriptor = desc_str.parse().unwrap();
+
+        // encoded back as sortedmulti(), with the keys kept in their original order
+        assert_eq!(desc.to_string(), desc_str);
+        let desc_with_checksum = desc.to_string_with_checksum();
+        assert_eq!(
+            ExtendedDescriptor::parse_with_checksum(&desc_with_checksum).unwrap(),
+            desc
+        );
+        // sortedmulti() is only supported as the top-level script
+        let nested = format!(
+            "wsh(or_d(sortedmulti(1,{}/0/*,{}/0/*),pk({}/1/*)))",
+            xpubs[0], xpubs[1], xpubs[0]
+        );
+        assert!(nested.parse::<ExtendedDescriptor>().is_err());
+
+        let unsorted: ExtendedDescriptor =
+            desc_str.replace("sortedmulti", "multi").parse().unwrap();
+        let wallet = test_wallet(&desc_str);
+        assert!(wallet.is_ranged);
+
+        // the address at each index should be the p2wsh of a multi() with the derived keys sorted
+        let secp = Secp256k1::verification_only();
+        let mut differs_from_unsorted = false;
+        for index in 0..10 {
+            let path = [ChildNumber::from(0), ChildNumber::from(index)];
+            let mut pubkeys: Vec<_> = xpubs
+                .iter()
+                .map(|xpub| {
+                    let xpub: ExtendedPubKey = xpub.parse().unwrap();
+                    xpub.derive_pub(&secp, &path).unwrap().public_key
+                })
+                .collect();
+            pubkeys.sort_by_key(|pubkey| pubkey.to_bytes());
+            let sorted: ExtendedDescriptor = format!("wsh(multi(1,{},{}))", pubkeys[0], pubkeys[1])
+                .parse()
+                .unwrap();
+
+            let address = wallet.derive_address(index);
+            assert_eq!(address, sorted.address(Network::Bitcoin).unwrap());
+            differs_from_unsorted |=
+                unsorted.derive(index.into()).address(Network::Bitcoin) != Some(address);
+        }
+        // the key order depends on the index, so it should not always match the unsorted one
+        assert!(differs_from_unsorted);
+    }
+
+    #[test]
+    fn test_wallet_options_compat() {
+        let opt: WalletOptions = serde_json::from_value(json!("now")).unwrap();
+        assert_eq!(opt, RescanSince::Now.into());
+
+        let opt: WalletOptions = serde_json::from_value(json!({ "gap_limit": 100 })).unwrap();
+        assert_eq!(opt.rescan, RescanSince::Timestamp(0));
+        assert_eq!(opt.gap_limit, Some(100));
+        assert_eq!(opt.initial_import_size, None);
+    }
+
+    #[test]
+    fn test_duplicated_descriptors() {
+        let desc = format!("wpkh({}/*)", XPUB);
+        let make_wallets = || (0..2).map(|_| test_wallet(&desc)).collect::<Vec<_>>();
+
+        // duplicates get ignored, with or without verifying the full descriptor
+        for &verify_full_desc in &[false, true] {
+            let watcher = WalletWatcher::new(make_wallets(), verify_full_desc).unwrap();
+            assert_eq!(watcher.wallets().len(), 1);
+        }
+    }
+
+    // Paging through the wallet transactions should yield the same results as reading them at once
+    #[test]
+    fn test_tx_changes_pages() {
+        use bitcoin::hashes::Hash;
+
+        let mut wallet = test_wallet(&format!("wpkh({}/*)", XPUB));
+        wallet.max_funded_index = Some(1);
+
+        let mut store = MemoryStore::new();
+        let scripthashes: Vec<ScriptHash> = (0..2)
+            .map(|index| {
+                let address = wallet.derive_address(index);
+                let scripthash = ScriptHash::from(&address);
+                let origin = KeyOrigin::Descriptor(wallet.checksum.clone(), index);
+                store.index_scripthash(&scripthash, &origin, &address);
+                scripthash
+            })
+            .collect();
+        // txs funding one of the addresses (alternately), with every third tx funding both
+        for i in 0..10u8 {
+            let txid = Txid::hash(&[i]);
+            store.upsert_tx(&txid, TxStatus::Confirmed(100 + i as u32));
+            let scripthash = scripthashes[i as usize % 2];
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1000));
+            if i % 3 == 0 {
+                let other = scripthashes[(i as usize + 1) % 2];
+                store.index_tx_output_funding(&txid, 1, FundingInfo(other, 500));
+            }
+        }
+
+        let all = wallet.get_tx_changes(&store, None, usize::MAX);
+        assert_eq!(all.len(), 10);
+        assert_eq!(all[0].balance_change, 1500);
+        assert_eq!(all[1].balance_change, 1000);
+
+        for &limit in &[1, 3, 4, 20] {
+            let mut paged: Vec<WalletTxChange> = Vec::new();
+            loop {
+                let after = paged.last().map(|tx| HistoryEntry::new(tx.txid, tx.status));
+                let page = wallet.get_tx_changes(&store, after.as_ref(), limit);
+                assert!(page.len() <= limit);
+                if page.is_empty() {
+                    break;
+                }
+                paged.extend(page);
+            }
+            let summarize = |txs: &[WalletTxChange]| -> Vec<_> {
+                txs.iter()
+                    .map(|tx| (tx.txid, tx.status, tx.balance_change))
+                    .collect()
+            };
+            assert_eq!(summarize(&paged), summarize(&all));
+        }
+    }
+
+    // Imports found in one bitcoind wallet should only count for the wallets that belong to it
+    #[test]
+    fn test_imports_per_bitcoind_wallet() {
+        let default_wallet = test_wallet(&format!("wpkh({}/0/*)", XPUB));
+        let named_wallet =
+            test_wallet(&format!("wpkh({}/1/*)", XPUB)).with_bitcoind_wallet(Some("named".into()));
+        let (default_checksum, named_checksum) = (
+            default_wallet.checksum.clone(),
+            named_wallet.checksum.clone(),
+        );
+        let mut watcher = WalletWatcher::new(vec![default_wallet, named_wallet], false).unwrap();
+
+        let make_rpc = |url: &str| {
+            let auth = rpc::Auth::UserPass("user".into(), "pass".into());
+            Arc::new(RpcClient::new(url.into(), auth).unwrap())
+        };
+        let mut rpcs = WalletRpcs::new(make_rpc("http://127.0.0.1:1"), true);
+        rpcs.add("named".into(), make_rpc("http://127.0.0.1:1/wallet/named"));
+
+        // both wallets appear imported in the default bitcoind wallet, but only the one that
+        // belongs to it should be updated
+        let imported_indexes: HashMap<Checksum, u32> =
+            vec![(default_checksum.clone(), 5), (named_checksum.clone(), 7)]
+                .into_iter()
+                .collect();
+        watcher.apply_imported_indexes(&rpcs, rpcs.default_rpc(), imported_indexes.clone());
+        assert_eq!(
+            watcher.wallets[&default_checksum].max_imported_index,
+            Some(5)
+        );
+        assert!(watcher.wallets[&default_checksum].done_initial_import);
+        assert_eq!(watcher.wallets[&named_checksum].max_imported_index, None);
+        assert!(!watcher.wallets[&named_checksum].done_initial_import);
+
+        // and the other way around for the named one
+        let named_rpc = rpcs.get(Some("named")).clone();
+        let imported_indexes: HashMap<Checksum, u32> =
+            vec![(default_checksum.clone(), 9), (named_checksum.clone(), 7)]
+                .into_iter()
+                .collect();
+        watcher.apply_imported_indexes(&rpcs, &named_rpc, imported_indexes);
+        assert_eq!(
+            watcher.wallets[&default_checksum].max_imported_index,
+            Some(5)
+        );
+        assert_eq!(watcher.wallets[&named_checksum].max_imported_index, Some(7));
+    }
+}
@@ -1,54 +1,575 @@
-use std::sync::mpsc;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{de, Deserialize, Deserializer};
 
 use ::reqwest::blocking as reqwest;
+use ::reqwest::header::CONTENT_TYPE;
+use bitcoin::Txid;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use bitcoin_hashes::{sha256, HashEngine};
 
+use crate::error::{Error, OptionExt, Result};
 use crate::indexer::IndexChange;
+use crate::query::Query;
+use crate::types::{BlockId, ScriptHash, TxStatus};
+use crate::util::descriptor::Checksum;
+use crate::util::{join_timeout, BoolThen, SHUTDOWN_TIMEOUT};
+use crate::wallet::KeyOrigin;
+
+const SIGNATURE_HEADER: &str = "X-Bwt-Signature";
 
 pub struct WebHookNotifier {
-    _thread: thread::JoinHandle<()>,
-    tx: mpsc::Sender<Vec<IndexChange>>,
-    num_urls: usize,
+    thread: Option<thread::JoinHandle<()>>,
+    // dropped on shutdown to signal the delivery thread to terminate
+    tx: Option<mpsc::Sender<WebHookUpdates>>,
+    urls: Vec<WebHookUrl>,
+    min_amounts: Vec<AmountThreshold>,
+    large_amounts: Vec<AmountThreshold>,
+    query: Arc<Query>,
+}
+
+/// An amount (in satoshis) that applies to a specific wallet, or to all wallets if none is specified.
+/// Formatted as `<sats>` or `<checksum>:<sats>`.
+#[derive(Debug, Clone)]
+pub struct AmountThreshold {
+    pub wallet: Option<Checksum>,
+    pub amount: u64,
+}
+
+/// A webhook url, with an optional filter for the updates delivered to it.
+/// Formatted as `<url>` or `<url>#<filter>,<filter>,...`, where each filter is one of `confirmed`,
+/// `unconfirmed`, `wallet=<checksum>` or `category=<category>`.
+#[derive(Debug, Clone)]
+pub struct WebHookUrl {
+    pub url: String,
+    pub filter: WebHookFilter,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WebHookFilter {
+    /// Only deliver updates for transactions that are confirmed (`Some(true)`) or unconfirmed (`Some(false)`)
+    pub confirmed: Option<bool>,
+    /// Only deliver updates related to these wallets
+    pub wallets: Vec<Checksum>,
+    /// Only deliver updates of these categories
+    pub categories: Vec<String>,
+}
+
+/// The policy for retrying failed webhook deliveries, with exponential backoff
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of delivery attempts, including the first one
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for every subsequent one
+    pub base_delay: Duration,
+}
+
+// The updates of a single sync cycle, filtered for each url
+struct WebHookUpdates {
+    tip_height: Option<u32>,
+    url_changelogs: Vec<(String, Vec<WebHookEvent>)>,
+}
+
+// The events delivered to webhooks: the index changes, along with the alerts derived from them
+#[derive(Clone, Serialize, Debug)]
+#[serde(untagged)]
+enum WebHookEvent {
+    Index(IndexChange),
+    Alert(WebHookAlert),
+}
+
+// Serialized in the same category/params format as the index changes
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "category", content = "params")]
+enum WebHookAlert {
+    // an incoming transaction that received more than the configured amount for the wallet
+    LargeDeposit(Txid, Option<Checksum>, u64, TxStatus),
+}
+
+// A webhook request pending delivery
+struct Delivery {
+    url: String,
+    payload: Arc<Payload>,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+// The JSON request body, along with its HMAC signature when a secret is configured
+struct Payload {
+    body: String,
+    signature: Option<String>,
+}
+
+impl Payload {
+    fn new(changelog: &[WebHookEvent], tip_height: Option<u32>, secret: Option<&str>) -> Self {
+        let body = json!({ "tip_height": tip_height, "updates": changelog }).to_string();
+        let signature = secret.map(|secret| sign(secret, &body));
+        Payload { body, signature }
+    }
 }
 
 impl WebHookNotifier {
-    pub fn start(urls: Vec<String>) -> Self {
-        let (tx, rx) = mpsc::channel::<Vec<IndexChange>>();
-        let num_urls = urls.len();
+    pub fn start(
+        urls: Vec<WebHookUrl>,
+        min_amounts: Vec<AmountThreshold>,
+        large_amounts: Vec<AmountThreshold>,
+        retry_policy: RetryPolicy,
+        secret: Option<String>,
+        batch_size: usize,
+        query: Arc<Query>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<WebHookUpdates>();
+        let batch_size = cmp::max(batch_size, 1);
 
         Self {
             // Spawn a separate thread for sending HTTP requests
             // TODO use reqwest's non-blocking mode
-            _thread: thread::spawn(move || {
+            thread: Some(thread::spawn(move || {
                 let client = reqwest::Client::new();
-                while let Ok(changelog) = rx.recv() {
-                    for url in &urls {
-                        // XXX attach full tx info json to webhook request?
-                        debug!("notifying {} with {} events", url, changelog.len());
-                        client
-                            .post(url)
-                            .json(&changelog)
-                            .send()
-                            .map(|r| debug!("notifying {} succeed: {:?}", url, r.status()))
-                            .map_err(|e| warn!("notifying {} failed: {:?}", url, e))
-                            .ok();
+                // failed deliveries waiting to be retried, kept in memory across syncs
+                let mut pending: Vec<Delivery> = vec![];
+                loop {
+                    // wait for new updates, or until the next retry is due
+                    let next_retry = pending.iter().map(|delivery| delivery.next_attempt).min();
+                    let updates = match next_retry {
+                        Some(next_retry) => {
+                            let timeout = next_retry.saturating_duration_since(Instant::now());
+                            match rx.recv_timeout(timeout) {
+                                Ok(updates) => Some(updates),
+                                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                        None => match rx.recv() {
+                            Ok(updates) => Some(updates),
+                            Err(mpsc::RecvError) => break,
+                        },
+                    };
+
+                    // send all the updates for each url in a single request, or split them into
+                    // multiple requests of up to `batch_size` updates each
+                    // XXX attach full tx info json to webhook request?
+                    if let Some(WebHookUpdates {
+                        tip_height,
+                        url_changelogs,
+                    }) = updates
+                    {
+                        for (url, changelog) in url_changelogs {
+                            pending.extend(changelog.chunks(batch_size).map(|batch| Delivery {
+                                url: url.clone(),
+                                payload: Arc::new(Payload::new(
+                                    batch,
+                                    tip_height,
+                                    secret.as_deref(),
+                                )),
+                                attempts: 0,
+                                next_attempt: Instant::now(),
+                            }));
+                        }
                     }
+
+                    let now = Instant::now();
+                    let (due, waiting) = pending
+                        .into_iter()
+                        .partition::<Vec<_>, _>(|delivery| delivery.next_attempt <= now);
+                    pending = waiting;
+                    pending.extend(
+                        due.into_iter()
+                            .filter_map(|delivery| deliver(&client, delivery, &retry_policy)),
+                    );
+                }
+                if !pending.is_empty() {
+                    warn!("dropping {} pending webhook deliveries", pending.len());
                 }
                 trace!("webhooks shutting down");
-            }),
-            tx,
-            num_urls,
+            })),
+            tx: Some(tx),
+            urls,
+            min_amounts,
+            large_amounts,
+            query,
         }
     }
 
     pub fn send_updates(&self, changelog: &[IndexChange]) {
+        let changelog = self.apply_thresholds(changelog);
+        if changelog.is_empty() {
+            return;
+        }
+
+        // apply the filters of each url, skipping urls that are left with no updates
+        let url_changelogs: Vec<(String, Vec<WebHookEvent>)> = self
+            .urls
+            .iter()
+            .filter_map(|webhook| {
+                let url_changelog = self.apply_filter(&webhook.filter, &changelog);
+                (!url_changelog.is_empty()).do_then(|| (webhook.url.clone(), url_changelog))
+            })
+            .collect();
+        if url_changelogs.is_empty() {
+            return;
+        }
+
         info!(
             "sending {} update(s) to {} url(s)",
             changelog.len(),
-            self.num_urls
+            url_changelogs.len()
+        );
+
+        let tip_height = self.query.get_synced_tip().map(|BlockId(height, _)| height);
+
+        let tx = some_or_ret!(&self.tx);
+        tx.send(WebHookUpdates {
+            tip_height,
+            url_changelogs,
+        })
+        .unwrap();
+    }
+
+    /// Stop the delivery thread, dropping any deliveries that are still pending a retry.
+    /// Waits for an in-flight delivery to complete for up to `SHUTDOWN_TIMEOUT`.
+    pub fn shutdown(&mut self) {
+        // disconnecting the channel makes the delivery thread exit its loop
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            join_timeout(thread, SHUTDOWN_TIMEOUT, "webhooks");
+        }
+    }
+
+    // Keep the updates matching the url filter. Transaction events are associated with wallets
+    // through the TxoFunded/TxoSpent events of the same transaction.
+    fn apply_filter(
+        &self,
+        filter: &WebHookFilter,
+        changelog: &[WebHookEvent],
+    ) -> Vec<WebHookEvent> {
+        if filter.is_empty() {
+            return changelog.to_vec();
+        }
+
+        let is_wallet_change = |change: &WebHookEvent| match change {
+            WebHookEvent::Index(IndexChange::TxoFunded(_, scripthash, ..))
+            | WebHookEvent::Index(IndexChange::TxoSpent(_, scripthash, ..)) => self
+                .wallet_of(scripthash)
+                .map_or(false, |wallet| filter.wallets.contains(&wallet)),
+            WebHookEvent::Alert(WebHookAlert::LargeDeposit(_, wallet, ..)) => wallet
+                .as_ref()
+                .map_or(false, |wallet| filter.wallets.contains(wallet)),
+            _ => false,
+        };
+        let wallet_txids: HashSet<Txid> = if filter.wallets.is_empty() {
+            HashSet::new()
+        } else {
+            changelog
+                .iter()
+                .filter(|change| is_wallet_change(change))
+                .filter_map(WebHookEvent::txo_txid)
+                .collect()
+        };
+
+        changelog
+            .iter()
+            .filter(|change| {
+                let status_matches = filter.confirmed.map_or(true, |confirmed| {
+                    change.status().map_or(false, |status| {
+                        status.is_confirmed() == confirmed && status.is_viable()
+                    })
+                });
+                let wallet_matches = filter.wallets.is_empty()
+                    || match change {
+                        WebHookEvent::Index(IndexChange::Transaction(txid, _))
+                        | WebHookEvent::Index(IndexChange::TransactionReplaced(txid)) => {
+                            wallet_txids.contains(txid)
+                        }
+                        _ => is_wallet_change(change),
+                    };
+                let category_matches = filter.categories.is_empty()
+                    || filter
+                        .categories
+                        .iter()
+                        .any(|category| category == change.category_str());
+                status_matches && wallet_matches && category_matches
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Drop the events of incoming transactions that received less than the minimum amount,
+    // and add LargeDeposit alerts for ones that received more than the large amount
+    fn apply_thresholds(&self, changelog: &[IndexChange]) -> Vec<WebHookEvent> {
+        if self.min_amounts.is_empty() && self.large_amounts.is_empty() {
+            return changelog.iter().cloned().map(WebHookEvent::Index).collect();
+        }
+
+        // sum up the amounts received by each transaction, separately for each wallet
+        let mut received: HashMap<(Txid, Option<Checksum>), (u64, TxStatus)> = HashMap::new();
+        for change in changelog {
+            if let IndexChange::TxoFunded(outpoint, scripthash, amount, status) = change {
+                let wallet = self.wallet_of(scripthash);
+                received
+                    .entry((outpoint.txid, wallet))
+                    .or_insert((0, *status))
+                    .0 += amount;
+            }
+        }
+
+        let below_min = |change: &IndexChange| match change {
+            IndexChange::TxoFunded(outpoint, scripthash, ..) => {
+                let wallet = self.wallet_of(scripthash);
+                let min_amount = some_or_ret!(get_threshold(&self.min_amounts, &wallet), false);
+                received[&(outpoint.txid, wallet)].0 < min_amount
+            }
+            _ => false,
+        };
+        let (dropped, mut changelog): (Vec<_>, Vec<_>) =
+            changelog.iter().cloned().partition(below_min);
+
+        // drop the Transaction events of transactions that had all of their txo events dropped
+        let dropped_txids: HashSet<Txid> = dropped.iter().filter_map(txo_txid).collect();
+        let kept_txids: HashSet<Txid> = changelog.iter().filter_map(txo_txid).collect();
+        changelog.retain(|change| match change {
+            IndexChange::Transaction(txid, _) => {
+                !dropped_txids.contains(txid) || kept_txids.contains(txid)
+            }
+            _ => true,
+        });
+
+        let mut events: Vec<WebHookEvent> =
+            changelog.into_iter().map(WebHookEvent::Index).collect();
+        events.extend(
+            received
+                .into_iter()
+                .filter_map(|((txid, wallet), (amount, status))| {
+                    let large_amount = get_threshold(&self.large_amounts, &wallet)?;
+                    (amount >= large_amount).do_then(|| {
+                        WebHookEvent::Alert(WebHookAlert::LargeDeposit(
+                            txid, wallet, amount, status,
+                        ))
+                    })
+                }),
+        );
+
+        events
+    }
+
+    fn wallet_of(&self, scripthash: &ScriptHash) -> Option<Checksum> {
+        match self.query.get_script_info(scripthash)?.origin {
+            KeyOrigin::Descriptor(checksum, _) => Some(checksum),
+            KeyOrigin::Standalone => None,
+        }
+    }
+}
+
+impl Drop for WebHookNotifier {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Attempt to deliver the webhook request, returning it back for a later retry if it failed and
+// there are remaining attempts left
+fn deliver(
+    client: &reqwest::Client,
+    mut delivery: Delivery,
+    retry_policy: &RetryPolicy,
+) -> Option<Delivery> {
+    debug!(
+        "notifying {} (attempt #{})",
+        delivery.url,
+        delivery.attempts + 1
+    );
+    let mut req = client
+        .post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(delivery.payload.body.clone());
+    if let Some(signature) = &delivery.payload.signature {
+        req = req.header(SIGNATURE_HEADER, signature);
+    }
+    let result = req.send().and_then(|res| res.error_for_status());
+
+    delivery.attempts += 1;
+    match result {
+        Ok(res) => {
+            debug!("notifying {} succeed: {:?}", delivery.url, res.status());
+            None
+        }
+        Err(e) if delivery.attempts < retry_policy.max_attempts => {
+            let delay = retry_policy.delay(delivery.attempts);
+            warn!(
+                "notifying {} failed, retrying in {:?}: {:?}",
+                delivery.url, delay, e
+            );
+            delivery.next_attempt = Instant::now() + delay;
+            Some(delivery)
+        }
+        Err(e) => {
+            warn!(
+                "notifying {} failed after {} attempts, dropping the update: {:?}",
+                delivery.url, delivery.attempts, e
+            );
+            None
+        }
+    }
+}
+
+// Sign the request body using HMAC-SHA256 with the shared secret, encoded as hex
+fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).to_hex()
+}
+
+impl RetryPolicy {
+    // The delay before the next retry, following the given number of failed attempts
+    fn delay(&self, attempts: u32) -> Duration {
+        // cap the exponent to avoid overflowing
+        let factor = 2u32.pow(cmp::min(attempts.saturating_sub(1), 16));
+        self.base_delay * factor
+    }
+}
+
+// Get the threshold for the wallet, falling back to the one set for all wallets
+fn get_threshold(thresholds: &[AmountThreshold], wallet: &Option<Checksum>) -> Option<u64> {
+    let specific = wallet.as_ref().and_then(|wallet| {
+        thresholds
+            .iter()
+            .find(|t| t.wallet.as_ref() == Some(wallet))
+    });
+    specific
+        .or_else(|| thresholds.iter().find(|t| t.wallet.is_none()))
+        .map(|t| t.amount)
+}
+
+// The txid of the transaction funding or spending the txo, if any
+fn txo_txid(change: &IndexChange) -> Option<Txid> {
+    match change {
+        IndexChange::TxoFunded(outpoint, ..) => Some(outpoint.txid),
+        IndexChange::TxoSpent(inpoint, ..) => Some(inpoint.txid),
+        _ => None,
+    }
+}
+
+impl WebHookEvent {
+    fn status(&self) -> Option<TxStatus> {
+        match self {
+            WebHookEvent::Index(change) => change.status(),
+            WebHookEvent::Alert(WebHookAlert::LargeDeposit(.., status)) => Some(*status),
+        }
+    }
+
+    fn category_str(&self) -> &str {
+        match self {
+            WebHookEvent::Index(change) => change.category_str(),
+            WebHookEvent::Alert(WebHookAlert::LargeDeposit(..)) => "LargeDeposit",
+        }
+    }
+
+    fn txo_txid(&self) -> Option<Txid> {
+        match self {
+            WebHookEvent::Index(change) => txo_txid(change),
+            WebHookEvent::Alert(_) => None,
+        }
+    }
+}
+
+impl WebHookFilter {
+    fn is_empty(&self) -> bool {
+        self.confirmed.is_none() && self.wallets.is_empty() && self.categories.is_empty()
+    }
+}
+
+impl FromStr for WebHookUrl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // the filters are provided in the url fragment, which is not sent to the server
+        let mut parts = s.trim().splitn(2, '#');
+        let url = parts.next().req()?.to_string();
+        let mut filter = WebHookFilter::default();
+        for part in parts.next().into_iter().flat_map(|f| f.split(',')) {
+            let mut kv = part.splitn(2, '=');
+            match (kv.next().req()?, kv.next()) {
+                ("confirmed", None) => filter.confirmed = Some(true),
+                ("unconfirmed", None) => filter.confirmed = Some(false),
+                ("wallet", Some(checksum)) => filter.wallets.push(checksum.parse()?),
+                ("category", Some(category)) => filter.categories.push(category.to_string()),
+                _ => bail!("invalid webhook filter: {}", part),
+            }
+        }
+        Ok(WebHookUrl { url, filter })
+    }
+}
+
+impl<'de> Deserialize<'de> for WebHookUrl {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for AmountThreshold {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().rsplitn(2, ':');
+        let amount = parts.next().req()?.parse()?;
+        let wallet = parts.next().map(str::parse).transpose()?;
+        Ok(AmountThreshold { wallet, amount })
+    }
+}
+
+impl<'de> Deserialize<'de> for AmountThreshold {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
         );
+    }
+
+    #[test]
+    fn test_parse_url_filter() {
+        let webhook: WebHookUrl = "https://example.com/hook?key=123".parse().unwrap();
+        assert_eq!(webhook.url, "https://example.com/hook?key=123");
+        assert!(webhook.filter.is_empty());
+
+        let webhook: WebHookUrl =
+            "https://example.com/hook#confirmed,wallet=2h5fntt3,category=TxoFunded"
+                .parse()
+                .unwrap();
+        assert_eq!(webhook.url, "https://example.com/hook");
+        assert_eq!(webhook.filter.confirmed, Some(true));
+        assert_eq!(webhook.filter.wallets.len(), 1);
+        assert_eq!(webhook.filter.categories, vec!["TxoFunded".to_string()]);
 
-        // TODO implement filter support
-        self.tx.send(changelog.to_vec()).unwrap();
+        assert!("https://example.com/hook#foo"
+            .parse::<WebHookUrl>()
+            .is_err());
+        assert!("https://example.com/hook#wallet=invalid"
+            .parse::<WebHookUrl>()
+            .is_err());
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use crate::error::{Context, Result};
+use crate::util::{join_timeout, SHUTDOWN_TIMEOUT};
+
+// the bitcoind notification topics that trigger a sync. subscribing to topics that the
+// endpoint does not publish is harmless, they will simply never arrive.
+const TOPICS: &[&str] = &["hashblock", "hashtx", "rawblock", "rawtx"];
+
+// how often the subscriber thread wakes up to check for the shutdown signal while idle
+const RECV_TIMEOUT_MS: i32 = 500;
+
+pub struct ZmqListener {
+    thread: Option<thread::JoinHandle<()>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl ZmqListener {
+    // Spawn a ZMQ subscriber that triggers an indexer sync whenever bitcoind announces a new block
+    // or transaction on one of the `endpoints` (configured in bitcoind using `zmqpub<topic>=<endpoint>`)
+    pub fn start(endpoints: Vec<String>, sync_tx: mpsc::Sender<()>) -> Result<Self> {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::SUB)?;
+        for endpoint in &endpoints {
+            info!("subscribing to bitcoind zmq notifications on {}", endpoint);
+            socket
+                .connect(endpoint)
+                .with_context(|| format!("invalid zmq endpoint {}", endpoint))?;
+        }
+        for topic in TOPICS {
+            socket.set_subscribe(topic.as_bytes())?;
+        }
+        socket.set_rcvtimeo(RECV_TIMEOUT_MS)?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_ = shutdown.clone();
+
+        let thread = thread::spawn(move || {
+            while !shutdown_.load(Ordering::Relaxed) {
+                match socket.recv_multipart(0) {
+                    Ok(parts) => {
+                        trace!(
+                            "received {} notification via zmq",
+                            String::from_utf8_lossy(&parts[0])
+                        );
+                        if sync_tx.send(()).is_err() {
+                            break;
+                        }
+                    }
+                    // timed out waiting for a notification, check the shutdown signal and retry
+                    Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => continue,
+                    Err(e) => {
+                        warn!("zmq subscriber failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            trace!("zmq listener shutting down");
+        });
+
+        Ok(ZmqListener {
+            thread: Some(thread),
+            shutdown,
+        })
+    }
+
+    /// Signal the subscriber thread to stop and wait for it to terminate
+    pub fn shutdown(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            join_timeout(thread, SHUTDOWN_TIMEOUT, "zmq listener");
+        }
+    }
+}
+
+impl Drop for ZmqListener {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
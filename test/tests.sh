@@ -11,6 +11,10 @@ NO_WATCH=1
 INTERVAL=1
 PRINT_LOGS=1
 
+# Track a 1-of-2 sorted multisig wallet, with the keys not provided in their sorted order
+MULTISIG_DESC="wsh(sortedmulti(1,tpubD6NzVbkrYhZ4WW4otLf83tsgmPFhs3La31J4rFXATYPJhRjjToQBQW9rLox3rMnmyeL63WmrXgjf5fFKKy4cU5onY93nh8W3TvauiVf4xEW/0/*,tpubD6NzVbkrYhZ4X92JdPN67j4RafKfwpTpkNSjrk9Upe5BYLkvyHDfkmMnstPB3CwaXevn9RJbhampi34xqNXCPznGvzYV3w1nwpizqKik7di/0/*))"
+BWT_OPTS="$BWT_OPTS --descriptor $MULTISIG_DESC"
+
 # Start regtest, electrum wallet and bwt
 source scripts/setup-env.sh
 
@@ -44,6 +48,14 @@ if [[ $FEATURES == *"electrum"* ]]; then
   test `jq -r length <<< "$utxos"` == 2
   test `jq -r .[0].address <<< "$utxos"` == $addr
   test `jq -r '.[] | select(.height != 0) | .value' <<< "$utxos"` == 1.234
+
+  echo - Testing broadcast
+  rawtx=`btc createrawtransaction '[]' "[{\"$addr\":0.1}]"`
+  rawtx=`btc fundrawtransaction $rawtx | jq -r .hex`
+  # unsigned transactions should be rejected
+  if ele1 broadcast $rawtx &> /dev/null; then echo unsigned tx was accepted; exit 1; fi
+  rawtx=`btc signrawtransactionwithwallet $rawtx | jq -r .hex`
+  test `ele1 broadcast $rawtx` == `btc decoderawtransaction $rawtx | jq -r .txid`
 fi
 
 # Test HTTP API
@@ -90,6 +102,59 @@ if [[ $FEATURES == *"http"* ]]; then
   txid=`jq -r .[0] <<< "$evt_Transaction"`
   test `get_jq .funding[0].amount /tx/$txid` == 977700000
   test `jq -r .[0] <<< "$evt_TxoFunded" | cut -d: -f1` == $txid
+
+  echo - Testing txs with OP_RETURN outputs
+  rawtx=`btc createrawtransaction '[]' "[{\"$addr\":0.5},{\"data\":\"6277740a\"}]"`
+  rawtx=`btc fundrawtransaction $rawtx | jq -r .hex`
+  rawtx=`btc signrawtransactionwithwallet $rawtx | jq -r .hex`
+  txid=`btc sendrawtransaction $rawtx`
+  sleep 1
+  tx=`get /tx/$txid`
+  test `jq -r '.funding | length' <<< "$tx"` == 1
+  test `jq -r .funding[0].address <<< "$tx"` == $addr
+  test `jq -r .balance_change <<< "$tx"` == 50000000
+  test `get_jq "[.[] | select(.txid == \"$txid\")] | length" /address/$addr/utxos` == 1
+
+  echo - Testing sortedmulti descriptors
+  multisig_checksum=`btc getdescriptorinfo "$MULTISIG_DESC" | jq -r .checksum`
+  # derive the addresses using bitcoind, which sorts the keys independently of bwt
+  multisig_addrs=`btc deriveaddresses "$MULTISIG_DESC#$multisig_checksum" "[0,4]"`
+  for i in `seq 0 4`; do
+    test `get_jq .address /wallet/$multisig_checksum/$i` == `jq -r .[$i] <<< "$multisig_addrs"`
+  done
+  multisig_addr=`jq -r .[3] <<< "$multisig_addrs"`
+  btc sendtoaddress $multisig_addr 0.25 > /dev/null
+  sleep 1
+  test `get_jq .origin /address/$multisig_addr` == "$multisig_checksum/3"
+  test `get_jq .unconfirmed_balance /address/$multisig_addr/stats` == 25000000
+  test `get_jq .max_funded_index /wallet/$multisig_checksum` == 3
+
+  echo - Testing /tx/:txid/merkle
+  # confirm the transaction in a block along with the other pending ones
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  sleep 1
+  block=`btc getblock $(btc getbestblockhash)`
+  test `jq -r '.tx | length' <<< "$block"` -gt 2
+  proof=`get /tx/$txid/merkle`
+  test `jq -r .block_height <<< "$proof"` == `jq -r .height <<< "$block"`
+  test `jq -r .pos <<< "$proof"` == `jq -r ".tx | index(\"$txid\")" <<< "$block"`
+  test `jq -r '.merkle | length' <<< "$proof"` -gt 0
+
+  echo - Testing reorg handling
+  # confirm a transaction, orphan its block and check that it goes back to being unconfirmed
+  txid=`btc sendtoaddress $addr 0.3`
+  blockhash=`btc generatetoaddress 1 $(btc getnewaddress) | jq -r .[0]`
+  height=`btc getblockcount`
+  sleep 1
+  test `get_jq .block_height /tx/$txid` == $height
+  btc invalidateblock $blockhash
+  sleep 1
+  test `get_jq .block_height /tx/$txid` == null
+  test `get_jq '.[] | select(.txid == "'$txid'") | .block_height' /address/$addr/txs` == null
+  # mine a competing chain and check that the transaction gets re-confirmed on it
+  btc generatetoaddress 2 `btc getnewaddress` > /dev/null
+  sleep 1
+  test `get_jq .block_height /tx/$txid` == $height
 fi
 
 echo -e "\e[32mAll tests pass.\e[0m"